@@ -0,0 +1,171 @@
+//! Benchmarks for the stages of building an index: reading documents,
+//! indexing a single document, merging in-memory indexes, writing an index to
+//! disk, and merging index files.
+//!
+//! The corpus is generated on the fly, so there are no fixtures to download.
+//! Run with `cargo bench`.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use fingertips::index::{AsciiTokenizer, DefaultTokenizer, InMemoryIndex, TermOrder};
+use fingertips::merge::FileMerge;
+use fingertips::tmp::TmpDir;
+use fingertips::write::write_index_to_tmp_file;
+
+/// Number of distinct words in the synthetic corpus.
+const VOCABULARY_SIZE: u64 = 5_000;
+
+/// Generate a document of `nwords` pseudo-random words. The same `seed`
+/// always produces the same document.
+///
+/// Word frequencies are skewed toward the start of the vocabulary, roughly
+/// like real text, where a few words are very common and most are rare.
+fn synthetic_document(seed: u64, nwords: usize) -> String {
+    let mut state = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+    let mut text = String::new();
+    for _ in 0..nwords {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let r = (state >> 33) % VOCABULARY_SIZE;
+        let word = r * r / VOCABULARY_SIZE;
+        text.push_str(&format!("w{} ", word));
+    }
+    text
+}
+
+/// Index `ndocs` synthetic documents, each in its own `InMemoryIndex`.
+fn synthetic_indexes(ndocs: usize, nwords: usize) -> Vec<InMemoryIndex> {
+    (0..ndocs)
+        .map(|i| InMemoryIndex::from_single_document(
+            i, &synthetic_document(i as u64, nwords), &DefaultTokenizer))
+        .collect()
+}
+
+/// Index `ndocs` synthetic documents into a single `InMemoryIndex`, numbering
+/// them starting at `first_id`.
+fn synthetic_index(first_id: usize, ndocs: usize, nwords: usize) -> InMemoryIndex {
+    let mut index = InMemoryIndex::new();
+    for i in first_id..first_id + ndocs {
+        let text = synthetic_document(i as u64, nwords);
+        index.merge(InMemoryIndex::from_single_document(i, &text, &DefaultTokenizer), None);
+    }
+    index
+}
+
+/// A fresh scratch directory for index files.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("fingertips-bench-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn bench_read_files(c: &mut Criterion) {
+    let dir = scratch_dir("read");
+    let files: Vec<PathBuf> = (0..1_000)
+        .map(|i| {
+            let path = dir.join(format!("{}.txt", i));
+            fs::write(&path, synthetic_document(i, 100)).unwrap();
+            path
+        })
+        .collect();
+
+    c.bench_function("read 1000 small files (new String each)", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            for path in &files {
+                let mut text = String::new();
+                File::open(path).unwrap().read_to_string(&mut text).unwrap();
+                total += text.len();
+            }
+            total
+        })
+    });
+    c.bench_function("read 1000 small files (reused String)", |b| {
+        b.iter(|| {
+            let mut total = 0;
+            let mut text = String::new();
+            for path in &files {
+                text.clear();
+                File::open(path).unwrap().read_to_string(&mut text).unwrap();
+                total += text.len();
+            }
+            total
+        })
+    });
+    fs::remove_dir_all(dir).unwrap();
+}
+
+fn bench_single_document(c: &mut Criterion) {
+    let text = synthetic_document(0, 10_000);
+    c.bench_function("from_single_document (10k words)", |b| {
+        b.iter(|| InMemoryIndex::from_single_document(0, &text, &DefaultTokenizer))
+    });
+    c.bench_function("from_single_document (10k words, AsciiTokenizer)", |b| {
+        b.iter(|| InMemoryIndex::from_single_document(0, &text, &AsciiTokenizer))
+    });
+}
+
+fn bench_in_memory_merge(c: &mut Criterion) {
+    c.bench_function("InMemoryIndex::merge (100 docs)", |b| {
+        b.iter_batched(|| synthetic_indexes(100, 1_000),
+                       |indexes| {
+                           let mut accumulated = InMemoryIndex::new();
+                           for index in indexes {
+                               accumulated.merge(index, None);
+                           }
+                           accumulated
+                       },
+                       BatchSize::LargeInput)
+    });
+}
+
+fn bench_write(c: &mut Criterion) {
+    let dir = scratch_dir("write");
+    let mut tmp_dir = TmpDir::new(&dir);
+    c.bench_function("write_index_to_tmp_file (100 docs)", |b| {
+        b.iter_batched(|| synthetic_index(0, 100, 1_000),
+                       |mut index| {
+                           let file = write_index_to_tmp_file(&mut index, &mut tmp_dir,
+                                                              &TermOrder::bytes()).unwrap();
+                           fs::remove_file(file).unwrap();
+                       },
+                       BatchSize::LargeInput)
+    });
+    fs::remove_dir_all(dir).unwrap();
+}
+
+fn bench_file_merge(c: &mut Criterion) {
+    let dir = scratch_dir("merge");
+    let mut tmp_dir = TmpDir::new(&dir);
+    c.bench_function("FileMerge (16 files of 20 docs)", |b| {
+        b.iter_batched(|| {
+                           (0..16)
+                               .map(|i| {
+                                   let mut index = synthetic_index(i * 20, 20, 1_000);
+                                   write_index_to_tmp_file(&mut index, &mut tmp_dir,
+                                                           &TermOrder::bytes()).unwrap()
+                               })
+                               .collect::<Vec<_>>()
+                       },
+                       |files| {
+                           let mut merge = FileMerge::new(&dir, None);
+                           for file in files {
+                               merge.add_file(file).unwrap();
+                           }
+                           merge.finish().unwrap();
+                       },
+                       BatchSize::PerIteration)
+    });
+    fs::remove_dir_all(dir).unwrap();
+}
+
+criterion_group!(benches,
+                 bench_read_files,
+                 bench_single_document,
+                 bench_in_memory_merge,
+                 bench_write,
+                 bench_file_merge);
+criterion_main!(benches);
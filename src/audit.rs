@@ -0,0 +1,101 @@
+//! Checking the terms in a finished index for signs of messy data.
+//!
+//! An index built with `--case-sensitive` keeps `Rust`, `rust`, and `RUST` as
+//! three different terms. Sometimes that's the point, but often it just
+//! scatters the hits for one word across several terms, and a search for any
+//! one spelling misses the rest. `case_variants` finds those terms, to help
+//! decide whether the index should have been lowercased after all.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, SeekFrom};
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::index::CaseLocale;
+use crate::read::{read_entry, read_header};
+
+/// Terms in an index that are the same word once lowercased.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CaseVariants {
+    /// The lowercase form they all share.
+    pub folded: String,
+
+    /// Each of the terms, with the number of documents it appears in, in term
+    /// order.
+    pub terms: Vec<(String, u32)>
+}
+
+/// Find the terms in the index file `filename` that differ only in case,
+/// like `"Rust"` and `"rust"`. Returns a group for each set of such terms,
+/// in order by their lowercase form. Terms are compared after lowercasing
+/// them with the default rules (see `CaseLocale::Default`).
+///
+/// In an index that was lowercased when it was built, which is every index
+/// not built with `--case-sensitive`, this finds nothing.
+///
+/// Terms that differ only in Unicode normalization form, like `é` written as
+/// one code point or as `e` followed by a combining accent, aren't found:
+/// telling those apart takes the Unicode normalization tables, which this
+/// crate doesn't have.
+///
+/// Only the table of contents is read, not the hits.
+pub fn case_variants<P: AsRef<Path>>(filename: P) -> io::Result<Vec<CaseVariants>> {
+    let mut file = File::open(filename)?;
+    let header = read_header(&mut file)?;
+    file.seek(SeekFrom::Start(header.contents_offset))?;
+    let mut contents = BufReader::new(file);
+
+    let mut by_folded: HashMap<String, Vec<(String, u32)>> = HashMap::new();
+    while let Some(entry) = read_entry(&mut contents, header.spellings)? {
+        by_folded.entry(CaseLocale::Default.lowercase(&entry.term))
+            .or_default()
+            .push((entry.term, entry.df));
+    }
+    let mut groups: Vec<CaseVariants> = by_folded.into_iter()
+        .filter(|(_, terms)| terms.len() > 1)
+        .map(|(folded, mut terms)| {
+            terms.sort();
+            CaseVariants { folded, terms }
+        })
+        .collect();
+    groups.sort_by(|a, b| a.folded.cmp(&b.folded));
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::index::{DefaultTokenizer, LocaleTokenizer};
+    use crate::test_util::{build_index, scratch_dir};
+
+    const DOCS: &[&str] = &["Rust is fast", "rust and RUST", "Go is fast", "go Rust"];
+
+    #[test]
+    fn finds_terms_that_differ_only_in_case() {
+        let dir = scratch_dir("audit-case");
+        let tokenizer = LocaleTokenizer::new(Box::new(DefaultTokenizer), CaseLocale::Unchanged);
+        let groups = case_variants(build_index(&dir, 0, DOCS, &tokenizer)).unwrap();
+        assert_eq!(groups, vec![
+            CaseVariants {
+                folded: "go".to_string(),
+                terms: vec![("Go".to_string(), 1), ("go".to_string(), 1)]
+            },
+            CaseVariants {
+                folded: "rust".to_string(),
+                terms: vec![("RUST".to_string(), 1), ("Rust".to_string(), 2),
+                            ("rust".to_string(), 1)]
+            }
+        ]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lowercased_index_has_no_case_variants() {
+        let dir = scratch_dir("audit-folded");
+        let groups = case_variants(build_index(&dir, 0, DOCS, &DefaultTokenizer)).unwrap();
+        assert!(groups.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,507 @@
+//! Compressing the hit data in index files.
+//!
+//! Every number in a `Hit` is stored as a 4-byte integer, but most of them
+//! are small: frequencies are usually 1 or 2, and each offset is usually only
+//! a little bigger than the one before it. Each `Codec` other than `Raw` takes
+//! advantage of that in its own way:
+//!
+//! *   `Varint` stores each number as a variable-length integer (7 bits per
+//!     byte, low bits first, with the high bit set on every byte but the
+//!     last); each document id as the difference from the previous hit's;
+//!     and each offset as the difference from the previous offset in the
+//!     same hit. This is simple and fast, and typically makes the data less
+//!     than half the size.
+//!
+//! *   `FrameOfReference` splits the hits into three lists of numbers: the
+//!     gaps between document ids, the frequencies, and the gaps between
+//!     offsets. Each list is cut into blocks of up to 128 numbers. A block is
+//!     stored as its smallest number, then every number minus that one,
+//!     using just as many bits as the biggest of them needs. This is slower,
+//!     but smaller still for long posting lists.
+//!
+//! An index built without frequencies stores only the document id of each
+//! hit (see `Codec::compress_doc_ids`), which the codecs compress the same
+//! way, as gaps.
+//!
+//! Storing differences between document ids works because the hits for a
+//! term are always in increasing order by document id (see `PostingList`).
+//! Compressing hits that aren't is an error.
+
+use std::io;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use crate::index::{HIT_HEADER_SIZE, hit_document_id, hit_frequency};
+
+/// The error for hits that aren't in increasing order by document id.
+fn out_of_order() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "hits are not in order by document id")
+}
+
+/// The number of values in each `FrameOfReference` block.
+const BLOCK_LEN: usize = 128;
+
+/// A way of storing the hit data for each term in an index file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Uncompressed, exactly as in memory.
+    Raw,
+    Varint,
+    FrameOfReference
+}
+
+impl Codec {
+    /// Look up a codec by the name used on the command line: `raw`,
+    /// `varint`, or `for`.
+    pub fn from_name(name: &str) -> io::Result<Codec> {
+        match name {
+            "raw" => Ok(Codec::Raw),
+            "varint" => Ok(Codec::Varint),
+            "for" => Ok(Codec::FrameOfReference),
+            _ => Err(io::Error::other(format!("unknown codec: {}", name)))
+        }
+    }
+
+    /// The number that identifies this codec in an index file header.
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::Raw => 0,
+            Codec::Varint => 1,
+            Codec::FrameOfReference => 2
+        }
+    }
+
+    /// The codec with the given `id`.
+    pub fn from_id(id: u8) -> io::Result<Codec> {
+        match id {
+            0 => Ok(Codec::Raw),
+            1 => Ok(Codec::Varint),
+            2 => Ok(Codec::FrameOfReference),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("index file uses unknown codec {}", id)))
+        }
+    }
+
+    /// Encode `hits`, the hit data for one term, appending the result to
+    /// `out`.
+    pub fn compress(self, hits: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Codec::Raw => {
+                out.extend_from_slice(hits);
+                Ok(())
+            }
+            Codec::Varint => compress_varint(hits, out),
+            Codec::FrameOfReference => compress_for(hits, out)
+        }
+    }
+
+    /// Undo `compress`, appending the original hit data to `out`.
+    pub fn decompress(self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Codec::Raw => {
+                out.extend_from_slice(data);
+                Ok(())
+            }
+            Codec::Varint => decompress_varint(data, out),
+            Codec::FrameOfReference => decompress_for(data, out)
+        }
+    }
+
+    /// Encode `ids`, the document ids of the hits for one term (4 bytes each),
+    /// appending the result to `out`. This is how index files built without
+    /// frequencies store their hits (see `IndexFileWriter::drop_frequencies`):
+    /// `Varint` stores the gaps between the ids, and `FrameOfReference` stores
+    /// the number of ids, then the gaps in blocks.
+    pub fn compress_doc_ids(self, ids: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if self == Codec::Raw {
+            out.extend_from_slice(ids);
+            return Ok(());
+        }
+        if !ids.len().is_multiple_of(4) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a document id"));
+        }
+        let mut gaps = Vec::with_capacity(ids.len() / 4);
+        let mut previous = None;
+        for id in ids.chunks_exact(4) {
+            let id = LittleEndian::read_u32(id);
+            gaps.push(match previous {
+                None => id,
+                Some(previous) if id > previous => id - previous,
+                Some(_) => return Err(out_of_order())
+            });
+            previous = Some(id);
+        }
+        if self == Codec::Varint {
+            for gap in gaps {
+                write_varint(out, gap);
+            }
+        } else if !gaps.is_empty() {
+            write_varint(out, gaps.len() as u32);
+            write_blocks(&gaps, out);
+        }
+        Ok(())
+    }
+
+    /// Undo `compress_doc_ids`, appending the document ids to `out`.
+    pub fn decompress_doc_ids(self, mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        let mut gaps = vec![];
+        match self {
+            Codec::Raw => {
+                out.extend_from_slice(data);
+                return Ok(());
+            }
+            Codec::Varint => {
+                while !data.is_empty() {
+                    gaps.push(read_varint(&mut data)?);
+                }
+            }
+            Codec::FrameOfReference => {
+                if !data.is_empty() {
+                    let n = read_varint(&mut data)? as usize;
+                    read_blocks(&mut data, n, &mut gaps)?;
+                    if !data.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "bad compressed hit data"));
+                    }
+                }
+            }
+        }
+        let mut document_id = None;
+        for gap in gaps {
+            let id = next_document_id(document_id, gap)?;
+            document_id = Some(id);
+            out.write_u32::<LittleEndian>(id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Call `f` with the gap between each hit's document id and the previous
+/// hit's (for the first hit, the document id itself), and the hit's frequency
+/// and offsets (still as bytes).
+fn for_each_hit<F>(mut hits: &[u8], mut f: F) -> io::Result<()>
+    where F: FnMut(u32, u32, &[u8])
+{
+    let mut previous = None;
+    while !hits.is_empty() {
+        if hits.len() < HIT_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a hit"));
+        }
+        let frequency = hit_frequency(hits);
+        let len = HIT_HEADER_SIZE + 4 * frequency as usize;
+        if hits.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a hit"));
+        }
+        let document_id = hit_document_id(hits);
+        let gap = match previous {
+            None => document_id,
+            Some(previous) if document_id > previous => document_id - previous,
+            Some(_) => return Err(out_of_order())
+        };
+        previous = Some(document_id);
+        f(gap, frequency, &hits[HIT_HEADER_SIZE..len]);
+        hits = &hits[len..];
+    }
+    Ok(())
+}
+
+fn compress_varint(hits: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    for_each_hit(hits, |document_gap, frequency, offsets| {
+        write_varint(out, document_gap);
+        write_varint(out, frequency);
+        let mut previous = 0u32;
+        for offset in offsets.chunks_exact(4) {
+            let offset = LittleEndian::read_u32(offset);
+            write_varint(out, offset.wrapping_sub(previous));
+            previous = offset;
+        }
+    })
+}
+
+fn decompress_varint(mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let mut document_id = None;
+    while !data.is_empty() {
+        let gap = read_varint(&mut data)?;
+        let id = next_document_id(document_id, gap)?;
+        document_id = Some(id);
+        let frequency = read_varint(&mut data)?;
+        out.write_u32::<LittleEndian>(id)?;
+        out.write_u32::<LittleEndian>(frequency)?;
+        let mut offset = 0u32;
+        for _ in 0..frequency {
+            offset = offset.wrapping_add(read_varint(&mut data)?);
+            out.write_u32::<LittleEndian>(offset)?;
+        }
+    }
+    Ok(())
+}
+
+fn compress_for(hits: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let mut document_gaps = vec![];
+    let mut frequencies = vec![];
+    let mut offset_gaps = vec![];
+    for_each_hit(hits, |document_gap, frequency, offsets| {
+        document_gaps.push(document_gap);
+        frequencies.push(frequency);
+        let mut previous = 0u32;
+        for offset in offsets.chunks_exact(4) {
+            let offset = LittleEndian::read_u32(offset);
+            offset_gaps.push(offset.wrapping_sub(previous));
+            previous = offset;
+        }
+    })?;
+    write_varint(out, document_gaps.len() as u32);
+    write_blocks(&document_gaps, out);
+    write_blocks(&frequencies, out);
+    write_blocks(&offset_gaps, out);
+    Ok(())
+}
+
+fn decompress_for(mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let nhits = read_varint(&mut data)? as usize;
+    let mut document_gaps = vec![];
+    read_blocks(&mut data, nhits, &mut document_gaps)?;
+    let mut frequencies = vec![];
+    read_blocks(&mut data, nhits, &mut frequencies)?;
+    let noffsets = frequencies.iter().map(|&f| f as usize).sum();
+    let mut offset_gaps = vec![];
+    read_blocks(&mut data, noffsets, &mut offset_gaps)?;
+    if !data.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad compressed hit data"));
+    }
+
+    let mut offset_gaps = offset_gaps.into_iter();
+    let mut document_id = None;
+    for (gap, frequency) in document_gaps.into_iter().zip(frequencies) {
+        let id = next_document_id(document_id, gap)?;
+        document_id = Some(id);
+        out.write_u32::<LittleEndian>(id)?;
+        out.write_u32::<LittleEndian>(frequency)?;
+        let mut offset = 0u32;
+        for gap in offset_gaps.by_ref().take(frequency as usize) {
+            offset = offset.wrapping_add(gap);
+            out.write_u32::<LittleEndian>(offset)?;
+        }
+    }
+    Ok(())
+}
+
+/// The document id that's `gap` after `previous`, the previous hit's; or, for
+/// the first hit, just `gap`. Gaps after the first hit must be at least 1.
+fn next_document_id(previous: Option<u32>, gap: u32) -> io::Result<u32> {
+    match previous {
+        None => Ok(gap),
+        Some(_) if gap == 0 => Err(out_of_order()),
+        Some(previous) => previous.checked_add(gap).ok_or_else(out_of_order)
+    }
+}
+
+/// Write `values` as frame-of-reference blocks: for each block, the smallest
+/// value, the number of bits per value, and then each value minus the
+/// smallest, packed together, low bits first.
+fn write_blocks(values: &[u32], out: &mut Vec<u8>) {
+    for block in values.chunks(BLOCK_LEN) {
+        let min = *block.iter().min().unwrap();
+        let bits = block.iter().map(|&v| 32 - (v - min).leading_zeros()).max().unwrap();
+        write_varint(out, min);
+        out.push(bits as u8);
+        let mut acc = 0u64;
+        let mut nbits = 0;
+        for &v in block {
+            acc |= ((v - min) as u64) << nbits;
+            nbits += bits;
+            while nbits >= 8 {
+                out.push(acc as u8);
+                acc >>= 8;
+                nbits -= 8;
+            }
+        }
+        if nbits > 0 {
+            out.push(acc as u8);
+        }
+    }
+}
+
+/// Read `n` values written by `write_blocks` from the front of `data`,
+/// appending them to `values` and advancing `data` past them.
+fn read_blocks(data: &mut &[u8], n: usize, values: &mut Vec<u32>) -> io::Result<()> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, "bad compressed hit data");
+    let mut remaining = n;
+    while remaining > 0 {
+        let len = remaining.min(BLOCK_LEN);
+        let min = read_varint(data)?;
+        let (&bits, rest) = data.split_first().ok_or_else(bad)?;
+        if bits > 32 {
+            return Err(bad());
+        }
+        let nbytes = (len * bits as usize).div_ceil(8);
+        if rest.len() < nbytes {
+            return Err(bad());
+        }
+        let (packed, rest) = rest.split_at(nbytes);
+        let mask = (1u64 << bits) - 1;
+        let mut bytes = packed.iter();
+        let mut acc = 0u64;
+        let mut nbits = 0;
+        for _ in 0..len {
+            while nbits < bits {
+                acc |= (*bytes.next().unwrap() as u64) << nbits;
+                nbits += 8;
+            }
+            values.push(min.wrapping_add((acc & mask) as u32));
+            acc >>= bits;
+            nbits -= bits;
+        }
+        *data = rest;
+        remaining -= len;
+    }
+    Ok(())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: u32) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Read a variable-length integer from the front of `data`, advancing `data`
+/// past it.
+fn read_varint(data: &mut &[u8]) -> io::Result<u32> {
+    let mut n = 0u32;
+    for (i, &byte) in data.iter().enumerate().take(5) {
+        n |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            *data = &data[i + 1..];
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "bad compressed hit data"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::PostingList;
+
+    const CODECS: [Codec; 3] = [Codec::Raw, Codec::Varint, Codec::FrameOfReference];
+
+    /// The hit data for hits in the documents `document_ids`, each with the
+    /// given `offsets`.
+    fn hits(document_ids: impl IntoIterator<Item = u32>, offsets: &[u32]) -> Vec<u8> {
+        let mut out = vec![];
+        for document_id in document_ids {
+            out.write_u32::<LittleEndian>(document_id).unwrap();
+            out.write_u32::<LittleEndian>(offsets.len() as u32).unwrap();
+            for &offset in offsets {
+                out.write_u32::<LittleEndian>(offset).unwrap();
+            }
+        }
+        out
+    }
+
+    fn compressed(codec: Codec, hits: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        codec.compress(hits, &mut data).unwrap();
+        data
+    }
+
+    fn assert_round_trip(hits: &[u8]) {
+        for codec in CODECS {
+            let mut out = vec![];
+            codec.decompress(&compressed(codec, hits), &mut out).unwrap();
+            assert_eq!(out, hits, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        assert_round_trip(&[]);
+    }
+
+    #[test]
+    fn round_trip_one_hit() {
+        assert_round_trip(&hits([17], &[3, 4, 90]));
+        assert_round_trip(&hits([0], &[]));
+    }
+
+    #[test]
+    fn round_trip_block_boundaries() {
+        let len = BLOCK_LEN as u32;
+        assert_round_trip(&hits((0..len).map(|i| i * 3), &[1, 5]));
+        assert_round_trip(&hits((0..len + 1).map(|i| i * 3), &[1, 5]));
+        // The offsets list is a block and one more.
+        let offsets: Vec<u32> = (0..len + 1).map(|i| i * i).collect();
+        assert_round_trip(&hits([2], &offsets));
+    }
+
+    #[test]
+    fn round_trip_zero_bits() {
+        // Every gap and every frequency in each block is the same, so the
+        // blocks need 0 bits per value.
+        let data = hits((1..=10).map(|i| i * 2), &[7]);
+        assert_round_trip(&data);
+        assert!(compressed(Codec::FrameOfReference, &data).len() < 12);
+    }
+
+    #[test]
+    fn round_trip_32_bits() {
+        assert_round_trip(&hits([0, u32::MAX - 1], &[0, u32::MAX]));
+        assert_round_trip(&hits([u32::MAX], &[]));
+    }
+
+    #[test]
+    fn truncated_data_is_invalid() {
+        // Cutting the data off exactly between two hits leaves valid data with
+        // fewer hits, for the raw and varint codecs; readers catch that by
+        // checking the entry's size. So these cuts all land mid-hit.
+        let data = hits((0..300).map(|i| i * 5), &[1, 2, 300]);
+        for codec in CODECS {
+            let stored = compressed(codec, &data);
+            for cut in [1, 2, 7, 13] {
+                let err = PostingList::decode(codec, &stored[..stored.len() - cut]).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?} cut by {}", codec, cut);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_sizes() {
+        // Ten thousand documents, each with a few nearby offsets: typical of
+        // a common word.
+        let data = hits((0..10_000).map(|i| i * 3), &[12, 40, 41, 300]);
+        let sizes: Vec<usize> =
+            CODECS.iter().map(|&codec| compressed(codec, &data).len()).collect();
+        assert!(sizes[2] < sizes[1] && sizes[1] < sizes[0],
+                "raw {} bytes, varint {} bytes, for {} bytes", sizes[0], sizes[1], sizes[2]);
+    }
+
+    #[test]
+    fn round_trip_document_ids() {
+        let len = BLOCK_LEN as u32;
+        for ids in [vec![], vec![0], vec![7, 8, u32::MAX], (0..len + 1).map(|i| i * 3).collect()] {
+            let data: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+            for codec in CODECS {
+                let mut stored = vec![];
+                codec.compress_doc_ids(&data, &mut stored).unwrap();
+                let mut out = vec![];
+                codec.decompress_doc_ids(&stored, &mut out).unwrap();
+                assert_eq!(out, data, "{:?}", codec);
+                if codec != Codec::Raw && ids.len() > BLOCK_LEN {
+                    assert!(stored.len() < data.len(), "{:?}", codec);
+                }
+            }
+        }
+
+        let mut stored = vec![];
+        let err = Codec::Varint.compress_doc_ids(&[5, 0, 0, 0, 5, 0, 0, 0], &mut stored)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,176 @@
+//! The error type for opening, reading, and searching index files.
+
+use std::error;
+use std::fmt;
+use std::io;
+use crate::query::ParseError;
+use crate::write::FORMAT_VERSION;
+
+/// What went wrong with an index file or a query.
+///
+/// Most of the crate works in terms of `io::Error`, and converts to this at
+/// the entry points where it matters what kind of failure it was, like
+/// `search::Index::open`. The conversion goes both ways: an `Error` turned
+/// into an `io::Error` (of kind `InvalidData`, or `InvalidInput` for a query)
+/// and back is the same `Error` again. Any other `io::Error` of kind
+/// `InvalidData` or `UnexpectedEof` becomes `Corrupt`, since those come from
+/// reading damaged or truncated files.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing failed, for reasons that have nothing to do with
+    /// what's in the file: it isn't there, say, or the disk is full.
+    Io(io::Error),
+
+    /// The file isn't an index file at all, or it's one written by a version
+    /// of fingertips from before index files had a version number.
+    Format(String),
+
+    /// The file is an index file, but in a version of the format that this
+    /// crate can't read (see `write::FORMAT_VERSION`).
+    UnsupportedVersion(u32),
+
+    /// A query string couldn't be parsed.
+    QueryParse(ParseError),
+
+    /// The file is an index file, but it's damaged or cut short.
+    Corrupt(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "{}", err),
+            Error::Format(ref message) => write!(f, "{}", message),
+            Error::UnsupportedVersion(version) =>
+                write!(f, "index file is in format version {}, but only version {} is \
+                           supported; rebuild the index with this version of fingertips",
+                       version, FORMAT_VERSION),
+            Error::QueryParse(ref err) => write!(f, "{}", err),
+            Error::Corrupt(ref message) => write!(f, "{}", message)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::QueryParse(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        let kind = err.kind();
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        match kind {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof =>
+                Error::Corrupt(err.to_string()),
+            _ => Error::Io(err)
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::QueryParse(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::QueryParse(_) => io::Error::new(io::ErrorKind::InvalidInput, err),
+            _ => io::Error::new(io::ErrorKind::InvalidData, err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::export::export_csv;
+    use crate::index::DefaultTokenizer;
+    use crate::merge::merge_files;
+    use crate::read::IndexFileReader;
+    use crate::search::{search, Index, SearchOptions};
+    use crate::test_util::{build_index, scratch_dir};
+
+    #[test]
+    fn missing_file_is_io() {
+        let dir = scratch_dir("error-missing");
+        match Index::open(dir.join("nope.dat")) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+            other => panic!("{:?}", other.err())
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn not_an_index_file_is_format() {
+        let dir = scratch_dir("error-format");
+        let filename = dir.join("notes.txt");
+        fs::write(&filename, "these are not the hits you're looking for").unwrap();
+        assert!(matches!(Index::open(&filename), Err(Error::Format(_))));
+        assert!(matches!(IndexFileReader::open(&filename), Err(Error::Format(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_version_is_unsupported() {
+        let dir = scratch_dir("error-version");
+        let filename = build_index(&dir, 0, &["red fish"], &DefaultTokenizer);
+        let mut bytes = fs::read(&filename).unwrap();
+        bytes[4] = 99;
+        fs::write(&filename, bytes).unwrap();
+        assert!(matches!(Index::open(&filename), Err(Error::UnsupportedVersion(99))));
+        assert!(matches!(IndexFileReader::open(&filename),
+                         Err(Error::UnsupportedVersion(99))));
+        match merge_files(std::slice::from_ref(&filename), &dir.join("out.dat")) {
+            Err(err @ Error::UnsupportedVersion(99)) =>
+                assert!(err.to_string().contains("format version 99"), "{}", err),
+            other => panic!("{:?}", other.err())
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_file_is_corrupt() {
+        let dir = scratch_dir("error-corrupt");
+        let filename = build_index(&dir, 0, &["red fish", "blue fish"], &DefaultTokenizer);
+        let bytes = fs::read(&filename).unwrap();
+        fs::write(&filename, &bytes[..bytes.len() - 3]).unwrap();
+        // Opening reads only the header; the damage shows up on the first
+        // lookup.
+        let index = Index::open(&filename).unwrap();
+        assert!(matches!(index.query(&["fish"]), Err(Error::Corrupt(_))));
+        assert!(matches!(index.count(&["fish", "red"]), Err(Error::Corrupt(_))));
+        assert!(matches!(export_csv(&filename, io::sink()), Err(Error::Corrupt(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_query_is_query_parse() {
+        let dir = scratch_dir("error-query");
+        let filename = build_index(&dir, 0, &["red fish"], &DefaultTokenizer);
+        let index = Index::open(&filename).unwrap();
+        let result = search(&index, "(red OR", &SearchOptions::default());
+        assert!(matches!(result, Err(Error::QueryParse(_))), "{:?}", result.err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn converting_through_io_error_keeps_the_variant() {
+        let err = io::Error::from(Error::UnsupportedVersion(7));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(Error::from(err), Error::UnsupportedVersion(7)));
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "nope");
+        assert!(matches!(Error::from(err), Error::Io(_)));
+    }
+}
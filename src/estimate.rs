@@ -0,0 +1,210 @@
+//! Estimating how big an index will be, without building it.
+//!
+//! Building the index for a big corpus can take hours and a lot of disk. To
+//! plan for that, `estimate_index_size` reads a sample of the documents and
+//! works out from them how big the finished index file would be.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use crate::index::Tokenizer;
+use crate::write::HEADER_SIZE;
+
+/// How big an index file for a set of documents is expected to be; see
+/// `estimate_index_size`.
+#[derive(Clone, Copy, Debug)]
+pub struct SizeEstimate {
+    /// The number of documents.
+    pub documents: usize,
+
+    /// How many of the documents were actually read to make the estimate.
+    pub sampled: usize,
+
+    /// The expected number of words in all the documents.
+    pub words: u64,
+
+    /// The expected number of distinct terms.
+    pub terms: u64,
+
+    /// The expected size of the index file, in bytes.
+    pub bytes: u64,
+
+    /// The smallest and largest of the sizes given by the two ways of
+    /// fitting the vocabulary's growth (see `estimate_index_size`). The
+    /// further apart they are, the less the estimate can be trusted. When
+    /// every document is sampled, both are just `bytes`, which is then exact.
+    pub low: u64,
+    pub high: u64
+}
+
+/// Estimate the size of the index file that would be built from `documents`
+/// with `tokenizer`, by reading up to `sample_size` of them, spread evenly
+/// through the list.
+///
+/// The number of words, and of (term, document) pairs, is assumed to grow in
+/// proportion to the number of bytes in the documents, which is known without
+/// reading them. The vocabulary grows more slowly: each new document has fewer
+/// words that haven't been seen before. This follows Heaps' law, that a text
+/// of `n` words has about `k * n^b` distinct ones, fitting `b` to how fast the
+/// vocabulary grows as the sample is read. Two fits, one from the first half
+/// of the sample and one from the second, give the range.
+///
+/// The estimate is for an index built with the default options: hits stored
+/// uncompressed, with frequencies and offsets, and without spellings. It's an
+/// error if a sampled document can't be read.
+pub fn estimate_index_size(documents: &[PathBuf], tokenizer: &dyn Tokenizer,
+                           sample_size: usize)
+    -> io::Result<SizeEstimate>
+{
+    let mut total_bytes = 0;
+    for path in documents {
+        total_bytes += fs::metadata(path)?.len();
+    }
+    let step = documents.len().div_ceil(sample_size.max(1)).max(1);
+
+    let mut sample = Sample::default();
+    let mut sampled = 0;
+    for path in documents.iter().step_by(step) {
+        let text = fs::read_to_string(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+        })?;
+        sample.add_document(&text, tokenizer);
+        sampled += 1;
+    }
+
+    // Scale up by bytes, since documents vary so much in size.
+    let scale = if sample.bytes == 0 { 0.0 } else { total_bytes as f64 / sample.bytes as f64 };
+    let words = (sample.words as f64 * scale).round() as u64;
+    let postings = (sample.postings as f64 * scale).round() as u64;
+    let (early, late) = sample.heaps_exponents();
+    let terms_with = |exponent: f64| sample.extrapolate_vocabulary(words, exponent);
+    let size_with = |terms: u64| {
+        index_file_size(words, postings, terms, terms as f64 * sample.average_term_len())
+    };
+    let terms = terms_with(late);
+    let (low, high) = (size_with(terms_with(early.min(late))),
+                       size_with(terms_with(early.max(late))));
+    Ok(SizeEstimate {
+        documents: documents.len(),
+        sampled,
+        words,
+        terms,
+        bytes: size_with(terms),
+        low,
+        high
+    })
+}
+
+/// The size of an uncompressed index file without spellings (see
+/// `IndexFileWriter`), with `words` words in all, `postings` (term, document)
+/// pairs, and `terms` distinct terms totaling `term_bytes` bytes.
+fn index_file_size(words: u64, postings: u64, terms: u64, term_bytes: f64) -> u64 {
+    // The header; then each hit's document id and frequency, and an offset
+    // for each word; then each term's table of contents entry.
+    HEADER_SIZE + 8 * postings + 4 * words + 24 * terms + term_bytes.round() as u64
+}
+
+/// What's been learned from the documents read so far.
+#[derive(Default)]
+struct Sample {
+    bytes: u64,
+    words: u64,
+    postings: u64,
+
+    /// Every distinct term seen, and their total length in bytes.
+    vocabulary: HashSet<String>,
+    vocabulary_bytes: u64,
+
+    /// The size of the vocabulary after 1 word, 2 words, 4 words, and so on,
+    /// for fitting Heaps' law.
+    growth: Vec<(u64, u64)>
+}
+
+impl Sample {
+    fn add_document(&mut self, text: &str, tokenizer: &dyn Tokenizer) {
+        self.bytes += text.len() as u64;
+        let text = tokenizer.lowercase(text);
+        let mut terms = HashSet::new();
+        for token in tokenizer.tokens(&text) {
+            self.words += 1;
+            if !self.vocabulary.contains(&*token) {
+                self.vocabulary_bytes += token.len() as u64;
+                self.vocabulary.insert(token.to_string());
+            }
+            if self.words.is_power_of_two() {
+                self.growth.push((self.words, self.vocabulary.len() as u64));
+            }
+            terms.insert(token);
+        }
+        self.postings += terms.len() as u64;
+    }
+
+    fn average_term_len(&self) -> f64 {
+        if self.vocabulary.is_empty() {
+            0.0
+        } else {
+            self.vocabulary_bytes as f64 / self.vocabulary.len() as f64
+        }
+    }
+
+    /// The exponent in Heaps' law, fitted to how the vocabulary grew over the
+    /// first half of the sample, and over the second half.
+    fn heaps_exponents(&self) -> (f64, f64) {
+        let end = (self.words, self.vocabulary.len() as u64);
+        let last_before = |n: u64| {
+            self.growth.iter().rev().find(|&&(words, _)| words <= n).copied()
+        };
+        let fit = |from: Option<(u64, u64)>, to: Option<(u64, u64)>| match (from, to) {
+            (Some((n1, v1)), Some((n2, v2))) if n1 < n2 && v1 > 0 => {
+                ((v2 as f64 / v1 as f64).ln() / (n2 as f64 / n1 as f64).ln()).clamp(0.0, 1.0)
+            }
+            // Too little text to tell; assume every word is new.
+            _ => 1.0
+        };
+        let (quarter, half) = (last_before(end.0 / 4), last_before(end.0 / 2));
+        (fit(quarter, half), fit(half, Some(end)))
+    }
+
+    /// The expected vocabulary of a text of `words` words, given that of the
+    /// sample, if it grows with the given exponent.
+    fn extrapolate_vocabulary(&self, words: u64, exponent: f64) -> u64 {
+        let sampled = self.vocabulary.len() as f64;
+        if self.words == 0 {
+            return 0;
+        }
+        (sampled * (words as f64 / self.words as f64).powf(exponent)).round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::DefaultTokenizer;
+    use crate::test_util::{build_index, scratch_dir};
+
+    #[test]
+    fn fully_sampled_estimate_matches_the_built_index() {
+        let dir = scratch_dir("estimate-full-sample");
+        let docs = ["one fish two fish", "red fish blue fish", "this one has a little star",
+                    "this one has a little car", "say what a lot of fish there are"];
+        let documents: Vec<PathBuf> = docs.iter().enumerate().map(|(i, text)| {
+            let path = dir.join(format!("{}.txt", i));
+            fs::write(&path, text).unwrap();
+            path
+        }).collect();
+
+        let estimate = estimate_index_size(&documents, &DefaultTokenizer, 100).unwrap();
+        assert_eq!(estimate.documents, docs.len());
+        assert_eq!(estimate.sampled, docs.len());
+        assert_eq!((estimate.low, estimate.high), (estimate.bytes, estimate.bytes));
+
+        let index_dir = dir.join("index");
+        fs::create_dir(&index_dir).unwrap();
+        let built = fs::metadata(build_index(&index_dir, 0, &docs, &DefaultTokenizer))
+            .unwrap().len();
+        assert!(estimate.bytes.abs_diff(built) <= built / 20,
+                "estimated {} bytes, built {}", estimate.bytes, built);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
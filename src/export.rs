@@ -0,0 +1,110 @@
+//! Exporting index files in formats other programs can read.
+//!
+//! The native index format is compact, but it's specific to `fingertips`. To
+//! feed the index into some other search engine or analysis tool, export it.
+
+use std::io::BufWriter;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::error::Error;
+use crate::read::IndexFileReader;
+
+/// Write the contents of the index file `filename` to `out` as CSV.
+///
+/// The output starts with the header line `term,doc_id,freq`. Then there's
+/// one line for each hit, sorted by term and then by document id. Terms that
+/// contain commas, quotes, or line breaks are quoted, with any `"` doubled,
+/// as described in RFC 4180. In an index built without frequencies, every
+/// `freq` is 0.
+///
+/// This reads the index one term at a time, so it doesn't need to fit in
+/// memory.
+pub fn export_csv<P: AsRef<Path>, W: Write>(filename: P, out: W) -> Result<(), Error> {
+    let mut reader = IndexFileReader::open(filename)?;
+    let mut out = BufWriter::new(out);
+    writeln!(out, "term,doc_id,freq").map_err(Error::Io)?;
+    while let Some(entry) = reader.peek() {
+        let term = csv_field(&entry.term);
+        for (doc_id, freq) in reader.take_postings()?.iter() {
+            writeln!(out, "{},{},{}", term, doc_id, freq).map_err(Error::Io)?;
+        }
+    }
+    out.flush().map_err(Error::Io)
+}
+
+/// Quote `field` for CSV, if necessary.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use regex::Regex;
+    use crate::index::{InMemoryIndex, RegexTokenizer};
+    use crate::test_util::{build_index, scratch_dir};
+
+    /// Split a line of CSV into fields, undoing `csv_field`.
+    fn parse_csv_line(line: &str) -> Vec<String> {
+        let mut fields = vec![];
+        let mut field = String::new();
+        let mut chars = line.chars().peekable();
+        let mut quoted = false;
+        while let Some(ch) = chars.next() {
+            match ch {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(std::mem::take(&mut field)),
+                ch => field.push(ch)
+            }
+        }
+        fields.push(field);
+        fields
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        let dir = scratch_dir("export-csv");
+        // Words are anything between spaces, punctuation and all.
+        let tokenizer = RegexTokenizer::new(Regex::new("[^ ]+").unwrap());
+        let docs = ["plain a,b plain", "say \"hi\" a,b", "\"quoted,comma\" plain"];
+        let index_file = build_index(&dir, 0, &docs, &tokenizer);
+        let mut csv = vec![];
+        export_csv(&index_file, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("term,doc_id,freq"));
+        let rows: Vec<(String, u32, u32)> = lines
+            .map(|line| match parse_csv_line(line).as_slice() {
+                [term, doc_id, freq] => (term.clone(), doc_id.parse().unwrap(),
+                                         freq.parse().unwrap()),
+                fields => panic!("expected 3 fields, got {:?}", fields)
+            })
+            .collect();
+
+        let mut expected = vec![];
+        let mut index = InMemoryIndex::new();
+        for (i, text) in docs.iter().enumerate() {
+            index.merge(InMemoryIndex::from_single_document(i, text, &tokenizer), None);
+        }
+        for (term, hits) in index.iter() {
+            expected.extend(hits.map(|(doc_id, freq)| (term.to_string(), doc_id, freq)));
+        }
+        assert_eq!(rows, expected);
+        assert!(rows.contains(&("a,b".to_string(), 0, 1)), "{:?}", rows);
+        assert!(rows.contains(&("\"hi\"".to_string(), 1, 1)), "{:?}", rows);
+        assert!(rows.contains(&("\"quoted,comma\"".to_string(), 2, 1)), "{:?}", rows);
+        assert!(csv.contains("\"\"\"quoted,comma\"\"\",2,1\n"), "{}", csv);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -4,14 +4,785 @@
 //! `InMemoryIndex` can be used to do that, up to the size of the machine's
 //! memory.
 
-use std::collections::HashMap;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::fmt;
+#[cfg(not(feature = "fast-hash"))]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "fast-hash")]
+use std::hash::{BuildHasherDefault, Hasher};
+use std::io::{self, Read};
+use std::iter;
+use std::mem;
+use std::str;
+use std::sync::Arc;
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use regex::Regex;
+use crate::compress::Codec;
 
-/// Break a string into words.
-fn tokenize(text: &str) -> Vec<&str> {
-    text.split(|ch: char| !ch.is_alphanumeric())
-        .filter(|word| !word.is_empty())
-        .collect()
+/// A way of breaking text into words.
+///
+/// The indexer lowercases each document (using the tokenizer's `lowercase`
+/// method) before handing it to the tokenizer, so tokenizers don't have to
+/// worry about case.
+///
+/// A tokenizer can be shared by several threads, and used to index any
+/// number of documents, so it shouldn't keep any state between calls.
+pub trait Tokenizer: Send + Sync {
+    /// Break `text` into terms, in the order they appear.
+    ///
+    /// Most tokenizers just return slices of `text`, but a term doesn't have
+    /// to appear verbatim in the text (see `NgramTokenizer`).
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a>;
+
+    /// Convert a document to lowercase, before it's passed to `tokens`.
+    ///
+    /// Tokenizers that wrap another tokenizer should pass this call on to it.
+    fn lowercase(&self, text: &str) -> String {
+        text.to_lowercase()
+    }
+
+    /// The rules `lowercase` follows. Searches lowercase query terms the same
+    /// way, so this is recorded in the index file.
+    ///
+    /// Tokenizers that wrap another tokenizer should pass this call on to it.
+    fn case_locale(&self) -> CaseLocale {
+        CaseLocale::Default
+    }
+
+    /// Find a place to cut `text`, the start of a longer document, so that
+    /// tokenizing the two pieces separately gives the same words as
+    /// tokenizing all of `text` at once, whatever follows it. This lets
+    /// `InMemoryIndex::from_reader` index a document a piece at a time.
+    ///
+    /// The default is `None`, meaning there's no safe place, and the whole
+    /// document has to be read first. Tokenizers that wrap another tokenizer
+    /// and work one word at a time should pass this call on to it.
+    fn split_point(&self, _text: &str) -> Option<usize> {
+        None
+    }
+}
+
+/// The position just after the last whitespace character in `text`, if any.
+/// For tokenizers that never make a word out of whitespace, that's a safe
+/// `Tokenizer::split_point`.
+fn after_last_whitespace(text: &str) -> Option<usize> {
+    text.char_indices().rev()
+        .find(|&(_, ch)| ch.is_whitespace())
+        .map(|(i, ch)| i + ch.len_utf8())
+}
+
+/// The default tokenizer. A word is a run of alphanumeric characters;
+/// everything else is a separator. That includes every kind of Unicode
+/// whitespace: tabs, `\r\n` line breaks, non-breaking spaces, ideographic
+/// spaces, and so on.
+pub struct DefaultTokenizer;
+
+impl Tokenizer for DefaultTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        Box::new(text.split(|ch: char| !ch.is_alphanumeric())
+                 .filter(|word| !word.is_empty())
+                 .map(Cow::Borrowed))
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        after_last_whitespace(text)
+    }
+}
+
+/// A `DefaultTokenizer` for documents that are all ASCII. It lowercases
+/// and splits text byte by byte, skipping the Unicode tables. A document with
+/// any other characters in it is handled just as `DefaultTokenizer` would
+/// handle it, so an index built with this tokenizer is always the same as
+/// one built with that one.
+pub struct AsciiTokenizer;
+
+impl Tokenizer for AsciiTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        if !text.is_ascii() {
+            return DefaultTokenizer.tokens(text);
+        }
+        let mut words = vec![];
+        let mut start = None;
+        for (i, byte) in text.bytes().enumerate() {
+            match (byte.is_ascii_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    words.push(Cow::Borrowed(&text[s..i]));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            words.push(Cow::Borrowed(&text[s..]));
+        }
+        Box::new(words.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        if text.is_ascii() {
+            text.to_ascii_lowercase()
+        } else {
+            text.to_lowercase()
+        }
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        after_last_whitespace(text)
+    }
+}
+
+/// Like `DefaultTokenizer`, but keeps numbers like `3.14` and `1,000` in one
+/// piece: a `.` or `,` between two digits doesn't end the word.
+pub struct NumberTokenizer;
+
+impl Tokenizer for NumberTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let mut words = vec![];
+        let mut start = None;
+        let mut chars = text.char_indices().peekable();
+        let mut prev = None;
+        while let Some((i, ch)) = chars.next() {
+            let joins_number = (ch == '.' || ch == ',')
+                && prev.is_some_and(char::is_numeric)
+                && chars.peek().is_some_and(|&(_, next)| next.is_numeric());
+            let in_word = ch.is_alphanumeric() || joins_number;
+            match (in_word, start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    words.push(Cow::Borrowed(&text[s..i]));
+                    start = None;
+                }
+                _ => {}
+            }
+            prev = Some(ch);
+        }
+        if let Some(s) = start {
+            words.push(Cow::Borrowed(&text[s..]));
+        }
+        Box::new(words.into_iter())
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        after_last_whitespace(text)
+    }
+}
+
+/// A tokenizer for source code.
+///
+/// Identifiers (runs of alphanumeric characters and `_`) are words, as usual.
+/// But punctuation isn't thrown away: each run of ASCII punctuation is a word
+/// too, so operators like `::`, `->`, and `()` are searchable.
+///
+/// If `join_paths` is true, paths like `std::io::Read` are also kept whole,
+/// as a single word, rather than split into identifiers and `::` operators.
+pub struct CodeTokenizer {
+    join_paths: bool
+}
+
+impl CodeTokenizer {
+    pub fn new(join_paths: bool) -> CodeTokenizer {
+        CodeTokenizer { join_paths }
+    }
+}
+
+/// The kinds of characters `CodeTokenizer` distinguishes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CodeChar {
+    Ident,
+    Symbol,
+    Space
+}
+
+impl CodeChar {
+    fn of(ch: char) -> CodeChar {
+        if ch.is_alphanumeric() || ch == '_' {
+            CodeChar::Ident
+        } else if ch.is_ascii_punctuation() {
+            CodeChar::Symbol
+        } else {
+            CodeChar::Space
+        }
+    }
+}
+
+impl Tokenizer for CodeTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        // First split the text into runs of identifier or symbol characters,
+        // remembering where each run starts and which kind it is.
+        let mut runs: Vec<(usize, usize, CodeChar)> = vec![];
+        let mut current: Option<(usize, CodeChar)> = None;
+        for (i, ch) in text.char_indices() {
+            let kind = CodeChar::of(ch);
+            match current {
+                Some((_, k)) if k == kind => {}
+                _ => {
+                    if let Some((start, k)) = current {
+                        runs.push((start, i, k));
+                    }
+                    current = if kind == CodeChar::Space { None } else { Some((i, kind)) };
+                }
+            }
+        }
+        if let Some((start, k)) = current {
+            runs.push((start, text.len(), k));
+        }
+
+        if !self.join_paths {
+            return Box::new(runs.into_iter().map(move |(start, stop, _)| {
+                Cow::Borrowed(&text[start..stop])
+            }));
+        }
+
+        // Glue together identifiers separated only by `::`.
+        let mut words = vec![];
+        let mut i = 0;
+        while i < runs.len() {
+            let (start, mut stop, kind) = runs[i];
+            i += 1;
+            if kind == CodeChar::Ident {
+                while i + 1 < runs.len()
+                    && runs[i].0 == stop && &text[runs[i].0..runs[i].1] == "::"
+                    && runs[i + 1].0 == runs[i].1 && runs[i + 1].2 == CodeChar::Ident
+                {
+                    stop = runs[i + 1].1;
+                    i += 2;
+                }
+            }
+            words.push(Cow::Borrowed(&text[start..stop]));
+        }
+        Box::new(words.into_iter())
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        after_last_whitespace(text)
+    }
+}
+
+/// A tokenizer that treats every match of a regular expression as a word.
+pub struct RegexTokenizer {
+    pattern: Regex
+}
+
+impl RegexTokenizer {
+    pub fn new(pattern: Regex) -> RegexTokenizer {
+        RegexTokenizer { pattern }
+    }
+}
+
+impl Tokenizer for RegexTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        // The matches borrow `self.pattern`, so they can't be returned lazily.
+        let words: Vec<Cow<'a, str>> = self.pattern.find_iter(text)
+            .map(|m| m.as_str())
+            .filter(|word| !word.is_empty())
+            .map(Cow::Borrowed)
+            .collect();
+        Box::new(words.into_iter())
+    }
+}
+
+/// A half-open range `start..end` of document ids.
+///
+/// Every index file records the range of document ids it covers, so that
+/// index shards built separately (with `--docid-base`) can later be merged
+/// without renumbering any documents, or, if their ranges overlap, with
+/// renumbering only where they do (see `merge::merge_files`). An empty range
+/// (`start == end`) means the index doesn't cover any documents yet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DocRange {
+    pub start: u32,
+    pub end: u32
+}
+
+impl DocRange {
+    /// The empty range.
+    pub fn empty() -> DocRange {
+        DocRange { start: 0, end: 0 }
+    }
+
+    /// The range containing the single document `document_id`.
+    pub fn single(document_id: u32) -> DocRange {
+        DocRange { start: document_id, end: document_id + 1 }
+    }
+
+    /// True if this range contains no document ids.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    /// The number of document ids in this range.
+    pub fn len(&self) -> u32 {
+        self.end - self.start
+    }
+
+    /// True if some document id is in both `self` and `other`.
+    pub fn overlaps(&self, other: &DocRange) -> bool {
+        !self.is_empty() && !other.is_empty()
+            && self.start < other.end && other.start < self.end
+    }
+
+    /// The smallest range that contains both `self` and `other`.
+    pub fn union(&self, other: &DocRange) -> DocRange {
+        if self.is_empty() {
+            *other
+        } else if other.is_empty() {
+            *self
+        } else {
+            DocRange {
+                start: self.start.min(other.start),
+                end: self.end.max(other.end)
+            }
+        }
+    }
+}
+
+/// Rules for lowercasing text that depend on the language.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseLocale {
+    /// The default Unicode rules, as used by `str::to_lowercase`. These are
+    /// right for most languages.
+    Default,
+
+    /// Turkish and Azerbaijani, where the lowercase form of `I` is dotless
+    /// `ı`, and the lowercase form of `İ` is plain `i`. (By default, `I`
+    /// becomes `i`, and `İ` becomes `i` followed by a combining dot.)
+    Turkic,
+
+    /// Don't lowercase anything, so that `Rust` and `rust` are different
+    /// terms (`--case-sensitive`). This isn't a language, but searches need to
+    /// know it the same way.
+    Unchanged
+}
+
+impl CaseLocale {
+    /// Look up the rules for a locale given as a language tag, like `"tr"` or
+    /// `"tr-TR"`. Only the language matters.
+    ///
+    /// Only Turkish and Azerbaijani have special rules so far. Every other
+    /// language gets `Default`, even ones with other exceptions, such as
+    /// Lithuanian, whose special cases involving accented `i` aren't handled.
+    pub fn from_tag(tag: &str) -> CaseLocale {
+        let language = tag.split(['-', '_']).next().unwrap_or("");
+        if language.eq_ignore_ascii_case("tr") || language.eq_ignore_ascii_case("az") {
+            CaseLocale::Turkic
+        } else {
+            CaseLocale::Default
+        }
+    }
+
+    /// Convert `text` to lowercase, following this locale's rules.
+    pub fn lowercase(self, text: &str) -> String {
+        match self {
+            CaseLocale::Turkic if text.contains(['I', 'İ']) => {
+                // Handle the two special letters first, then let the standard
+                // library do the rest (it knows about things like final sigma).
+                let text: String = text.chars()
+                    .map(|ch| match ch {
+                        'I' => 'ı',
+                        'İ' => 'i',
+                        ch => ch
+                    })
+                    .collect();
+                text.to_lowercase()
+            }
+            CaseLocale::Unchanged => text.to_string(),
+            _ => text.to_lowercase()
+        }
+    }
+
+    /// The number that stands for this locale in index files.
+    pub fn id(self) -> u8 {
+        match self {
+            CaseLocale::Default => 0,
+            CaseLocale::Turkic => 1,
+            CaseLocale::Unchanged => 2
+        }
+    }
+
+    /// The locale with the given `id`.
+    pub fn from_id(id: u8) -> io::Result<CaseLocale> {
+        match id {
+            0 => Ok(CaseLocale::Default),
+            1 => Ok(CaseLocale::Turkic),
+            2 => Ok(CaseLocale::Unchanged),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("index file uses unknown case locale {}", id)))
+        }
+    }
+}
+
+/// A tokenizer that lowercases text following the rules of a particular
+/// locale, then hands it to another tokenizer.
+pub struct LocaleTokenizer {
+    inner: Box<dyn Tokenizer>,
+    locale: CaseLocale
+}
+
+impl LocaleTokenizer {
+    pub fn new(inner: Box<dyn Tokenizer>, locale: CaseLocale) -> LocaleTokenizer {
+        LocaleTokenizer { inner, locale }
+    }
+}
+
+impl Tokenizer for LocaleTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        self.inner.tokens(text)
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.locale.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.locale
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// Replace every run of whitespace in `text`, of whatever kind, with a single
+/// ASCII space.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// A tokenizer that normalizes the whitespace in the text (see
+/// `normalize_whitespace`) before handing it to another tokenizer.
+///
+/// This makes no difference to the built-in tokenizers, which treat all
+/// whitespace alike anyway. It's for regular expressions, which might match
+/// `" "` but not `"\u{a0}"` or `"\r\n"`.
+pub struct WhitespaceNormalizingTokenizer {
+    inner: Box<dyn Tokenizer>
+}
+
+impl WhitespaceNormalizingTokenizer {
+    pub fn new(inner: Box<dyn Tokenizer>) -> WhitespaceNormalizingTokenizer {
+        WhitespaceNormalizingTokenizer { inner }
+    }
+}
+
+impl Tokenizer for WhitespaceNormalizingTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        // The words borrow from `normalized`, not `text`, so they have to be
+        // copied before we return.
+        let normalized = normalize_whitespace(text);
+        let words: Vec<Cow<'a, str>> =
+            self.inner.tokens(&normalized)
+            .map(|word| Cow::Owned(word.into_owned()))
+            .collect();
+        Box::new(words.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// A tokenizer that truncates the words produced by another tokenizer to at
+/// most `max_len` characters.
+///
+/// Some files contain enormous "words", like a minified blob of JavaScript
+/// with no spaces in it. Without a limit, each of these would become a huge
+/// term in the index.
+pub struct TruncatingTokenizer {
+    inner: Box<dyn Tokenizer>,
+    max_len: usize
+}
+
+impl TruncatingTokenizer {
+    pub fn new(inner: Box<dyn Tokenizer>, max_len: usize) -> TruncatingTokenizer {
+        TruncatingTokenizer { inner, max_len }
+    }
+}
+
+impl Tokenizer for TruncatingTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let max_len = self.max_len;
+        Box::new(self.inner.tokens(text).map(move |word| {
+            match word.char_indices().nth(max_len) {
+                None => word,
+                Some((end, _)) => match word {
+                    Cow::Borrowed(w) => Cow::Borrowed(&w[..end]),
+                    Cow::Owned(mut w) => {
+                        w.truncate(end);
+                        Cow::Owned(w)
+                    }
+                }
+            }
+        }))
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// A tokenizer that keeps only the words in a fixed vocabulary, dropping
+/// everything else the tokenizer it wraps produces. This is for building
+/// indexes that can be compared with each other, because they're all limited
+/// to the same terms.
+///
+/// The words that are kept are numbered as if the others weren't there, so
+/// their offsets don't leave gaps where words were dropped.
+///
+/// Search needs no matching filter: terms outside the vocabulary aren't in
+/// the index, so looking them up finds nothing.
+pub struct VocabularyTokenizer {
+    inner: Box<dyn Tokenizer>,
+    vocabulary: HashSet<String>
+}
+
+impl VocabularyTokenizer {
+    /// Make a tokenizer that keeps only the words produced by `inner` that are
+    /// in `vocabulary`. The vocabulary should be in the form `inner` produces:
+    /// for most tokenizers, lowercase.
+    pub fn new(inner: Box<dyn Tokenizer>, vocabulary: HashSet<String>) -> VocabularyTokenizer {
+        VocabularyTokenizer { inner, vocabulary }
+    }
+}
+
+impl Tokenizer for VocabularyTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let words: Vec<_> =
+            self.inner.tokens(text)
+            .filter(|word| self.vocabulary.contains(&**word))
+            .collect();
+        Box::new(words.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// True if `ch` belongs to a script that's written without spaces between
+/// words: Chinese, Japanese, or Korean.
+pub fn is_cjk(ch: char) -> bool {
+    matches!(ch,
+             '\u{3040}'..='\u{30ff}' |      // Hiragana and Katakana
+             '\u{3400}'..='\u{4dbf}' |      // CJK Unified Ideographs Extension A
+             '\u{4e00}'..='\u{9fff}' |      // CJK Unified Ideographs
+             '\u{1100}'..='\u{11ff}' |      // Hangul Jamo
+             '\u{3130}'..='\u{318f}' |      // Hangul Compatibility Jamo
+             '\u{ac00}'..='\u{d7af}' |      // Hangul Syllables
+             '\u{f900}'..='\u{faff}' |      // CJK Compatibility Ideographs
+             '\u{20000}'..='\u{2fa1f}')     // Supplementary Ideographic Plane
+}
+
+/// A tokenizer that splits runs of Chinese, Japanese, and Korean characters
+/// in the words produced by another tokenizer into overlapping bigrams.
+///
+/// These languages don't put spaces between words, so other tokenizers treat
+/// a whole sentence as one word. Without a dictionary, we can't tell where the
+/// words are, but indexing every pair of adjacent characters works well:
+/// `"中国人"` becomes `"中国"` and `"国人"`, and searching for any two-character
+/// word finds it. A run of just one character is kept as it is. Parts of a
+/// word in other scripts are left whole, so `"rust编程"` becomes `"rust"` and
+/// `"编程"`.
+pub struct CjkBigramTokenizer {
+    inner: Box<dyn Tokenizer>
+}
+
+impl CjkBigramTokenizer {
+    pub fn new(inner: Box<dyn Tokenizer>) -> CjkBigramTokenizer {
+        CjkBigramTokenizer { inner }
+    }
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let mut words = vec![];
+        for word in self.inner.tokens(text) {
+            if !word.chars().any(is_cjk) {
+                words.push(word);
+                continue;
+            }
+            let chars: Vec<char> = word.chars().collect();
+            for run in chars.chunk_by(|a, b| is_cjk(*a) == is_cjk(*b)) {
+                if !is_cjk(run[0]) || run.len() == 1 {
+                    words.push(Cow::Owned(run.iter().collect()));
+                } else {
+                    for pair in run.windows(2) {
+                        words.push(Cow::Owned(pair.iter().collect()));
+                    }
+                }
+            }
+        }
+        Box::new(words.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// A tokenizer that breaks each word produced by another tokenizer into
+/// overlapping character n-grams, so that searches can match parts of words.
+///
+/// Each word is padded with `^` at the start and `$` at the end before it's
+/// cut up, so `"fox"` with `n = 3` produces `"^fo"`, `"fox"`, and `"ox$"`.
+/// Words too short to fill a single n-gram are kept whole, with padding.
+///
+/// The padding isn't escaped. `DefaultTokenizer` and `NumberTokenizer` never
+/// produce words containing `^` or `$`, but `CodeTokenizer` does (`^=` is
+/// a word to it), and so can `RegexTokenizer`. With those, an n-gram like
+/// `"^^="` can't be told apart from one that really starts with `^`, so a
+/// search for it may match a few more documents than it should.
+pub struct NgramTokenizer {
+    inner: Box<dyn Tokenizer>,
+    n: usize,
+    keep_words: bool
+}
+
+impl NgramTokenizer {
+    /// Make a tokenizer that produces `n`-grams of the words produced by
+    /// `inner`. If `keep_words` is true, each whole word is also produced,
+    /// just before its n-grams.
+    pub fn new(inner: Box<dyn Tokenizer>, n: usize, keep_words: bool) -> NgramTokenizer {
+        assert!(n > 0, "n-grams must be at least one character long");
+        NgramTokenizer { inner, n, keep_words }
+    }
+}
+
+impl Tokenizer for NgramTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        let mut grams = vec![];
+        for word in self.inner.tokens(text) {
+            let padded: Vec<char> =
+                iter::once('^')
+                .chain(word.chars())
+                .chain(iter::once('$'))
+                .collect();
+            if self.keep_words {
+                grams.push(word);
+            }
+            if padded.len() <= self.n {
+                grams.push(Cow::Owned(padded.into_iter().collect()));
+            } else {
+                for window in padded.windows(self.n) {
+                    grams.push(Cow::Owned(window.iter().collect()));
+                }
+            }
+        }
+        Box::new(grams.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+
+    fn split_point(&self, text: &str) -> Option<usize> {
+        self.inner.split_point(text)
+    }
+}
+
+/// The size, in bytes, above which an `InMemoryIndex` is considered large
+/// (see `InMemoryIndex::is_large` and `InMemoryIndex::byte_size`).
+///
+/// This depends on how much memory your computer has, of course.
+pub const LARGE_INDEX_BYTES: usize = 400_000_000;
+
+/// How many bytes `InMemoryIndex::from_reader` reads at a time.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How `InMemoryIndex` hashes terms.
+///
+/// By default, this is the standard library's hasher, which is randomly
+/// seeded, so that no one can pick terms that all land in the same bucket and
+/// slow indexing to a crawl. That's a good default for documents from
+/// anywhere. For a corpus you trust, building with the `fast-hash` feature
+/// switches to `FxHasher`, which makes indexing 10-20% faster. The index
+/// files are exactly the same either way, since terms are sorted before
+/// they're written.
+#[cfg(not(feature = "fast-hash"))]
+pub type TermHasher = RandomState;
+
+#[cfg(feature = "fast-hash")]
+pub type TermHasher = BuildHasherDefault<FxHasher>;
+
+/// The hash function used in the Rust compiler (and, before that, Firefox):
+/// very fast for short keys like terms, but with no defense against keys
+/// chosen to collide. See `TermHasher`.
+#[cfg(feature = "fast-hash")]
+#[derive(Default)]
+pub struct FxHasher {
+    hash: u64
+}
+
+#[cfg(feature = "fast-hash")]
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+        self.hash = (self.hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+    }
+}
+
+#[cfg(feature = "fast-hash")]
+impl Hasher for FxHasher {
+    fn write(&mut self, mut bytes: &[u8]) {
+        while bytes.len() >= 8 {
+            self.add(LittleEndian::read_u64(bytes));
+            bytes = &bytes[8..];
+        }
+        if bytes.len() >= 4 {
+            self.add(LittleEndian::read_u32(bytes) as u64);
+            bytes = &bytes[4..];
+        }
+        for &byte in bytes {
+            self.add(byte as u64);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.hash
+    }
 }
 
 /// An in-memory index.
@@ -25,16 +796,29 @@ pub struct InMemoryIndex {
     /// The total number of words in the indexed documents.
     pub word_count: usize,
 
+    /// An estimate of the memory used by this index, in bytes. See
+    /// `byte_size`.
+    pub byte_count: usize,
+
+    /// The range of document ids covered by this index.
+    pub docs: DocRange,
+
     /// For every term that appears in the index, the list of all search hits
     /// for that term (i.e. which documents contain that term, and where).
-    ///
-    /// It's possible for an index to be "sorted by document id", which means
-    /// that for every `Vec<Hit>` in this map, the `Hit` elements all have
-    /// distinct document ids (the first u32) and the `Hit`s are arranged by
-    /// document id in increasing order. This is handy for some algorithms you
-    /// might want to run on the index, so we preserve this property wherever
-    /// possible.
-    pub map: HashMap<String, Vec<Hit>>
+    pub map: HashMap<String, PostingList, TermHasher>,
+
+    /// For each term, how it was spelled in the documents before the
+    /// tokenizer lowercased it. This is only filled in by `add_spellings`;
+    /// otherwise it's empty.
+    pub spellings: HashMap<String, Spellings, TermHasher>,
+
+    /// How the documents were lowercased (see `Tokenizer::case_locale`).
+    pub case_locale: CaseLocale,
+
+    /// True if `drop_frequencies` has been called, so that the hits say only
+    /// which documents contain each term. Such an index is written to disk
+    /// without frequencies (see `IndexFileWriter::drop_frequencies`).
+    pub presence_only: bool
 }
 
 /// A `Hit` indicates that a particular document contains some term, how many
@@ -42,71 +826,1229 @@ pub struct InMemoryIndex {
 /// beginning of the document, of each place where the term appears).
 ///
 /// The buffer contains all the hit data in binary form, little-endian. The
-/// first u32 of the data is the document id. The remaining [u32] are offsets.
+/// first u32 of the data is the document id. The second is the number of
+/// offsets that follow, which is the term's frequency in that document. The
+/// remaining [u32] are offsets.
+///
+/// In an index built without frequencies (see
+/// `InMemoryIndex::drop_frequencies`), the frequency is 0 and there are no
+/// offsets: the hit says only that the document contains the term.
+///
+/// Since every hit records its own length, the hits for a term can be stored
+/// back-to-back in an index file and split apart again with `split_hits`.
 pub type Hit = Vec<u8>;
 
+/// The different ways a term was spelled in the original text (`"Rust"`,
+/// `"RUST"`, `"rust"`), each with the number of times it appeared that way.
+pub type Spellings = Vec<(String, u32)>;
+
+/// Add the counts in `other` to `spellings`.
+pub fn merge_spellings(spellings: &mut Spellings, other: &[(String, u32)]) {
+    for (spelling, count) in other {
+        match spellings.iter_mut().find(|(s, _)| s == spelling) {
+            Some((_, total)) => *total += count,
+            None => spellings.push((spelling.clone(), *count))
+        }
+    }
+}
+
+/// The spelling in `spellings` that appeared most often, if any. Ties go to
+/// whichever comes first in byte order.
+pub fn most_common_spelling(spellings: &[(String, u32)]) -> Option<&str> {
+    spellings.iter()
+        .max_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then_with(|| b.cmp(a)))
+        .map(|(spelling, _)| spelling.as_str())
+}
+
+/// Counts describing the words in some text, as tokenized. See
+/// `InMemoryIndex::document_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DocumentStats {
+    /// The number of tokens.
+    pub tokens: usize,
+
+    /// The number of distinct terms among those tokens.
+    pub distinct_terms: usize,
+
+    /// The total length of all the tokens, in characters.
+    pub token_chars: usize
+}
+
+impl DocumentStats {
+    /// The average length of a token, in characters, or 0 if there are no
+    /// tokens.
+    pub fn average_token_len(&self) -> f64 {
+        if self.tokens == 0 {
+            0.0
+        } else {
+            self.token_chars as f64 / self.tokens as f64
+        }
+    }
+}
+
+/// The memory used by each term in an `InMemoryIndex`, apart from the text of
+/// the term and its hits: the hash table slot, including its control byte.
+const TERM_OVERHEAD: usize = mem::size_of::<(String, PostingList)>() + 1;
+
+/// The memory used by `term` in an `InMemoryIndex`, not counting its hits.
+fn term_size(term: &String) -> usize {
+    TERM_OVERHEAD + term.capacity()
+}
+
+/// The memory used by a term's `spellings` in an `InMemoryIndex`, not counting
+/// the term itself.
+fn spellings_size(spellings: &Spellings) -> usize {
+    spellings.iter()
+        .map(|(spelling, _)| mem::size_of::<(String, u32)>() + spelling.capacity())
+        .sum()
+}
+
+/// The memory used by `hit` in an `InMemoryIndex`: the `Vec` itself, in its
+/// term's list of hits, and the buffer it points to.
+fn hit_size(hit: &Hit) -> usize {
+    mem::size_of::<Hit>() + hit.capacity()
+}
+
+/// The size of the fixed part of a `Hit`: the document id and the frequency.
+pub(crate) const HIT_HEADER_SIZE: usize = 8;
+
+/// The largest possible document id. Ids are stored as `u32`, and a
+/// `DocRange` has to be able to represent the id after the last one.
+pub const MAX_DOCUMENT_ID: u32 = u32::MAX - 1;
+
+/// Convert a document id passed in by the caller to the form it's stored in.
+///
+/// Panics if `document_id` is too big, rather than silently wrapping around
+/// and mixing up one document's hits with another's.
+fn stored_document_id(document_id: usize) -> u32 {
+    assert!(document_id <= MAX_DOCUMENT_ID as usize,
+            "document id {} is too big (the limit is {})", document_id, MAX_DOCUMENT_ID);
+    document_id as u32
+}
+
+/// The document id of a hit.
+pub fn hit_document_id(hit: &[u8]) -> u32 {
+    LittleEndian::read_u32(&hit[0..4])
+}
+
+/// The number of times the term appears in the document, according to `hit`.
+pub fn hit_frequency(hit: &[u8]) -> u32 {
+    LittleEndian::read_u32(&hit[4..8])
+}
+
+/// The document ids of `hits`, the hit data for one term, as stored in an
+/// index file built without frequencies: 4 bytes each.
+pub fn hit_document_ids(hits: &[u8]) -> io::Result<Vec<u8>> {
+    let mut ids = Vec::with_capacity(hits.len() / 2);
+    for hit in split_hits(hits)? {
+        ids.extend_from_slice(&hit[..4]);
+    }
+    Ok(ids)
+}
+
+/// Undo `hit_document_ids`, giving a hit with a frequency of 0 for each
+/// document id in `ids`.
+pub fn hits_from_document_ids(ids: &[u8]) -> io::Result<Vec<u8>> {
+    if !ids.len().is_multiple_of(4) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "index entry ends in the middle of a document id"));
+    }
+    let mut hits = Vec::with_capacity(2 * ids.len());
+    for id in ids.chunks_exact(4) {
+        hits.extend_from_slice(id);
+        hits.extend_from_slice(&[0; 4]);
+    }
+    Ok(hits)
+}
+
+/// Split the hit data for one term, as stored in an index file, back into
+/// separate `Hit`s.
+pub fn split_hits(mut bytes: &[u8]) -> io::Result<Vec<Hit>> {
+    let mut hits = vec![];
+    while !bytes.is_empty() {
+        if bytes.len() < HIT_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a hit"));
+        }
+        let len = HIT_HEADER_SIZE + 4 * hit_frequency(bytes) as usize;
+        if bytes.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a hit"));
+        }
+        hits.push(bytes[..len].to_vec());
+        bytes = &bytes[len..];
+    }
+    Ok(hits)
+}
+
+/// All the hits for one term, in increasing order by document id, with at
+/// most one hit per document.
+///
+/// Every part of the crate that handles hits keeps them this way: indexing,
+/// merging, capping (see `PostingsCap::trim`), and the index files
+/// themselves. The codecs rely on it to store the gaps between document ids
+/// rather than the ids themselves.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PostingList {
+    hits: Vec<Hit>
+}
+
+impl PostingList {
+    /// Create an empty list.
+    pub fn new() -> PostingList {
+        PostingList { hits: vec![] }
+    }
+
+    /// A list holding just `hit`.
+    pub fn single(hit: Hit) -> PostingList {
+        PostingList { hits: vec![hit] }
+    }
+
+    /// Make a list of `hits`. It's an error if they aren't in increasing
+    /// order by document id.
+    pub fn from_hits(hits: Vec<Hit>) -> io::Result<PostingList> {
+        let in_order = hits.windows(2)
+            .all(|pair| hit_document_id(&pair[0]) < hit_document_id(&pair[1]));
+        if !in_order {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "hits are not in order by document id"));
+        }
+        Ok(PostingList { hits })
+    }
+
+    /// The number of hits, which is the number of documents the term
+    /// appears in.
+    pub fn len(&self) -> usize {
+        self.hits.len()
+    }
+
+    /// True if there are no hits.
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    /// The hits, in order by document id.
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    /// Take the hits out of the list.
+    pub fn into_hits(self) -> Vec<Hit> {
+        self.hits
+    }
+
+    /// Iterate over the document id and frequency of each hit.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.hits.iter().map(|hit| (hit_document_id(hit), hit_frequency(hit)))
+    }
+
+    /// The size of the hits, uncompressed, in bytes.
+    pub fn nbytes(&self) -> u64 {
+        self.hits.iter().map(|hit| hit.len() as u64).sum()
+    }
+
+    /// Add the hits from `other` to this list, keeping it in order. If both
+    /// lists have a hit for the same document, the two are combined into one,
+    /// with `other`'s offsets after this list's.
+    ///
+    /// The usual case, where all of `other`'s documents come after this
+    /// list's, just appends them.
+    pub fn merge_from(&mut self, other: PostingList) {
+        let appending = match (self.hits.last(), other.hits.first()) {
+            (Some(last), Some(first)) => hit_document_id(last) < hit_document_id(first),
+            _ => true
+        };
+        if appending {
+            self.hits.extend(other.hits);
+            return;
+        }
+
+        let mut mine = mem::take(&mut self.hits).into_iter().peekable();
+        let mut theirs = other.hits.into_iter().peekable();
+        self.hits.reserve(mine.len() + theirs.len());
+        loop {
+            let order = match (mine.peek(), theirs.peek()) {
+                (Some(a), Some(b)) => hit_document_id(a).cmp(&hit_document_id(b)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => break
+            };
+            let hit = match order {
+                Ordering::Less => mine.next().unwrap(),
+                Ordering::Greater => theirs.next().unwrap(),
+                Ordering::Equal => {
+                    let mut hit = mine.next().unwrap();
+                    let other = theirs.next().unwrap();
+                    let frequency = hit_frequency(&hit) + hit_frequency(&other);
+                    hit.extend_from_slice(&other[HIT_HEADER_SIZE..]);
+                    LittleEndian::write_u32(&mut hit[4..8], frequency);
+                    hit
+                }
+            };
+            self.hits.push(hit);
+        }
+    }
+
+    /// Store the hits using `codec`, appending the result to `out`, as they'd
+    /// be stored in an index file.
+    pub fn encode(&self, codec: Codec, out: &mut Vec<u8>) -> io::Result<()> {
+        let raw = self.hits.concat();
+        codec.compress(&raw, out)
+    }
+
+    /// Undo `encode`. It's an error if `data` isn't a valid list of hits
+    /// stored using `codec`.
+    pub fn decode(codec: Codec, data: &[u8]) -> io::Result<PostingList> {
+        if codec == Codec::Raw {
+            return PostingList::from_hits(split_hits(data)?);
+        }
+        let mut raw = vec![];
+        codec.decompress(data, &mut raw)?;
+        PostingList::from_hits(split_hits(&raw)?)
+    }
+
+    /// Like `decode`, for an index file built without frequencies, which
+    /// stores only the document ids (see `Codec::compress_doc_ids`). Each hit
+    /// has a frequency of 0.
+    pub fn decode_doc_ids(codec: Codec, data: &[u8]) -> io::Result<PostingList> {
+        let mut ids = vec![];
+        codec.decompress_doc_ids(data, &mut ids)?;
+        PostingList::from_hits(split_hits(&hits_from_document_ids(&ids)?)?)
+    }
+}
+
+/// What to do with a term that appears in more documents than a
+/// `PostingsCap` allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapPolicy {
+    /// Leave the term out of the index entirely.
+    DropTerm,
+
+    /// Keep only the hits for the documents where the term appears most often.
+    KeepMostFrequent
+}
+
+/// A limit on the number of hits stored for any one term.
+///
+/// Terms that appear in nearly every document, like "the", have enormous hit
+/// lists that dominate the size of the index without being very useful for
+/// searching. The cap is applied every time hits are combined, whether in
+/// memory or while merging files, so it also bounds the memory used for them.
+#[derive(Clone, Copy, Debug)]
+pub struct PostingsCap {
+    pub max: usize,
+    pub policy: CapPolicy
+}
+
+impl PostingsCap {
+    /// Trim `hits`, all the hits known so far for some term.
+    ///
+    /// With `KeepMostFrequent`, this keeps the `max` hits with the highest
+    /// frequency (preferring lower document ids in case of a tie), still in
+    /// document id order.
+    ///
+    /// With `DropTerm`, we can't decide to drop a term until all its hits have
+    /// been seen. So this just discards hits beyond `max + 1`, which is enough
+    /// to remember that the term is over the limit; the term is actually
+    /// dropped only when writing the final index (see `drops`).
+    pub fn trim(&self, postings: &mut PostingList) {
+        let hits = &mut postings.hits;
+        if hits.len() <= self.max {
+            return;
+        }
+        match self.policy {
+            CapPolicy::DropTerm => hits.truncate(self.max + 1),
+            CapPolicy::KeepMostFrequent => {
+                hits.sort_by_key(|hit| Reverse(hit_frequency(hit)));
+                hits.truncate(self.max);
+                hits.sort_by_key(|hit| hit_document_id(hit));
+            }
+        }
+    }
+
+    /// True if a term with `df` hits should be left out of the final index.
+    pub fn drops(&self, df: usize) -> bool {
+        self.policy == CapPolicy::DropTerm && df > self.max
+    }
+}
+
+/// The order in which terms are stored in index files.
+///
+/// Merging relies on every file being sorted the same way, so the order used
+/// to write temporary files must match the one given to `FileMerge`. Index
+/// files don't record which order they were written in: an index built with
+/// one order can't be merged with files written in another, and has to be
+/// rebuilt from the documents instead.
+#[derive(Clone, Default)]
+pub struct TermOrder {
+    /// The user-supplied comparison, or `None` to compare the bytes of the
+    /// terms.
+    cmp: Option<Arc<TermComparator>>
+}
+
+type TermComparator = dyn Fn(&str, &str) -> Ordering + Send + Sync;
+
+impl TermOrder {
+    /// Byte-lexicographic order. This is the default.
+    pub fn bytes() -> TermOrder {
+        TermOrder { cmp: None }
+    }
+
+    /// Order terms using `cmp`, which must be a consistent total order, like
+    /// the ones `slice::sort_by` expects.
+    ///
+    /// Distinct terms that `cmp` considers equal, like "Rust" and "rust" under
+    /// a case-insensitive comparison, are still kept apart: ties are broken
+    /// by comparing bytes.
+    pub fn new<F>(cmp: F) -> TermOrder
+        where F: Fn(&str, &str) -> Ordering + Send + Sync + 'static
+    {
+        TermOrder { cmp: Some(Arc::new(cmp)) }
+    }
+
+    /// Compare two terms.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match self.cmp {
+            None => a.cmp(b),
+            Some(ref cmp) => cmp(a, b).then_with(|| a.cmp(b))
+        }
+    }
+}
+
+impl fmt::Debug for TermOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cmp {
+            None => f.write_str("TermOrder::bytes()"),
+            Some(_) => f.write_str("TermOrder::new(..)")
+        }
+    }
+}
+
+impl Default for InMemoryIndex {
+    fn default() -> InMemoryIndex {
+        InMemoryIndex::new()
+    }
+}
+
 impl InMemoryIndex {
     /// Create a new, empty index.
     pub fn new() -> InMemoryIndex {
         InMemoryIndex {
             word_count: 0,
-            map: HashMap::new()
+            byte_count: 0,
+            docs: DocRange::empty(),
+            map: HashMap::default(),
+            spellings: HashMap::default(),
+            case_locale: CaseLocale::Default,
+            presence_only: false
         }
     }
 
-    /// Index a single document.
+    /// Index a single document, using `tokenizer` to break it into words.
     ///
     /// The resulting index contains exactly one `Hit` per term.
-    pub fn from_single_document(document_id: usize, text: String) -> InMemoryIndex {
-        let document_id = document_id as u32;
+    ///
+    /// Panics if `document_id` is greater than `MAX_DOCUMENT_ID`.
+    pub fn from_single_document(document_id: usize, text: &str, tokenizer: &dyn Tokenizer)
+        -> InMemoryIndex
+    {
+        let document_id = stored_document_id(document_id);
+        let index = InMemoryIndex::from_text(document_id, text, tokenizer, 0);
+        index.report_progress(document_id, text.len());
+        index
+    }
+
+    /// Every hundredth document, print a line showing that indexing is making
+    /// progress.
+    fn report_progress(&self, document_id: u32, nbytes: usize) {
+        if document_id.is_multiple_of(100) {
+            println!("indexed document {}, {} bytes, {} words",
+                     document_id, nbytes, self.word_count);
+        }
+    }
+
+    /// Index a single document read from `reader`, such as a file, a network
+    /// connection, or a `Cursor` over text that's already in memory. The
+    /// result is the same as `from_single_document` on the text.
+    ///
+    /// The text is read and indexed a piece at a time, cutting it wherever the
+    /// tokenizer says it's safe to (see `Tokenizer::split_point`), so only
+    /// the index has to fit in memory, not the whole document. A tokenizer
+    /// that can't be cut, like `MarkupStrippingTokenizer`, gets the whole
+    /// document at once. It's an error if the text isn't valid UTF-8.
+    ///
+    /// Panics if `document_id` is greater than `MAX_DOCUMENT_ID`.
+    pub fn from_reader<R: Read>(document_id: usize, mut reader: R, tokenizer: &dyn Tokenizer)
+        -> io::Result<InMemoryIndex>
+    {
+        let document_id = stored_document_id(document_id);
         let mut index = InMemoryIndex::new();
+        index.docs = DocRange::single(document_id);
+        index.case_locale = tokenizer.case_locale();
 
-        let text = text.to_lowercase();
-        let tokens = tokenize(&text);
-        for (i, token) in tokens.iter().enumerate() {
-            let hits =
-                index.map
-                .entry(token.to_string())
-                .or_insert_with(|| {
-                    let mut hits = Vec::with_capacity(4 + 4);
-                    hits.write_u32::<LittleEndian>(document_id).unwrap();
-                    vec![hits]
-                });
-            hits[0].write_u32::<LittleEndian>(i as u32).unwrap();
+        // `pending` holds text that's been read but not yet indexed. A read
+        // can end partway through a character; `chunk` keeps those bytes
+        // until the rest of the character arrives.
+        let mut pending = String::new();
+        let mut chunk: Vec<u8> = vec![];
+        let mut nbytes = 0;
+        loop {
+            let n = reader.by_ref().take(READ_CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            nbytes += n;
+
+            let valid = match str::from_utf8(&chunk) {
+                Ok(text) => text.len(),
+                Err(err) if err.error_len().is_none() => err.valid_up_to(),
+                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err))
+            };
+            pending.push_str(str::from_utf8(&chunk[..valid]).unwrap());
+            chunk.drain(..valid);
+            if let Some(cut) = tokenizer.split_point(&pending) {
+                let first_offset = index.word_count as u32;
+                index.add_text(document_id, &pending[..cut], tokenizer, first_offset);
+                pending.drain(..cut);
+            }
+        }
+        if let Err(err) = str::from_utf8(&chunk) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        let first_offset = index.word_count as u32;
+        index.add_text(document_id, &pending, tokenizer, first_offset);
+
+        index.report_progress(document_id, nbytes);
+        Ok(index)
+    }
+
+    /// Index `text` as part of the document `document_id`, numbering the
+    /// words starting at `first_offset`.
+    fn from_text(document_id: u32, text: &str, tokenizer: &dyn Tokenizer, first_offset: u32)
+        -> InMemoryIndex
+    {
+        let mut index = InMemoryIndex::new();
+        index.docs = DocRange::single(document_id);
+        index.case_locale = tokenizer.case_locale();
+
+        let text = tokenizer.lowercase(text);
+        for (i, token) in tokenizer.tokens(&text).enumerate() {
+            let offset = first_offset + i as u32;
+            // Look the term up by reference first. Most tokens are repeats of
+            // terms already seen, and those don't need a `String` of their
+            // own; only a new key in the map does. (The keys are always owned,
+            // so the index doesn't keep `text` alive.)
+            match index.map.get_mut(&*token) {
+                Some(postings) => postings.hits[0].write_u32::<LittleEndian>(offset).unwrap(),
+                None => {
+                    let mut hit = Vec::with_capacity(HIT_HEADER_SIZE + 4);
+                    hit.write_u32::<LittleEndian>(document_id).unwrap();
+                    hit.write_u32::<LittleEndian>(0).unwrap();
+                    hit.write_u32::<LittleEndian>(offset).unwrap();
+                    index.map.insert(token.into_owned(), PostingList::single(hit));
+                }
+            }
             index.word_count += 1;
         }
 
-        if document_id % 100 == 0 {
-            println!("indexed document {}, {} bytes, {} words", document_id, text.len(), index.word_count);
+        // Now that we know how many times each term appears, fill in the
+        // frequencies.
+        for (term, postings) in index.map.iter_mut() {
+            let hit = &mut postings.hits[0];
+            let frequency = (hit.len() - HIT_HEADER_SIZE) / 4;
+            LittleEndian::write_u32(&mut hit[4..8], frequency as u32);
+            index.byte_count += term_size(term) + hit_size(hit);
         }
 
         index
     }
 
-    /// Add all search hits from `other` to this index.
+    /// Add more text to the end of a document that's already in this index.
+    ///
+    /// This is for documents that grow: instead of reindexing the whole
+    /// document, index just the new text. The new words are numbered as if
+    /// they came right after the last word already indexed for the document.
+    /// For each term, the new offsets are added to the document's existing
+    /// `Hit`, if any, and its frequency goes up accordingly. (Unlike
+    /// `from_single_document`, this doesn't assume `document_id` is new, but
+    /// it works fine if it is.)
+    ///
+    /// This has to scan the whole index to find the end of the document, so
+    /// it's meant for occasional updates, not bulk indexing.
+    pub fn add_to_document(&mut self, document_id: usize, text: &str, tokenizer: &dyn Tokenizer) {
+        let document_id = stored_document_id(document_id);
+        let first_offset =
+            self.map.values()
+            .flat_map(PostingList::hits)
+            .filter(|hit| hit_document_id(hit) == document_id && hit.len() > HIT_HEADER_SIZE)
+            .map(|hit| LittleEndian::read_u32(&hit[hit.len() - 4..]) + 1)
+            .max()
+            .unwrap_or(0);
+        self.add_text(document_id, text, tokenizer, first_offset);
+    }
+
+    /// Index `text` as part of the document `document_id`, numbering the
+    /// words starting at `first_offset`, and add it to this index.
+    fn add_text(&mut self, document_id: u32, text: &str, tokenizer: &dyn Tokenizer,
+                first_offset: u32)
+    {
+        let mut addition = InMemoryIndex::from_text(document_id, text, tokenizer, first_offset);
+        if self.presence_only {
+            addition.drop_frequencies();
+        }
+
+        for (term, new_postings) in addition.map {
+            let new_term_size = term_size(&term);
+            let postings = match self.map.entry(term) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    self.byte_count += new_term_size;
+                    e.insert(PostingList::new())
+                }
+            };
+            // This either adds the new offsets to the document's existing hit,
+            // or inserts a new one.
+            self.byte_count -= postings.hits.iter().map(hit_size).sum::<usize>();
+            postings.merge_from(new_postings);
+            self.byte_count += postings.hits.iter().map(hit_size).sum::<usize>();
+        }
+        self.word_count += addition.word_count;
+        self.docs = self.docs.union(&addition.docs);
+    }
+
+    /// Record how each term in `text` is spelled before lowercasing, in
+    /// `self.spellings`. `text` and `tokenizer` should be the ones this index
+    /// was built from (as by `from_single_document`).
     ///
-    /// If both `*self` and `other` are sorted by document id, and all document
-    /// ids in `other` are greater than every document id in `*self`, then
-    /// `*self` remains sorted by document id after merging.
-    pub fn merge(&mut self, other: InMemoryIndex) {
-        for (term, hits) in other.map {
-            self.map.entry(term)
-                .or_insert_with(|| vec![])
-                .extend(hits)
+    /// This works by tokenizing the text as it is, as well as lowercased, and
+    /// pairing up the tokens. If lowercasing changes where the tokens fall,
+    /// so that they don't pair up, nothing is recorded.
+    pub fn add_spellings(&mut self, text: &str, tokenizer: &dyn Tokenizer) {
+        let lowercase = tokenizer.lowercase(text);
+        let terms: Vec<_> = tokenizer.tokens(&lowercase).collect();
+        let originals: Vec<_> = tokenizer.tokens(text).collect();
+        if terms.len() != originals.len() {
+            return;
+        }
+        let mut spellings: HashMap<&str, Spellings> = HashMap::new();
+        for (term, original) in terms.iter().zip(&originals) {
+            merge_spellings(spellings.entry(term).or_default(), &[(original.to_string(), 1)]);
+        }
+        for (term, new) in spellings {
+            self.add_term_spellings(term, &new);
+        }
+    }
+
+    /// Add `new` to the spellings recorded for `term`, keeping `byte_count`
+    /// up to date.
+    fn add_term_spellings(&mut self, term: &str, new: &[(String, u32)]) {
+        match self.spellings.get_mut(term) {
+            Some(all) => {
+                let before = spellings_size(all);
+                merge_spellings(all, new);
+                self.byte_count += spellings_size(all) - before;
+            }
+            None => {
+                let term = term.to_string();
+                let all = new.to_vec();
+                self.byte_count += term_size(&term) + spellings_size(&all);
+                self.spellings.insert(term, all);
+            }
+        }
+    }
+
+    /// Add all search hits (and spellings) from `other` to this index. If
+    /// `cap` is given, the hits for each term in `other` are trimmed to fit it
+    /// afterwards.
+    ///
+    /// This is fastest when all the document ids in `other` are greater than
+    /// every document id in `*self`, as they are when documents are indexed
+    /// in order; see `PostingList::merge_from`.
+    ///
+    /// Both indexes should have been built with the same tokenizer. If this
+    /// one covers no documents yet, it takes on `other`'s `case_locale`. If
+    /// either one is `presence_only`, so is the result.
+    pub fn merge(&mut self, mut other: InMemoryIndex, cap: Option<&PostingsCap>) {
+        if other.presence_only && !self.presence_only {
+            self.drop_frequencies();
+        } else if self.presence_only && !other.presence_only {
+            other.drop_frequencies();
+        }
+        for (term, postings) in other.map {
+            let new_term_size = term_size(&term);
+            let all_postings = match self.map.entry(term) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    self.byte_count += new_term_size;
+                    e.insert(PostingList::new())
+                }
+            };
+            self.byte_count -= all_postings.hits.iter().map(hit_size).sum::<usize>();
+            all_postings.merge_from(postings);
+            if let Some(cap) = cap {
+                cap.trim(all_postings);
+            }
+            self.byte_count += all_postings.hits.iter().map(hit_size).sum::<usize>();
+        }
+        for (term, spellings) in other.spellings {
+            self.add_term_spellings(&term, &spellings);
+        }
+        if self.docs.is_empty() {
+            self.case_locale = other.case_locale;
         }
         self.word_count += other.word_count;
+        self.docs = self.docs.union(&other.docs);
+    }
+
+    /// The number of distinct terms in this index.
+    pub fn term_count(&self) -> usize {
+        self.map.len()
     }
 
-    /// True if this index contains no data.
+    /// Count the tokens and terms in this index. For an index made by
+    /// `from_single_document`, these are the statistics for that document.
+    ///
+    /// Token lengths are those of the terms as indexed, that is, after the
+    /// tokenizer has lowercased or otherwise changed them.
+    pub fn document_stats(&self) -> DocumentStats {
+        let token_chars =
+            self.map.iter()
+            .map(|(term, postings)| {
+                let frequency: usize = postings.iter().map(|(_, tf)| tf as usize).sum();
+                term.chars().count() * frequency
+            })
+            .sum();
+        DocumentStats {
+            tokens: self.word_count,
+            distinct_terms: self.map.len(),
+            token_chars
+        }
+    }
+
+    /// Iterate over the terms in this index in sorted order, along with the
+    /// document id and frequency of each hit for the term, in order by
+    /// document id (see `PostingList::iter`).
+    pub fn iter(&self)
+        -> impl Iterator<Item = (&str, impl Iterator<Item = (u32, u32)> + '_)> + '_
+    {
+        let mut terms: Vec<(&str, &PostingList)> =
+            self.map.iter()
+            .map(|(term, postings)| (term.as_str(), postings))
+            .collect();
+        terms.sort_by_key(|&(term, _)| term);
+        terms.into_iter().map(|(term, postings)| (term, postings.iter()))
+    }
+
+    /// True if this index contains no terms.
+    ///
+    /// An index of documents that contain no words is empty, even though it
+    /// covers some document ids. There's no point writing it to disk.
     pub fn is_empty(&self) -> bool {
-        self.word_count == 0
+        self.map.is_empty()
+    }
+
+    /// An estimate of the memory this index uses, in bytes: the text of every
+    /// term and the buffer of every hit, by capacity rather than length, plus
+    /// a fixed overhead for each term's hash table slot and each hit's `Vec`;
+    /// and likewise for `spellings`, if any.
+    ///
+    /// This is kept up to date as the index changes, so it's cheap to call.
+    /// It leaves out the spare capacity of the hash table and of each term's
+    /// list of hits, so the real figure is somewhat higher.
+    pub fn byte_size(&self) -> usize {
+        self.byte_count
+    }
+
+    /// Remove everything from this index, leaving it just like a new one,
+    /// except that the memory for its hash tables stays allocated. Reusing an
+    /// index this way saves growing the tables all over again, one rehash at a
+    /// time, as terms are added.
+    pub fn clear(&mut self) {
+        self.word_count = 0;
+        self.byte_count = 0;
+        self.docs = DocRange::empty();
+        self.map.clear();
+        self.spellings.clear();
+        self.case_locale = CaseLocale::Default;
+        self.presence_only = false;
+    }
+
+    /// Throw away the frequency and offsets in every hit, keeping only which
+    /// documents each term appears in. This makes the index much smaller, for
+    /// applications that only need to know which documents match a query,
+    /// not how well. Each hit is left with a frequency of 0, meaning it isn't
+    /// known.
+    pub fn drop_frequencies(&mut self) {
+        for hit in self.map.values_mut().flat_map(|postings| &mut postings.hits) {
+            self.byte_count -= hit_size(hit);
+            hit.truncate(HIT_HEADER_SIZE);
+            hit.shrink_to_fit();
+            LittleEndian::write_u32(&mut hit[4..8], 0);
+            self.byte_count += hit_size(hit);
+        }
+        self.presence_only = true;
+    }
+
+    /// Free the memory this index has allocated but isn't using: the spare
+    /// capacity of each hit, of each term's list of hits, and of the hash
+    /// tables. `byte_size` goes down to match.
+    ///
+    /// Hits are built up one word at a time, so a hit's buffer typically has
+    /// room for more offsets than it holds, and the lists of hits grow as
+    /// indexes are merged. Shrinking means copying all of them, though, so
+    /// it's worth doing only for an index that's going to stay in memory for
+    /// a while, not one that's about to be written to disk and dropped.
+    pub fn shrink_to_fit(&mut self) {
+        for postings in self.map.values_mut() {
+            postings.hits.shrink_to_fit();
+            for hit in postings.hits.iter_mut() {
+                let before = hit_size(hit);
+                hit.shrink_to_fit();
+                self.byte_count -= before - hit_size(hit);
+            }
+        }
+        self.map.shrink_to_fit();
+        for spellings in self.spellings.values_mut() {
+            spellings.shrink_to_fit();
+        }
+        self.spellings.shrink_to_fit();
     }
 
     /// True if this index is large enough that we should dump it to disk rather
-    /// than keep adding more data to it.
+    /// than keep adding more data to it: that is, if `byte_size` is greater
+    /// than `LARGE_INDEX_BYTES`.
     pub fn is_large(&self) -> bool {
-        // This depends on how much memory your computer has, of course.
-        const REASONABLE_SIZE: usize = 100_000_000;
-        self.word_count > REASONABLE_SIZE
+        self.byte_size() > LARGE_INDEX_BYTES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markup::{Markup, MarkupStrippingTokenizer};
+
+    /// A hit for `document_id`, with the word at each of `offsets`.
+    fn hit(document_id: u32, offsets: &[u32]) -> Hit {
+        let mut hit = vec![];
+        hit.write_u32::<LittleEndian>(document_id).unwrap();
+        hit.write_u32::<LittleEndian>(offsets.len() as u32).unwrap();
+        for &offset in offsets {
+            hit.write_u32::<LittleEndian>(offset).unwrap();
+        }
+        hit
+    }
+
+    fn postings(hits: Vec<Hit>) -> PostingList {
+        PostingList::from_hits(hits).unwrap()
+    }
+
+    /// A reader that hands out its text a few bytes at a time, cutting
+    /// through words and characters.
+    struct Trickle<'a> {
+        text: &'a [u8],
+        reads: usize
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            let n = (self.reads % 7 + 1).min(buf.len()).min(self.text.len());
+            buf[..n].copy_from_slice(&self.text[..n]);
+            self.text = &self.text[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn from_reader_matches_from_single_document() {
+        // Long enough to be read in pieces, with a character cut in two at
+        // the end of the first piece.
+        let long = "a\u{f6}bbb ".repeat(READ_CHUNK_SIZE / 3);
+        assert!(!long.is_char_boundary(READ_CHUNK_SIZE));
+        let texts = ["", "one", "The cat sat on the mat.\nThe END",
+                     "caf\u{e9} \u{65e5}\u{672c}\u{8a9e} na\u{ef}ve",
+                     "\u{3a3}\u{3a3} \u{3a3}\u{3a3}\t\u{130}stanbul", &long,
+                     "<p>a <b\nclass=x>tag</b> &amp; more</p>"];
+        let tokenizers: Vec<Box<dyn Tokenizer>> = vec![
+            Box::new(DefaultTokenizer),
+            Box::new(CodeTokenizer::new(true)),
+            Box::new(CjkBigramTokenizer::new(Box::new(DefaultTokenizer))),
+            Box::new(NgramTokenizer::new(Box::new(DefaultTokenizer), 3, true)),
+            Box::new(LocaleTokenizer::new(Box::new(DefaultTokenizer), CaseLocale::Turkic)),
+            Box::new(MarkupStrippingTokenizer::new(Box::new(DefaultTokenizer), Markup::Html)),
+        ];
+        for tokenizer in &tokenizers {
+            for text in texts {
+                let expected = InMemoryIndex::from_single_document(3, text, &**tokenizer);
+                let from_cursor =
+                    InMemoryIndex::from_reader(3, io::Cursor::new(text), &**tokenizer).unwrap();
+                let trickle = Trickle { text: text.as_bytes(), reads: 0 };
+                let from_trickle = InMemoryIndex::from_reader(3, trickle, &**tokenizer).unwrap();
+                for index in [from_cursor, from_trickle] {
+                    assert_eq!(index.map, expected.map, "{:?}", text);
+                    assert_eq!(index.word_count, expected.word_count);
+                    assert_eq!(index.docs, expected.docs);
+                    assert_eq!(index.case_locale, expected.case_locale);
+                }
+            }
+        }
+
+        for bad in [&b"ok \xff bad"[..], b"cut off \xc3"] {
+            let err = InMemoryIndex::from_reader(0, bad, &DefaultTokenizer).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn borrowed_tokens_index_the_same_as_owned_ones() {
+        let text = "The cat sat. THE CAT! the_cat x += cat_sat; 猫が座った cat cat";
+        let tokenizers: Vec<Box<dyn Tokenizer>> = vec![
+            Box::new(DefaultTokenizer),
+            Box::new(CodeTokenizer::new(false)),
+            Box::new(NgramTokenizer::new(Box::new(DefaultTokenizer), 3, true)),
+            Box::new(CjkBigramTokenizer::new(Box::new(DefaultTokenizer))),
+            Box::new(TruncatingTokenizer::new(Box::new(DefaultTokenizer), 2)),
+        ];
+        for tokenizer in &tokenizers {
+            // The straightforward way: an owned `String` for every token.
+            let lowercase = tokenizer.lowercase(text);
+            let mut expected: HashMap<String, Vec<u32>> = HashMap::new();
+            let mut words = 0;
+            for (i, token) in tokenizer.tokens(&lowercase).enumerate() {
+                expected.entry(token.into_owned()).or_default().push(i as u32);
+                words += 1;
+            }
+
+            let index = InMemoryIndex::from_single_document(7, text, &**tokenizer);
+            assert_eq!(index.word_count, words);
+            assert_eq!(index.map.len(), expected.len());
+            for (term, offsets) in &expected {
+                assert_eq!(index.map[term], postings(vec![hit(7, offsets)]), "term {:?}", term);
+            }
+        }
+    }
+
+    #[test]
+    fn posting_list_rejects_hits_out_of_order() {
+        let err = PostingList::from_hits(vec![hit(2, &[0]), hit(1, &[0])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = PostingList::from_hits(vec![hit(1, &[0]), hit(1, &[3])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn posting_list_merge_appends() {
+        let mut list = postings(vec![hit(0, &[1]), hit(3, &[2, 5])]);
+        list.merge_from(postings(vec![hit(4, &[0]), hit(9, &[7])]));
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![(0, 1), (3, 2), (4, 1), (9, 1)]);
+    }
+
+    #[test]
+    fn posting_list_merge_interleaved_stays_sorted() {
+        let mut list = postings(vec![hit(1, &[0]), hit(5, &[0]), hit(8, &[0])]);
+        list.merge_from(postings(vec![hit(0, &[0]), hit(6, &[0]), hit(9, &[0])]));
+        let ids: Vec<u32> = list.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![0, 1, 5, 6, 8, 9]);
+        assert!(PostingList::from_hits(list.into_hits()).is_ok());
+    }
+
+    #[test]
+    fn posting_list_merge_combines_same_document() {
+        let mut list = postings(vec![hit(1, &[0]), hit(2, &[4])]);
+        list.merge_from(postings(vec![hit(2, &[6, 9])]));
+        assert_eq!(list.hits(), &[hit(1, &[0]), hit(2, &[4, 6, 9])]);
+        assert_eq!(list.len(), 2);
+    }
+
+    #[test]
+    fn posting_list_encode_decode() {
+        let list = postings(vec![hit(0, &[1, 2]), hit(7, &[]), hit(1000, &[3])]);
+        for codec in [Codec::Raw, Codec::Varint, Codec::FrameOfReference] {
+            let mut data = vec![];
+            list.encode(codec, &mut data).unwrap();
+            assert_eq!(PostingList::decode(codec, &data).unwrap(), list, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn codecs_refuse_hits_out_of_order() {
+        let raw = [hit(5, &[0]), hit(2, &[0])].concat();
+        for codec in [Codec::Varint, Codec::FrameOfReference] {
+            let err = codec.compress(&raw, &mut vec![]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn postings_cap_keeps_document_order() {
+        let cap = PostingsCap { max: 2, policy: CapPolicy::KeepMostFrequent };
+        let mut list = postings(vec![hit(0, &[0, 1, 2]), hit(1, &[0]), hit(2, &[0, 1])]);
+        cap.trim(&mut list);
+        assert_eq!(list.iter().collect::<Vec<_>>(), vec![(0, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn index_records_case_locale() {
+        let turkic = || Box::new(LocaleTokenizer::new(Box::new(DefaultTokenizer),
+                                                      CaseLocale::Turkic));
+        let wrapped = WhitespaceNormalizingTokenizer::new(turkic());
+        assert_eq!(wrapped.case_locale(), CaseLocale::Turkic);
+        let index = InMemoryIndex::from_single_document(1, "Irmak", &wrapped);
+        assert_eq!(index.case_locale, CaseLocale::Turkic);
+        assert!(index.map.contains_key("\u{131}rmak"));
+
+        let mut all = InMemoryIndex::new();
+        all.merge(index, None);
+        assert_eq!(all.case_locale, CaseLocale::Turkic);
+        all.clear();
+        assert_eq!(all.case_locale, CaseLocale::Default);
+    }
+
+    #[test]
+    fn case_locale_ids() {
+        for locale in [CaseLocale::Default, CaseLocale::Turkic, CaseLocale::Unchanged] {
+            assert_eq!(CaseLocale::from_id(locale.id()).unwrap(), locale);
+        }
+        assert_eq!(CaseLocale::from_id(3).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn turkic_lowercase() {
+        assert_eq!(CaseLocale::Turkic.lowercase("\u{130}I"), "i\u{131}");
+        assert_eq!(CaseLocale::Default.lowercase("\u{130}I"), "i\u{307}i");
+        assert_eq!(CaseLocale::Unchanged.lowercase("\u{130}I Rust"), "\u{130}I Rust");
+        assert_eq!(CaseLocale::Turkic.lowercase("D\u{130}YARBAKIR"), "diyarbak\u{131}r");
+        // Final sigma still works once the special letters are out of the way.
+        assert_eq!(CaseLocale::Turkic.lowercase("I\u{3a3}\u{3a3}"), "\u{131}\u{3c3}\u{3c2}");
+        assert_eq!(CaseLocale::Turkic.lowercase("Rust"), CaseLocale::Default.lowercase("Rust"));
+    }
+
+    #[test]
+    fn case_locale_from_tag() {
+        assert_eq!(CaseLocale::from_tag("tr"), CaseLocale::Turkic);
+        assert_eq!(CaseLocale::from_tag("TR-tr"), CaseLocale::Turkic);
+        assert_eq!(CaseLocale::from_tag("az_Latn"), CaseLocale::Turkic);
+        assert_eq!(CaseLocale::from_tag("en-US"), CaseLocale::Default);
+        assert_eq!(CaseLocale::from_tag(""), CaseLocale::Default);
+    }
+
+    fn tokens(tokenizer: &dyn Tokenizer, text: &str) -> Vec<String> {
+        tokenizer.tokens(text).map(Cow::into_owned).collect()
+    }
+
+    /// Splits text at commas, keeping everything else, spaces included.
+    struct CommaTokenizer;
+
+    impl Tokenizer for CommaTokenizer {
+        fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+            Box::new(text.split(',').filter(|s| !s.is_empty()).map(Cow::Borrowed))
+        }
+    }
+
+    #[test]
+    fn custom_tokenizer_decides_the_terms() {
+        let text = "New York,Paris,new york";
+        let index = InMemoryIndex::from_single_document(3, text, &CommaTokenizer);
+        let contents: Vec<(&str, Vec<(u32, u32)>)> =
+            index.iter().map(|(term, hits)| (term, hits.collect())).collect();
+        assert_eq!(contents, vec![("new york", vec![(3, 2)]), ("paris", vec![(3, 1)])]);
+        assert_eq!(index.map["new york"].hits(), &[hit(3, &[0, 2])]);
+    }
+
+    #[test]
+    fn ngrams_are_padded() {
+        let trigrams = NgramTokenizer::new(Box::new(DefaultTokenizer), 3, false);
+        assert_eq!(tokens(&trigrams, "fox a"), ["^fo", "fox", "ox$", "^a$"]);
+        let with_words = NgramTokenizer::new(Box::new(DefaultTokenizer), 3, true);
+        assert_eq!(tokens(&with_words, "ox"), ["ox", "^ox", "ox$"]);
+    }
+
+    #[test]
+    fn ngram_padding_is_not_escaped() {
+        // `^=` is a word to the code tokenizer, so its n-grams look just like
+        // those of a word that starts with `^`.
+        let trigrams = NgramTokenizer::new(Box::new(CodeTokenizer::new(false)), 3, false);
+        assert_eq!(tokens(&trigrams, "x ^= y"), ["^x$", "^^=", "^=$", "^y$"]);
+    }
+
+    #[test]
+    fn iter_yields_sorted_terms_with_ids_and_frequencies() {
+        let mut index = InMemoryIndex::from_single_document(4, "b a b", &DefaultTokenizer);
+        index.merge(InMemoryIndex::from_single_document(7, "c b", &DefaultTokenizer), None);
+        assert_eq!(index.term_count(), 3);
+        let contents: Vec<(&str, Vec<(u32, u32)>)> =
+            index.iter().map(|(term, hits)| (term, hits.collect())).collect();
+        assert_eq!(contents, vec![
+            ("a", vec![(4, 1)]),
+            ("b", vec![(4, 2), (7, 1)]),
+            ("c", vec![(7, 1)])
+        ]);
+    }
+
+    #[test]
+    fn add_to_document_accumulates_frequencies() {
+        let mut index = InMemoryIndex::from_single_document(2, "red fish", &DefaultTokenizer);
+        index.merge(InMemoryIndex::from_single_document(3, "blue fish", &DefaultTokenizer), None);
+        index.add_to_document(2, "Red red boat", &DefaultTokenizer);
+        index.add_to_document(2, "fish", &DefaultTokenizer);
+
+        let contents: Vec<(&str, Vec<(u32, u32)>)> =
+            index.iter().map(|(term, hits)| (term, hits.collect())).collect();
+        assert_eq!(contents, vec![
+            ("blue", vec![(3, 1)]),
+            ("boat", vec![(2, 1)]),
+            ("fish", vec![(2, 2), (3, 1)]),
+            ("red", vec![(2, 3)])
+        ]);
+        // The new words are numbered after the old ones.
+        assert_eq!(index.map["red"].hits(), &[hit(2, &[0, 2, 3])]);
+        assert_eq!(index.map["fish"].hits(), &[hit(2, &[1, 5]), hit(3, &[1])]);
+        assert_eq!(index.word_count, 8);
+
+        // Adding to a document that isn't there yet is like indexing it.
+        index.add_to_document(9, "new", &DefaultTokenizer);
+        assert_eq!(index.map["new"].hits(), &[hit(9, &[0])]);
+        assert_eq!(index.docs, DocRange { start: 2, end: 10 });
+    }
+
+    #[test]
+    fn every_kind_of_whitespace_separates_words() {
+        let texts = ["one two three", "one\ttwo\r\nthree", "one\u{a0}two\u{3000}three",
+                     " one \t\r\n two\u{a0}\u{a0}three\n"];
+        let normalizing = |inner: Box<dyn Tokenizer>| WhitespaceNormalizingTokenizer::new(inner);
+        let space = || RegexTokenizer::new(Regex::new("[^ ]+").unwrap());
+        for text in texts {
+            assert_eq!(tokens(&DefaultTokenizer, text), ["one", "two", "three"], "{:?}", text);
+            assert_eq!(tokens(&normalizing(Box::new(space())), text), ["one", "two", "three"],
+                       "{:?}", text);
+            let index = InMemoryIndex::from_single_document(0, text, &DefaultTokenizer);
+            let terms: Vec<&str> = index.iter().map(|(term, _)| term).collect();
+            assert_eq!(terms, ["one", "three", "two"], "{:?}", text);
+        }
+        // Without normalizing, a regex that splits at spaces doesn't see the
+        // others as separators.
+        assert_eq!(tokens(&space(), "one\ttwo three"), ["one\ttwo", "three"]);
+    }
+
+    #[test]
+    fn is_empty_means_no_terms() {
+        let mut index = InMemoryIndex::new();
+        assert!(index.is_empty());
+        // A document with no words covers an id but adds no terms.
+        index.merge(InMemoryIndex::from_single_document(0, "!?", &DefaultTokenizer), None);
+        assert!(index.is_empty());
+        assert!(!index.docs.is_empty());
+        index.merge(InMemoryIndex::from_single_document(1, "word", &DefaultTokenizer), None);
+        assert!(!index.is_empty());
+        index.clear();
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn is_large_means_more_than_the_threshold() {
+        let mut index = InMemoryIndex::from_single_document(0, "word", &DefaultTokenizer);
+        assert!(!index.is_large());
+        index.byte_count = LARGE_INDEX_BYTES;
+        assert!(!index.is_large());
+        index.byte_count = LARGE_INDEX_BYTES + 1;
+        assert!(index.is_large());
+    }
+
+    /// The memory `index` uses, counted from scratch the way `byte_size`
+    /// describes.
+    fn recount_bytes(index: &InMemoryIndex) -> usize {
+        let terms: usize = index.map.iter()
+            .map(|(term, postings)| {
+                term_size(term) + postings.hits.iter().map(hit_size).sum::<usize>()
+            })
+            .sum();
+        let spellings: usize = index.spellings.iter()
+            .map(|(term, all)| term_size(term) + spellings_size(all))
+            .sum();
+        terms + spellings
+    }
+
+    #[test]
+    fn shrink_to_fit_frees_spare_capacity() {
+        let mut index = InMemoryIndex::new();
+        for id in 0..50 {
+            let text = format!("fish {} fish fish w{} {}", "one ".repeat(id % 7), id, id % 3);
+            index.merge(InMemoryIndex::from_single_document(id, &text, &DefaultTokenizer), None);
+        }
+        let spare = |index: &InMemoryIndex| -> usize {
+            index.map.values()
+                .map(|postings| {
+                    postings.hits.capacity() - postings.hits.len()
+                        + postings.hits.iter().map(|hit| hit.capacity() - hit.len()).sum::<usize>()
+                })
+                .sum()
+        };
+        let terms: Vec<(String, Vec<(u32, u32)>)> = index.iter()
+            .map(|(term, hits)| (term.to_string(), hits.collect()))
+            .collect();
+        let (before, bytes_before) = (spare(&index), index.byte_size());
+        assert!(before > 0);
+
+        index.shrink_to_fit();
+        assert_eq!(spare(&index), 0);
+        assert!(index.byte_size() < bytes_before);
+        assert_eq!(index.byte_size(), recount_bytes(&index));
+        let after: Vec<(String, Vec<(u32, u32)>)> = index.iter()
+            .map(|(term, hits)| (term.to_string(), hits.collect()))
+            .collect();
+        assert_eq!(after, terms);
+    }
+
+    #[test]
+    fn byte_size_grows_with_each_document_and_matches_a_recount() {
+        let docs = ["The quick brown fox", "jumps over the lazy dog", "the the the",
+                    "Fox and Dog", "", "one more document with some new words"];
+        let mut index = InMemoryIndex::new();
+        let mut text_bytes = 0;
+        for (id, text) in docs.iter().enumerate() {
+            let before = index.byte_size();
+            let mut doc = InMemoryIndex::from_single_document(id, text, &DefaultTokenizer);
+            doc.add_spellings(text, &DefaultTokenizer);
+            assert_eq!(doc.byte_size(), recount_bytes(&doc), "{:?}", text);
+            index.merge(doc, None);
+            text_bytes += text.len();
+            if !text.is_empty() {
+                assert!(index.byte_size() > before, "{:?}", text);
+            }
+            assert_eq!(index.byte_size(), recount_bytes(&index), "{:?}", text);
+            assert!(index.byte_size() >= text_bytes);
+        }
+        index.drop_frequencies();
+        assert_eq!(index.byte_size(), recount_bytes(&index));
+    }
+
+    #[test]
+    fn truncation_boundaries() {
+        let truncate = TruncatingTokenizer::new(Box::new(DefaultTokenizer), 4);
+        assert_eq!(tokens(&truncate, "abcd"), ["abcd"]);
+        assert_eq!(tokens(&truncate, "abcde"), ["abcd"]);
+        // The limit is in characters, not bytes, and a multi-byte character
+        // is never split.
+        assert_eq!(tokens(&truncate, "\u{e9}t\u{e9}s"), ["\u{e9}t\u{e9}s"]);
+        assert_eq!(tokens(&truncate, "abc\u{e9}\u{e9}"), ["abc\u{e9}"]);
+        assert_eq!(tokens(&truncate, "\u{65e5}\u{672c}\u{8a9e}\u{6587}\u{5b57}"),
+                   ["\u{65e5}\u{672c}\u{8a9e}\u{6587}"]);
+    }
+
+    #[test]
+    fn ascii_tokenizer_matches_default() {
+        let texts = [
+            "",
+            "The quick brown fox, the LAZY dog; fox\tdog\r\n42 x86_64 3.14",
+            "  leading and trailing  ",
+            "MiXeD CaSe wOrDs",
+            "caf\u{e9} CAF\u{c9} na\u{ef}ve \u{130}stanbul \u{3a3}\u{3a3}",
+        ];
+        for text in texts {
+            assert_eq!(AsciiTokenizer.lowercase(text), DefaultTokenizer.lowercase(text));
+            let lowered = DefaultTokenizer.lowercase(text);
+            assert_eq!(tokens(&AsciiTokenizer, &lowered), tokens(&DefaultTokenizer, &lowered),
+                       "{:?}", text);
+        }
     }
 }
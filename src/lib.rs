@@ -0,0 +1,57 @@
+//! `fingertips` creates an inverted index for a set of text files.
+//!
+//! The library contains the building blocks; `main.rs` puts them together
+//! into the command-line tool.
+//!
+//! *   `index` turns documents into in-memory indexes.
+//!
+//! *   `markup` strips HTML and Markdown formatting from documents.
+//!
+//! *   `write` saves an in-memory index to a file, and `read` reads such files
+//!     back linearly. `compress` has the codecs that can shrink those files.
+//!
+//! *   `merge` combines many index files into one big one.
+//!
+//! *   `search` looks up terms in a finished index file, and finds and ranks the
+//!     documents that match a query. It can also search several unmerged
+//!     index files as one.
+//!
+//! *   `query` parses query strings, with phrases, exclusions, and prefixes.
+//!
+//! *   `audit` checks the terms in a finished index for signs of messy data.
+//!
+//! *   `export` writes an index file out in formats other programs can read.
+//!
+//! *   `estimate` predicts how big an index will be, from a sample of the
+//!     documents.
+//!
+//! *   `tmp` hands out names for temporary files.
+//!
+//! The main entry points for using a finished index, like `search::Index`,
+//! `read::IndexFileReader::open`, `merge::merge_files`, and
+//! `export::export_csv`, return an `Error`, which says whether the file
+//! wasn't an index file, was in another version of the file format, was
+//! damaged, or couldn't be read at all, or whether a query didn't parse.
+//! Everything else returns an `io::Error`. Reading an index file that's
+//! damaged fails with `io::ErrorKind::InvalidData` (or `UnexpectedEof`, if
+//! the file is cut short), so that can still be told apart from other
+//! errors; see `Error` for how the two convert.
+
+pub mod error;
+pub mod index;
+pub mod markup;
+pub mod read;
+pub mod write;
+pub mod compress;
+pub mod merge;
+pub mod search;
+pub mod query;
+pub mod audit;
+pub mod export;
+pub mod estimate;
+pub mod tmp;
+
+pub use crate::error::Error;
+
+#[cfg(test)]
+mod test_util;
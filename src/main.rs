@@ -1,42 +1,424 @@
-/// `fingertips` creates an inverted index for a set of text files.
+//! `fingertips` creates an inverted index for a set of text files.
+//!
+//! Most of the actual work is done by the library modules `index`, `read`,
+//! `write`, and `merge`.  In this file, `main.rs`, we put the pieces together
+//! in two different ways.
+//!
+//! *   `run_single_threaded` simply does everything in one thread, in
+//!     the most straightforward possible way.
+//!
+//! *   Then, we break the work into a five-stage pipeline so that we can run
+//!     it on multiple CPUs. `run_pipeline` puts the five stages together.
+//!
+//! The `main` function at the end handles command-line arguments. It calls one
+//! of the two functions above to do the work.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::io::prelude::*;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use argparse::{ArgumentParser, StoreTrue, Store, StoreOption, Collect};
+use regex::Regex;
+
+use fingertips::index::{InMemoryIndex, Tokenizer, DefaultTokenizer, NumberTokenizer, RegexTokenizer,
+                        AsciiTokenizer, CodeTokenizer, WhitespaceNormalizingTokenizer,
+                        TruncatingTokenizer, NgramTokenizer, LocaleTokenizer, CaseLocale,
+                        CjkBigramTokenizer, VocabularyTokenizer, DocumentStats,
+                        PostingsCap, CapPolicy, TermOrder, MAX_DOCUMENT_ID};
+use fingertips::compress::Codec;
+use fingertips::markup::{Markup, MarkupStrippingTokenizer};
+use fingertips::write::write_index_to_tmp_file;
+use fingertips::merge::{AddedFile, FileMerge, MERGED_FILENAME, merge_files};
+use fingertips::export::export_csv;
+use fingertips::estimate::estimate_index_size;
+use fingertips::search::{Index, SearchOptions, SortOrder, read_doc_stats};
+use fingertips::query::{Query, parse_query};
+use fingertips::audit::case_variants;
+use fingertips::tmp::TmpDir;
+
+/// Options that affect what ends up in the index, as opposed to how the work
+/// of building it is done.
+struct IndexOptions {
+    /// The id of the first document. The rest are numbered consecutively.
+    docid_base: usize,
+
+    /// How to break documents into words.
+    tokenizer: Arc<dyn Tokenizer>,
+
+    /// The limit on the number of hits stored for any one term, if any.
+    postings_cap: Option<PostingsCap>,
+
+    /// Terms that appear in fewer documents than this are left out.
+    min_doc_count: usize,
+
+    /// Whether to record how each term is spelled before lowercasing (see
+    /// `InMemoryIndex::add_spellings`).
+    spellings: bool,
+
+    /// Whether to index the words in each document's path too (see
+    /// `add_path_words`).
+    filenames: bool,
+
+    /// Whether to store only which documents each term appears in, without
+    /// frequencies or offsets (see `InMemoryIndex::drop_frequencies`).
+    presence_only: bool,
+
+    /// Where to write statistics about each document as it's indexed, if
+    /// anywhere.
+    doc_stats: Option<DocStatsWriter>,
+
+    /// Write the in-memory index to a temporary file after this many
+    /// documents, if it hasn't been written already for getting too big (see
+    /// `is_time_to_flush`).
+    flush_every: Option<usize>
+}
+
+/// Writes the `--doc-stats` file: one line of tab-separated values for each
+/// document, written as soon as the document is indexed. Paths are escaped
+/// with `tsv_field`.
+struct DocStatsWriter {
+    out: io::BufWriter<File>,
+
+    /// The file's name, so it can be kept out of the index.
+    path: PathBuf
+}
+
+impl DocStatsWriter {
+    fn create(filename: &Path) -> io::Result<DocStatsWriter> {
+        let mut out = io::BufWriter::new(File::create(filename)?);
+        writeln!(out, "doc_id\tpath\ttokens\tdistinct_terms\tavg_token_len")?;
+        Ok(DocStatsWriter { out, path: filename.to_owned() })
+    }
+
+    fn write(&mut self, document_id: usize, path: &Path, stats: &DocumentStats)
+        -> io::Result<()>
+    {
+        writeln!(self.out, "{}\t{}\t{}\t{}\t{:.2}",
+                 document_id, tsv_field(&path.to_string_lossy()), stats.tokens,
+                 stats.distinct_terms, stats.average_token_len())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Options that control how documents are loaded from disk.
+#[derive(Clone, Copy)]
+struct ReadOptions {
+    /// How many times to retry reading a file after a transient error.
+    retries: u32,
+
+    /// How many files to read at once, in the pipeline. Reading many files in
+    /// parallel helps when there are lots of small ones, since then the time
+    /// is mostly spent waiting for `open` and `read` system calls.
+    threads: usize,
+
+    /// How many read errors to print before going quiet, if there's a limit.
+    /// The rest are only counted (see `report_read_error`).
+    quiet_errors_after: Option<usize>,
+
+    /// Files bigger than this many bytes are skipped, if there's a limit, so
+    /// that one huge file that got into the corpus by accident can't use up
+    /// all the memory.
+    max_file_size: Option<u64>,
+
+    /// How long to wait for a file to be read before giving up on it, if
+    /// there's a limit. This is for network filesystems, where a read can
+    /// hang indefinitely (see `read_with_timeout`).
+    read_timeout: Option<Duration>,
+
+    /// Whether documents with non-ASCII text in them count as unreadable
+    /// (`--ascii-strict`).
+    ascii_only: bool
+}
+
+/// True if `err` might go away if we just try again, as can happen on
+/// network filesystems.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(err.kind(),
+             io::ErrorKind::Interrupted |
+             io::ErrorKind::WouldBlock |
+             io::ErrorKind::TimedOut)
+}
+
+/// Load the contents of a document into memory.
+fn read_whole_file(filename: &Path, options: &ReadOptions) -> io::Result<String> {
+    read_document(&Filesystem, filename, options)
+}
+
+/// `read_whole_file`, reading from `source`.
+fn read_document(source: &'static dyn DocumentSource, filename: &Path, options: &ReadOptions)
+    -> io::Result<String>
+{
+    let mut text = String::new();
+    read_document_into(source, filename, options, &mut text)?;
+    Ok(text)
+}
+
+/// Load the contents of a document into `text`, replacing whatever was there
+/// before. Reusing the same `String` for one file after another saves
+/// allocating a new buffer for each file, which adds up when there are lots
+/// of small files. On error, `text` is left with unspecified contents.
 ///
-/// Most of the actual work is done by the modules `index`, `read`, `write`,
-/// and `merge`.  In this file, `main.rs`, we put the pieces together in two
-/// different ways.
+/// If reading the file fails with a transient error, this waits a bit and
+/// tries again, up to `options.retries` times, doubling the wait each time.
 ///
-/// *   `run_single_threaded` simply does everything in one thread, in
-///     the most straightforward possible way.
+/// Whatever the file contains at the time it's read is what gets indexed.
+/// Its size was checked against `options.max_file_size` earlier, when the
+/// list of documents was made, but the file may have grown since then, so
+/// the limit is enforced again here; a file that's now too big is an error.
 ///
-/// *   Then, we break the work into a five-stage pipeline so that we can run
-///     it on multiple CPUs. `run_pipeline` puts the five stages together.
+/// With `options.read_timeout`, a read that takes too long fails with
+/// `io::ErrorKind::TimedOut`, which is transient, so it's retried too.
 ///
-/// The `main` function at the end handles command-line arguments. It calls one
-/// of the two functions above to do the work.
+/// With `options.ascii_only`, a file that isn't all ASCII fails with
+/// `io::ErrorKind::InvalidData`, just like one that isn't UTF-8.
+fn read_whole_file_into(filename: &Path, options: &ReadOptions, text: &mut String)
+    -> io::Result<()>
+{
+    read_document_into(&Filesystem, filename, options, text)
+}
 
-mod index;
-mod read;
-mod write;
-mod merge;
-mod tmp;
+/// Where documents are read from: the filesystem, except in tests, which
+/// substitute sources that fail or hang, like a flaky network mount.
+trait DocumentSource: Sync {
+    fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>>;
+}
 
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
-use std::thread::{spawn, JoinHandle};
-use argparse::{ArgumentParser, StoreTrue, Collect};
+/// The usual `DocumentSource`.
+struct Filesystem;
 
-use crate::index::InMemoryIndex;
-use crate::write::write_index_to_tmp_file;
-use crate::merge::FileMerge;
-use crate::tmp::TmpDir;
+impl DocumentSource for Filesystem {
+    fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(filename)?))
+    }
+}
 
-/// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
-fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf)
+/// `read_whole_file_into`, reading from `source`. It has to be `'static`,
+/// since a read that times out is left running on a thread of its own (see
+/// `read_with_timeout`).
+fn read_document_into(source: &'static dyn DocumentSource, filename: &Path,
+                      options: &ReadOptions, text: &mut String)
+    -> io::Result<()>
+{
+    let mut delay = Duration::from_millis(10);
+    let mut attempt = 0;
+    loop {
+        // `read_to_string` appends, so start from scratch every time,
+        // including after a failed attempt that read part of the file.
+        text.clear();
+        let result = match options.read_timeout {
+            None => source.open(filename)
+                .and_then(|f| read_to_string_limited(f, options.max_file_size, text)),
+            Some(timeout) => read_with_timeout(source, filename, options, timeout, text)
+        };
+        match result {
+            Err(ref err) if attempt < options.retries && is_transient(err) => {
+                println!("retrying {}: {}", filename.display(), err);
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Ok(()) if options.ascii_only && !text.is_ascii() =>
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "file contains non-ASCII text (--ascii-strict)")),
+            _ => return result
+        }
+    }
+}
+
+/// How many threads started by `read_with_timeout` are still running.
+static READ_HELPERS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many of those threads can be stuck in reads that timed out before
+/// `read_with_timeout` stops starting new ones.
+const MAX_STUCK_READS: usize = 16;
+
+/// Append the contents of `filename`, from `source`, to `text`, like
+/// `read_to_string_limited`, but give up if that takes longer than `timeout`.
+///
+/// The file is read on a new thread, while this one waits. There's no way to
+/// interrupt a read that's stuck, so on timeout the helper thread is simply
+/// abandoned: nothing waits for it, and it exits as soon as its read returns.
+/// To keep a dead network mount from tying up more and more threads this way,
+/// once `MAX_STUCK_READS` reads are stuck (beyond the `options.threads` that
+/// may be running normally), further reads fail right away, until some of the
+/// stuck ones finish.
+fn read_with_timeout(source: &'static dyn DocumentSource, filename: &Path,
+                     options: &ReadOptions, timeout: Duration, text: &mut String)
+    -> io::Result<()>
+{
+    if READ_HELPERS.fetch_add(1, Ordering::SeqCst) >= options.threads + MAX_STUCK_READS {
+        READ_HELPERS.fetch_sub(1, Ordering::SeqCst);
+        return Err(io::Error::other("too many earlier reads are stuck; not trying this one"));
+    }
+
+    // The helper thread gets `text` to read into, and hands it back when it's
+    // done, so the buffer can still be reused for the next file.
+    let (sender, receiver) = channel();
+    let path = filename.to_path_buf();
+    let mut buffer = mem::take(text);
+    let max_file_size = options.max_file_size;
+    spawn(move || {
+        let result = source.open(&path)
+            .and_then(|f| read_to_string_limited(f, max_file_size, &mut buffer));
+        // If the read timed out, no one is listening any more.
+        let _ = sender.send((result, buffer));
+        READ_HELPERS.fetch_sub(1, Ordering::SeqCst);
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok((result, buffer)) => {
+            *text = buffer;
+            result
+        }
+        Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("read timed out after {} ms", timeout.as_millis()))),
+        Err(RecvTimeoutError::Disconnected) =>
+            Err(io::Error::other("thread reading the file panicked"))
+    }
+}
+
+/// Append the contents of `f` to `text`, failing if there are more than
+/// `max_file_size` bytes. Only one byte more than the limit is ever read.
+fn read_to_string_limited<R: Read>(mut f: R, max_file_size: Option<u64>, text: &mut String)
     -> io::Result<()>
 {
+    let max = match max_file_size {
+        Some(max) => max,
+        None => return f.read_to_string(text).map(|_| ())
+    };
+    let start = text.len();
+    f.take(max.saturating_add(1)).read_to_string(text)?;
+    if (text.len() - start) as u64 > max {
+        return Err(io::Error::other(format!(
+            "file grew past the --max-file-size limit of {} bytes after it was checked", max)));
+    }
+    Ok(())
+}
+
+/// What happened while building an index, beyond the index itself.
+#[derive(Default)]
+struct BuildReport {
+    /// Files that couldn't be read, and so were left out of the index.
+    failed_files: Vec<PathBuf>,
+
+    /// How long each stage of the build took, in order.
+    stage_times: Vec<(&'static str, Duration)>,
+
+    /// The number of terms in the finished index, counted as `FileMerge`
+    /// writes them (see `make_file_merge`).
+    terms: Arc<AtomicUsize>,
+
+    /// The temporary index files that were merged into the finished index,
+    /// in the order they were written.
+    merged_files: Vec<AddedFile>
+}
+
+/// A record of when each piece of work started and finished, for `--profile`.
+///
+/// This is global, rather than passed to every stage of the pipeline, so that
+/// when profiling is off, the only cost is checking whether `PROFILER` is set.
+struct Profiler {
+    /// Event times are measured from this moment.
+    started: Instant,
+
+    /// The events recorded so far, in no particular order.
+    events: Mutex<Vec<ProfileEvent>>
+}
+
+struct ProfileEvent {
+    /// Which stage of the build did the work.
+    stage: &'static str,
+
+    /// Which item the work was on: a document id for most stages, or a
+    /// temporary file number for writing and merging.
+    item: usize,
+
+    start: Duration,
+    end: Duration
+}
+
+static PROFILER: OnceLock<Profiler> = OnceLock::new();
+
+/// Call `f`, recording how long it takes if profiling is turned on.
+fn profile<T, F: FnOnce() -> T>(stage: &'static str, item: usize, f: F) -> T {
+    let profiler = match PROFILER.get() {
+        Some(profiler) => profiler,
+        None => return f()
+    };
+    let start = profiler.started.elapsed();
+    let result = f();
+    let end = profiler.started.elapsed();
+    profiler.events.lock().unwrap().push(ProfileEvent { stage, item, start, end });
+    result
+}
+
+/// Start recording profile events.
+fn start_profiling() {
+    let _ = PROFILER.set(Profiler { started: Instant::now(), events: Mutex::new(vec![]) });
+}
+
+/// Write the profile events recorded so far to `filename`, as tab-separated
+/// values: the stage, the item number, and the start and end times in
+/// microseconds.
+fn write_profile(filename: &Path) -> io::Result<()> {
+    let profiler = match PROFILER.get() {
+        Some(profiler) => profiler,
+        None => return Ok(())
+    };
+    let mut events = profiler.events.lock().unwrap();
+    events.sort_by_key(|event| event.start);
+    let mut out = io::BufWriter::new(File::create(filename)?);
+    writeln!(out, "stage\titem\tstart_us\tend_us")?;
+    for event in events.iter() {
+        writeln!(out, "{}\t{}\t{}\t{}", event.stage, event.item,
+                 event.start.as_micros(), event.end.as_micros())?;
+    }
+    out.flush()
+}
+
+/// Tell the user that `filename` couldn't be read, and add it to
+/// `failed_files`. The file is left out of the index, and the rest of the
+/// documents are indexed as usual.
+///
+/// Once `options.quiet_errors_after` files have failed, later errors aren't
+/// printed, so a directory full of unreadable files doesn't flood the
+/// terminal; `main` says how many were left out at the end.
+fn report_read_error(filename: PathBuf, err: &io::Error, options: &ReadOptions,
+                     failed_files: &mut Vec<PathBuf>)
+{
+    if options.quiet_errors_after.is_none_or(|max| failed_files.len() < max) {
+        println!("error: {}: {}", filename.display(), err);
+    }
+    failed_files.push(filename);
+}
+
+/// Create an inverted index for the given list of `documents`,
+/// storing it in the directory given by `output`.
+fn run_single_threaded(documents: Vec<PathBuf>, output: &OutputOptions,
+                       read_options: ReadOptions, options: IndexOptions)
+    -> io::Result<BuildReport>
+{
+    let IndexOptions {
+        docid_base, tokenizer, postings_cap, min_doc_count, spellings, filenames, presence_only,
+        mut doc_stats, flush_every
+    } = options;
+    let mut report = BuildReport::default();
+
     // If all the documents fit comfortably in memory, we'll create the whole
     // index in memory.
     let mut accumulated_index = InMemoryIndex::new();
@@ -44,87 +426,292 @@ fn run_single_threaded(documents: Vec<PathBuf>, output_dir: PathBuf)
     // If not, then as memory fills up, we'll write largeish temporary index
     // files to disk, saving the temporary filenames in `merge` so that later we
     // can merge them all into a single huge file.
-    let mut merge = FileMerge::new(&output_dir);
+    let mut merge = make_file_merge(output, postings_cap, min_doc_count, &report.terms);
 
     // A tool for generating temporary filenames.
-    let mut tmp_dir = TmpDir::new(&output_dir);
+    let mut tmp_dir = TmpDir::new(output.tmp_dir());
+    tmp_dir.set_codec(output.tmp_codec());
+
+    // The order in which terms are written to index files. Every file must use
+    // the same order as `merge`.
+    let order = TermOrder::bytes();
+
+    // Documents that can't be read are skipped, and don't use up a document
+    // id, so that the numbering matches what `run_pipeline` does.
+    let mut next_docid = docid_base;
+    let started = Instant::now();
+
+    // Each document is read into the same buffer, in turn.
+    let mut text = String::new();
+    let mut nfiles = 0;
 
     // For each document in the set...
-    for (doc_id, filename) in documents.into_iter().enumerate() {
+    for (i, filename) in documents.into_iter().enumerate() {
         // ...load it into memory...
-        let mut f = File::open(filename)?;
-        let mut text = String::new();
-        f.read_to_string(&mut text)?;
+        let result = profile("read", i, || {
+            read_whole_file_into(&filename, &read_options, &mut text)
+        });
+        if let Err(err) = result {
+            report_read_error(filename, &err, &read_options, &mut report.failed_files);
+            continue;
+        }
 
         // ...and add its contents to the in-memory `accumulated_index`.
-        let index = InMemoryIndex::from_single_document(doc_id, text);
-        accumulated_index.merge(index);
-        if accumulated_index.is_large() {
+        let mut index = profile("index", next_docid, || {
+            let mut index = InMemoryIndex::from_single_document(next_docid, &text, &*tokenizer);
+            if spellings {
+                index.add_spellings(&text, &*tokenizer);
+            }
+            index
+        });
+        if let Some(doc_stats) = doc_stats.as_mut() {
+            doc_stats.write(next_docid, &filename, &index.document_stats())?;
+        }
+        if filenames {
+            add_path_words(&mut index, next_docid, &filename, &*tokenizer, spellings);
+        }
+        if presence_only {
+            index.drop_frequencies();
+        }
+        profile("accumulate", next_docid, || accumulated_index.merge(index, postings_cap.as_ref()));
+        next_docid += 1;
+        if is_time_to_flush(&accumulated_index, flush_every) {
             // To avoid running out of memory, dump `accumulated_index` to disk.
-            let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-            merge.add_file(file)?;
-            accumulated_index = InMemoryIndex::new();
+            // This leaves it empty, ready for the next batch of documents.
+            let file = profile("write", nfiles, || {
+                write_index_to_tmp_file(&mut accumulated_index, &mut tmp_dir, &order)
+            })?;
+            profile("merge", nfiles, || merge.add_file(file))?;
+            nfiles += 1;
         }
     }
 
-    // Done reading documents! Save the last data set to disk, then merge the
-    // temporary index files if there are more than one.
-    if !accumulated_index.is_empty() {
-        let file = write_index_to_tmp_file(accumulated_index, &mut tmp_dir)?;
-        merge.add_file(file)?;
+    if let Some(doc_stats) = doc_stats {
+        doc_stats.finish()?;
+    }
+
+    // Done reading documents! Hand the last data set to `merge` as it is, to
+    // be merged with the temporary index files, if there are any. (Even if
+    // the last few documents have no words in them, the index should cover
+    // their ids.)
+    if !accumulated_index.docs.is_empty() {
+        merge.add_last_index(accumulated_index);
+    }
+    report.stage_times.push(("index", started.elapsed()));
+
+    finish_merge(merge, postings_cap, min_doc_count, &mut report)?;
+    Ok(report)
+}
+
+/// A document that has been loaded into memory, on its way to be indexed.
+struct Document {
+    /// The file the document was read from.
+    path: PathBuf,
+
+    text: String
+}
+
+/// Index the words in `path` as part of the document `document_id`, as if
+/// they came right after the last word of the text, so that documents can be
+/// found by their filenames. `index` must already contain the document.
+fn add_path_words(index: &mut InMemoryIndex, document_id: usize, path: &Path,
+                  tokenizer: &dyn Tokenizer, spellings: bool)
+{
+    let path = path.to_string_lossy();
+    index.add_to_document(document_id, &path, tokenizer);
+    if spellings {
+        index.add_spellings(&path, tokenizer);
     }
-    merge.finish()
 }
 
-/// Start a thread that loads documents from the filesystem into memory.
+/// True if `index`, which documents are being added to, should be written to
+/// a temporary file now. That's when it gets too big to keep in memory, or
+/// when it holds `flush_every` documents, if given, whichever comes first.
+/// Flushing every so many documents makes the temporary files come out the
+/// same every time, which is handy for testing.
+fn is_time_to_flush(index: &InMemoryIndex, flush_every: Option<usize>) -> bool {
+    index.is_large() || flush_every.is_some_and(|n| index.docs.len() as usize >= n)
+}
+
+/// Start a thread that loads documents from `source` (normally `Filesystem`)
+/// into memory.
 ///
 /// `documents` is a list of filenames to load.
 ///
-/// This returns a pair of values: a receiver that receives the documents, as
-/// Strings; and a `JoinHandle` that can be used to wait for this thread to
-/// exit and to get the `io::Error` value if anything goes wrong.
-fn start_file_reader_thread(documents: Vec<PathBuf>)
-    -> (Receiver<String>, JoinHandle<io::Result<()>>)
+/// This returns a pair of values: a receiver that receives the documents;
+/// and a `JoinHandle` that can be used to wait for this thread to exit and
+/// to get the list of files that couldn't be read.
+fn start_file_reader_thread(source: &'static dyn DocumentSource, documents: Vec<PathBuf>,
+                            read_options: ReadOptions)
+    -> (Receiver<Document>, JoinHandle<Vec<PathBuf>>)
 {
     let (sender, receiver) = channel();
 
     let handle = spawn(move || {
-        for filename in documents {
-            let mut f = File::open(filename)?;
-            let mut text = String::new();
-            f.read_to_string(&mut text)?;
+        if read_options.threads > 1 {
+            return read_files_in_parallel(source, documents, read_options, sender);
+        }
+        let mut failed_files = vec![];
+        for (i, filename) in documents.into_iter().enumerate() {
+            let result = profile("read", i, || read_document(source, &filename, &read_options));
+            let text = match result {
+                Ok(text) => text,
+                Err(err) => {
+                    report_read_error(filename, &err, &read_options, &mut failed_files);
+                    continue;
+                }
+            };
 
-            if sender.send(text).is_err() {
+            if sender.send(Document { path: filename, text }).is_err() {
                 break;
             }
         }
-        Ok(())
+        failed_files
     });
 
     (receiver, handle)
 }
 
+/// How many documents, per reader thread, `read_files_in_parallel` reads
+/// ahead of the first one it hasn't been able to send on yet.
+const READ_AHEAD_PER_THREAD: usize = 16;
+
+/// How far `read_files_in_parallel` has got sending documents on, shared
+/// with its reader threads so they can wait instead of reading too far ahead.
+#[derive(Default)]
+struct ReadProgress {
+    /// The number of documents sent on (or reported as unreadable) so far.
+    sent: usize,
+
+    /// True once no more documents are wanted.
+    stopped: bool
+}
+
+/// Read `documents` from `source` using several threads at once, and send their contents
+/// to `sender` in the original order, so that document ids are assigned the
+/// same way as when reading one file at a time.
+///
+/// A file that's slow to read holds up all the ones after it. So that those
+/// don't pile up in memory meanwhile, the readers don't start on a document
+/// more than `READ_AHEAD_PER_THREAD` documents per thread past the slow one;
+/// they wait for it. (If it never finishes, neither does the build, just as
+/// when reading one file at a time; see `--read-timeout`.)
+fn read_files_in_parallel(source: &'static dyn DocumentSource, documents: Vec<PathBuf>,
+                          read_options: ReadOptions, sender: Sender<Document>)
+    -> Vec<PathBuf>
+{
+    let queue = Arc::new(Mutex::new(documents.into_iter().enumerate()));
+    let progress = Arc::new((Mutex::new(ReadProgress::default()), Condvar::new()));
+    let max_ahead = read_options.threads * READ_AHEAD_PER_THREAD;
+    let (results_sender, results) = channel();
+    for _ in 0..read_options.threads {
+        let queue = queue.clone();
+        let progress = progress.clone();
+        let results_sender = results_sender.clone();
+        spawn(move || loop {
+            let next = queue.lock().unwrap().next();
+            let (i, filename) = match next {
+                Some(item) => item,
+                None => break
+            };
+            let (ref lock, ref cvar) = *progress;
+            let mut state = lock.lock().unwrap();
+            while i >= state.sent + max_ahead && !state.stopped {
+                state = cvar.wait(state).unwrap();
+            }
+            if state.stopped {
+                break;
+            }
+            drop(state);
+            let read = profile("read", i, || read_document(source, &filename, &read_options));
+            let result = match read {
+                Ok(text) => Ok(Document { path: filename, text }),
+                Err(err) => Err((filename, err))
+            };
+            if results_sender.send((i, result)).is_err() {
+                break;
+            }
+        });
+    }
+    drop(results_sender);
+
+    // Texts arrive in whatever order the reads happen to finish. Hold on to
+    // each one until all the documents before it have been sent. (If we bail
+    // out early, dropping `results` tells the workers to stop.) Files that
+    // couldn't be read are reported in order too, and then skipped.
+    let mut pending = HashMap::new();
+    let mut next_to_send = 0;
+    let mut failed_files = vec![];
+    let (ref lock, ref cvar) = *progress;
+    'results: for (i, result) in results {
+        pending.insert(i, result);
+        while let Some(result) = pending.remove(&next_to_send) {
+            next_to_send += 1;
+            match result {
+                Ok(document) => {
+                    if sender.send(document).is_err() {
+                        break 'results;
+                    }
+                }
+                Err((filename, err)) => {
+                    report_read_error(filename, &err, &read_options, &mut failed_files);
+                }
+            }
+            lock.lock().unwrap().sent = next_to_send;
+            cvar.notify_all();
+        }
+    }
+    // Wake any readers still waiting, so they can exit.
+    lock.lock().unwrap().stopped = true;
+    cvar.notify_all();
+    failed_files
+}
+
 /// Start a thread that tokenizes each text and converts it into an in-memory
 /// index. (We assume that every document fits comfortably in memory.)
 ///
-/// `texts` is the stream of documents from the file reader thread.
+/// `documents` is the stream of documents from the file reader thread.
 ///
-/// This assigns each document a number. It returns a pair of values: a
+/// This assigns each document a number, starting at `docid_base`. It returns a pair of values: a
 /// receiver, the sequence of in-memory indexes; and a `JoinHandle` that can be
-/// used to wait for this thread to exit. This stage of the pipeline is
-/// infallible (it performs no I/O, so there are no possible errors).
-fn start_file_indexing_thread(texts: Receiver<String>)
-    -> (Receiver<InMemoryIndex>, JoinHandle<()>)
+/// used to wait for this thread to exit. The only I/O this stage does is
+/// writing `doc_stats`, if given, so that's the only possible error.
+fn start_file_indexing_thread(documents: Receiver<Document>, docid_base: usize,
+                              tokenizer: Arc<dyn Tokenizer>, spellings: bool, filenames: bool,
+                              presence_only: bool, mut doc_stats: Option<DocStatsWriter>)
+    -> (Receiver<InMemoryIndex>, JoinHandle<io::Result<()>>)
 {
     let (sender, receiver) = channel();
 
     let handle = spawn(move || {
-        for (doc_id, text) in texts.into_iter().enumerate() {
-            let index = InMemoryIndex::from_single_document(doc_id, text);
+        for (i, document) in documents.into_iter().enumerate() {
+            let text = &document.text;
+            let mut index = profile("index", docid_base + i, || {
+                let mut index =
+                    InMemoryIndex::from_single_document(docid_base + i, text, &*tokenizer);
+                if spellings {
+                    index.add_spellings(text, &*tokenizer);
+                }
+                index
+            });
+            if let Some(doc_stats) = doc_stats.as_mut() {
+                doc_stats.write(docid_base + i, &document.path, &index.document_stats())?;
+            }
+            if filenames {
+                add_path_words(&mut index, docid_base + i, &document.path, &*tokenizer,
+                               spellings);
+            }
+            if presence_only {
+                index.drop_frequencies();
+            }
             if sender.send(index).is_err() {
                 break;
             }
         }
+        match doc_stats {
+            Some(doc_stats) => doc_stats.finish(),
+            None => Ok(())
+        }
     });
 
     (receiver, handle)
@@ -139,11 +726,22 @@ fn start_file_indexing_thread(texts: Receiver<String>)
 /// The thread created by this function merges those indexes into "large"
 /// indexes and passes these large indexes on to a new channel.
 ///
+/// The hits for each term are trimmed to fit `postings_cap`, if given. An
+/// index is passed on once it holds `flush_every` documents, if given, even if
+/// it isn't large yet.
+///
+/// Once the index writer thread is done with a large index, it sends it back
+/// empty on `recycled`, to be filled up again. That saves growing a new
+/// index's hash table from scratch each time.
+///
 /// This returns a pair: a receiver, the sequence of large indexes produced by
 /// merging the input indexes; and a `JoinHandle` that can be used to wait for
 /// this thread to exit. This stage of the pipeline is infallible (it performs
 /// no I/O).
-fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>)
+fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>,
+                                postings_cap: Option<PostingsCap>,
+                                flush_every: Option<usize>,
+                                recycled: Receiver<InMemoryIndex>)
     -> (Receiver<InMemoryIndex>, JoinHandle<()>)
 {
     let (sender, receiver) = channel();
@@ -151,15 +749,18 @@ fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>)
     let handle = spawn(move || {
         let mut accumulated_index = InMemoryIndex::new();
         for fi in file_indexes {
-            accumulated_index.merge(fi);
-            if accumulated_index.is_large() {
+            let docid = fi.docs.start as usize;
+            profile("accumulate", docid, || accumulated_index.merge(fi, postings_cap.as_ref()));
+            if is_time_to_flush(&accumulated_index, flush_every) {
                 if sender.send(accumulated_index).is_err() {
                     return;
                 }
-                accumulated_index = InMemoryIndex::new();
+                accumulated_index = recycled.try_recv().unwrap_or_default();
             }
         }
-        if !accumulated_index.is_empty() {
+        // Send the rest, even if it has no words in it, so that the finished
+        // index covers every document (see `check_all_documents_indexed`).
+        if !accumulated_index.docs.is_empty() {
             let _ = sender.send(accumulated_index);
         }
     });
@@ -171,20 +772,27 @@ fn start_in_memory_merge_thread(file_indexes: Receiver<InMemoryIndex>)
 ///
 /// This thread generates a meaningless unique filename for each index in
 /// `big_indexes`, saves the data, and passes the filename on to a new channel.
+/// The emptied index goes back to the in-memory merge thread on `recycle`.
 ///
 /// This returns a pair: a receiver that receives the filenames; and a
 /// `JoinHandle` that can be used to wait for this thread to exit and receive
 /// any I/O errors it encountered.
 fn start_index_writer_thread(big_indexes: Receiver<InMemoryIndex>,
-                             output_dir: &Path)
+                             tmp_dir: &Path, codec: Codec, recycle: Sender<InMemoryIndex>)
     -> (Receiver<PathBuf>, JoinHandle<io::Result<()>>)
 {
     let (sender, receiver) = channel();
 
-    let mut tmp_dir = TmpDir::new(output_dir);
+    let mut tmp_dir = TmpDir::new(tmp_dir);
+    tmp_dir.set_codec(codec);
     let handle = spawn(move || {
-        for index in big_indexes {
-            let file = write_index_to_tmp_file(index, &mut tmp_dir)?;
+        for (i, mut index) in big_indexes.into_iter().enumerate() {
+            let file = profile("write", i, || {
+                write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+            })?;
+            // If the in-memory merge thread is already done, it doesn't need
+            // this any more.
+            let _ = recycle.send(index);
             if sender.send(file).is_err() {
                 break;
             }
@@ -195,46 +803,427 @@ fn start_index_writer_thread(big_indexes: Receiver<InMemoryIndex>,
     (receiver, handle)
 }
 
-/// Given a sequence of filenames of index data files, merge all the files
-/// into a single index data file.
-fn merge_index_files(files: Receiver<PathBuf>, output_dir: &Path)
+/// Given a sequence of filenames of index data files, start merging them
+/// into a single index data file. The merge is finished by `finish_merge`.
+///
+/// This records in `report` how long it took for the rest of the pipeline to
+/// produce all the files (the "index" stage).
+fn merge_index_files(files: Receiver<PathBuf>, output: &OutputOptions,
+                     postings_cap: Option<PostingsCap>, min_doc_count: usize,
+                     report: &mut BuildReport)
+    -> io::Result<FileMerge>
+{
+    let started = Instant::now();
+    let mut merge = make_file_merge(output, postings_cap, min_doc_count, &report.terms);
+    for (i, file) in files.into_iter().enumerate() {
+        profile("merge", i, || merge.add_file(file))?;
+    }
+    report.stage_times.push(("index", started.elapsed()));
+    Ok(merge)
+}
+
+/// Finish `merge`, writing the finished index, and record in `report` the
+/// files that were merged and how long it took. `postings_cap` and
+/// `min_doc_count` should be the ones `merge` was made with (see
+/// `make_file_merge`); the terms they left out are counted here.
+fn finish_merge(merge: FileMerge, postings_cap: Option<PostingsCap>, min_doc_count: usize,
+                report: &mut BuildReport)
+    -> io::Result<()>
+{
+    let started = Instant::now();
+    report.merged_files = merge.added_files().to_vec();
+    let summary = profile("final merge", 0, || merge.finish())?;
+    report.stage_times.push(("merge", started.elapsed()));
+    if let Some(cap) = postings_cap.filter(|cap| cap.policy == CapPolicy::DropTerm) {
+        println!("dropped {} terms that appear in more than {} documents",
+                 summary.over_cap, cap.max);
+    }
+    if min_doc_count > 1 {
+        println!("pruned {} terms that appear in fewer than {} documents",
+                 summary.rare, min_doc_count);
+    }
+    Ok(())
+}
+
+/// Check that `merge` covers exactly the `ndocuments` documents numbered
+/// from `docid_base`: every document that was read, and nothing else.
+///
+/// If a stage of the pipeline stops early, the stages after it just see
+/// their input run out, and would go on to write an index that's missing
+/// documents as if nothing were wrong. This catches that before the index
+/// is written.
+fn check_all_documents_indexed(merge: &FileMerge, docid_base: usize, ndocuments: usize)
     -> io::Result<()>
 {
-    let mut merge = FileMerge::new(output_dir);
-    for file in files {
-        merge.add_file(file)?;
+    let docs = merge.doc_range();
+    let indexed = if docs.is_empty() { 0 } else { (docs.end - docs.start) as usize };
+    if indexed == ndocuments && (ndocuments == 0 || docs.start as usize == docid_base) {
+        return Ok(());
     }
-    merge.finish()
+    Err(io::Error::other(format!(
+        "{} documents were read, but the index would cover {} (ids {}..{}); \
+         not writing it",
+        ndocuments, indexed, docs.start, docs.end)))
+}
+
+/// Set up a `FileMerge` to build the finished index as `output` says, adding
+/// up the number of terms in it in `terms`.
+fn make_file_merge(output: &OutputOptions, postings_cap: Option<PostingsCap>,
+                   min_doc_count: usize, terms: &Arc<AtomicUsize>)
+    -> FileMerge
+{
+    let mut merge = FileMerge::new(&output.dir, postings_cap);
+    merge.set_durable(output.durable);
+    merge.set_tmp_codec(output.tmp_codec());
+    merge.set_codec(output.codec);
+    merge.set_tmp_dir(output.tmp_dir());
+    merge.set_min_doc_count(min_doc_count);
+    let terms = terms.clone();
+    merge.set_term_callback(Box::new(move |_, _| {
+        terms.fetch_add(1, Ordering::Relaxed);
+    }));
+    merge
 }
 
 /// Create an inverted index for the given list of `documents`,
-/// storing it in the specified `output_dir`.
+/// storing it in the directory given by `output`.
 ///
 /// On success this does exactly the same thing as `run_single_threaded`, but
 /// faster since it uses multiple CPUs and keeps them busy while I/O is
 /// happening.
-fn run_pipeline(documents: Vec<PathBuf>, output_dir: PathBuf)
-    -> io::Result<()>
+fn run_pipeline(documents: Vec<PathBuf>, output: &OutputOptions,
+                read_options: ReadOptions, options: IndexOptions)
+    -> io::Result<BuildReport>
 {
+    let IndexOptions {
+        docid_base, tokenizer, postings_cap, min_doc_count, spellings, filenames, presence_only,
+        doc_stats, flush_every
+    } = options;
+    let ndocuments = documents.len();
+
     // Launch all five stages of the pipeline.
-    let (texts,   h1) = start_file_reader_thread(documents);
-    let (pints,   h2) = start_file_indexing_thread(texts);
-    let (gallons, h3) = start_in_memory_merge_thread(pints);
-    let (files,   h4) = start_index_writer_thread(gallons, &output_dir);
-    let result = merge_index_files(files, &output_dir);
-
-    // Wait for threads to finish, holding on to any errors that they encounter.
-    let r1 = h1.join().unwrap();
-    h2.join().unwrap();
+    let (texts,   h1) = start_file_reader_thread(&Filesystem, documents, read_options);
+    let (pints,   h2) = start_file_indexing_thread(texts, docid_base, tokenizer, spellings,
+                                                   filenames, presence_only, doc_stats);
+    let (recycle, recycled) = channel();
+    let (gallons, h3) = start_in_memory_merge_thread(pints, postings_cap, flush_every,
+                                                     recycled);
+    let (files,   h4) = start_index_writer_thread(gallons, output.tmp_dir(), output.tmp_codec(),
+                                                  recycle);
+    let mut report = BuildReport::default();
+    let result = merge_index_files(files, output, postings_cap, min_doc_count, &mut report);
+
+    // Wait for threads to finish, holding on to any errors that they
+    // encounter. This has to happen before the merge is finished, so that if
+    // a thread panicked, the panic happens here, before a partial index can
+    // be written.
+    report.failed_files = h1.join().unwrap();
+    let r2 = h2.join().unwrap();
     h3.join().unwrap();
     let r4 = h4.join().unwrap();
 
     // Return the first error encountered, if any.
-    // (As it happens, h2 and h3 can't fail: those threads
-    // are pure in-memory data processing.)
-    r1?;
+    // (As it happens, h1 and h3 can't fail: files that can't be read are just
+    // listed in the report, and merging in memory is pure data processing.)
+    r2?;
     r4?;
-    result
+    let merge = result?;
+    check_all_documents_indexed(&merge, docid_base, ndocuments - report.failed_files.len())?;
+    finish_merge(merge, postings_cap, min_doc_count, &mut report)?;
+    Ok(report)
+}
+
+/// The directory, in the output directory, where `--resume-reading` keeps the
+/// pieces of the index finished so far.
+const CHECKPOINT_DIRNAME: &str = "checkpoint";
+
+/// The file in the checkpoint directory that lists the finished pieces.
+const CHECKPOINT_FILENAME: &str = "checkpoint.tsv";
+
+/// How many documents go in each piece of a `--resume-reading` build, unless
+/// `--checkpoint-every` says otherwise.
+const DEFAULT_CHECKPOINT_EVERY: usize = 10_000;
+
+/// Which pieces of a `--resume-reading` build are finished.
+///
+/// The checkpoint file starts with a line describing the build (see
+/// `describe_build`), followed by a line for each finished piece, in order:
+/// the id of the first document after the piece, then a tab, then the
+/// positions in the list of documents of the ones in the piece that couldn't
+/// be read, separated by spaces.
+///
+/// Each piece is a finished index, `piece<N>/index.dat`, built with
+/// `--durable`, so it's safely on disk before its line is added. The file is
+/// replaced as a whole each time, never appended to, so after a crash it
+/// lists either all the pieces it did before or one more; never half a line.
+struct Checkpoint {
+    /// The checkpoint directory.
+    dir: PathBuf,
+
+    /// The first line of the checkpoint file.
+    build: String,
+
+    /// The pieces finished so far, in order.
+    pieces: Vec<Piece>
+}
+
+/// A finished piece of a `--resume-reading` build.
+struct Piece {
+    /// The id of the first document after this piece.
+    next_docid: usize,
+
+    /// The positions in the list of all the documents of the ones in this
+    /// piece that couldn't be read.
+    failed: Vec<usize>
+}
+
+impl Checkpoint {
+    /// Open the checkpoint in `dir` left by an earlier run of the same
+    /// `build`, or start a new one if there isn't one.
+    fn open(dir: &Path, build: String) -> io::Result<Checkpoint> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(CHECKPOINT_FILENAME);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound =>
+                return Ok(Checkpoint { dir: dir.to_owned(), build, pieces: vec![] }),
+            Err(err) => return Err(err)
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(build.as_str()) {
+            return Err(io::Error::other(format!(
+                "{} is from a build with different options or files; \
+                 delete it to start over", dir.display())));
+        }
+        let pieces = lines.map(Piece::parse).collect::<Option<Vec<_>>>()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!(
+                "{} is damaged; delete it to start over", path.display())))?;
+        Ok(Checkpoint { dir: dir.to_owned(), build, pieces })
+    }
+
+    /// The id of the first document in the next piece.
+    fn next_docid(&self, docid_base: usize) -> usize {
+        self.pieces.last().map_or(docid_base, |piece| piece.next_docid)
+    }
+
+    /// The directory the `i`th piece is built in.
+    fn piece_dir(&self, i: usize) -> PathBuf {
+        self.dir.join(format!("piece{}", i))
+    }
+
+    /// Record that the next piece is finished.
+    fn add(&mut self, piece: Piece) -> io::Result<()> {
+        self.pieces.push(piece);
+        let (tmp_filename, mut out) = TmpDir::new(&self.dir).create()?;
+        writeln!(out, "{}", self.build)?;
+        for piece in &self.pieces {
+            let failed: Vec<String> = piece.failed.iter().map(usize::to_string).collect();
+            writeln!(out, "{}\t{}", piece.next_docid, failed.join(" "))?;
+        }
+        out.flush()?;
+        out.get_ref().sync_all()?;
+        drop(out);
+        fs::rename(tmp_filename, self.dir.join(CHECKPOINT_FILENAME))
+    }
+
+    /// A file with the same contents as the `i`th piece's index, for
+    /// `FileMerge` to merge and then delete, leaving the piece itself alone in
+    /// case the merge is interrupted. It's a hard link, if possible, so that
+    /// nothing has to be copied.
+    fn link_piece(&self, i: usize) -> io::Result<PathBuf> {
+        let dir = self.piece_dir(i);
+        let (piece, link) = (dir.join(MERGED_FILENAME), dir.join("merging.dat"));
+        match fs::remove_file(&link) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        if fs::hard_link(&piece, &link).is_err() {
+            fs::copy(&piece, &link)?;
+        }
+        Ok(link)
+    }
+}
+
+impl Piece {
+    /// Parse a line of the checkpoint file.
+    fn parse(line: &str) -> Option<Piece> {
+        let (next_docid, failed) = line.split_once('\t')?;
+        Some(Piece {
+            next_docid: next_docid.parse().ok()?,
+            failed: failed.split(' ').filter(|s| !s.is_empty())
+                .map(|s| s.parse().ok())
+                .collect::<Option<_>>()?
+        })
+    }
+}
+
+/// The first line of the checkpoint file for a build of `documents`, in pieces
+/// of `piece_size`. A checkpoint can only be resumed by the same build: the
+/// same command line, giving the same list of documents.
+fn describe_build(documents: &[PathBuf], piece_size: usize) -> String {
+    let args: Vec<_> = env::args_os().skip(1).collect();
+    // FNV-1a, which, unlike the standard library's hashers, is sure to give
+    // the same answer next time.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for path in documents {
+        for &byte in path.to_string_lossy().as_bytes().iter().chain(&[0]) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    format!("{:?}\t{}\t{:016x}\t{}", args, documents.len(), hash, piece_size)
+}
+
+/// Create an inverted index for the given list of `documents`, like
+/// `run_single_threaded` or `run_pipeline`, but `piece_size` documents at a
+/// time, saving each piece in a checkpoint in the output directory (see
+/// `Checkpoint`). If an earlier run of the same build was interrupted, the
+/// pieces it finished are used as they are, and their documents aren't read
+/// again. The pieces are merged into the finished index at the end, and the
+/// checkpoint is deleted once the index is written.
+///
+/// `options` must not ask for `--doc-stats`; those would only cover the
+/// documents read by this run.
+fn run_checkpointed(documents: Vec<PathBuf>, output: &OutputOptions, single_threaded: bool,
+                    piece_size: usize, read_options: ReadOptions, options: IndexOptions)
+    -> io::Result<BuildReport>
+{
+    let IndexOptions {
+        docid_base, tokenizer, postings_cap, min_doc_count, spellings, filenames, presence_only,
+        doc_stats, flush_every
+    } = options;
+    assert!(doc_stats.is_none(), "--doc-stats with --resume-reading");
+    let dir = output.dir.join(CHECKPOINT_DIRNAME);
+    let mut checkpoint = Checkpoint::open(&dir, describe_build(&documents, piece_size))?;
+    let npieces = documents.len().div_ceil(piece_size);
+    if checkpoint.pieces.len() > npieces {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "{} lists more pieces than there are; delete it to start over", dir.display())));
+    }
+    if !checkpoint.pieces.is_empty() {
+        println!("resuming: {} of {} documents already indexed",
+                 documents.len().min(checkpoint.pieces.len() * piece_size), documents.len());
+    }
+
+    let mut report = BuildReport::default();
+    let started = Instant::now();
+    for (i, piece) in documents.chunks(piece_size).enumerate().skip(checkpoint.pieces.len()) {
+        let first_docid = checkpoint.next_docid(docid_base);
+        let piece_output = OutputOptions {
+            dir: checkpoint.piece_dir(i),
+            format: OutputFormat::Standard,
+            stats_json: false,
+            audit_terms: false,
+            durable: true,
+            codec: output.codec,
+            compress_tmp: output.compress_tmp,
+            tmp_dir: Some(output.tmp_dir().to_owned()),
+            profile: None
+        };
+        fs::create_dir_all(&piece_output.dir)?;
+        // Terms over a `DropTerm` cap, or under the minimum document count,
+        // can only be dropped once all their hits are merged together; that
+        // is, in the final merge.
+        let piece_options = IndexOptions {
+            docid_base: first_docid,
+            tokenizer: tokenizer.clone(),
+            postings_cap: postings_cap.filter(|cap| cap.policy != CapPolicy::DropTerm),
+            min_doc_count: 0,
+            spellings,
+            filenames,
+            presence_only,
+            doc_stats: None,
+            flush_every
+        };
+        let piece_report = if single_threaded {
+            run_single_threaded(piece.to_vec(), &piece_output, read_options, piece_options)?
+        } else {
+            run_pipeline(piece.to_vec(), &piece_output, read_options, piece_options)?
+        };
+        let failed: HashSet<&PathBuf> = piece_report.failed_files.iter().collect();
+        checkpoint.add(Piece {
+            next_docid: first_docid + piece.len() - piece_report.failed_files.len(),
+            failed: (i * piece_size..i * piece_size + piece.len())
+                .filter(|&j| failed.contains(&documents[j]))
+                .collect()
+        })?;
+    }
+    report.failed_files = checkpoint.pieces.iter()
+        .flat_map(|piece| piece.failed.iter().map(|&j| documents[j].clone()))
+        .collect();
+    report.stage_times.push(("index", started.elapsed()));
+
+    let mut merge = make_file_merge(output, postings_cap, min_doc_count, &report.terms);
+    for i in 0..npieces {
+        merge.add_file(checkpoint.link_piece(i)?)?;
+    }
+    check_all_documents_indexed(&merge, docid_base, documents.len() - report.failed_files.len())?;
+    finish_merge(merge, postings_cap, min_doc_count, &mut report)?;
+    fs::remove_dir_all(&dir)?;
+    Ok(report)
+}
+
+/// The files this program writes, so that they can be kept out of the index
+/// if they turn up among its inputs, as they will if the output directory is
+/// also an input directory.
+struct OutputFiles {
+    /// The index itself, its export, the stats report, and the `--doc-stats`
+    /// and `--profile` files, if any. Each is the canonical path of the
+    /// directory it goes in, plus its name, since it may not exist yet.
+    files: HashSet<PathBuf>,
+
+    /// The directories temporary files are written to, canonical.
+    tmp_dirs: Vec<PathBuf>
+}
+
+impl OutputFiles {
+    /// The files written for `output`, and the `--doc-stats` file
+    /// `doc_stats`, if any. The output and temporary directories must exist
+    /// already.
+    fn new(output: &OutputOptions, doc_stats: Option<&Path>) -> io::Result<OutputFiles> {
+        let dir = output.dir.canonicalize()?;
+        let mut files: HashSet<PathBuf> = [MERGED_FILENAME, "index.csv", STATS_FILENAME]
+            .iter()
+            .map(|name| dir.join(name))
+            .collect();
+        for path in doc_stats.into_iter().chain(output.profile.as_deref()) {
+            // A file whose directory doesn't exist can't be an input.
+            if let Some(path) = canonical_file_path(path).ok().flatten() {
+                files.insert(path);
+            }
+        }
+        let tmp_dirs = vec![dir, output.tmp_dir().canonicalize()?];
+        Ok(OutputFiles { files, tmp_dirs })
+    }
+
+    /// True if `path`, an existing file, is one of these output files or a
+    /// temporary file in one of the temporary directories.
+    fn contains(&self, path: &Path) -> io::Result<bool> {
+        let path = match canonical_file_path(path)? {
+            Some(path) => path,
+            None => return Ok(false)
+        };
+        if self.files.contains(&path) {
+            return Ok(true);
+        }
+        let is_tmp_name = path.file_name().and_then(OsStr::to_str)
+            .is_some_and(TmpDir::is_tmp_filename);
+        Ok(is_tmp_name && path.parent().is_some_and(|dir| self.tmp_dirs.iter().any(|d| d == dir)))
+    }
+}
+
+/// `path` with its directory made canonical (see `fs::canonicalize`), but its
+/// file name left alone, so the file itself needn't exist. Returns `None` if
+/// `path` has no file name.
+fn canonical_file_path(path: &Path) -> io::Result<Option<PathBuf>> {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Ok(None)
+    };
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.canonicalize()?,
+        _ => Path::new(".").canonicalize()?
+    };
+    Ok(Some(dir.join(name)))
 }
 
 /// Given some paths, generate the complete list of text files to index. We check
@@ -242,58 +1231,1758 @@ fn run_pipeline(documents: Vec<PathBuf>, output_dir: PathBuf)
 /// directories, all .txt files immediately under the directory are indexed.
 /// Relative paths are fine.
 ///
+/// The `outputs` of this program, if given, are never indexed, in case the
+/// output directory (or the directory of any other file it writes) is also
+/// one of the input directories. They're skipped when they turn up in a
+/// directory, and it's an error to name one explicitly.
+///
+/// Files bigger than `max_file_size` bytes, if given, are skipped with a
+/// notice, whether they're named explicitly or found in a directory. Files
+/// that match any of the `exclude` patterns (see `matches_glob`) are skipped
+/// without one.
+///
 /// It's an error if any of the `args` is not a valid path to an existing file
 /// or directory.
-fn expand_filename_arguments(args: Vec<String>) -> io::Result<Vec<PathBuf>> {
+fn expand_filename_arguments(args: Vec<PathBuf>, outputs: Option<&OutputFiles>,
+                             max_file_size: Option<u64>, exclude: &[String])
+    -> io::Result<Vec<PathBuf>>
+{
+    let is_output = |path: &Path| -> io::Result<bool> {
+        match outputs {
+            Some(outputs) => outputs.contains(path),
+            None => Ok(false)
+        }
+    };
+    let is_excluded = |path: &Path| exclude.iter().any(|pattern| matches_glob(pattern, path));
     let mut filenames = vec![];
-    for arg in args {
-        let path = PathBuf::from(arg);
-        if path.metadata()?.is_dir() {
+    for path in args {
+        let metadata = path.metadata()?;
+        if metadata.is_dir() {
             for entry in path.read_dir()? {
                 let entry = entry?;
-                if entry.file_type()?.is_file() {
-                    filenames.push(entry.path());
+                if entry.file_type()?.is_file() && !is_excluded(&entry.path()) {
+                    if is_output(&entry.path())? {
+                        println!("skipping {}: it's an output file", entry.path().display());
+                    } else if !skip_if_too_big(&entry.path(), entry.metadata()?.len(),
+                                               max_file_size) {
+                        filenames.push(entry.path());
+                    }
                 }
             }
-        } else {
+        } else if is_excluded(&path) {
+            continue;
+        } else if is_output(&path)? {
+            return Err(io::Error::other(format!(
+                "{} is an output file; refusing to index it", path.display())));
+        } else if !skip_if_too_big(&path, metadata.len(), max_file_size) {
             filenames.push(path);
         }
     }
     Ok(filenames)
 }
 
-/// Generate an index for a bunch of text files.
-fn run(filenames: Vec<String>, single_threaded: bool) -> io::Result<()> {
-    let output_dir = PathBuf::from(".");
-    let documents = expand_filename_arguments(filenames)?;
-
-    if single_threaded {
-        run_single_threaded(documents, output_dir)
+/// True if `path` matches the `--exclude-glob` pattern `pattern`. In the
+/// pattern, `*` matches any run of characters, and `?` any one character,
+/// except that neither matches `/`.
+///
+/// A pattern with no `/` in it is matched against just the file's name, so
+/// `*.min.txt` leaves out such files in every directory. Otherwise it's
+/// matched against the whole path, as named on the command line or found in
+/// a directory named there (less any leading `./`), so `tmp/*` leaves out
+/// the files in `tmp`.
+fn matches_glob(pattern: &str, path: &Path) -> bool {
+    let text = if pattern.contains('/') {
+        path.strip_prefix(".").unwrap_or(path).to_string_lossy()
     } else {
-        run_pipeline(documents, output_dir)
+        path.file_name().map_or(Cow::Borrowed(""), OsStr::to_string_lossy)
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match(&pattern, &text)
+}
+
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('*', rest)) => {
+            // Try each length for the run of characters `*` matches, stopping
+            // at the first `/`.
+            (0..=text.len())
+                .take_while(|&n| n == 0 || text[n - 1] != '/')
+                .any(|n| glob_match(rest, &text[n..]))
+        }
+        Some(('?', rest)) =>
+            text.first().is_some_and(|&ch| ch != '/') && glob_match(rest, &text[1..]),
+        Some((ch, rest)) => text.first() == Some(ch) && glob_match(rest, &text[1..])
     }
 }
 
-fn main() {
-    let mut single_threaded = false;
-    let mut filenames = vec![];
+/// If `len`, the size of the file at `path`, is over `max_file_size`, tell
+/// the user that the file is being skipped and return true.
+fn skip_if_too_big(path: &Path, len: u64, max_file_size: Option<u64>) -> bool {
+    match max_file_size {
+        Some(max) if len > max => {
+            println!("skipping {}: {} bytes is over the --max-file-size limit of {}",
+                     path.display(), len, max);
+            true
+        }
+        _ => false
+    }
+}
 
-    {
-        let mut ap = ArgumentParser::new();
-        ap.set_description("Make an inverted index for searching documents.");
-        ap.refer(&mut single_threaded)
-            .add_option(&["-1", "--single-threaded"], StoreTrue,
-                        "Do all the work on a single thread.");
+/// Read a list of paths separated by NUL bytes, as written by `find -print0`.
+/// Unlike a list with one path per line, this works for every filename,
+/// even ones containing newlines. A trailing NUL is optional.
+fn read_nul_separated_paths<R: BufRead>(input: R) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for bytes in input.split(b'\0') {
+        let bytes = bytes?;
+        if !bytes.is_empty() {
+            paths.push(path_from_bytes(bytes)?);
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> io::Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| io::Error::other("filename is not valid UTF-8"))
+}
+
+/// Command-line options that select a tokenizer.
+struct TokenizerOptions {
+    name: String,
+    ascii: bool,
+    token_regex: Option<String>,
+    code_paths: bool,
+    normalize_whitespace: bool,
+    max_token_len: usize,
+    ngram: usize,
+    ngram_keep_words: bool,
+    locale: Option<String>,
+    case_sensitive: bool,
+    cjk_bigram: bool,
+    strip_html: bool,
+    strip_markdown: bool,
+    terms_file: Option<String>
+}
+
+/// Build the tokenizer selected on the command line.
+fn make_tokenizer(options: TokenizerOptions) -> io::Result<Box<dyn Tokenizer>> {
+    let TokenizerOptions {
+        name, ascii, token_regex, code_paths, normalize_whitespace, max_token_len, ngram,
+        ngram_keep_words, locale, case_sensitive, cjk_bigram, strip_html, strip_markdown,
+        terms_file
+    } = options;
+    if code_paths && name != "code" {
+        return Err(io::Error::other("--code-paths can only be used with --mode code"));
+    }
+    if case_sensitive && locale.is_some() {
+        return Err(io::Error::other("--case-sensitive and --locale can't be used together"));
+    }
+    let mut words = make_word_tokenizer(&name, token_regex, code_paths)?;
+    if ascii {
+        if name != "default" || locale.is_some() || case_sensitive {
+            return Err(io::Error::other(
+                "--ascii can only be used with the default tokenizer, and without --locale \
+                 or --case-sensitive"));
+        }
+        words = Box::new(AsciiTokenizer);
+    }
+    if cjk_bigram {
+        words = Box::new(CjkBigramTokenizer::new(words));
+    }
+    let case_locale = match locale {
+        Some(tag) => Some(CaseLocale::from_tag(&tag)),
+        None if case_sensitive => Some(CaseLocale::Unchanged),
+        None => None
+    };
+    if let Some(case_locale) = case_locale {
+        words = Box::new(LocaleTokenizer::new(words, case_locale));
+    }
+    if normalize_whitespace {
+        words = Box::new(WhitespaceNormalizingTokenizer::new(words));
+    }
+    // Each wrapper sees the text before the tokenizer it wraps does, so this
+    // strips HTML, then Markdown, and then normalizes whitespace. (Markdown
+    // has to be stripped before the line breaks are gone.)
+    if strip_markdown {
+        words = Box::new(MarkupStrippingTokenizer::new(words, Markup::Markdown));
+    }
+    if strip_html {
+        words = Box::new(MarkupStrippingTokenizer::new(words, Markup::Html));
+    }
+    if max_token_len != 0 {
+        words = Box::new(TruncatingTokenizer::new(words, max_token_len));
+    }
+    if ngram != 0 {
+        words = Box::new(NgramTokenizer::new(words, ngram, ngram_keep_words));
+    }
+    // This goes last, so it sees the terms that would go into the index.
+    if let Some(path) = terms_file {
+        let vocabulary = read_vocabulary(Path::new(&path), &*words)?;
+        words = Box::new(VocabularyTokenizer::new(words, vocabulary));
+    }
+    Ok(words)
+}
+
+/// Read the `--terms-file` list of terms to index: one per line, ignoring
+/// blank lines and whitespace around each term. The terms are lowercased the
+/// way `tokenizer` lowercases documents, so `Rust` in the list matches `rust`
+/// in the index.
+fn read_vocabulary(path: &Path, tokenizer: &dyn Tokenizer) -> io::Result<HashSet<String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    Ok(text.lines()
+       .map(str::trim)
+       .filter(|line| !line.is_empty())
+       .map(|line| tokenizer.lowercase(line))
+       .collect())
+}
+
+/// Build the tokenizer that splits text into whole words.
+fn make_word_tokenizer(name: &str, token_regex: Option<String>, code_paths: bool)
+    -> io::Result<Box<dyn Tokenizer>>
+{
+    match (name, token_regex) {
+        ("default", None) => Ok(Box::new(DefaultTokenizer)),
+        ("numbers", None) => Ok(Box::new(NumberTokenizer)),
+        ("code", None) => Ok(Box::new(CodeTokenizer::new(code_paths))),
+        ("default", Some(pattern)) | ("regex", Some(pattern)) => {
+            let pattern = Regex::new(&pattern).map_err(io::Error::other)?;
+            Ok(Box::new(RegexTokenizer::new(pattern)))
+        }
+        ("regex", None) =>
+            Err(io::Error::other("--tokenizer regex requires --token-regex")),
+        (_, Some(_)) =>
+            Err(io::Error::other("--token-regex can only be used with the regex tokenizer")),
+        (name, None) =>
+            Err(io::Error::other(format!("unknown tokenizer: {}", name)))
+    }
+}
+
+/// Build the `PostingsCap` selected by the `--max-postings-per-term` and
+/// `--postings-cap-policy` command-line options.
+fn make_postings_cap(max: usize, policy: &str) -> io::Result<Option<PostingsCap>> {
+    let policy = match policy {
+        "drop" => CapPolicy::DropTerm,
+        "most-frequent" => CapPolicy::KeepMostFrequent,
+        _ => return Err(io::Error::other(format!("unknown postings cap policy: {}", policy)))
+    };
+    if max == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(PostingsCap { max, policy }))
+    }
+}
+
+/// The formats the finished index can be written in.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Just the native binary format, `index.dat`.
+    Standard,
+
+    /// `index.dat`, plus a copy in CSV format, `index.csv`, for use by other
+    /// programs.
+    Csv
+}
+
+impl OutputFormat {
+    fn from_name(name: &str) -> io::Result<OutputFormat> {
+        match name {
+            "standard" => Ok(OutputFormat::Standard),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(io::Error::other(format!("unknown output format: {}", name)))
+        }
+    }
+}
+
+/// Settings for indexing only a fraction of the documents, chosen at random
+/// but reproducibly.
+#[derive(Clone, Copy)]
+struct Sample {
+    /// The fraction of documents to index, between 0 and 1.
+    rate: f64,
+
+    /// Different seeds pick different documents.
+    seed: u64
+}
+
+impl Sample {
+    /// True if the document at `path` is part of the sample. This depends
+    /// only on the path (as given on the command line) and the seed, so
+    /// indexing the same files with the same seed always picks the same ones.
+    fn includes(&self, path: &Path) -> bool {
+        // 64-bit FNV-1a over the seed and the path...
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let bytes = self.seed.to_le_bytes();
+        for &byte in bytes.iter().chain(path.as_os_str().as_encoded_bytes()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        // ...with the bits mixed a little more thoroughly at the end, since
+        // FNV's high bits barely depend on the last few bytes.
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+
+        // Use the top 53 bits as a number in [0, 1).
+        ((hash >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+}
+
+fn make_sample(rate: f64, seed: u64) -> io::Result<Option<Sample>> {
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(io::Error::other(format!("sample rate must be between 0 and 1, not {}", rate)));
+    }
+    if rate == 1.0 {
+        return Ok(None);
+    }
+    Ok(Some(Sample { rate, seed }))
+}
+
+/// Check that `ndocuments` documents, numbered starting at `docid_base`, all
+/// get valid document ids. It's much better to find out now than after
+/// reading billions of documents.
+fn check_document_ids(docid_base: usize, ndocuments: usize) -> io::Result<()> {
+    check_document_ids_up_to(docid_base, ndocuments, MAX_DOCUMENT_ID)
+}
+
+/// Like `check_document_ids`, with `max_id` as the largest document id.
+fn check_document_ids_up_to(docid_base: usize, ndocuments: usize, max_id: u32)
+    -> io::Result<()>
+{
+    if ndocuments == 0 {
+        return Ok(());
+    }
+    match docid_base.checked_add(ndocuments - 1) {
+        Some(last) if last <= max_id as usize => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "too many documents: numbering {} documents starting at {} would go \
+             past the largest document id, {}",
+            ndocuments, docid_base, max_id)))
+    }
+}
+
+/// The name of the build report written by `--stats-json`.
+const STATS_FILENAME: &str = "stats.json";
+
+/// Quote `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch)
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Escape `s` for use as a field in a tab-separated values file: backslashes,
+/// tabs, and line breaks are written as `\\`, `\t`, `\n`, and `\r`.
+fn tsv_field(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            ch => out.push(ch)
+        }
+    }
+    out
+}
+
+/// Write `stats.json`, a summary of the build for other programs to read,
+/// into `output_dir`, which must already contain the finished index.
+///
+/// The file is written under a temporary name and then renamed into place,
+/// so readers never see a partly written report.
+fn write_stats_json(output_dir: &Path, ndocuments: usize, report: &BuildReport,
+                    elapsed: Duration)
+    -> io::Result<()>
+{
+    let index_file = output_dir.join(MERGED_FILENAME);
+    let nterms = report.terms.load(Ordering::Relaxed);
+    let nbytes = fs::metadata(&index_file)?.len();
+
+    let stages: Vec<String> = report.stage_times.iter()
+        .map(|&(name, time)| format!("{}: {:.3}", json_string(name), time.as_secs_f64()))
+        .collect();
+    let failed_files: Vec<String> = report.failed_files.iter()
+        .map(|path| json_string(&path.to_string_lossy()))
+        .collect();
+    let merged_files: Vec<String> = report.merged_files.iter()
+        .map(|file| format!("{{\"path\": {}, \"terms\": {}, \"postings\": {}}}",
+                            json_string(&file.path.to_string_lossy()),
+                            file.terms, file.postings))
+        .collect();
+
+    let (tmp_filename, mut out) = TmpDir::new(output_dir).create()?;
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"documents\": {},", ndocuments - report.failed_files.len())?;
+    writeln!(out, "  \"terms\": {},", nterms)?;
+    writeln!(out, "  \"bytes\": {},", nbytes)?;
+    writeln!(out, "  \"elapsed_secs\": {:.3},", elapsed.as_secs_f64())?;
+    writeln!(out, "  \"stage_secs\": {{{}}},", stages.join(", "))?;
+    writeln!(out, "  \"failed_files\": [{}],", failed_files.join(", "))?;
+    writeln!(out, "  \"merged_files\": [{}]", merged_files.join(", "))?;
+    writeln!(out, "}}")?;
+    out.flush()?;
+    drop(out);
+    fs::rename(tmp_filename, output_dir.join(STATS_FILENAME))
+}
+
+/// Where and how to write the finished index, and its temporary files.
+struct OutputOptions {
+    /// The directory to write the index to.
+    dir: PathBuf,
+
+    /// Which files to write the index itself as.
+    format: OutputFormat,
+
+    /// Whether to write a `stats.json` report as well.
+    stats_json: bool,
+
+    /// Whether to list the terms that differ only in case, once the index is
+    /// written (see `print_case_variants`).
+    audit_terms: bool,
+
+    /// Whether to make sure the index is safely on disk before finishing.
+    durable: bool,
+
+    /// How to store the hits in the finished index.
+    codec: Codec,
+
+    /// Whether to compress temporary files, to save disk space while the
+    /// index is being built.
+    compress_tmp: bool,
+
+    /// The directory for temporary files, if not `dir`.
+    tmp_dir: Option<PathBuf>,
+
+    /// Where to write a profile of the build, if anywhere.
+    profile: Option<PathBuf>
+}
+
+impl OutputOptions {
+    /// The codec to use for temporary files.
+    fn tmp_codec(&self) -> Codec {
+        if self.compress_tmp { Codec::Varint } else { Codec::Raw }
+    }
+
+    /// The directory to write temporary files to.
+    fn tmp_dir(&self) -> &Path {
+        self.tmp_dir.as_deref().unwrap_or(&self.dir)
+    }
+}
+
+/// The files to index, as given on the command line.
+struct FileArguments {
+    /// Names of files and directories to index.
+    filenames: Vec<String>,
+
+    /// If true, more filenames are read from stdin, separated by NUL bytes,
+    /// and indexed after the ones in `filenames`.
+    files_from0: bool,
+
+    /// `--exclude-glob` patterns for files to leave out.
+    exclude: Vec<String>
+}
+
+impl FileArguments {
+    /// Generate the complete list of files to index; see
+    /// `expand_filename_arguments`.
+    fn expand(self, outputs: Option<&OutputFiles>, max_file_size: Option<u64>)
+        -> io::Result<Vec<PathBuf>>
+    {
+        let mut args: Vec<PathBuf> = self.filenames.into_iter().map(PathBuf::from).collect();
+        if self.files_from0 {
+            args.extend(read_nul_separated_paths(io::stdin().lock())?);
+        }
+        expand_filename_arguments(args, outputs, max_file_size, &self.exclude)
+    }
+}
+
+/// Generate an index for a bunch of text files. With `checkpoint_every`, the
+/// index is built in pieces of that many documents, and the build can be
+/// resumed if it's interrupted (see `run_checkpointed`).
+///
+/// On success, returns the list of files that couldn't be read and were
+/// left out of the index.
+fn run(files: FileArguments, single_threaded: bool, checkpoint_every: Option<usize>,
+       output: OutputOptions, sample: Option<Sample>,
+       read_options: ReadOptions, options: IndexOptions)
+    -> io::Result<Vec<PathBuf>>
+{
+    let started = Instant::now();
+    if output.profile.is_some() {
+        start_profiling();
+    }
+    let output_dir = output.dir.clone();
+    fs::create_dir_all(&output_dir)?;
+    fs::create_dir_all(output.tmp_dir())?;
+    let outputs = OutputFiles::new(&output, options.doc_stats.as_ref().map(|w| &*w.path))?;
+    let mut documents = files.expand(Some(&outputs), read_options.max_file_size)?;
+
+    // Sample before any document ids are assigned, so the documents that are
+    // indexed are still numbered consecutively.
+    if let Some(sample) = sample {
+        let total = documents.len();
+        documents.retain(|path| sample.includes(path));
+        println!("sampled {} of {} documents", documents.len(), total);
+    }
+    check_document_ids(options.docid_base, documents.len())?;
+
+    let ndocuments = documents.len();
+    let mut report = if let Some(piece_size) = checkpoint_every {
+        run_checkpointed(documents, &output, single_threaded, piece_size, read_options, options)?
+    } else if single_threaded {
+        run_single_threaded(documents, &output, read_options, options)?
+    } else {
+        run_pipeline(documents, &output, read_options, options)?
+    };
+    if report.failed_files.len() == ndocuments {
+        println!("0 documents indexed; the index is empty");
+    }
+
+    if output.format == OutputFormat::Csv {
+        let export_started = Instant::now();
+        let csv_file = File::create(output_dir.join("index.csv"))?;
+        export_csv(output_dir.join(MERGED_FILENAME), csv_file)?;
+        report.stage_times.push(("export", export_started.elapsed()));
+    }
+    if output.stats_json {
+        write_stats_json(&output_dir, ndocuments, &report, started.elapsed())?;
+    }
+    if output.audit_terms {
+        print_case_variants(&output_dir.join(MERGED_FILENAME))?;
+    }
+    if let Some(filename) = output.profile {
+        write_profile(&filename)?;
+    }
+    Ok(report.failed_files)
+}
+
+/// Print the terms in the finished index `filename` that differ only in case
+/// (see `case_variants`), for `--audit-terms`.
+fn print_case_variants(filename: &Path) -> io::Result<()> {
+    let groups = case_variants(filename)?;
+    if groups.is_empty() {
+        println!("no terms differ only in case");
+        return Ok(());
+    }
+    for group in &groups {
+        let terms: Vec<String> = group.terms.iter()
+            .map(|(term, df)| format!("{} ({} documents)", term, df))
+            .collect();
+        println!("terms that differ only in case: {}", terms.join(", "));
+    }
+    println!("{} word(s) are indexed as more than one term; without --case-sensitive, \
+              each would be a single lowercase term", groups.len());
+    Ok(())
+}
+
+/// Instead of building an index, print the words the indexer would see in
+/// each of the `files`, one per line, after all the tokenizer options have
+/// been applied. This is for trying out tokenizer options on a few files
+/// before building a big index. With `positions`, each word is preceded by
+/// its offset in the document, as stored in the index. With `path_words`,
+/// the words in each document's path follow the words in its text (see
+/// `add_path_words`).
+///
+/// Like `run`, this returns the list of files that couldn't be read.
+fn print_tokens(files: FileArguments, positions: bool, path_words: bool,
+                read_options: ReadOptions, tokenizer: &dyn Tokenizer)
+    -> io::Result<Vec<PathBuf>>
+{
+    let documents = files.expand(None, read_options.max_file_size)?;
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let mut failed_files = vec![];
+    for filename in documents {
+        let text = match read_whole_file(&filename, &read_options) {
+            Ok(text) => text,
+            Err(err) => {
+                out.flush()?;
+                report_read_error(filename, &err, &read_options, &mut failed_files);
+                continue;
+            }
+        };
+        writeln!(out, "==> {} <==", filename.display())?;
+        // The same steps as `InMemoryIndex::from_single_document`.
+        let text = tokenizer.lowercase(&text);
+        let path = if path_words {
+            tokenizer.lowercase(&filename.to_string_lossy())
+        } else {
+            String::new()
+        };
+        for (i, token) in tokenizer.tokens(&text).chain(tokenizer.tokens(&path)).enumerate() {
+            if positions {
+                writeln!(out, "{}\t{}", i, token)?;
+            } else {
+                writeln!(out, "{}", token)?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(failed_files)
+}
+
+/// Instead of building an index, count the documents in `files`, and the
+/// bytes and words in them, and print the totals. Each document is read and
+/// tokenized just as for indexing (with `path_words`, the words in its path
+/// count too; see `add_path_words`), but the words are thrown away. This is
+/// for sizing up a corpus before building an index of it.
+///
+/// Like `run`, this returns the list of files that couldn't be read.
+fn count_tokens(files: FileArguments, path_words: bool,
+                read_options: ReadOptions, tokenizer: &dyn Tokenizer)
+    -> io::Result<Vec<PathBuf>>
+{
+    let documents = files.expand(None, read_options.max_file_size)?;
+
+    let mut failed_files = vec![];
+    let mut text = String::new();
+    let (mut ndocuments, mut nbytes, mut ntokens) = (0, 0, 0);
+    for filename in documents {
+        if let Err(err) = read_whole_file_into(&filename, &read_options, &mut text) {
+            report_read_error(filename, &err, &read_options, &mut failed_files);
+            continue;
+        }
+        ndocuments += 1;
+        nbytes += text.len();
+        ntokens += tokenizer.tokens(&tokenizer.lowercase(&text)).count();
+        if path_words {
+            ntokens += tokenizer.tokens(&tokenizer.lowercase(&filename.to_string_lossy())).count();
+        }
+    }
+    println!("{} documents, {} bytes, {} words", ndocuments, nbytes, ntokens);
+    Ok(failed_files)
+}
+
+/// Instead of building an index, estimate how big it would be, from a sample
+/// of up to `sample_size` of the `files` (see `estimate_index_size`), and
+/// print the estimate.
+fn print_estimate(files: FileArguments, sample_size: usize, read_options: ReadOptions,
+                  tokenizer: &dyn Tokenizer)
+    -> io::Result<()>
+{
+    let documents = files.expand(None, read_options.max_file_size)?;
+    let estimate = estimate_index_size(&documents, tokenizer, sample_size)?;
+    println!("estimated index size: {} bytes (between {} and {})",
+             estimate.bytes, estimate.low, estimate.high);
+    println!("{} documents ({} sampled), about {} words and {} distinct terms",
+             estimate.documents, estimate.sampled, estimate.words, estimate.terms);
+    Ok(())
+}
+
+/// The index file named by a command-line argument: either the file itself,
+/// or a directory with an `index.dat` in it.
+fn index_path(arg: &str) -> PathBuf {
+    let path = PathBuf::from(arg);
+    if path.is_dir() { path.join(MERGED_FILENAME) } else { path }
+}
+
+/// Instead of building an index, merge finished indexes, such as shards
+/// built separately with different `--docid-base` values, into `index.dat` in
+/// `output_dir` (see `merge_files`, which renumbers documents if the ids
+/// overlap). Each of `inputs` is an index file, or a directory with an
+/// `index.dat` in it.
+fn merge_shards(inputs: Vec<String>, output_dir: &Path) -> io::Result<()> {
+    if inputs.is_empty() {
+        return Err(io::Error::other("--merge needs at least one index to merge"));
+    }
+    let inputs: Vec<PathBuf> = inputs.iter().map(|arg| index_path(arg)).collect();
+    fs::create_dir_all(output_dir)?;
+    let output = output_dir.join(MERGED_FILENAME);
+    merge_files(&inputs, &output)?;
+    println!("merged {} indexes into {}", inputs.len(), output.display());
+    Ok(())
+}
+
+/// What `search` prints for the documents it finds.
+enum SearchOutput {
+    /// A line for each document (see `fingertips::search::search`): its id,
+    /// then, when sorting by score, its score, and, with `doc_stats`, the path
+    /// read from that `--doc-stats` file. The documents come in `sort` order.
+    /// With `limit`, only that many documents are printed for each
+    /// index. With `context`, each document is followed by the lines in it
+    /// that contain a query word, and that many lines around each one (see
+    /// `print_matching_lines`); this needs `doc_stats`, to find the files.
+    Hits {
+        sort: SortOrder,
+        limit: Option<usize>,
+        doc_stats: Option<PathBuf>,
+        context: Option<usize>
+    },
+
+    /// A line for each document: its id, then how often each word appears in
+    /// it and in how many documents overall (see `Index::explain`).
+    Explain,
+
+    /// Just the number of matching documents (see `Index::count`).
+    CountOnly
+}
+
+/// Instead of building an index, print the documents that match `query`
+/// (see `fingertips::query`), as `output` says. The indexes searched are the ones
+/// named in `inputs` (see `index_path`), or the one in `output_dir`. With a
+/// `min_freq` above 1, a document only counts as containing a word if the
+/// word appears in it at least that many times.
+///
+/// Indexes built separately can reuse the same document ids, so when there's
+/// more than one, each line starts with the argument naming the index the
+/// document is in, then a tab. An index named twice (say, as `shard` and
+/// `shard/index.dat`) is only searched once, so no document is listed twice.
+fn search(query: &str, inputs: Vec<String>, output_dir: &Path, output: SearchOutput,
+          min_freq: u32)
+    -> io::Result<()>
+{
+    let parsed = parse_query(query).map_err(|err| io::Error::other(err.to_string()))?;
+    if parsed == Query::And(vec![]) {
+        return Err(io::Error::other("--search needs at least one word to look for"));
+    }
+    let sources: Vec<(String, PathBuf)> = if inputs.is_empty() {
+        let path = output_dir.join(MERGED_FILENAME);
+        vec![(path.display().to_string(), path)]
+    } else {
+        inputs.into_iter()
+            .map(|arg| {
+                let path = index_path(&arg);
+                (arg, path)
+            })
+            .collect()
+    };
+    let mut seen = HashSet::new();
+    let mut indexes = vec![];
+    for (source, path) in sources {
+        let index = Index::open(&path).map_err(|err| {
+            let err = io::Error::from(err);
+            io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+        })?;
+        if seen.insert(fs::canonicalize(&path)?) {
+            indexes.push((source, index));
+        }
+    }
+
+    let several = indexes.len() > 1;
+    let docs = match &output {
+        SearchOutput::Hits { doc_stats: Some(doc_stats), .. } => {
+            if several {
+                return Err(io::Error::other(
+                    "--doc-stats can only be used when searching one index"));
+            }
+            let file = File::open(doc_stats).map_err(|err| {
+                io::Error::new(err.kind(), format!("{}: {}", doc_stats.display(), err))
+            })?;
+            Some(read_doc_stats(io::BufReader::new(file))?)
+        }
+        _ => None
+    };
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    for (source, index) in &indexes {
+        let prefix = if several { format!("{}\t", source) } else { String::new() };
+        match output {
+            SearchOutput::CountOnly =>
+                writeln!(out, "{}{}", prefix, parsed.count(index, min_freq)?)?,
+            SearchOutput::Explain => {
+                let options = SearchOptions { min_freq, ..SearchOptions::default() };
+                for hit in fingertips::search::search(index, query, &options)? {
+                    write!(out, "{}{}", prefix, hit.doc_id)?;
+                    for (term, tf) in &hit.term_freqs {
+                        let df = index.doc_freq(term)?;
+                        match tf {
+                            Some(tf) => write!(out, "\t{} tf={} df={}", term, tf, df)?,
+                            None => write!(out, "\t{} frequencies unavailable df={}", term, df)?
+                        }
+                    }
+                    writeln!(out)?;
+                }
+            }
+            SearchOutput::Hits { sort, limit, context, .. } => {
+                let options = SearchOptions { sort, limit, min_freq, docs: docs.as_ref() };
+                for hit in fingertips::search::search(index, query, &options)? {
+                    write!(out, "{}{}", prefix, hit.doc_id)?;
+                    if sort == SortOrder::ByScore {
+                        write!(out, "\t{:.4}", hit.score)?;
+                    }
+                    if docs.is_some() {
+                        match &hit.path {
+                            Some(path) => write!(out, "\t{}", path.display())?,
+                            None => write!(out, "\t")?
+                        }
+                    }
+                    writeln!(out)?;
+                    if let (Some(context), Some(path)) = (context, &hit.path) {
+                        let words: Vec<String> = hit.term_freqs.iter()
+                            .map(|(term, _)| index.case_locale().lowercase(term))
+                            .collect();
+                        print_matching_lines(&mut out, path, &words, context,
+                                             index.case_locale())?;
+                    }
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+/// Print the lines of the file `path` that contain any of `words`, with
+/// `context` lines before and after each, like `grep -n -C`: each line starts
+/// with a tab, then its line number, then `:` if it matched or `-` if it's
+/// context. With context, groups of lines that aren't next to each other
+/// are separated by a `--` line.
+///
+/// The file is read again, so if it changed after it was indexed, this shows
+/// what it says now. A line matches if, lowercased like the index, it
+/// contains one of `words` that isn't just part of a longer word. A file that
+/// can't be read gets a line saying so instead.
+fn print_matching_lines(out: &mut dyn Write, path: &Path, words: &[String], context: usize,
+                        case_locale: CaseLocale)
+    -> io::Result<()>
+{
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => return writeln!(out, "\t{}: {}", path.display(), err)
+    };
+    let text = String::from_utf8_lossy(&bytes);
+    let lines: Vec<&str> = text.lines().collect();
+    let matched: Vec<usize> = lines.iter().enumerate()
+        .filter(|(_, line)| {
+            let line = case_locale.lowercase(line);
+            words.iter().any(|word| contains_word(&line, word))
+        })
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut printed_to = 0;
+    for (n, &i) in matched.iter().enumerate() {
+        let start = i.saturating_sub(context).max(printed_to);
+        let end = (i + context + 1).min(lines.len());
+        if context > 0 && n > 0 && start > printed_to {
+            writeln!(out, "\t--")?;
+        }
+        for (j, line) in lines.iter().enumerate().take(end).skip(start) {
+            let mark = if matched.binary_search(&j).is_ok() { ':' } else { '-' };
+            writeln!(out, "\t{}{}{}", j + 1, mark, line)?;
+        }
+        printed_to = printed_to.max(end);
+    }
+    Ok(())
+}
+
+/// True if `word` appears in `line` without a letter or digit right before or
+/// after it (when it starts or ends with one itself).
+fn contains_word(line: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+    let is_word_char = |ch: Option<char>| ch.is_some_and(char::is_alphanumeric);
+    line.match_indices(word).any(|(at, _)| {
+        let joined_before = is_word_char(word.chars().next())
+            && is_word_char(line[..at].chars().next_back());
+        let joined_after = is_word_char(word.chars().next_back())
+            && is_word_char(line[at + word.len()..].chars().next());
+        !joined_before && !joined_after
+    })
+}
+
+fn main() {
+    let mut single_threaded = false;
+    let mut docid_base: u32 = 0;
+    let mut tokenizer = "default".to_string();
+    let mut ascii = false;
+    let mut ascii_strict = false;
+    let mut token_regex = None;
+    let mut ngram = 0;
+    let mut ngram_keep_words = false;
+    let mut code_paths = false;
+    let mut max_token_len = 0;
+    let mut normalize_whitespace = false;
+    let mut locale = None;
+    let mut case_sensitive = false;
+    let mut audit_terms = false;
+    let mut strip_html = false;
+    let mut cjk_bigram = false;
+    let mut strip_markdown = false;
+    let mut terms_file: Option<String> = None;
+    let mut max_postings_per_term = 0;
+    let mut postings_cap_policy = "drop".to_string();
+    let mut min_doc_count = 0;
+    let mut spellings = false;
+    let mut no_frequencies = false;
+    let mut index_filenames = false;
+    let mut tokenize_only = false;
+    let mut dry_run_count = false;
+    let mut estimate = false;
+    let mut estimate_sample = 1000;
+    let mut merge = false;
+    let mut search_query: Option<String> = None;
+    let mut explain = false;
+    let mut count_only = false;
+    let mut ranked = false;
+    let mut sort: Option<String> = None;
+    let mut limit: Option<usize> = None;
+    let mut min_freq = 0;
+    let mut print_matches = false;
+    let mut context: Option<usize> = None;
+    let mut with_positions = false;
+    let mut doc_stats: Option<String> = None;
+    let mut retries = 0;
+    let mut read_threads = 1;
+    let mut quiet_errors_after: Option<usize> = None;
+    let mut max_file_size: Option<u64> = None;
+    let mut read_timeout_ms: Option<u64> = None;
+    let mut sample_rate = 1.0;
+    let mut sample_seed = 0;
+    let mut output_format = "standard".to_string();
+    let mut stats_json = false;
+    let mut durable = false;
+    let mut compress_tmp = false;
+    let mut codec = "raw".to_string();
+    let mut tmp_dir: Option<String> = None;
+    let mut flush_every: Option<usize> = None;
+    let mut resume_reading = false;
+    let mut checkpoint_every: Option<usize> = None;
+    let mut profile: Option<String> = None;
+    let mut files_from0 = false;
+    let mut exclude_globs: Vec<String> = vec![];
+    let mut output_dir = ".".to_string();
+    let mut filenames = vec![];
+
+    {
+        let mut ap = ArgumentParser::new();
+        ap.set_description("Make an inverted index for searching documents.");
+        ap.refer(&mut single_threaded)
+            .add_option(&["-1", "--single-threaded"], StoreTrue,
+                        "Do all the work on a single thread.");
+        ap.refer(&mut docid_base)
+            .add_option(&["--docid-base"], Store,
+                        "Number documents starting at this id, so that \
+                         index shards built separately don't collide.");
+        ap.refer(&mut tokenizer)
+            .add_option(&["--tokenizer", "--mode"], Store,
+                        "How to break text into words: default, numbers \
+                         (keeps numbers like 3.14 together), code (makes \
+                         operators like :: searchable), or regex.");
+        ap.refer(&mut ascii)
+            .add_option(&["--ascii"], StoreTrue,
+                        "The documents are plain ASCII text: lowercase and \
+                         split them byte by byte, without Unicode tables. \
+                         Documents with other characters in them are \
+                         still indexed as usual.");
+        ap.refer(&mut ascii_strict)
+            .add_option(&["--ascii-strict"], StoreTrue,
+                        "Like --ascii, but report documents with non-ASCII \
+                         characters in them as unreadable and leave them out.");
+        ap.refer(&mut code_paths)
+            .add_option(&["--code-paths"], StoreTrue,
+                        "With --mode code, index paths like foo::bar as \
+                         single words.");
+        ap.refer(&mut token_regex)
+            .add_option(&["--token-regex"], StoreOption,
+                        "Treat every match of this regular expression as a \
+                         word (implies --tokenizer regex).");
+        ap.refer(&mut normalize_whitespace)
+            .add_option(&["--normalize-whitespace"], StoreTrue,
+                        "Turn every run of whitespace (tabs, line breaks, \
+                         non-breaking spaces...) into a single space before \
+                         breaking text into words. Useful with --token-regex.");
+        ap.refer(&mut strip_html)
+            .add_option(&["--strip-html"], StoreTrue,
+                        "Remove HTML tags, comments, and scripts from \
+                         documents before indexing them.");
+        ap.refer(&mut strip_markdown)
+            .add_option(&["--strip-markdown"], StoreTrue,
+                        "Remove Markdown formatting and link URLs from \
+                         documents before indexing them.");
+        ap.refer(&mut cjk_bigram)
+            .add_option(&["--cjk-bigram"], StoreTrue,
+                        "Index Chinese, Japanese, and Korean text, which has \
+                         no spaces between words, as overlapping pairs of \
+                         characters.");
+        ap.refer(&mut locale)
+            .add_option(&["--locale"], StoreOption,
+                        "Lowercase documents following the rules for this \
+                         language, e.g. tr for Turkish dotted and dotless i. \
+                         Only Turkish and Azerbaijani (az) have special \
+                         rules; other languages use the default.");
+        ap.refer(&mut case_sensitive)
+            .add_option(&["--case-sensitive"], StoreTrue,
+                        "Don't lowercase documents, so that e.g. Rust and \
+                         rust are different terms. Searches are then \
+                         case-sensitive too.");
+        ap.refer(&mut max_token_len)
+            .add_option(&["--max-token-len"], Store,
+                        "Truncate words longer than this many characters \
+                         (0 means no limit).");
+        ap.refer(&mut ngram)
+            .add_option(&["--ngram"], Store,
+                        "Index overlapping character n-grams of each word, \
+                         of this length, instead of whole words.");
+        ap.refer(&mut ngram_keep_words)
+            .add_option(&["--ngram-keep-words"], StoreTrue,
+                        "With --ngram, index whole words as well as n-grams.");
+        ap.refer(&mut terms_file)
+            .add_option(&["--terms-file"], StoreOption,
+                        "Index only the terms listed in this file, one per \
+                         line; drop every other word.");
+        ap.refer(&mut max_postings_per_term)
+            .add_option(&["--max-postings-per-term"], Store,
+                        "Limit the number of documents stored for any one \
+                         term (0 means no limit).");
+        ap.refer(&mut postings_cap_policy)
+            .add_option(&["--postings-cap-policy"], Store,
+                        "What to do with terms over the limit: drop (leave \
+                         them out of the index) or most-frequent (keep the \
+                         documents where they appear most often).");
+        ap.refer(&mut min_doc_count)
+            .add_option(&["--min-doc-count"], Store,
+                        "Leave out terms that appear in fewer than this many \
+                         documents.");
+        ap.refer(&mut spellings)
+            .add_option(&["--keep-spellings"], StoreTrue,
+                        "Also store how each term is spelled in the documents \
+                         before lowercasing, so searches can show the usual \
+                         capitalization (\"Rust\", not \"rust\").");
+        ap.refer(&mut index_filenames)
+            .add_option(&["--index-filenames"], StoreTrue,
+                        "Also index the words in each document's path, as \
+                         given on the command line, so documents can be \
+                         found by their names.");
+        ap.refer(&mut no_frequencies)
+            .add_option(&["--no-frequencies"], StoreTrue,
+                        "Store only which documents each word appears in, not \
+                         how often or where. Makes the index much smaller, \
+                         for searches that only need to know which documents \
+                         match. (Frequencies in index.csv are then 0.)");
+        ap.refer(&mut tokenize_only)
+            .add_option(&["--tokenize-only"], StoreTrue,
+                        "Don't build an index; just print the words found in \
+                         each document, one per line, as they would be \
+                         indexed with the other options given.");
+        ap.refer(&mut with_positions)
+            .add_option(&["--with-positions"], StoreTrue,
+                        "With --tokenize-only, print each word's offset in the \
+                         document before it.");
+        ap.refer(&mut estimate)
+            .add_option(&["--estimate"], StoreTrue,
+                        "Don't build an index; just estimate how big the \
+                         index file would be, from a sample of the documents.");
+        ap.refer(&mut estimate_sample)
+            .add_option(&["--estimate-sample"], Store,
+                        "With --estimate, how many documents to read \
+                         (default 1000).");
+        ap.refer(&mut dry_run_count)
+            .add_option(&["--dry-run-count"], StoreTrue,
+                        "Don't build an index; just count the documents, and \
+                         the bytes and words in them, as they would be \
+                         indexed with the other options given.");
+        ap.refer(&mut merge)
+            .add_option(&["--merge"], StoreTrue,
+                        "Don't build an index; merge the indexes given as \
+                         arguments (index files, or directories containing \
+                         index.dat), such as shards built with different \
+                         --docid-base values, into one in the output \
+                         directory. Documents whose ids overlap an earlier \
+                         index's are renumbered to follow it.");
+        ap.refer(&mut search_query)
+            .add_option(&["--search"], StoreOption,
+                        "Don't build an index; print the ids of the documents \
+                         in an existing one that contain every word of this \
+                         query. The index is the one in the output \
+                         directory, or the ones given as arguments; with \
+                         several, each id is labeled with its index.");
+        ap.refer(&mut explain)
+            .add_option(&["--explain"], StoreTrue,
+                        "With --search, also show for each document how \
+                         often each word appears in it (tf) and in how many \
+                         documents overall (df).");
+        ap.refer(&mut count_only)
+            .add_option(&["--count-only"], StoreTrue,
+                        "With --search, print just the number of matching \
+                         documents.");
+        ap.refer(&mut ranked)
+            .add_option(&["--ranked"], StoreTrue,
+                        "With --search, put the best matches first, and show \
+                         each one's score: how often each word appears in \
+                         it, weighted by how rare the word is. The same as \
+                         --sort by-score.");
+        ap.refer(&mut sort)
+            .add_option(&["--sort"], StoreOption,
+                        "With --search, the order to print documents in: \
+                         by-score (as with --ranked), by-docid (the \
+                         default), or by-path (which needs --doc-stats).");
+        ap.refer(&mut limit)
+            .add_option(&["--limit"], StoreOption,
+                        "With --search, print at most this many documents \
+                         (from each index).");
+        ap.refer(&mut min_freq)
+            .add_option(&["--min-freq"], Store,
+                        "With --search, only count a document as containing \
+                         a word if the word appears in it at least this many \
+                         times.");
+        ap.refer(&mut print_matches)
+            .add_option(&["--print-matches-with-context"], StoreTrue,
+                        "With --search and --doc-stats, after each document \
+                         print the lines in it that contain a query word, \
+                         reading the file again.");
+        ap.refer(&mut context)
+            .add_option(&["--context"], StoreOption,
+                        "With --print-matches-with-context, also print this \
+                         many lines before and after each matching line.");
+        ap.refer(&mut retries)
+            .add_option(&["--retries"], Store,
+                        "Retry reading a file up to this many times after \
+                         a transient error, such as a timeout.");
+        ap.refer(&mut read_threads)
+            .add_option(&["--read-threads", "--threads-per-read"], Store,
+                        "Read this many files at once. Helps with lots of \
+                         small files. (Ignored with --single-threaded.)");
+        ap.refer(&mut quiet_errors_after)
+            .add_option(&["--quiet-errors-after"], StoreOption,
+                        "Print only this many errors about files that can't \
+                         be read, then just count the rest.");
+        ap.refer(&mut max_file_size)
+            .add_option(&["--max-file-size"], StoreOption,
+                        "Skip files bigger than this many bytes.");
+        ap.refer(&mut read_timeout_ms)
+            .add_option(&["--read-timeout"], StoreOption,
+                        "Give up on reading a file after this many \
+                         milliseconds, and count it as a transient error \
+                         (see --retries). For network filesystems.");
+        ap.refer(&mut sample_rate)
+            .add_option(&["--sample-rate"], Store,
+                        "Index only this fraction (0.0 to 1.0) of the \
+                         documents, for a quick approximate index of a huge \
+                         corpus.");
+        ap.refer(&mut sample_seed)
+            .add_option(&["--sample-seed"], Store,
+                        "With --sample-rate, which sample to take. The same \
+                         seed always picks the same documents.");
+        ap.refer(&mut output_dir)
+            .add_option(&["-o", "--output"], Store,
+                        "Directory to write the index to (default: the \
+                         current directory).");
+        ap.refer(&mut output_format)
+            .add_option(&["--output-format"], Store,
+                        "standard (the default) writes just index.dat; csv \
+                         also writes index.csv, with one term,doc_id,freq \
+                         line per hit, for other programs to read.");
+        ap.refer(&mut durable)
+            .add_option(&["--durable"], StoreTrue,
+                        "Make sure index.dat is completely written to disk \
+                         (with fsync) before it appears, so a crash can't \
+                         leave a partial index behind. Slower.");
+        ap.refer(&mut codec)
+            .add_option(&["--codec"], Store,
+                        "How to store the hits in index.dat: raw (default), \
+                         varint, or for (frame-of-reference blocks; \
+                         smallest, but slowest).");
+        ap.refer(&mut compress_tmp)
+            .add_option(&["--compress-tmp"], StoreTrue,
+                        "Compress temporary files, so building a big index \
+                         takes less disk space (but more CPU time). The \
+                         finished index is not compressed.");
+        ap.refer(&mut flush_every)
+            .add_option(&["--flush-every"], StoreOption,
+                        "Write a temporary file after every N documents, \
+                         or sooner if memory fills up.");
+        ap.refer(&mut resume_reading)
+            .add_option(&["--resume-reading"], StoreTrue,
+                        "Build the index in pieces, keeping the finished \
+                         ones in a checkpoint in the output directory. If \
+                         the build is interrupted, run the same command \
+                         again to pick up after the last finished piece.");
+        ap.refer(&mut checkpoint_every)
+            .add_option(&["--checkpoint-every"], StoreOption,
+                        "With --resume-reading, the number of documents in \
+                         each piece (default 10000).");
+        ap.refer(&mut tmp_dir)
+            .add_option(&["--temp-dir"], StoreOption,
+                        "Write temporary files to this directory instead \
+                         of the output directory, for example to use a \
+                         faster disk.");
+        ap.refer(&mut profile)
+            .add_option(&["--profile"], StoreOption,
+                        "Write a trace to this file, recording when each \
+                         stage started and finished work on each document \
+                         or temporary file, as tab-separated values.");
+        ap.refer(&mut doc_stats)
+            .add_option(&["--doc-stats"], StoreOption,
+                        "Write statistics about each document (its path, \
+                         number of tokens, number of distinct terms, and \
+                         average token length) to this file, as \
+                         tab-separated values. With --search, read this \
+                         file instead, written when the index was built, \
+                         and show each document's path. With --ranked, its \
+                         token counts make a word count for less in a long \
+                         document than in a short one.");
+        ap.refer(&mut stats_json)
+            .add_option(&["--stats-json"], StoreTrue,
+                        "Also write stats.json, a summary of the build \
+                         (counts, sizes, timings, and files that couldn't be \
+                         read) for other programs to read.");
+        ap.refer(&mut audit_terms)
+            .add_option(&["--audit-terms"], StoreTrue,
+                        "After building the index, list terms that differ \
+                         only in case, like Rust and rust (which only \
+                         happens with --case-sensitive).");
+        ap.refer(&mut files_from0)
+            .add_option(&["-0", "--files-from0"], StoreTrue,
+                        "Also read names of files/directories to index from \
+                         stdin, separated by NUL characters, as written by \
+                         `find -print0`.");
+        ap.refer(&mut exclude_globs)
+            .add_option(&["--exclude-glob"], Collect,
+                        "Don't index files matching this pattern, like \
+                         '*.min.txt' or 'tmp/*' (* and ? don't match /). \
+                         A pattern without / matches just the file name. \
+                         Can be given more than once.");
         ap.refer(&mut filenames)
             .add_argument("filenames", Collect,
                           "Names of files/directories to index. \
                            For directories, all .txt files immediately \
-                           under the directory are indexed.");
+                           under the directory are indexed. (With --merge, \
+                           the indexes to merge; with --search, the index \
+                           to search.)");
         ap.parse_args_or_exit();
     }
 
-    match run(filenames, single_threaded) {
-        Ok(()) => {}
-        Err(err) => println!("error: {}", err)
+    let tokenizer_options = TokenizerOptions {
+        name: tokenizer,
+        ascii: ascii || ascii_strict,
+        token_regex,
+        code_paths,
+        normalize_whitespace,
+        max_token_len,
+        ngram,
+        ngram_keep_words,
+        locale,
+        case_sensitive,
+        cjk_bigram,
+        strip_html,
+        strip_markdown,
+        terms_file
+    };
+    let result = make_tokenizer(tokenizer_options)
+        .and_then(|tokenizer| {
+            let read_options = ReadOptions {
+                retries,
+                threads: read_threads,
+                quiet_errors_after,
+                max_file_size,
+                read_timeout: read_timeout_ms.map(Duration::from_millis),
+                ascii_only: ascii_strict
+            };
+            // Each of these does something other than build an index, so only
+            // one can be used at a time.
+            let modes: Vec<&str> = [("--tokenize-only", tokenize_only),
+                                    ("--dry-run-count", dry_run_count),
+                                    ("--estimate", estimate),
+                                    ("--merge", merge),
+                                    ("--search", search_query.is_some())]
+                .iter()
+                .filter(|&&(_, on)| on)
+                .map(|&(name, _)| name)
+                .collect();
+            if modes.len() > 1 {
+                return Err(io::Error::other(format!(
+                    "{} can't be used together", modes.join(" and "))));
+            }
+            if context.is_some() && !print_matches {
+                return Err(io::Error::other(
+                    "--context can only be used with --print-matches-with-context"));
+            }
+            if print_matches && (search_query.is_none() || doc_stats.is_none()) {
+                return Err(io::Error::other(
+                    "--print-matches-with-context needs --search and --doc-stats"));
+            }
+            let sort_order = match (&sort, ranked) {
+                (Some(_), true) => return Err(io::Error::other(
+                    "--ranked and --sort can't be used together")),
+                (Some(name), false) => SortOrder::from_name(name)?,
+                (None, true) => SortOrder::ByScore,
+                (None, false) => SortOrder::ByDocId
+            };
+            let hit_flags: Vec<&str> = [("--ranked", ranked),
+                                        ("--sort", sort.is_some()),
+                                        ("--limit", limit.is_some()),
+                                        ("--doc-stats", doc_stats.is_some())]
+                .iter()
+                .filter(|&&(_, on)| on)
+                .map(|&(name, _)| name)
+                .collect();
+            if let Some(query) = search_query {
+                let output = match (explain, count_only) {
+                    (true, true) => return Err(io::Error::other(
+                        "--explain and --count-only can't be used together")),
+                    (false, false) => SearchOutput::Hits {
+                        sort: sort_order,
+                        limit,
+                        doc_stats: doc_stats.map(PathBuf::from),
+                        context: if print_matches { Some(context.unwrap_or(0)) } else { None }
+                    },
+                    (explain, _) if !hit_flags.is_empty() => {
+                        let flag = if explain { "--explain" } else { "--count-only" };
+                        return Err(io::Error::other(format!(
+                            "{} and {} can't be used together", flag, hit_flags.join(" and "))));
+                    }
+                    (true, false) => SearchOutput::Explain,
+                    (false, true) => SearchOutput::CountOnly
+                };
+                search(&query, filenames, Path::new(&output_dir), output, min_freq)?;
+                return Ok(vec![]);
+            }
+            if explain || count_only || ranked || sort.is_some() || limit.is_some()
+                || min_freq != 0
+            {
+                return Err(io::Error::other(
+                    "--explain, --count-only, --ranked, --sort, --limit, and --min-freq can \
+                     only be used with --search"));
+            }
+            if merge {
+                merge_shards(filenames, Path::new(&output_dir))?;
+                return Ok(vec![]);
+            }
+            let files = FileArguments { filenames, files_from0, exclude: exclude_globs };
+            if dry_run_count {
+                return count_tokens(files, index_filenames, read_options, &*tokenizer);
+            }
+            if estimate {
+                print_estimate(files, estimate_sample, read_options, &*tokenizer)?;
+                return Ok(vec![]);
+            }
+            if tokenize_only {
+                return print_tokens(files, with_positions, index_filenames,
+                                    read_options, &*tokenizer);
+            }
+            let postings_cap = make_postings_cap(max_postings_per_term, &postings_cap_policy)?;
+            if no_frequencies
+                && postings_cap.is_some_and(|cap| cap.policy == CapPolicy::KeepMostFrequent)
+            {
+                return Err(io::Error::other(
+                    "--postings-cap-policy most-frequent can't be used with --no-frequencies"));
+            }
+            if flush_every == Some(0) {
+                return Err(io::Error::other("--flush-every must be at least 1"));
+            }
+            let checkpoint_every = match (resume_reading, checkpoint_every) {
+                (false, None) => None,
+                (false, Some(_)) => return Err(io::Error::other(
+                    "--checkpoint-every can only be used with --resume-reading")),
+                (true, Some(0)) => return Err(io::Error::other(
+                    "--checkpoint-every must be at least 1")),
+                (true, n) => Some(n.unwrap_or(DEFAULT_CHECKPOINT_EVERY))
+            };
+            if checkpoint_every.is_some() && doc_stats.is_some() {
+                return Err(io::Error::other(
+                    "--doc-stats can't be used with --resume-reading"));
+            }
+            let options = IndexOptions {
+                docid_base: docid_base as usize,
+                tokenizer: Arc::from(tokenizer),
+                postings_cap,
+                min_doc_count,
+                spellings,
+                filenames: index_filenames,
+                presence_only: no_frequencies,
+                doc_stats: doc_stats.as_deref().map(Path::new).map(DocStatsWriter::create)
+                    .transpose()?,
+                flush_every
+            };
+            let output = OutputOptions {
+                dir: PathBuf::from(output_dir),
+                format: OutputFormat::from_name(&output_format)?,
+                stats_json,
+                audit_terms,
+                durable,
+                codec: Codec::from_name(&codec)?,
+                compress_tmp,
+                tmp_dir: tmp_dir.map(PathBuf::from),
+                profile: profile.map(PathBuf::from)
+            };
+            let sample = make_sample(sample_rate, sample_seed)?;
+            run(files, single_threaded, checkpoint_every, output, sample, read_options, options)
+        });
+    // Exit status: 0 if everything was indexed, 2 if the index was built but
+    // some files had to be left out, 1 if no index was built at all.
+    match result {
+        Ok(failed_files) if failed_files.is_empty() => {}
+        Ok(failed_files) => {
+            if let Some(max) = quiet_errors_after.filter(|&max| failed_files.len() > max) {
+                println!("... and {} more errors", failed_files.len() - max);
+            }
+            println!("{} file(s) could not be read and were not indexed", failed_files.len());
+            process::exit(2);
+        }
+        Err(err) => {
+            println!("error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_options(retries: u32) -> ReadOptions {
+        ReadOptions {
+            retries,
+            threads: 1,
+            quiet_errors_after: None,
+            max_file_size: None,
+            read_timeout: None,
+            ascii_only: false
+        }
+    }
+
+    /// A `DocumentSource` that fails with an error of the given `kind` the
+    /// first `failures` times it's asked for a file, then succeeds, giving the
+    /// file's name as its text.
+    struct Flaky {
+        failures: usize,
+        kind: io::ErrorKind,
+        attempts: AtomicUsize
+    }
+
+    impl DocumentSource for Flaky {
+        fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(io::Error::new(self.kind, "flaky"));
+            }
+            Ok(Box::new(io::Cursor::new(filename.display().to_string())))
+        }
+    }
+
+    /// A new `Flaky`. Sources must be `'static`, and each test needs its own
+    /// count of attempts, so it's leaked.
+    fn flaky(failures: usize, kind: io::ErrorKind) -> &'static Flaky {
+        Box::leak(Box::new(Flaky { failures, kind, attempts: AtomicUsize::new(0) }))
+    }
+
+    /// A `DocumentSource` where the file named `N`, for a number N, holds the
+    /// word `wN` N times.
+    struct Generated;
+
+    impl DocumentSource for Generated {
+        fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>> {
+            let n: usize = filename.to_str().unwrap().parse().unwrap();
+            Ok(Box::new(io::Cursor::new(format!("w{} ", n).repeat(n))))
+        }
+    }
+
+    #[test]
+    fn reusing_the_buffer_reads_the_same_text() {
+        let options = read_options(0);
+        let mut text = String::new();
+        // Biggest first, so anything left over from the file before would show.
+        for n in (0..300).rev().chain(0..20) {
+            let filename = PathBuf::from(n.to_string());
+            read_document_into(&Generated, &filename, &options, &mut text).unwrap();
+            let mut fresh = String::new();
+            read_document_into(&Generated, &filename, &options, &mut fresh).unwrap();
+            assert_eq!(text, fresh, "{}", n);
+            let index = InMemoryIndex::from_single_document(n, &text, &DefaultTokenizer);
+            assert_eq!(index.term_count(), if n == 0 { 0 } else { 1 });
+        }
+    }
+
+    #[test]
+    fn transient_errors_are_retried_until_the_read_succeeds() {
+        let source = flaky(2, io::ErrorKind::Interrupted);
+        let mut text = "left over".to_string();
+        read_document_into(source, Path::new("doc.txt"), &read_options(2), &mut text).unwrap();
+        assert_eq!(text, "doc.txt");
+        assert_eq!(source.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn retries_run_out() {
+        let source = flaky(3, io::ErrorKind::TimedOut);
+        let mut text = String::new();
+        let err = read_document_into(source, Path::new("doc.txt"), &read_options(2), &mut text)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(source.attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        let source = flaky(1, io::ErrorKind::NotFound);
+        let mut text = String::new();
+        let err = read_document_into(source, Path::new("doc.txt"), &read_options(5), &mut text)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(source.attempts.load(Ordering::SeqCst), 1);
+    }
+
+    /// A `DocumentSource` like a network mount with one bad file: reading a
+    /// file whose name starts with `slow` takes `delay`. Every file's text is
+    /// its name.
+    struct Slow {
+        delay: Duration
+    }
+
+    impl DocumentSource for Slow {
+        fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>> {
+            if filename.to_string_lossy().starts_with("slow") {
+                thread::sleep(self.delay);
+            }
+            Ok(Box::new(io::Cursor::new(filename.display().to_string())))
+        }
+    }
+
+    #[test]
+    fn a_read_that_times_out_fails_and_the_rest_go_on() {
+        static SLOW: Slow = Slow { delay: Duration::from_secs(5) };
+        let options = ReadOptions { read_timeout: Some(Duration::from_millis(100)),
+                                    ..read_options(0) };
+        let start = Instant::now();
+        let (mut texts, mut failed) = (vec![], vec![]);
+        let mut text = String::new();
+        for name in ["a", "slow.txt", "b"] {
+            match read_document_into(&SLOW, Path::new(name), &options, &mut text) {
+                Ok(()) => texts.push(text.clone()),
+                Err(err) => {
+                    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+                    failed.push(name);
+                }
+            }
+        }
+        assert_eq!(texts, ["a", "b"]);
+        assert_eq!(failed, ["slow.txt"]);
+        // Nothing waited for the slow read to finish.
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+    }
+
+    /// A `DocumentSource` where reading the file named `N`, for a number N,
+    /// takes anywhere from 0 to 9 ms, so that reads on several threads finish
+    /// out of order. Every file's text is its name.
+    struct Jittery;
+
+    impl DocumentSource for Jittery {
+        fn open(&self, filename: &Path) -> io::Result<Box<dyn Read + Send>> {
+            let n: u64 = filename.to_str().unwrap().parse().unwrap();
+            thread::sleep(Duration::from_millis(n * 7 % 10));
+            Ok(Box::new(io::Cursor::new(filename.display().to_string())))
+        }
+    }
+
+    #[test]
+    fn parallel_reads_keep_the_original_order() {
+        let names: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        let paths = names.iter().map(PathBuf::from).collect();
+        let options = ReadOptions { threads: 8, ..read_options(0) };
+        let (documents, reader) = start_file_reader_thread(&Jittery, paths, options);
+        let texts: Vec<String> = documents.into_iter().map(|doc| doc.text).collect();
+        assert!(reader.join().unwrap().is_empty());
+        assert_eq!(texts, names);
+    }
+
+    #[test]
+    fn files_are_read_as_they_are_when_read_not_when_checked() {
+        let dir = env::temp_dir().join(format!("fingertips-main-resize-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (small, big) = (dir.join("small.txt"), dir.join("big.txt"));
+        fs::write(&small, "alpha beta gamma delta epsilon").unwrap();
+        fs::write(&big, "a short one").unwrap();
+        let options = ReadOptions { max_file_size: Some(20), ..read_options(0) };
+        let listed = expand_filename_arguments(vec![small.clone(), big.clone()], None,
+                                               options.max_file_size, &[]).unwrap();
+        // `small` is over the limit when it's checked, so it isn't listed.
+        assert_eq!(listed, vec![big.clone()]);
+
+        // The listed file shrinks before it's read: whatever is there then
+        // gets read, with no error about the size it used to be.
+        fs::write(&big, "tiny").unwrap();
+        assert_eq!(read_whole_file(&big, &options).unwrap(), "tiny");
+        fs::write(&big, "").unwrap();
+        assert_eq!(read_whole_file(&big, &options).unwrap(), "");
+
+        // If it grows past the limit instead, that's an error for that file.
+        fs::write(&big, "x".repeat(21)).unwrap();
+        let err = read_whole_file(&big, &options).unwrap_err();
+        assert!(err.to_string().contains("grew past"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn document_ids_past_the_cap_are_an_error() {
+        // With a cap of 9, there are ten ids, 0 through 9.
+        assert!(check_document_ids_up_to(0, 10, 9).is_ok());
+        assert!(check_document_ids_up_to(5, 5, 9).is_ok());
+        assert!(check_document_ids_up_to(100, 0, 9).is_ok());
+        for (base, n) in [(0, 11), (5, 6), (10, 1), (usize::MAX, 2)] {
+            let err = check_document_ids_up_to(base, n, 9).unwrap_err();
+            assert!(err.to_string().contains("too many documents"), "{}", err);
+        }
+        assert!(check_document_ids(MAX_DOCUMENT_ID as usize, 1).is_ok());
+        assert!(check_document_ids(MAX_DOCUMENT_ID as usize, 2).is_err());
+    }
+
+    #[test]
+    fn filenames_reach_the_indexing_stage() {
+        let dir = env::temp_dir().join(format!("fingertips-main-filenames-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        let paths = [dir.join("notes/alpha.txt"), dir.join("notes/beta.txt")];
+        fs::write(&paths[0], "first").unwrap();
+        fs::write(&paths[1], "second").unwrap();
+
+        let (documents, reader) = start_file_reader_thread(&Filesystem, paths.to_vec(),
+                                                               read_options(0));
+        let doc_stats = DocStatsWriter::create(&dir.join("stats.tsv")).unwrap();
+        let (indexes, indexer) = start_file_indexing_thread(
+            documents, 0, Arc::new(DefaultTokenizer), false, true, false, Some(doc_stats));
+        let mut index = InMemoryIndex::new();
+        for doc in indexes {
+            index.merge(doc, None);
+        }
+        assert!(reader.join().unwrap().is_empty());
+        indexer.join().unwrap().unwrap();
+
+        let docs_with = |term: &str| -> Vec<u32> {
+            index.iter().find(|&(t, _)| t == term)
+                .map_or(vec![], |(_, hits)| hits.map(|(id, _)| id).collect())
+        };
+        assert_eq!(docs_with("notes"), [0, 1]);
+        assert_eq!(docs_with("alpha"), [0]);
+        assert_eq!(docs_with("beta"), [1]);
+        let stats = fs::read_to_string(dir.join("stats.tsv")).unwrap();
+        let stats_paths: Vec<&str> = stats.lines().skip(1)
+            .map(|line| line.split('\t').nth(1).unwrap())
+            .collect();
+        assert_eq!(stats_paths, [paths[0].to_str().unwrap(), paths[1].to_str().unwrap()]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Options for writing a standard index to `dir`, with nothing extra.
+    fn output_options(dir: &Path) -> OutputOptions {
+        OutputOptions {
+            dir: dir.to_path_buf(),
+            format: OutputFormat::Standard,
+            stats_json: false,
+            audit_terms: false,
+            durable: false,
+            codec: Codec::Raw,
+            compress_tmp: false,
+            tmp_dir: None,
+            profile: None
+        }
+    }
+
+    /// Options for indexing documents from id 0 with `tokenizer`, flushing
+    /// every `flush_every` documents, with nothing extra.
+    fn index_options(tokenizer: Arc<dyn Tokenizer>, flush_every: Option<usize>)
+        -> IndexOptions
+    {
+        IndexOptions {
+            docid_base: 0,
+            tokenizer,
+            postings_cap: None,
+            min_doc_count: 0,
+            spellings: false,
+            filenames: false,
+            presence_only: false,
+            doc_stats: None,
+            flush_every
+        }
+    }
+
+    /// A tokenizer that panics if it sees the word "boom".
+    struct Boom;
+
+    impl Tokenizer for Boom {
+        fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+            assert!(!text.contains("boom"), "injected panic");
+            DefaultTokenizer.tokens(text)
+        }
+    }
+
+    #[test]
+    fn pipeline_writes_no_index_if_a_stage_dies() {
+        let dir = env::temp_dir().join(format!("fingertips-main-stage-dies-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut documents = vec![];
+        for (i, text) in ["one fish", "two fish", "boom", "red fish"].iter().enumerate() {
+            let path = dir.join(format!("{}.txt", i));
+            fs::write(&path, text).unwrap();
+            documents.push(path);
+        }
+        let output = output_options(&dir);
+        let options = index_options(Arc::new(Boom), Some(1));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run_pipeline(documents, &output, read_options(0), options)
+        }));
+        assert!(result.is_err());
+        assert!(!dir.join(MERGED_FILENAME).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flush_every_writes_one_tmp_file_per_batch() {
+        let dir = env::temp_dir().join(format!("fingertips-main-flush-every-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let total: usize = 23;
+        let mut documents = vec![];
+        for i in 0..total {
+            let path = dir.join(format!("{}.txt", i));
+            fs::write(&path, format!("fish number{}", i)).unwrap();
+            documents.push(path);
+        }
+        for n in [1, 5, 7, 22, 23, 100] {
+            let options = index_options(Arc::new(DefaultTokenizer), Some(n));
+            let report = run_pipeline(documents.clone(), &output_options(&dir),
+                                      read_options(0), options).unwrap();
+            assert_eq!(report.merged_files.len(), total.div_ceil(n), "--flush-every {}", n);
+            let index = Index::open(dir.join(MERGED_FILENAME)).unwrap();
+            assert_eq!(index.query(&["fish"]).unwrap().len(), total);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_documents_are_detected_before_the_index_is_written() {
+        let dir = env::temp_dir().join(format!("fingertips-main-missing-docs-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut merge = FileMerge::new(&dir, None);
+        let mut index = InMemoryIndex::new();
+        for (id, text) in ["one fish", "two fish"].iter().enumerate() {
+            index.merge(InMemoryIndex::from_single_document(id, text, &DefaultTokenizer), None);
+        }
+        merge.add_last_index(index);
+
+        assert!(check_all_documents_indexed(&merge, 0, 2).is_ok());
+        // A stage that stopped early, one document short.
+        let err = check_all_documents_indexed(&merge, 0, 3).unwrap_err();
+        assert!(err.to_string().contains("3 documents were read, but the index would cover 2"),
+                "{}", err);
+        // The right number of documents, but not the ones that were read.
+        assert!(check_all_documents_indexed(&merge, 1, 2).is_err());
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,351 @@
+//! Removing markup from documents before they're indexed.
+//!
+//! These are deliberately simple, line-at-a-time strippers, not real HTML or
+//! Markdown parsers. The goal is just to keep tag names, attributes, URLs, and
+//! formatting characters out of the index, so that `<b>rust</b>` is indexed
+//! as `rust`. Odd or broken markup is mostly passed through as text.
+
+use std::borrow::Cow;
+
+use crate::index::{CaseLocale, Tokenizer};
+
+/// A kind of markup that `MarkupStrippingTokenizer` can remove.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Markup {
+    Html,
+    Markdown
+}
+
+impl Markup {
+    /// Remove this kind of markup from `text`.
+    pub fn strip(self, text: &str) -> String {
+        match self {
+            Markup::Html => strip_html(text),
+            Markup::Markdown => strip_markdown(text)
+        }
+    }
+}
+
+/// A tokenizer that strips markup from the text (see `Markup::strip`) before
+/// handing it to another tokenizer.
+pub struct MarkupStrippingTokenizer {
+    inner: Box<dyn Tokenizer>,
+    markup: Markup
+}
+
+impl MarkupStrippingTokenizer {
+    pub fn new(inner: Box<dyn Tokenizer>, markup: Markup) -> MarkupStrippingTokenizer {
+        MarkupStrippingTokenizer { inner, markup }
+    }
+}
+
+impl Tokenizer for MarkupStrippingTokenizer {
+    fn tokens<'a>(&self, text: &'a str) -> Box<dyn Iterator<Item = Cow<'a, str>> + 'a> {
+        // As in `WhitespaceNormalizingTokenizer`, the words borrow from
+        // `stripped`, so they have to be copied before we return.
+        let stripped = self.markup.strip(text);
+        let words: Vec<Cow<'a, str>> =
+            self.inner.tokens(&stripped)
+            .map(|word| Cow::Owned(word.into_owned()))
+            .collect();
+        Box::new(words.into_iter())
+    }
+
+    fn lowercase(&self, text: &str) -> String {
+        self.inner.lowercase(text)
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        self.inner.case_locale()
+    }
+}
+
+/// Remove HTML tags and comments from `text`, along with the contents of
+/// `<script>` and `<style>` elements, and decode character references like
+/// `&amp;`. Each tag is replaced with a space, so that `one<br>two` is still
+/// two words.
+///
+/// A `<` that doesn't start something that looks like a tag (as in `a < b`)
+/// is left alone.
+pub fn strip_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find(['<', '&']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end + 3..],
+                None => ""
+            };
+            out.push(' ');
+        } else if rest.starts_with('<') {
+            match rest.find('>').filter(|&end| looks_like_tag(&rest[1..end])) {
+                Some(end) => {
+                    let name = tag_name(&rest[1..end]);
+                    rest = &rest[end + 1..];
+                    if name.eq_ignore_ascii_case("script") || name.eq_ignore_ascii_case("style") {
+                        rest = skip_element_contents(rest, name);
+                    }
+                    out.push(' ');
+                }
+                None => {
+                    out.push('<');
+                    rest = &rest[1..];
+                }
+            }
+        } else {
+            match decode_entity(rest) {
+                Some((ch, len)) => {
+                    out.push(ch);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push('&');
+                    rest = &rest[1..];
+                }
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// True if `inside`, the text between a `<` and the next `>`, looks like the
+/// inside of a tag: `p class="x"`, `/p`, or `!DOCTYPE html`.
+fn looks_like_tag(inside: &str) -> bool {
+    let inside = inside.strip_prefix(['/', '!']).unwrap_or(inside);
+    inside.starts_with(|ch: char| ch.is_ascii_alphabetic())
+}
+
+/// The element name in a start tag: `p` for `p class="x"`. For end tags and
+/// the like, this returns an empty string.
+fn tag_name(inside: &str) -> &str {
+    let end = inside.find(|ch: char| !ch.is_ascii_alphanumeric()).unwrap_or(inside.len());
+    &inside[..end]
+}
+
+/// Skip past the end tag `</name>`, returning the text after it. `text` is
+/// the text right after the start tag.
+fn skip_element_contents<'a>(text: &'a str, name: &str) -> &'a str {
+    let end_tag = format!("</{}", name.to_ascii_lowercase());
+    match text.to_ascii_lowercase().find(&end_tag) {
+        Some(i) => match text[i..].find('>') {
+            Some(end) => &text[i + end + 1..],
+            None => ""
+        },
+        None => ""
+    }
+}
+
+/// Decode the character reference at the start of `text`, like `&amp;` or
+/// `&#233;`. Returns the character and the length of the reference, or `None`
+/// if `text` doesn't start with one we know.
+fn decode_entity(text: &str) -> Option<(char, usize)> {
+    // The longest reference we decode is `&#x10ffff;`.
+    let semi = text.char_indices().take(10).find(|&(_, ch)| ch == ';')?.0;
+    let name = &text[1..semi];
+    let ch = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let hex = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X"));
+            let code = if let Some(hex) = hex {
+                u32::from_str_radix(hex, 16).ok()?
+            } else {
+                name.strip_prefix('#')?.parse().ok()?
+            };
+            char::from_u32(code)?
+        }
+    };
+    Some((ch, semi + 1))
+}
+
+/// Remove Markdown formatting from `text`: heading and blockquote markers,
+/// code fences, emphasis, and the URLs of links and images (the link text and
+/// image descriptions are kept). Link reference definitions, like
+/// `[1]: http://example.com`, are removed entirely.
+///
+/// The contents of code spans and code blocks are kept exactly as they are.
+pub fn strip_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_code_block = false;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code_block = !in_code_block;
+            out.push('\n');
+        } else if in_code_block {
+            out.push_str(line);
+        } else if is_link_definition(trimmed) {
+            out.push('\n');
+        } else {
+            let mut line = trimmed;
+            while let Some(rest) = line.strip_prefix('>') {
+                line = rest.trim_start();
+            }
+            let unhashed = line.trim_start_matches('#');
+            if unhashed.len() < line.len() && unhashed.starts_with([' ', '\t', '\n', '\r']) {
+                line = unhashed;
+            }
+            strip_markdown_inline(line, &mut out);
+        }
+    }
+    out
+}
+
+/// True if `line` is a link reference definition, like `[id]: url`.
+fn is_link_definition(line: &str) -> bool {
+    line.starts_with('[')
+        && line.find("]:").is_some_and(|i| !line[1..i].contains(']'))
+}
+
+/// Strip Markdown formatting from a single line of text, appending the
+/// result to `out`.
+fn strip_markdown_inline(line: &str, out: &mut String) {
+    let mut rest = line;
+    while let Some(i) = rest.find(['`', '*', '_', '[', '!']) {
+        out.push_str(&rest[..i]);
+        let before = out.chars().next_back();
+        rest = &rest[i..];
+        if rest.starts_with('`') {
+            // A code span: copy it verbatim, without the backticks.
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            let fence = &rest[..ticks];
+            match rest[ticks..].find(fence) {
+                Some(end) => {
+                    out.push_str(&rest[ticks..ticks + end]);
+                    rest = &rest[ticks + end + ticks..];
+                }
+                None => {
+                    out.push_str(fence);
+                    rest = &rest[ticks..];
+                }
+            }
+        } else if rest.starts_with("![") || rest.starts_with('[') {
+            let open = if rest.starts_with('!') { 2 } else { 1 };
+            match link_text_and_len(&rest[open..]) {
+                Some((text, len)) => {
+                    strip_markdown_inline(text, out);
+                    rest = &rest[open + len..];
+                }
+                None => {
+                    out.push_str(&rest[..open]);
+                    rest = &rest[open..];
+                }
+            }
+        } else if rest.starts_with('!') {
+            out.push('!');
+            rest = &rest[1..];
+        } else if rest.starts_with('*') {
+            rest = &rest[1..];
+        } else {
+            // `_` is emphasis only at the edge of a word; inside one, as in
+            // `snake_case`, it's part of the text.
+            let after = rest[1..].chars().next();
+            let inside_word = before.is_some_and(char::is_alphanumeric)
+                && after.is_some_and(char::is_alphanumeric);
+            if inside_word {
+                out.push('_');
+            }
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+}
+
+/// Given the text after the `[` of a link or image, return the link text and
+/// the length of the rest of the link: `text](url)` or `text][id]`. Returns
+/// `None` if it isn't a link.
+fn link_text_and_len(text: &str) -> Option<(&str, usize)> {
+    let close = text.find(']')?;
+    let after = &text[close + 1..];
+    let end = if after.starts_with('(') {
+        after.find(')')?
+    } else if after.starts_with('[') {
+        after.find(']')?
+    } else {
+        return None;
+    };
+    Some((&text[..close], close + 1 + end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::DefaultTokenizer;
+
+    fn words(text: &str) -> Vec<&str> {
+        text.split_whitespace().collect()
+    }
+
+    #[test]
+    fn html_tags_are_removed() {
+        assert_eq!(words(&strip_html("<b>rust</b>")), ["rust"]);
+        assert_eq!(words(&strip_html("<p class=\"x\">fast <i>and</i> safe</p>")),
+                   ["fast", "and", "safe"]);
+        assert_eq!(words(&strip_html("<!DOCTYPE html>one<!-- not -->two")), ["one", "two"]);
+        assert_eq!(words(&strip_html("one<br>two")), ["one", "two"]);
+    }
+
+    #[test]
+    fn scripts_and_styles_are_skipped() {
+        let html = "<style>p { color: red }</style>kept<SCRIPT type=\"x\">var hidden = 1;\
+                    </Script> also kept";
+        assert_eq!(words(&strip_html(html)), ["kept", "also", "kept"]);
+        assert_eq!(words(&strip_html("before<script>never closed")), ["before"]);
+    }
+
+    #[test]
+    fn character_references_are_decoded() {
+        assert_eq!(strip_html("salt &amp; pepper"), "salt & pepper");
+        assert_eq!(strip_html("&lt;tag&gt; &quot;q&quot; caf&#233; caf&#xE9;"),
+                   "<tag> \"q\" caf\u{e9} caf\u{e9}");
+        assert_eq!(strip_html("AT&T &bogus; &"), "AT&T &bogus; &");
+    }
+
+    #[test]
+    fn a_lone_less_than_sign_is_text() {
+        assert_eq!(strip_html("a < b"), "a < b");
+        assert_eq!(strip_html("if x <= y > z"), "if x <= y > z");
+        assert_eq!(strip_html("x <3"), "x <3");
+    }
+
+    #[test]
+    fn markdown_links_keep_their_text() {
+        assert_eq!(words(&strip_markdown("see [the docs](http://example.com/x) now")),
+                   ["see", "the", "docs", "now"]);
+        assert_eq!(words(&strip_markdown("![a cat](cat.png) and [ref][1]\n[1]: http://x.y\n")),
+                   ["a", "cat", "and", "ref"]);
+        assert_eq!(strip_markdown("[not a link] here"), "[not a link] here");
+    }
+
+    #[test]
+    fn markdown_emphasis_is_removed() {
+        assert_eq!(strip_markdown("**bold** and _italic_ and *em*"), "bold and italic and em");
+        assert_eq!(strip_markdown("snake_case stays"), "snake_case stays");
+        assert_eq!(words(&strip_markdown("# Title\n> quoted *text*\n")),
+                   ["Title", "quoted", "text"]);
+    }
+
+    #[test]
+    fn markdown_code_is_kept_verbatim() {
+        assert_eq!(strip_markdown("call `foo_bar(*x)` now"), "call foo_bar(*x) now");
+        assert_eq!(strip_markdown("```rust\nlet _x = *y;\n```\nafter *it*\n"),
+                   "\nlet _x = *y;\n\nafter it\n");
+    }
+
+    #[test]
+    fn stripping_tokenizer_indexes_only_the_text() {
+        let tokenizer = MarkupStrippingTokenizer::new(Box::new(DefaultTokenizer), Markup::Html);
+        let text = "<a href=\"http://example.com\">Rust</a> &amp; <b>Go</b>";
+        let tokens: Vec<String> = tokenizer.tokens(&tokenizer.lowercase(text))
+            .map(|token| token.into_owned())
+            .collect();
+        assert_eq!(tokens, ["rust", "go"]);
+    }
+}
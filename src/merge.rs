@@ -1,33 +1,208 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::{self, BufWriter};
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::io::prelude::*;
 use std::mem;
 use std::path::{Path, PathBuf};
 
+use crate::compress::Codec;
+use crate::error::Error;
+use crate::index::{CaseLocale, DocRange, InMemoryIndex, PostingList, PostingsCap, CapPolicy,
+                   Spellings, TermHasher, TermOrder, merge_spellings};
 use crate::tmp::TmpDir;
-use crate::read::IndexFileReader;
-use crate::write::IndexFileWriter;
+use crate::read::{IndexFileReader, Entry, read_entry, read_header};
+use crate::write::{IndexFileWriter, explain_write_error, write_index_to_tmp_file};
 
 pub struct FileMerge {
     output_dir: PathBuf,
     tmp_dir: TmpDir,
-    stacks: Vec<Vec<PathBuf>>
+    stacks: Vec<Vec<PathBuf>>,
+    cap: Option<PostingsCap>,
+    order: TermOrder,
+    last_index: Option<InMemoryIndex>,
+    durable: bool,
+    min_doc_count: usize,
+    codec: Codec,
+    on_term: Option<TermCallback>,
+    added_files: Vec<AddedFile>,
+    progress: Progress
+}
+
+/// A file passed to `FileMerge::add_file`, and how much it contributes to the
+/// finished index.
+#[derive(Clone, Debug)]
+pub struct AddedFile {
+    pub path: PathBuf,
+
+    /// The range of document ids covered by the file.
+    pub docs: DocRange,
+
+    /// The number of terms in the file.
+    pub terms: u64,
+
+    /// The number of hits in the file, over all terms. (A term that's later
+    /// dropped, say by `set_min_doc_count`, still counts here.)
+    pub postings: u64
+}
+
+/// A callback that's passed each term written to the finished index, along
+/// with the number of documents it appears in.
+pub type TermCallback = Box<dyn FnMut(&str, u32) + Send>;
+
+/// Counts passed to a `FileMerge` progress callback.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MergeProgress {
+    /// The number of files passed to `add_file` so far.
+    pub files_added: usize,
+
+    /// The number of files that have been merged into bigger files so far.
+    /// This includes the intermediate files `FileMerge` creates itself.
+    pub files_merged: usize,
+
+    /// The number of terms written out by merges so far. A term is counted
+    /// again each time it's merged, so this doesn't say how many distinct
+    /// terms there are.
+    pub terms_merged: u64
+}
+
+/// The terms `FileMerge::finish` left out of the finished index, so the caller
+/// can report them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// The number of terms dropped for appearing in more documents than a
+    /// `CapPolicy::DropTerm` cap allows.
+    pub over_cap: u64,
+
+    /// The number of terms pruned for appearing in fewer documents than
+    /// `set_min_doc_count` asks for.
+    pub rare: u64
+}
+
+/// A progress callback, along with the counts to pass to it.
+#[derive(Default)]
+struct Progress {
+    counts: MergeProgress,
+    callback: Option<Box<dyn FnMut(MergeProgress) + Send>>,
+
+    /// The `FileMerge`'s term callback, but only during the merge that writes
+    /// the finished index.
+    on_term: Option<TermCallback>
+}
+
+// How many terms to merge between calls to the progress callback.
+const TERMS_PER_PROGRESS_REPORT: u64 = 10_000;
+
+impl Progress {
+    fn report(&mut self) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(self.counts);
+        }
+    }
+
+    fn term_written(&mut self, term: &str, df: u32) {
+        if let Some(on_term) = self.on_term.as_mut() {
+            on_term(term, df);
+        }
+    }
 }
 
 // How many files to merge at a time, at most.
 const NSTREAMS: usize = 8;
 
-const MERGED_FILENAME: &'static str = "index.dat";
+/// The name of the finished index file, in the output directory.
+pub const MERGED_FILENAME: &str = "index.dat";
 
 impl FileMerge {
-    pub fn new(output_dir: &Path) -> FileMerge {
+    /// Prepare to merge index files into a single index in `output_dir`,
+    /// trimming the hits for each term to fit `cap`, if given.
+    pub fn new(output_dir: &Path, cap: Option<PostingsCap>) -> FileMerge {
         FileMerge {
             output_dir: output_dir.to_owned(),
-            tmp_dir: TmpDir::new(output_dir.to_owned()),
-            stacks: vec![]
+            tmp_dir: TmpDir::new(output_dir),
+            stacks: vec![],
+            cap,
+            order: TermOrder::bytes(),
+            last_index: None,
+            durable: false,
+            min_doc_count: 0,
+            codec: Codec::Raw,
+            on_term: None,
+            added_files: vec![],
+            progress: Progress::default()
         }
     }
 
+    /// Call `callback` with updated counts every time a file is added, every
+    /// time a merge finishes, and every so often during each merge.
+    pub fn set_progress(&mut self, callback: Box<dyn FnMut(MergeProgress) + Send>) {
+        self.progress.callback = Some(callback);
+    }
+
+    /// Call `callback` with each term written to the finished index, in
+    /// order, along with the number of documents it appears in. This is
+    /// called while `finish` writes the index, so anything built from the
+    /// terms (a Bloom filter, say) is ready as soon as the index is, without
+    /// reading it back.
+    pub fn set_term_callback(&mut self, callback: TermCallback) {
+        self.on_term = Some(callback);
+    }
+
+    /// Expect the terms in each file to be sorted in `order`, and keep them in
+    /// that order in the merged file. The default is `TermOrder::bytes()`.
+    /// This must match the order used to write the files passed to
+    /// `add_file`.
+    pub fn set_term_order(&mut self, order: TermOrder) {
+        self.order = order;
+    }
+
+    /// If `durable` is true, `finish` makes sure the finished index is safely
+    /// on disk (using `fsync`) before it appears under its final name, so a
+    /// crash or power loss can't leave a partly written `index.dat` behind.
+    /// This makes `finish` slower.
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// Store the hits in the intermediate files this creates while merging
+    /// using `codec`, for example to save disk space. The default is
+    /// `Codec::Raw`. Files passed to `add_file` can use any codec.
+    pub fn set_tmp_codec(&mut self, codec: Codec) {
+        self.tmp_dir.set_codec(codec);
+    }
+
+    /// Store the hits in the finished index using `codec`. The default is
+    /// `Codec::Raw`.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Put the intermediate files this creates in `dir`, instead of the output
+    /// directory. Call this before adding any files.
+    ///
+    /// `dir` can be on a different filesystem, such as a fast scratch disk.
+    /// Then the finished index can't simply be renamed into place, so
+    /// `finish` has to copy it, which takes longer.
+    pub fn set_tmp_dir(&mut self, dir: &Path) {
+        let codec = self.tmp_dir.codec();
+        self.tmp_dir = TmpDir::new(dir);
+        self.tmp_dir.set_codec(codec);
+    }
+
+    /// Leave out of the finished index any term that appears in fewer than
+    /// `min_doc_count` documents. Terms that appear only once or twice in a
+    /// big corpus are often just noise: typos, ids, and the like.
+    pub fn set_min_doc_count(&mut self, min_doc_count: usize) {
+        self.min_doc_count = min_doc_count;
+    }
+
     pub fn add_file(&mut self, mut file: PathBuf) -> io::Result<()> {
+        assert!(self.last_index.is_none(), "add_file called after add_last_index");
+        let (docs, terms, postings) = count_entries(&file)?;
+        self.added_files.push(AddedFile { path: file.clone(), docs, terms, postings });
+        self.progress.counts.files_added += 1;
+        self.progress.report();
+
         let mut level = 0;
         loop {
             if level == self.stacks.len() {
@@ -40,93 +215,1095 @@ impl FileMerge {
             let (filename, out) = self.tmp_dir.create()?;
             let mut to_merge = vec![];
             mem::swap(&mut self.stacks[level], &mut to_merge);
-            merge_streams(to_merge, out)?;
+            let result = merge_streams(open_and_delete_all(to_merge)?, None, out,
+                                       self.tmp_dir.codec(), self.cap.as_ref(), &self.order,
+                                       &mut self.progress);
+            check_tmp_file(&filename, result)?;
             file = filename;
             level += 1;
         }
         Ok(())
     }
 
-    pub fn finish(mut self) -> io::Result<()> {
+    /// The files passed to `add_file` so far, in order. Each one is merged
+    /// into the finished index (and deleted) by the time `finish` returns.
+    pub fn added_files(&self) -> &[AddedFile] {
+        &self.added_files
+    }
+
+    /// The range of document ids covered by everything added so far. This is
+    /// the range the finished index will cover, so it can be checked against
+    /// the documents that were supposed to go into the index before `finish`
+    /// makes the index visible.
+    pub fn doc_range(&self) -> DocRange {
+        let files = self.added_files.iter()
+            .fold(DocRange::empty(), |docs, file| docs.union(&file.docs));
+        match self.last_index {
+            Some(ref index) => files.union(&index.docs),
+            None => files
+        }
+    }
+
+    /// Add the last batch of documents, still in memory, without writing it
+    /// to a temporary file first. This saves writing the data out and reading
+    /// it back in when it's merged with the files already added.
+    ///
+    /// `index` must cover later document ids than all the files, and no more
+    /// files can be added after it.
+    pub fn add_last_index(&mut self, index: InMemoryIndex) {
+        assert!(self.last_index.is_none(), "add_last_index called twice");
+        self.last_index = Some(index);
+    }
+
+    /// Merge everything added so far into the finished index, `index.dat` in
+    /// the output directory.
+    ///
+    /// The index is built under a temporary name and only renamed to
+    /// `index.dat` at the very end. Renaming within a directory is atomic, so
+    /// anyone reading `index.dat` sees either the previous index or the
+    /// complete new one; if this fails or is interrupted partway, the previous
+    /// index is left alone. (See also `set_durable` and `set_tmp_dir`.)
+    ///
+    /// If nothing was added, this writes an index with no terms in it.
+    ///
+    /// Returns the number of terms left out by the cap and by
+    /// `set_min_doc_count`.
+    pub fn finish(mut self) -> io::Result<MergeSummary> {
+        let mut last_index = self.last_index.take();
         let mut tmp = Vec::with_capacity(NSTREAMS);
         for stack in self.stacks {
             for file in stack.into_iter().rev() {
                 tmp.push(file);
                 if tmp.len() == NSTREAMS {
-                    merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+                    merge_reversed(&mut tmp, None, &mut self.tmp_dir, self.cap.as_ref(),
+                                   &self.order, &mut self.progress)?;
                 }
             }
         }
 
-        if tmp.len() > 1 {
-            merge_reversed(&mut tmp, &mut self.tmp_dir)?;
+        // The in-memory index, if any, goes into the last merge. If there are
+        // no files to merge it with, it just has to be written out. Either
+        // way, the result is the finished index, so it uses its codec.
+        //
+        // Terms over the cap, or under the minimum document count, can only
+        // be dropped once all their hits have been merged together; see
+        // `PostingsCap::trim`. That takes another pass, which is then the one
+        // that writes the finished index and reports each term.
+        let drop_cap = self.cap.filter(|cap| cap.policy == CapPolicy::DropTerm);
+        let min_doc_count = self.min_doc_count;
+        let filtering = drop_cap.is_some() || min_doc_count > 1;
+        self.tmp_dir.set_codec(self.codec);
+        if tmp.len() > 1 || (!tmp.is_empty() && last_index.is_some()) {
+            if !filtering {
+                self.progress.on_term = self.on_term.take();
+            }
+            merge_reversed(&mut tmp, last_index.take(), &mut self.tmp_dir, self.cap.as_ref(),
+                           &self.order, &mut self.progress)?;
+        }
+        if let Some(mut index) = last_index {
+            tmp.push(write_index_to_tmp_file(&mut index, &mut self.tmp_dir, &self.order)?);
         }
         assert!(tmp.len() <= 1);
-        match tmp.pop() {
-            Some(last_file) =>
-                fs::rename(last_file, self.output_dir.join(MERGED_FILENAME)),
-            None =>
-                Err(io::Error::new(io::ErrorKind::Other,
-                                   "no documents were parsed or none contained any words"))
+        let mut last_file = match tmp.pop() {
+            Some(file) => file,
+            None => {
+                // No documents were added, or none of them had any words in
+                // them. That's not an error; the index is just empty.
+                let (filename, out) = self.tmp_dir.create()?;
+                let result = IndexFileWriter::with_codec(out, DocRange::empty(), self.codec)
+                    .and_then(IndexFileWriter::finish);
+                check_tmp_file(&filename, result)?;
+                filename
+            }
+        };
+
+        // The filtering pass also converts the file to the right codec, if
+        // there was no final merge to do it, and reports the terms, if nothing
+        // has yet. If there's no filtering or converting to do, the file is
+        // already the finished index; any terms still to be reported are read
+        // from its table of contents, and it's renamed into place without
+        // copying the hits.
+        let mut on_term = self.on_term.take();
+        let mut summary = MergeSummary::default();
+        let codec = read_header(&mut File::open(&last_file)?)?.codec;
+        if filtering || codec != self.codec {
+            let (filename, out) = self.tmp_dir.create()?;
+            let result = filter_terms(last_file, out, self.codec, |e| {
+                let df = e.df as usize;
+                if drop_cap.is_some_and(|cap| cap.drops(df)) {
+                    summary.over_cap += 1;
+                    false
+                } else if df < min_doc_count {
+                    summary.rare += 1;
+                    false
+                } else {
+                    if let Some(on_term) = on_term.as_mut() {
+                        on_term(&e.term, e.df);
+                    }
+                    true
+                }
+            });
+            check_tmp_file(&filename, result)?;
+            last_file = filename;
+        } else if let Some(on_term) = on_term.as_mut() {
+            report_terms(&last_file, on_term)?;
+        }
+        publish(last_file, &self.output_dir, self.durable)?;
+        Ok(summary)
+    }
+}
+
+/// Get the range of document ids covered by the index file `filename`, and
+/// count its terms and the hits for all of them, reading only the header and
+/// the table of contents.
+fn count_entries(filename: &Path) -> io::Result<(DocRange, u64, u64)> {
+    let mut file = File::open(filename)?;
+    let header = read_header(&mut file)?;
+    file.seek(SeekFrom::Start(header.contents_offset))?;
+    let mut contents = BufReader::new(file);
+    let (mut terms, mut postings) = (0, 0);
+    while let Some(entry) = read_entry(&mut contents, header.spellings)? {
+        terms += 1;
+        postings += entry.df as u64;
+    }
+    Ok((header.docs, terms, postings))
+}
+
+/// Call `on_term` with each term in the index file `filename`, in order, along
+/// with the number of documents it appears in, reading only the table of
+/// contents.
+fn report_terms(filename: &Path, on_term: &mut TermCallback) -> io::Result<()> {
+    let mut file = File::open(filename)?;
+    let header = read_header(&mut file)?;
+    file.seek(SeekFrom::Start(header.contents_offset))?;
+    let mut contents = BufReader::new(file);
+    while let Some(entry) = read_entry(&mut contents, header.spellings)? {
+        on_term(&entry.term, entry.df);
+    }
+    Ok(())
+}
+
+/// Move the finished index `file` to `index.dat` in `dir`, replacing any
+/// index already there in a single step.
+fn publish(file: PathBuf, dir: &Path, durable: bool) -> io::Result<()> {
+    let dest = dir.join(MERGED_FILENAME);
+    if durable {
+        File::open(&file)?.sync_all()?;
+    }
+    match fs::rename(&file, &dest) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            // `file` is on another filesystem (see `set_tmp_dir`), so it can't
+            // be renamed into place. Copy it into `dir` under a temporary name
+            // first. The copy can be renamed, so the switch is still atomic.
+            let (copy, _) = TmpDir::new(dir).create()?;
+            if let Err(err) = fs::copy(&file, &copy) {
+                let _ = fs::remove_file(&copy);
+                return Err(err);
+            }
+            if durable {
+                File::open(&copy)?.sync_all()?;
+            }
+            fs::rename(&copy, &dest)?;
+            fs::remove_file(&file)?;
         }
+        result => result?
     }
+    if durable {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Make sure the entries in `dir` are on disk, including any files just
+/// renamed into it.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
 }
 
-fn merge_streams(files: Vec<PathBuf>, out: BufWriter<File>)
+/// Elsewhere, directories can't be opened like files. Hope for the best.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// A source of entries for `merge_streams`: an index file, or an index that's
+/// still in memory.
+enum Stream {
+    File(IndexFileReader),
+    Memory(MemoryStream)
+}
+
+impl Stream {
+    fn doc_range(&self) -> DocRange {
+        match *self {
+            Stream::File(ref reader) => reader.doc_range(),
+            Stream::Memory(ref stream) => stream.docs
+        }
+    }
+
+    fn has_spellings(&self) -> bool {
+        match *self {
+            Stream::File(ref reader) => reader.has_spellings(),
+            Stream::Memory(ref stream) => !stream.spellings.is_empty()
+        }
+    }
+
+    fn has_frequencies(&self) -> bool {
+        match *self {
+            Stream::File(ref reader) => reader.has_frequencies(),
+            Stream::Memory(ref stream) => !stream.presence_only
+        }
+    }
+
+    fn case_locale(&self) -> CaseLocale {
+        match *self {
+            Stream::File(ref reader) => reader.case_locale(),
+            Stream::Memory(ref stream) => stream.case_locale
+        }
+    }
+
+    fn peek(&self) -> Option<&Entry> {
+        match *self {
+            Stream::File(ref reader) => reader.peek(),
+            Stream::Memory(ref stream) => stream.next.as_ref().map(|(entry, _)| entry)
+        }
+    }
+
+    fn is_at(&self, term: &str) -> bool {
+        self.peek().is_some_and(|e| e.term == term)
+    }
+
+    /// Having just taken the entry for `term`, make sure the next one comes
+    /// after it. The merge relies on each file's terms being sorted, and
+    /// everything in this crate writes them that way, but a damaged file, or
+    /// one written some other way, or in a different `TermOrder`, might not
+    /// be; merging it anyway would quietly produce a broken index.
+    fn check_sorted_after(&self, term: &str, order: &TermOrder) -> io::Result<()> {
+        match *self {
+            Stream::File(ref reader) => match reader.peek() {
+                Some(next) if order.compare(&next.term, term) != Ordering::Greater =>
+                    Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "{}: terms are out of order ({:?} comes after {:?})",
+                        reader.path().display(), next.term, term))),
+                _ => Ok(())
+            },
+            // `MemoryStream::new` sorts the entries itself.
+            Stream::Memory(_) => Ok(())
+        }
+    }
+
+    fn take_postings(&mut self) -> io::Result<PostingList> {
+        match *self {
+            Stream::File(ref mut reader) => reader.take_postings(),
+            Stream::Memory(ref mut stream) => Ok(stream.take_postings())
+        }
+    }
+
+    fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<()> {
+        match *self {
+            Stream::File(ref mut reader) => reader.move_entry_to(out),
+            Stream::Memory(ref mut stream) => {
+                out.write_main(&stream.take_postings().hits().concat())
+            }
+        }
+    }
+}
+
+/// Reads the entries of an `InMemoryIndex` in order, the same way
+/// `IndexFileReader` reads an index file.
+struct MemoryStream {
+    docs: DocRange,
+    case_locale: CaseLocale,
+    presence_only: bool,
+    entries: std::vec::IntoIter<(String, PostingList)>,
+
+    /// The spellings of the terms not yet read.
+    spellings: HashMap<String, Spellings, TermHasher>,
+
+    /// The next entry, with its hits, or `None` at the end.
+    next: Option<(Entry, PostingList)>
+}
+
+impl MemoryStream {
+    fn new(index: InMemoryIndex, order: &TermOrder) -> MemoryStream {
+        let mut entries: Vec<_> = index.map.into_iter().collect();
+        entries.sort_by(|(a, _), (b, _)| order.compare(a, b));
+        let mut stream = MemoryStream {
+            docs: index.docs,
+            case_locale: index.case_locale,
+            presence_only: index.presence_only,
+            entries: entries.into_iter(),
+            spellings: index.spellings,
+            next: None
+        };
+        stream.advance();
+        stream
+    }
+
+    fn advance(&mut self) {
+        let spellings = &mut self.spellings;
+        self.next = self.entries.next().map(|(term, postings)| {
+            let entry = Entry {
+                spellings: spellings.remove(&term).unwrap_or_default(),
+                term,
+                df: postings.len() as u32,
+                offset: 0,  // not stored anywhere
+                nbytes: postings.nbytes()
+            };
+            (entry, postings)
+        });
+    }
+
+    fn take_postings(&mut self) -> PostingList {
+        let (_, postings) = self.next.take().expect("no entry to move");
+        self.advance();
+        postings
+    }
+}
+
+/// Open each of `files` to be merged, deleting it (see
+/// `IndexFileReader::open_and_delete`).
+fn open_and_delete_all(files: Vec<PathBuf>) -> io::Result<Vec<IndexFileReader>> {
+    files.into_iter().map(IndexFileReader::open_and_delete).collect()
+}
+
+/// Merge `files`, and then `index` if given, into `out`.
+///
+/// The hits in the output are stored using `codec`.
+fn merge_streams(files: Vec<IndexFileReader>, index: Option<InMemoryIndex>, out: BufWriter<File>,
+                 codec: Codec, cap: Option<&PostingsCap>, order: &TermOrder,
+                 progress: &mut Progress)
     -> io::Result<()>
 {
-    let mut streams: Vec<IndexFileReader> =
-        files.into_iter()
-            .map(IndexFileReader::open_and_delete)
-            .collect::<io::Result<_>>()?;
+    let nfiles = files.len();
+    let mut streams: Vec<Stream> = files.into_iter().map(Stream::File).collect();
+
+    // The hits for each term are written in the order of the streams, so the
+    // in-memory index, which has the latest documents, goes last.
+    if let Some(index) = index {
+        streams.push(Stream::Memory(MemoryStream::new(index, order)));
+    }
+
+    // Document ids are never renumbered when merging, so the files being
+    // merged must not have any document ids in common.
+    let mut docs = DocRange::empty();
+    for (i, a) in streams.iter().enumerate() {
+        for b in &streams[..i] {
+            if a.doc_range().overlaps(&b.doc_range()) {
+                let (a, b) = (a.doc_range(), b.doc_range());
+                return Err(io::Error::other(format!(
+                    "can't merge index files with overlapping document ids \
+                     ({}..{} and {}..{})",
+                    b.start, b.end, a.start, a.end)));
+            }
+        }
+        docs = docs.union(&a.doc_range());
+    }
+
+    // Searches lowercase query terms the way the documents were, so that has
+    // to be the same for all of them. Files that cover no documents don't
+    // count.
+    let mut locales = streams.iter()
+        .filter(|s| !s.doc_range().is_empty())
+        .map(Stream::case_locale);
+    let case_locale = locales.next().unwrap_or(CaseLocale::Default);
+    if let Some(other) = locales.find(|&locale| locale != case_locale) {
+        return Err(io::Error::other(format!(
+            "can't merge index files lowercased with different locales ({:?} and {:?})",
+            case_locale, other)));
+    }
 
-    let mut output = IndexFileWriter::new(out)?;
+    let mut output = IndexFileWriter::with_codec(out, docs, codec)?;
+    if streams.iter().any(Stream::has_spellings) {
+        output.keep_spellings();
+    }
+    // Hits with and without frequencies can't be mixed in one entry, so if
+    // any of the inputs lacks them, they all do.
+    if !streams.iter().all(Stream::has_frequencies) {
+        output.drop_frequencies();
+    }
+    output.set_case_locale(case_locale);
 
-    let mut point: u64 = 0;
     let mut count = streams.iter().filter(|s| s.peek().is_some()).count();
     while count > 0 {
-        let mut term = None;
-        let mut nbytes = 0;
+        let mut term: Option<String> = None;
         let mut df = 0;
         for s in &streams {
             match s.peek() {
                 None => {}
-                Some(entry) => {
-                    if term.is_none() || entry.term < *term.as_ref().unwrap() {
+                Some(entry) => match term.as_ref().map(|t| order.compare(&entry.term, t)) {
+                    Some(Ordering::Greater) => {}
+                    Some(Ordering::Equal) => {
+                        df += entry.df;
+                    }
+                    _ => {
                         term = Some(entry.term.clone()); // XXX LAME clone
-                        nbytes = entry.nbytes;
                         df = entry.df;
-                    } else if entry.term == *term.as_ref().unwrap() {
-                        nbytes += entry.nbytes;
-                        df += entry.df;
                     }
                 }
             }
         }
         let term = term.expect("bug in algorithm!");
+        let mut spellings = vec![];
+        for s in &streams {
+            if let Some(entry) = s.peek().filter(|e| e.term == term) {
+                merge_spellings(&mut spellings, &entry.spellings);
+            }
+        }
 
+        // Without a cap, the data for this term can be copied straight
+        // through. With one, we have to look at the individual hits.
+        let start = output.offset();
+        let mut postings = PostingList::new();
         for s in &mut streams {
             if s.is_at(&term) {
-                s.move_entry_to(&mut output)?;
+                match cap {
+                    None => s.move_entry_to(&mut output)?,
+                    Some(_) => postings.merge_from(s.take_postings()?)
+                }
+                s.check_sorted_after(&term, order)?;
                 if s.peek().is_none() {
                     count -= 1;
                 }
             }
         }
-        output.write_contents_entry(term, df, point, nbytes as u64);
-        point += nbytes as u64;
+        if let Some(cap) = cap {
+            cap.trim(&mut postings);
+            df = postings.len() as u32;
+            for hit in postings.hits() {
+                output.write_main(hit)?;
+            }
+        }
+        // The inputs' own sizes for the entry won't do: an input with
+        // frequencies takes up more room than the output without them.
+        let nbytes = output.offset() - start;
+        progress.term_written(&term, df);
+        output.write_contents_entry(term, df, start, nbytes, &spellings)?;
+
+        progress.counts.terms_merged += 1;
+        if progress.counts.terms_merged.is_multiple_of(TERMS_PER_PROGRESS_REPORT) {
+            progress.report();
+        }
     }
 
     assert!(streams.iter().all(|s| s.peek().is_none()));
+    output.finish()?;
+    progress.counts.files_merged += nfiles;
+    progress.report();
+    Ok(())
+}
+
+/// Copy the index file `file` to `out`, storing the hits using `codec`, and
+/// leaving out the terms for which `keep` returns false.
+fn filter_terms<F>(file: PathBuf, out: BufWriter<File>, codec: Codec, mut keep: F)
+    -> io::Result<()>
+    where F: FnMut(&Entry) -> bool
+{
+    let mut input = IndexFileReader::open_and_delete(file)?;
+    let mut output = IndexFileWriter::with_codec(out, input.doc_range(), codec)?;
+    if input.has_spellings() {
+        output.keep_spellings();
+    }
+    if !input.has_frequencies() {
+        output.drop_frequencies();
+    }
+    output.set_case_locale(input.case_locale());
+    while let Some(entry) = input.peek() {
+        if keep(entry) {
+            let (term, df) = (entry.term.clone(), entry.df);
+            let spellings = entry.spellings.clone();
+            let start = output.offset();
+            input.move_entry_to(&mut output)?;
+            let nbytes = output.offset() - start;
+            output.write_contents_entry(term, df, start, nbytes, &spellings)?;
+        } else {
+            input.skip_entry()?;
+        }
+    }
     output.finish()
 }
 
-fn merge_reversed(filenames: &mut Vec<PathBuf>, tmp_dir: &mut TmpDir) -> io::Result<()> {
+fn merge_reversed(filenames: &mut Vec<PathBuf>, index: Option<InMemoryIndex>,
+                  tmp_dir: &mut TmpDir, cap: Option<&PostingsCap>,
+                  order: &TermOrder, progress: &mut Progress)
+    -> io::Result<()>
+{
     filenames.reverse();
     let (merged_filename, out) = tmp_dir.create()?;
     let mut to_merge = Vec::with_capacity(NSTREAMS);
     mem::swap(filenames, &mut to_merge);
-    merge_streams(to_merge, out)?;
+    let result = merge_streams(open_and_delete_all(to_merge)?, index, out, tmp_dir.codec(), cap,
+                               order, progress);
+    check_tmp_file(&merged_filename, result)?;
     filenames.push(merged_filename);
     Ok(())
 }
+
+/// Check the `result` of writing the temporary file `filename`. If writing
+/// failed, delete the partly written file, and if that's because the disk
+/// filled up, say so plainly (see `explain_write_error`).
+fn check_tmp_file(filename: &Path, result: io::Result<()>) -> io::Result<()> {
+    result.map_err(|err| {
+        let _ = fs::remove_file(filename);
+        explain_write_error(err, filename)
+    })
+}
+
+/// Merge the finished index files `inputs`, such as shards built separately
+/// with different `--docid-base` values, into a single index file, `output`.
+/// Unlike `FileMerge`, this leaves the input files in place.
+///
+/// The inputs can be given in any order. Shards whose document ids don't
+/// overlap keep them. If a file's ids overlap those of the files before it
+/// (in order by first id, and then in the order given), its documents are
+/// renumbered to follow theirs, keeping their order: two shards both built
+/// from id 0, with 10 and 5 documents, end up with ids 0..10 and 10..15. The
+/// hits in the output are stored using the same codec as the inputs, if they
+/// all use the same one, or uncompressed otherwise.
+///
+/// Like `FileMerge::finish`, this writes the output under a temporary name
+/// and then renames it, so `output` is never seen half written.
+pub fn merge_files(inputs: &[PathBuf], output: &Path) -> Result<(), Error> {
+    let mut readers = inputs.iter()
+        .map(IndexFileReader::open)
+        .collect::<Result<Vec<_>, Error>>()?;
+    // The hits for each term are written in the order of the files, so put
+    // the files in order by document id.
+    readers.sort_by_key(|reader| reader.doc_range().start);
+    let mut end = 0;
+    for reader in &mut readers {
+        let docs = reader.doc_range();
+        if docs.is_empty() {
+            continue;
+        }
+        if docs.start < end {
+            reader.shift_doc_ids(end - docs.start)?;
+        }
+        end = reader.doc_range().end;
+    }
+    let codec = match readers.first() {
+        Some(first) if readers.iter().all(|reader| reader.codec() == first.codec()) =>
+            first.codec(),
+        _ => Codec::Raw
+    };
+
+    let dir = match output.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+    let (tmp_filename, out) = TmpDir::new(dir).create()?;
+    let result = merge_streams(readers, None, out, codec, None, &TermOrder::bytes(),
+                               &mut Progress::default())
+        .and_then(|()| fs::rename(&tmp_filename, output));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_filename);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{DefaultTokenizer, LocaleTokenizer, hit_frequency};
+    use crate::search::Index;
+    use crate::test_util::{build_index, scratch_dir};
+    use std::sync::{Arc, Mutex};
+
+    /// Build two shards in `dir`, with documents numbered from 0 and 100.
+    fn build_shards(dir: &Path) -> [PathBuf; 2] {
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        [build_index(&a, 0, &["red fish", "blue fish"], &DefaultTokenizer),
+         build_index(&b, 100, &["one fish", "two fish", "red car"], &DefaultTokenizer)]
+    }
+
+    #[test]
+    fn merges_two_shards() {
+        let dir = scratch_dir("merge-shards");
+        let [a, b] = build_shards(&dir);
+        let output = dir.join("out.dat");
+        merge_files(&[a, b], &output).unwrap();
+
+        let index = Index::open(&output).unwrap();
+        assert_eq!(index.doc_range(), DocRange { start: 0, end: 103 });
+        assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 1, 100, 101]);
+        assert_eq!(index.query(&["red"]).unwrap(), vec![0, 102]);
+        assert_eq!(index.query(&["two"]).unwrap(), vec![101]);
+        assert_eq!(index.term_count().unwrap(), 6);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn last_index_in_memory_gives_the_same_file_as_a_tmp_file() {
+        let dir = scratch_dir("merge-last-index");
+        let chunks = [&["red fish", "blue fish"][..], &["one fish", "two fish", "red car"]];
+        let chunk = |i: usize| {
+            let mut index = InMemoryIndex::new();
+            for (j, text) in chunks[i].iter().enumerate() {
+                let id = i * 10 + j;
+                index.merge(InMemoryIndex::from_single_document(id, text, &DefaultTokenizer),
+                            None);
+            }
+            index
+        };
+
+        let mut outputs = vec![];
+        for in_memory in [false, true] {
+            let out = dir.join(format!("out-{}", in_memory));
+            fs::create_dir(&out).unwrap();
+            let mut tmp_dir = TmpDir::new(&out);
+            let mut merge = FileMerge::new(&out, None);
+            merge.add_file(write_index_to_tmp_file(&mut chunk(0), &mut tmp_dir,
+                                                   &TermOrder::bytes()).unwrap()).unwrap();
+            if in_memory {
+                merge.add_last_index(chunk(1));
+            } else {
+                merge.add_file(write_index_to_tmp_file(&mut chunk(1), &mut tmp_dir,
+                                                       &TermOrder::bytes()).unwrap()).unwrap();
+            }
+            merge.finish().unwrap();
+            outputs.push(fs::read(out.join(MERGED_FILENAME)).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn durable_and_plain_finish_write_the_same_complete_file() {
+        let dir = scratch_dir("merge-durable");
+        let mut outputs = vec![];
+        for durable in [true, false] {
+            let out = dir.join(format!("out-{}", durable));
+            fs::create_dir(&out).unwrap();
+            let mut tmp_dir = TmpDir::new(&out);
+            let mut merge = FileMerge::new(&out, None);
+            merge.set_durable(durable);
+            for (id, text) in ["red fish", "blue fish", "red car"].iter().enumerate() {
+                let mut index = InMemoryIndex::from_single_document(id, text, &DefaultTokenizer);
+                merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir,
+                                                       &TermOrder::bytes()).unwrap()).unwrap();
+            }
+            merge.finish().unwrap();
+
+            let index = Index::open(out.join(MERGED_FILENAME)).unwrap();
+            assert_eq!(index.query(&["red"]).unwrap(), vec![0, 2]);
+            assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 1]);
+            assert_eq!(index.term_count().unwrap(), 4);
+            let names: Vec<_> = fs::read_dir(&out).unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, [MERGED_FILENAME], "durable: {}", durable);
+            outputs.push(fs::read(out.join(MERGED_FILENAME)).unwrap());
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_tmp_files_give_the_same_index_in_less_space() {
+        let dir = scratch_dir("merge-compress-tmp");
+        // Enough repetition that there are plenty of offsets to compress.
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(50);
+        let mut outputs = vec![];
+        let mut tmp_bytes = vec![];
+        for codec in [Codec::Raw, Codec::Varint] {
+            let out = dir.join(format!("{:?}", codec));
+            fs::create_dir(&out).unwrap();
+            let mut tmp_dir = TmpDir::new(&out);
+            tmp_dir.set_codec(codec);
+            let mut merge = FileMerge::new(&out, None);
+            merge.set_tmp_codec(codec);
+            let mut bytes = 0;
+            for id in 0..10 {
+                let mut index = InMemoryIndex::from_single_document(id, &text, &DefaultTokenizer);
+                let file = write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                    .unwrap();
+                bytes += fs::metadata(&file).unwrap().len();
+                merge.add_file(file).unwrap();
+            }
+            merge.finish().unwrap();
+            outputs.push(fs::read(out.join(MERGED_FILENAME)).unwrap());
+            tmp_bytes.push(bytes);
+        }
+        assert_eq!(outputs[0], outputs[1]);
+        assert!(tmp_bytes[1] < tmp_bytes[0], "{:?}", tmp_bytes);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merging_with_a_presence_only_shard_drops_frequencies() {
+        let dir = scratch_dir("merge-presence");
+        let [a, b] = build_shards(&dir);
+        let c = dir.join("c");
+        fs::create_dir(&c).unwrap();
+        let mut index = InMemoryIndex::new();
+        for (i, text) in ["red red fish", "green fish"].iter().enumerate() {
+            index.merge(InMemoryIndex::from_single_document(200 + i, text, &DefaultTokenizer),
+                        None);
+        }
+        index.drop_frequencies();
+        let mut merge = FileMerge::new(&c, None);
+        merge.add_last_index(index);
+        merge.finish().unwrap();
+        let c = c.join(MERGED_FILENAME);
+        assert!(!IndexFileReader::open(&c).unwrap().has_frequencies());
+
+        let output = dir.join("out.dat");
+        merge_files(&[a, c, b], &output).unwrap();
+        let index = Index::open(&output).unwrap();
+        assert!(!index.has_frequencies());
+        assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 1, 100, 101, 200, 201]);
+        assert_eq!(index.query(&["red"]).unwrap(), vec![0, 102, 200]);
+        assert!(index.hits("fish").unwrap().iter().all(|hit| hit_frequency(hit) == 0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shard_order_does_not_matter() {
+        let dir = scratch_dir("merge-shard-order");
+        let [a, b] = build_shards(&dir);
+        merge_files(&[a.clone(), b.clone()], &dir.join("ab.dat")).unwrap();
+        merge_files(&[b, a], &dir.join("ba.dat")).unwrap();
+        assert_eq!(fs::read(dir.join("ab.dat")).unwrap(), fs::read(dir.join("ba.dat")).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overlapping_shards_are_renumbered() {
+        let dir = scratch_dir("merge-overlap");
+        let [a, b] = build_shards(&dir);
+        let c = dir.join("c");
+        fs::create_dir(&c).unwrap();
+        let c = build_index(&c, 0, &["one fish", "red car"], &DefaultTokenizer);
+        let output = dir.join("out.dat");
+        // `a` and `c` both start at 0, and `a` is given twice. `b`, at 100,
+        // is past all of them, so it keeps its ids.
+        merge_files(&[a.clone(), b, c, a], &output).unwrap();
+
+        let index = Index::open(&output).unwrap();
+        assert_eq!(index.doc_range(), DocRange { start: 0, end: 103 });
+        assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 1, 2, 4, 5, 100, 101]);
+        assert_eq!(index.query(&["red"]).unwrap(), vec![0, 3, 4, 102]);
+        assert_eq!(index.query(&["blue"]).unwrap(), vec![1, 5]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Every term in the index file `filename`, in order, with the number of
+    /// hits actually stored for it.
+    fn terms_in_file(filename: &Path) -> Vec<(String, u32)> {
+        let mut reader = IndexFileReader::open(filename).unwrap();
+        let mut terms = vec![];
+        while let Some(entry) = reader.peek() {
+            let term = entry.term.clone();
+            terms.push((term, reader.take_postings().unwrap().len() as u32));
+        }
+        terms
+    }
+
+    #[test]
+    fn term_callback_sees_the_terms_in_the_finished_file() {
+        let docs = ["red fish", "blue fish", "one fish typo", "red xyzzy"];
+        // Several files merged; one file renamed into place; and terms
+        // filtered out on the way.
+        for (nfiles, min_doc_count) in [(4, 0), (1, 0), (4, 2)] {
+            let dir = scratch_dir("merge-term-callback");
+            let mut merge = FileMerge::new(&dir, None);
+            merge.set_min_doc_count(min_doc_count);
+            let seen = Arc::new(Mutex::new(vec![]));
+            let sink = seen.clone();
+            merge.set_term_callback(Box::new(move |term, df| {
+                sink.lock().unwrap().push((term.to_string(), df));
+            }));
+            let mut tmp_dir = TmpDir::new(&dir);
+            let per_file = docs.len() / nfiles;
+            for (i, chunk) in docs.chunks(per_file).enumerate() {
+                let mut index = InMemoryIndex::new();
+                for (j, text) in chunk.iter().enumerate() {
+                    let id = i * per_file + j;
+                    index.merge(InMemoryIndex::from_single_document(id, text, &DefaultTokenizer),
+                                None);
+                }
+                merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir,
+                                                       &TermOrder::bytes()).unwrap()).unwrap();
+            }
+            merge.finish().unwrap();
+
+            let seen = seen.lock().unwrap().clone();
+            assert!(!seen.is_empty());
+            assert_eq!(seen, terms_in_file(&dir.join(MERGED_FILENAME)),
+                       "{} file(s), min doc count {}", nfiles, min_doc_count);
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn single_file_is_renamed_into_place() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch_dir("merge-single-rename");
+        let mut merge = FileMerge::new(&dir, None);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        merge.set_term_callback(Box::new(move |term, df| {
+            sink.lock().unwrap().push((term.to_string(), df));
+        }));
+        let mut index = InMemoryIndex::new();
+        for (id, text) in ["red fish", "blue fish"].iter().enumerate() {
+            index.merge(InMemoryIndex::from_single_document(id, text, &DefaultTokenizer), None);
+        }
+        let file = write_index_to_tmp_file(&mut index, &mut TmpDir::new(&dir),
+                                           &TermOrder::bytes()).unwrap();
+        let before = fs::metadata(&file).unwrap();
+        merge.add_file(file.clone()).unwrap();
+        merge.finish().unwrap();
+
+        // The same file, not a copy of it: the terms were reported without
+        // writing the index out again.
+        assert!(!file.exists());
+        let after = fs::metadata(dir.join(MERGED_FILENAME)).unwrap();
+        assert_eq!((after.dev(), after.ino()), (before.dev(), before.ino()));
+        assert_eq!(*seen.lock().unwrap(), terms_in_file(&dir.join(MERGED_FILENAME)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn added_files_lists_exactly_the_files_added() {
+        let dir = scratch_dir("merge-added-files");
+        let mut merge = FileMerge::new(&dir, None);
+        let mut tmp_dir = TmpDir::new(&dir);
+        // More files than get merged at once, so some are merged early.
+        let docs: Vec<String> = (0..NSTREAMS + 3).map(|i| format!("fish w{} x{}", i, i)).collect();
+        let mut added = vec![];
+        for (id, text) in docs.iter().enumerate() {
+            let mut index = InMemoryIndex::from_single_document(id, text, &DefaultTokenizer);
+            let file = write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                .unwrap();
+            added.push(file.clone());
+            merge.add_file(file).unwrap();
+        }
+        merge.add_last_index(InMemoryIndex::from_single_document(docs.len(), "last",
+                                                                 &DefaultTokenizer));
+
+        let listed = merge.added_files().to_vec();
+        let paths: Vec<PathBuf> = listed.iter().map(|file| file.path.clone()).collect();
+        assert_eq!(paths, added);
+        assert!(listed.iter().all(|file| file.terms == 3 && file.postings == 3), "{:?}", listed);
+        merge.finish().unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn min_doc_count_prunes_rare_terms() {
+        let dir = scratch_dir("merge-min-doc-count");
+        let mut merge = FileMerge::new(&dir, None);
+        merge.set_min_doc_count(2);
+        let mut tmp_dir = TmpDir::new(&dir);
+        // "fish" is common, "red" is in two documents, but in different
+        // files, and the rest are in one document each.
+        let docs = ["red fish", "blue fish", "one fish typo", "red xyzzy"];
+        for (id, text) in docs[..3].iter().enumerate() {
+            let mut index = InMemoryIndex::from_single_document(id, text, &DefaultTokenizer);
+            merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                           .unwrap()).unwrap();
+        }
+        merge.add_last_index(InMemoryIndex::from_single_document(3, docs[3], &DefaultTokenizer));
+        let summary = merge.finish().unwrap();
+        assert_eq!(summary, MergeSummary { over_cap: 0, rare: 4 });
+
+        let index = Index::open(dir.join(MERGED_FILENAME)).unwrap();
+        assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 1, 2]);
+        assert_eq!(index.query(&["red"]).unwrap(), vec![0, 3]);
+        for rare in ["blue", "one", "typo", "xyzzy"] {
+            assert_eq!(index.doc_freq(rare).unwrap(), 0, "{}", rare);
+        }
+        assert_eq!(index.term_count().unwrap(), 2);
+        assert_eq!(index.doc_range(), DocRange { start: 0, end: 4 });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drop_term_cap_applies_to_all_files_together() {
+        let dir = scratch_dir("merge-drop-term");
+        let cap = PostingsCap { max: 2, policy: CapPolicy::DropTerm };
+        let mut merge = FileMerge::new(&dir, Some(cap));
+        let mut tmp_dir = TmpDir::new(&dir);
+        // "common" is in one document of each file: under the cap in every
+        // file, but over it once they're merged. "pair" ends up just at it.
+        let files: [&[&str]; 3] = [&["common pair", "one"], &["common pair"], &["common two"]];
+        let mut next_id = 0;
+        for docs in files {
+            let mut index = InMemoryIndex::new();
+            for text in docs {
+                index.merge(InMemoryIndex::from_single_document(next_id, text, &DefaultTokenizer),
+                            Some(&cap));
+                next_id += 1;
+            }
+            merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                           .unwrap()).unwrap();
+        }
+        let summary = merge.finish().unwrap();
+        assert_eq!(summary, MergeSummary { over_cap: 1, rare: 0 });
+
+        let index = Index::open(dir.join(MERGED_FILENAME)).unwrap();
+        assert_eq!(index.doc_freq("common").unwrap(), 0);
+        assert_eq!(index.query(&["pair"]).unwrap(), vec![0, 2]);
+        assert_eq!(index.query(&["two"]).unwrap(), vec![3]);
+        assert_eq!(index.term_count().unwrap(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn progress_counts_only_go_up() {
+        use std::sync::{Arc, Mutex};
+
+        let dir = scratch_dir("merge-progress");
+        let reports = Arc::new(Mutex::new(vec![]));
+        let mut merge = FileMerge::new(&dir, None);
+        let sink = reports.clone();
+        merge.set_progress(Box::new(move |counts| sink.lock().unwrap().push(counts)));
+        // Enough files for some to be merged before `finish`.
+        let mut tmp_dir = TmpDir::new(&dir);
+        let nfiles = NSTREAMS * 2 + 3;
+        for i in 0..nfiles {
+            let text = format!("common word{}", i);
+            let mut index = InMemoryIndex::from_single_document(i, &text, &DefaultTokenizer);
+            merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                           .unwrap()).unwrap();
+        }
+        merge.finish().unwrap();
+
+        let reports = reports.lock().unwrap();
+        for pair in reports.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            assert!(a.files_added <= b.files_added && a.files_merged <= b.files_merged
+                    && a.terms_merged <= b.terms_merged, "{:?} then {:?}", a, b);
+        }
+        let last = reports.last().unwrap();
+        assert_eq!(last.files_added, nfiles);
+        assert!(last.files_merged >= nfiles, "{:?}", last);
+        assert!(last.terms_merged >= 2 * nfiles as u64, "{:?}", last);
+        // The callback saw files being added and merges finishing in between.
+        assert!(reports.iter().any(|r| r.files_merged > 0 && r.files_added < nfiles),
+                "{:?}", reports);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn custom_term_order_is_kept_in_the_merged_file() {
+        let dir = scratch_dir("merge-term-order");
+        let order = TermOrder::new(|a: &str, b: &str| {
+            a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b))
+        });
+        let case_sensitive = LocaleTokenizer::new(Box::new(DefaultTokenizer),
+                                                  CaseLocale::Unchanged);
+        let mut merge = FileMerge::new(&dir, None);
+        merge.set_term_order(order.clone());
+        let mut tmp_dir = TmpDir::new(&dir);
+        for (i, text) in ["banana Cherry", "Apple apple"].iter().enumerate() {
+            let mut index = InMemoryIndex::from_single_document(i, text, &case_sensitive);
+            merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir, &order).unwrap())
+                .unwrap();
+        }
+        merge.add_last_index(InMemoryIndex::from_single_document(2, "Banana date",
+                                                                 &case_sensitive));
+        merge.finish().unwrap();
+
+        let mut reader = IndexFileReader::open(dir.join(MERGED_FILENAME)).unwrap();
+        let mut terms = vec![];
+        while let Some(entry) = reader.peek() {
+            terms.push(entry.term.clone());
+            reader.skip_entry().unwrap();
+        }
+        // In byte order, every capitalized term would come first.
+        assert_eq!(terms, ["Apple", "apple", "Banana", "banana", "Cherry", "date"]);
+        let index = Index::open(dir.join(MERGED_FILENAME)).unwrap();
+        assert_eq!(index.query(&["Banana"]).unwrap(), vec![2]);
+        assert_eq!(index.query(&["banana"]).unwrap(), vec![0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_tmp_file_is_deleted_and_explained() {
+        let dir = scratch_dir("merge-tmp-full");
+        let filename = dir.join("tmp00000001.dat");
+        fs::write(&filename, "partial").unwrap();
+        let full = io::Error::new(io::ErrorKind::StorageFull, "No space left on device");
+        let err = check_tmp_file(&filename, Err(full)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(err.to_string().contains("--temp-dir"), "{}", err);
+        assert!(!filename.exists());
+        check_tmp_file(&filename, Ok(())).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_finish_leaves_the_previous_index_alone() {
+        let dir = scratch_dir("merge-interrupted");
+        build_index(&dir, 0, &["the old index"], &DefaultTokenizer);
+        let before = fs::read(dir.join(MERGED_FILENAME)).unwrap();
+
+        let mut tmp_dir = TmpDir::new(&dir);
+        let mut merge = FileMerge::new(&dir, None);
+        for (id, text) in ["a new index", "with more words"].iter().enumerate() {
+            let mut index = InMemoryIndex::from_single_document(id, text, &DefaultTokenizer);
+            merge.add_file(write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                           .unwrap()).unwrap();
+        }
+        // Stop partway through writing the finished index: after some of it
+        // has been written, but before it's renamed into place.
+        merge.set_term_callback(Box::new(|term, _| {
+            if term == "new" {
+                panic!("interrupted");
+            }
+        }));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| merge.finish()));
+        assert!(result.is_err());
+
+        assert_eq!(fs::read(dir.join(MERGED_FILENAME)).unwrap(), before);
+        let index = Index::open(dir.join(MERGED_FILENAME)).unwrap();
+        assert_eq!(index.query(&["old"]).unwrap(), vec![0]);
+        assert!(index.query(&["new"]).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsorted_input_fails_naming_the_file() {
+        let dir = scratch_dir("merge-unsorted");
+        let sorted = build_index(&dir, 0, &["red fish", "blue fish"], &DefaultTokenizer);
+        // Written in reverse order, as if by a tool with a different idea of
+        // how terms sort.
+        let reverse = TermOrder::new(|a: &str, b: &str| b.cmp(a));
+        let mut index = InMemoryIndex::from_single_document(100, "apple banana cherry",
+                                                            &DefaultTokenizer);
+        let unsorted = write_index_to_tmp_file(&mut index, &mut TmpDir::new(&dir), &reverse)
+            .unwrap();
+        match merge_files(&[sorted, unsorted.clone()], &dir.join("out.dat")) {
+            Err(err @ Error::Corrupt(_)) => {
+                let message = err.to_string();
+                assert!(message.contains(&unsorted.display().to_string()), "{}", message);
+                assert!(message.contains("out of order"), "{}", message);
+            }
+            other => panic!("{:?}", other.err())
+        }
+        assert!(!dir.join("out.dat").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn merging_different_locales_fails() {
+        let dir = scratch_dir("merge-locales");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        let turkic = LocaleTokenizer::new(Box::new(DefaultTokenizer), CaseLocale::Turkic);
+        let inputs = [build_index(&a, 0, &["one"], &DefaultTokenizer),
+                      build_index(&b, 1, &["two"], &turkic)];
+        let err = merge_files(&inputs, &dir.join("out.dat")).err().unwrap();
+        assert!(err.to_string().contains("different locales"), "{}", err);
+        assert!(!dir.join("out.dat").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
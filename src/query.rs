@@ -0,0 +1,655 @@
+//! Query strings, and running them against a search `Index`.
+//!
+//! A query is a list of clauses, all of which a document has to match:
+//!
+//! *   `word` matches documents containing the term `word`, lowercased the way
+//!     the index was (see `Index::hits`).
+//!
+//! *   `"quick fox"` matches documents where the words appear one right after
+//!     the other, in that order. This uses the word offsets stored in the
+//!     index.
+//!
+//! *   `run*` matches documents containing any term that starts with `run`.
+//!
+//! *   `-slow` matches documents that *don't* match `slow`. A `-` is only an
+//!     exclusion at the start of a clause, followed by a letter, a digit, a
+//!     quote, or a parenthesis; so `->` and `x-y` are just words. `NOT slow`
+//!     means the same.
+//!
+//! *   `rust OR go` matches documents that match either side. `rust AND go`
+//!     is the same as `rust go`.
+//!
+//! *   Parentheses group clauses: `(rust OR go) -slow`.
+//!
+//! `AND`, `OR`, and `NOT` are only operators when written in capitals, so
+//! `cats and dogs` is three words. `NOT` and `-` bind tightest, then `AND`
+//! (written or not), then `OR`: `a b OR c` means `(a b) OR c`.
+//!
+//! Index files don't divide documents into fields, so `title:fox` is an
+//! error rather than a search for the term `title:fox`. (Terms with `::` in
+//! them, like `std::io`, are fine.) A quoted phrase can be used to search for
+//! anything the syntax would otherwise get in the way of.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::index::{hit_document_id, hit_frequency, HIT_HEADER_SIZE};
+use crate::search::Index;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// A parsed query string; see the module documentation for the syntax.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// Documents containing the term.
+    Term(String),
+
+    /// Documents containing all the words, one right after the other.
+    Phrase(Vec<String>),
+
+    /// Documents containing a term that starts with this.
+    Prefix(String),
+
+    /// Documents that match every one of the queries. With none, that's no
+    /// documents at all, since an empty query shouldn't find everything.
+    And(Vec<Query>),
+
+    /// Documents that match any of the queries.
+    Or(Vec<Query>),
+
+    /// Documents that don't match the query.
+    Not(Box<Query>)
+}
+
+/// A query string that couldn't be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Where in the query string the problem is, in bytes from the start.
+    pub position: usize,
+
+    /// What the problem is.
+    pub message: String,
+
+    /// The character the problem is at, counting from 1, for showing to a
+    /// user.
+    column: usize
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (at character {} of the query)", self.message, self.column)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parse `query`; see the module documentation for the syntax.
+pub fn parse_query(query: &str) -> Result<Query, ParseError> {
+    let mut parser = Parser { query, pos: 0, depth: 0 };
+    if !parser.skip_whitespace() {
+        return Ok(Query::And(vec![]));
+    }
+    parser.alternatives()
+}
+
+/// A recursive-descent parser for query strings.
+struct Parser<'q> {
+    query: &'q str,
+
+    /// How far the parser has got, in bytes.
+    pos: usize,
+
+    /// How many parentheses are open.
+    depth: usize
+}
+
+impl<'q> Parser<'q> {
+    fn rest(&self) -> &'q str {
+        &self.query[self.pos..]
+    }
+
+    fn error(&self, position: usize, message: String) -> ParseError {
+        let column = self.query[..position].chars().count() + 1;
+        ParseError { position, message, column }
+    }
+
+    /// Skip to the next clause. Returns false if there isn't one.
+    fn skip_whitespace(&mut self) -> bool {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+        self.pos < self.query.len()
+    }
+
+    /// True if the text at the current position is the operator `keyword`.
+    fn at_keyword(&self, keyword: &str) -> bool {
+        self.rest().strip_prefix(keyword).is_some_and(|after| {
+            after.chars().next().is_none_or(|ch| ch.is_whitespace() || "()\"".contains(ch))
+        })
+    }
+
+    /// True if the current position ends a group of clauses: the end of the
+    /// query, or the `)` closing an open parenthesis.
+    fn at_end_of_group(&mut self) -> bool {
+        !self.skip_whitespace() || (self.depth > 0 && self.rest().starts_with(')'))
+    }
+
+    /// Skip the operator `keyword`, at `at`, checking that a clause follows.
+    fn operator(&mut self, keyword: &str, at: usize) -> Result<(), ParseError> {
+        self.pos += keyword.len();
+        if self.at_end_of_group() || self.at_keyword("AND") || self.at_keyword("OR") {
+            return Err(self.error(at, format!("{} needs a search term after it", keyword)));
+        }
+        Ok(())
+    }
+
+    /// alternatives = clauses ("OR" clauses)*
+    fn alternatives(&mut self) -> Result<Query, ParseError> {
+        let mut alternatives = vec![];
+        loop {
+            // `(a OR b) OR c` is the same as `a OR b OR c`.
+            match self.clauses()? {
+                Query::Or(queries) => alternatives.extend(queries),
+                query => alternatives.push(query)
+            }
+            if self.at_end_of_group() || !self.at_keyword("OR") {
+                break;
+            }
+            self.operator("OR", self.pos)?;
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Query::Or(alternatives)
+        })
+    }
+
+    /// clauses = clause (["AND"] clause)*
+    fn clauses(&mut self) -> Result<Query, ParseError> {
+        let mut clauses = vec![];
+        while !self.at_end_of_group() && !self.at_keyword("OR") {
+            if self.at_keyword("AND") {
+                let at = self.pos;
+                if clauses.is_empty() {
+                    return Err(self.error(at, "AND needs a search term before it".to_string()));
+                }
+                self.operator("AND", at)?;
+            }
+            match self.clause()? {
+                Query::And(queries) => clauses.extend(queries),
+                query => clauses.push(query)
+            }
+        }
+        if clauses.is_empty() {
+            let message = if self.at_keyword("OR") {
+                "OR needs a search term before it"
+            } else {
+                "expected a search term"
+            };
+            return Err(self.error(self.pos, message.to_string()));
+        }
+        Ok(if clauses.len() == 1 { clauses.pop().unwrap() } else { Query::And(clauses) })
+    }
+
+    /// clause = ("NOT" | "-") clause | atom
+    fn clause(&mut self) -> Result<Query, ParseError> {
+        if self.at_keyword("NOT") {
+            self.operator("NOT", self.pos)?;
+            return Ok(Query::Not(Box::new(self.clause()?)));
+        }
+        let mut chars = self.rest().chars();
+        if chars.next() == Some('-')
+            && chars.next().is_some_and(|ch| ch.is_alphanumeric() || ch == '"' || ch == '(')
+        {
+            self.pos += 1;
+            return Ok(Query::Not(Box::new(self.atom()?)));
+        }
+        self.atom()
+    }
+
+    /// atom = "(" alternatives ")" | phrase | word
+    fn atom(&mut self) -> Result<Query, ParseError> {
+        let rest = self.rest();
+        if rest.starts_with('(') {
+            let open = self.pos;
+            self.pos += 1;
+            self.depth += 1;
+            if !self.skip_whitespace() {
+                return Err(self.error(open, "parenthesis is never closed".to_string()));
+            }
+            if self.rest().starts_with(')') {
+                return Err(self.error(open, "nothing between parentheses".to_string()));
+            }
+            let query = self.alternatives()?;
+            if !self.skip_whitespace() {
+                return Err(self.error(open, "parenthesis is never closed".to_string()));
+            }
+            self.pos += 1;
+            self.depth -= 1;
+            Ok(query)
+        } else if rest.starts_with(')') {
+            Err(self.error(self.pos, "closing parenthesis without an opening one".to_string()))
+        } else if rest.starts_with('"') {
+            self.phrase()
+        } else {
+            self.word()
+        }
+    }
+
+    /// phrase = '"' words '"'
+    fn phrase(&mut self) -> Result<Query, ParseError> {
+        let start = self.pos;
+        let inside = &self.rest()[1..];
+        let len = match inside.find('"') {
+            Some(len) => len,
+            None => return Err(self.error(start, "phrase has no closing quote".to_string()))
+        };
+        self.pos += len + 2;
+        let mut words: Vec<String> = inside[..len].split_whitespace().map(str::to_string).collect();
+        match words.len() {
+            0 => Err(self.error(start, "empty phrase".to_string())),
+            1 => Ok(Query::Term(words.pop().unwrap())),
+            _ => Ok(Query::Phrase(words))
+        }
+    }
+
+    /// word = term ["*"]
+    fn word(&mut self) -> Result<Query, ParseError> {
+        let start = self.pos;
+        let rest = self.rest();
+        // Inside parentheses, a `)` ends the word, unless it closes a `(` in
+        // the word itself, as in `f(x)`.
+        let mut open = 0;
+        let len = rest.find(|ch: char| match ch {
+            '(' => {
+                open += 1;
+                false
+            }
+            ')' if open > 0 => {
+                open -= 1;
+                false
+            }
+            ')' => self.depth > 0,
+            ch => ch.is_whitespace() || ch == '"'
+        }).unwrap_or(rest.len());
+        let word = &rest[..len];
+        self.pos += len;
+
+        if let Some(colon) = word.find(':') {
+            let (field, after) = (&word[..colon], &word[colon + 1..]);
+            if !field.is_empty() && field.chars().all(char::is_alphanumeric)
+                && !after.is_empty() && !after.starts_with(':')
+            {
+                return Err(self.error(start, format!(
+                    "can't search the field {:?}: the index doesn't record fields (to look \
+                     for {:?} as a word, put it in quotes)", field, word)));
+            }
+        }
+        match word.strip_suffix('*') {
+            Some(prefix) if prefix.ends_with(char::is_alphanumeric) =>
+                Ok(Query::Prefix(prefix.to_string())),
+            _ => Ok(Query::Term(word.to_string()))
+        }
+    }
+}
+
+impl Query {
+    /// The ids of the documents in `index` that match this query, in
+    /// increasing order.
+    ///
+    /// With a `min_freq` above 1, a document only counts as containing a term
+    /// if the term appears in it at least that many times (see
+    /// `Index::query_min_freq`); for a prefix, one of the terms it matches
+    /// has to; and for a phrase, the whole phrase has to. Exclusions ignore
+    /// it: `-slow` leaves out every document that contains `slow` at all.
+    pub fn doc_ids(&self, index: &Index, min_freq: u32) -> io::Result<Vec<u32>> {
+        match self {
+            Query::Term(term) => index.doc_ids(term, min_freq),
+            Query::Phrase(words) => phrase_doc_ids(index, words, min_freq),
+            Query::Prefix(prefix) => {
+                let mut ids = vec![];
+                for term in index.terms_with_prefix(prefix)? {
+                    ids = union(&ids, &index.doc_ids(&term, min_freq)?);
+                }
+                Ok(ids)
+            }
+            Query::And(queries) => {
+                let (negative, positive): (Vec<&Query>, Vec<&Query>) =
+                    queries.iter().partition(|query| matches!(query, Query::Not(_)));
+                if positive.is_empty() && negative.is_empty() {
+                    return Ok(vec![]);
+                }
+                let mut ids = match positive.split_first() {
+                    Some((first, _)) => first.doc_ids(index, min_freq)?,
+                    None => all_doc_ids(index)
+                };
+                for query in positive.iter().skip(1) {
+                    if ids.is_empty() {
+                        break;
+                    }
+                    let other = query.doc_ids(index, min_freq)?;
+                    ids.retain(|id| other.binary_search(id).is_ok());
+                }
+                for query in negative {
+                    if let Query::Not(query) = query {
+                        let other = query.doc_ids(index, 0)?;
+                        ids.retain(|id| other.binary_search(id).is_err());
+                    }
+                }
+                Ok(ids)
+            }
+            Query::Or(queries) => {
+                let mut ids = vec![];
+                for query in queries {
+                    ids = union(&ids, &query.doc_ids(index, min_freq)?);
+                }
+                Ok(ids)
+            }
+            Query::Not(query) => {
+                let other = query.doc_ids(index, 0)?;
+                let mut ids = all_doc_ids(index);
+                ids.retain(|id| other.binary_search(id).is_err());
+                Ok(ids)
+            }
+        }
+    }
+
+    /// The number of documents in `index` that match this query; that is,
+    /// the length of `doc_ids(index, min_freq)`. A plain list of words is
+    /// counted by `Index::count_min_freq`, which can often skip reading hits.
+    pub fn count(&self, index: &Index, min_freq: u32) -> io::Result<usize> {
+        match self.plain_terms() {
+            Some(terms) => index.count_min_freq(&terms, min_freq),
+            None => Ok(self.doc_ids(index, min_freq)?.len())
+        }
+    }
+
+    /// If this query is just a list of words, all of which a document has to
+    /// contain, the words.
+    fn plain_terms(&self) -> Option<Vec<&str>> {
+        match self {
+            Query::Term(term) => Some(vec![term]),
+            Query::And(queries) if !queries.is_empty() => queries.iter()
+                .map(|query| match query {
+                    Query::Term(term) => Some(term.as_str()),
+                    _ => None
+                })
+                .collect(),
+            _ => None
+        }
+    }
+
+    /// The terms that count towards a document's score: every term the query
+    /// looks for, in the order they appear, except those it excludes. Prefixes
+    /// stand for every term in `index` they match.
+    pub fn scoring_terms(&self, index: &Index) -> io::Result<Vec<String>> {
+        let mut terms = vec![];
+        self.add_scoring_terms(index, &mut terms)?;
+        Ok(terms)
+    }
+
+    fn add_scoring_terms(&self, index: &Index, terms: &mut Vec<String>) -> io::Result<()> {
+        match self {
+            Query::Term(term) => terms.push(term.clone()),
+            Query::Phrase(words) => terms.extend(words.iter().cloned()),
+            Query::Prefix(prefix) => terms.extend(index.terms_with_prefix(prefix)?),
+            Query::And(queries) | Query::Or(queries) => {
+                for query in queries {
+                    query.add_scoring_terms(index, terms)?;
+                }
+            }
+            Query::Not(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Every document id in `index`.
+fn all_doc_ids(index: &Index) -> Vec<u32> {
+    let docs = index.doc_range();
+    (docs.start..docs.end).collect()
+}
+
+/// The ids in either of two sorted lists, in order.
+fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut all = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] <= b[j] {
+            if a[i] == b[j] {
+                j += 1;
+            }
+            all.push(a[i]);
+            i += 1;
+        } else {
+            all.push(b[j]);
+            j += 1;
+        }
+    }
+    all.extend_from_slice(&a[i..]);
+    all.extend_from_slice(&b[j..]);
+    all
+}
+
+/// The ids of the documents in `index` where `words` appear one after the
+/// other, at least `min_freq` times.
+fn phrase_doc_ids(index: &Index, words: &[String], min_freq: u32) -> io::Result<Vec<u32>> {
+    let mut hits = Vec::with_capacity(words.len());
+    for word in words {
+        let word_hits = index.hits(word)?;
+        if word_hits.iter().any(|hit| hit_frequency(hit) == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "the index was built without word positions, so it \
+                                       can't be searched for phrases"));
+        }
+        hits.push(word_hits);
+    }
+
+    let mut ids = vec![];
+    'documents: for first in &hits[0] {
+        let doc_id = hit_document_id(first);
+        let mut rest = Vec::with_capacity(hits.len() - 1);
+        for word_hits in &hits[1..] {
+            match word_hits.binary_search_by_key(&doc_id, |hit| hit_document_id(hit)) {
+                Ok(i) => rest.push(offsets(&word_hits[i])),
+                Err(_) => continue 'documents
+            }
+        }
+        // Offsets are stored in increasing order.
+        let occurrences = offsets(first)
+            .filter(|&start| {
+                rest.iter().enumerate().all(|(i, following)| {
+                    let want = start + i as u32 + 1;
+                    following.clone().any(|offset| offset == want)
+                })
+            })
+            .count();
+        if occurrences > 0 && occurrences as u32 >= min_freq {
+            ids.push(doc_id);
+        }
+    }
+    Ok(ids)
+}
+
+/// The offsets of the words recorded in `hit`.
+fn offsets(hit: &[u8]) -> impl Iterator<Item = u32> + Clone + '_ {
+    hit[HIT_HEADER_SIZE..].chunks_exact(4).map(LittleEndian::read_u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::index::{DefaultTokenizer, InMemoryIndex};
+    use crate::test_util::{build_index, scratch_dir};
+
+    fn term(word: &str) -> Query {
+        Query::Term(word.to_string())
+    }
+
+    fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+
+    #[test]
+    fn parses_each_kind_of_clause() {
+        assert_eq!(parse_query("rust").unwrap(), term("rust"));
+        assert_eq!(parse_query("\"quick  fox\"").unwrap(),
+                   Query::Phrase(vec!["quick".to_string(), "fox".to_string()]));
+        assert_eq!(parse_query("\"fox\"").unwrap(), term("fox"));
+        assert_eq!(parse_query("run*").unwrap(), Query::Prefix("run".to_string()));
+        assert_eq!(parse_query("-slow").unwrap(), not(term("slow")));
+        assert_eq!(parse_query("-\"slow boat\"").unwrap(),
+                   not(Query::Phrase(vec!["slow".to_string(), "boat".to_string()])));
+        assert_eq!(parse_query("").unwrap(), Query::And(vec![]));
+        assert_eq!(parse_query("  ").unwrap(), Query::And(vec![]));
+    }
+
+    #[test]
+    fn punctuation_that_isnt_syntax_is_part_of_the_word() {
+        for word in ["->", "x-y", "--", "*", "**", "a*b", "std::io", "::", "\"\u{e9}t\u{e9}"] {
+            let expected = if word.starts_with('"') { None } else { Some(term(word)) };
+            assert_eq!(parse_query(word).ok(), expected, "{:?}", word);
+        }
+    }
+
+    #[test]
+    fn parses_a_combination() {
+        assert_eq!(parse_query(" \"quick fox\" -slow run*  jumps ").unwrap(), Query::And(vec![
+            Query::Phrase(vec!["quick".to_string(), "fox".to_string()]),
+            not(term("slow")),
+            Query::Prefix("run".to_string()),
+            term("jumps")
+        ]));
+    }
+
+    #[test]
+    fn parses_operators_and_parentheses() {
+        let or = |queries: Vec<Query>| Query::Or(queries);
+        let and = |queries: Vec<Query>| Query::And(queries);
+        assert_eq!(parse_query("rust OR go").unwrap(), or(vec![term("rust"), term("go")]));
+        assert_eq!(parse_query("rust AND go").unwrap(), parse_query("rust go").unwrap());
+        assert_eq!(parse_query("NOT slow").unwrap(), parse_query("-slow").unwrap());
+        assert_eq!(parse_query("a b OR c d").unwrap(),
+                   or(vec![and(vec![term("a"), term("b")]), and(vec![term("c"), term("d")])]));
+        assert_eq!(parse_query("a (b OR c)").unwrap(),
+                   and(vec![term("a"), or(vec![term("b"), term("c")])]));
+        assert_eq!(parse_query("(rust OR go) -(slow OR old)").unwrap(),
+                   and(vec![or(vec![term("rust"), term("go")]),
+                            not(or(vec![term("slow"), term("old")]))]));
+        assert_eq!(parse_query("NOT NOT a OR b").unwrap(),
+                   or(vec![not(not(term("a"))), term("b")]));
+        assert_eq!(parse_query("((a))").unwrap(), term("a"));
+        assert_eq!(parse_query("(a)b").unwrap(), and(vec![term("a"), term("b")]));
+
+        // Lowercase, or stuck to other text, they're just words.
+        assert_eq!(parse_query("cats and dogs or not").unwrap(),
+                   and(vec![term("cats"), term("and"), term("dogs"), term("or"), term("not")]));
+        assert_eq!(parse_query("ORACLE ANDROID").unwrap(),
+                   and(vec![term("ORACLE"), term("ANDROID")]));
+        // Outside parentheses, a `)` inside a word is part of it.
+        assert_eq!(parse_query("main()").unwrap(), term("main()"));
+        assert_eq!(parse_query("(f(x) OR y)").unwrap(), or(vec![term("f(x)"), term("y")]));
+        assert_eq!(parse_query("(a b)c)").unwrap(), and(vec![term("a"), term("b"), term("c)")]));
+    }
+
+    #[test]
+    fn operator_errors_say_where_the_problem_is() {
+        let position = |query| parse_query(query).unwrap_err().position;
+        assert_eq!(position("a OR"), 2);
+        assert_eq!(position("OR a"), 0);
+        assert_eq!(position("a AND OR b"), 2);
+        assert_eq!(position("AND a"), 0);
+        assert_eq!(position("a NOT"), 2);
+        assert_eq!(position("a (b OR c"), 2);
+        assert_eq!(position("a ( "), 2);
+        assert_eq!(position("a () b"), 2);
+        assert_eq!(position("a ) c"), 2);
+        assert_eq!(position("(a OR (b title:c))"), 9);
+
+        let err = parse_query("x (y OR").unwrap_err();
+        assert!(err.to_string().contains("OR needs a search term after it"), "{}", err);
+        assert!(err.to_string().contains("character 6"), "{}", err);
+        let err = parse_query("\u{e9} (y").unwrap_err();
+        assert!(err.to_string().contains("never closed (at character 3"), "{}", err);
+    }
+
+    #[test]
+    fn errors_say_where_the_problem_is() {
+        let err = parse_query("fast \"slow fox").unwrap_err();
+        assert_eq!(err.position, 5);
+        assert!(err.to_string().contains("no closing quote"), "{}", err);
+        assert!(err.to_string().contains("character 6"), "{}", err);
+
+        let err = parse_query("\u{e9}t\u{e9} title:fox").unwrap_err();
+        assert_eq!(err.position, 6);
+        assert!(err.to_string().contains("character 5"), "{}", err);
+        assert!(err.to_string().contains("field \"title\""), "{}", err);
+
+        assert_eq!(parse_query("a \"  \" b").unwrap_err().position, 2);
+    }
+
+    #[test]
+    fn queries_find_the_right_documents() {
+        let dir = scratch_dir("query-run");
+        let docs = ["the quick brown fox", "a quick fox runs", "the slow fox", "running late",
+                    "fox quick"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let find = |query: &str| {
+            let query = parse_query(query).unwrap();
+            let ids = query.doc_ids(&index, 0).unwrap();
+            assert_eq!(query.count(&index, 0).unwrap(), ids.len());
+            ids
+        };
+        assert_eq!(find("fox"), vec![0, 1, 2, 4]);
+        assert_eq!(find("Quick FOX"), vec![0, 1, 4]);
+        assert_eq!(find("\"quick fox\""), vec![1]);
+        assert_eq!(find("\"quick brown fox\""), vec![0]);
+        assert_eq!(find("\"fox quick\""), vec![4]);
+        assert_eq!(find("run*"), vec![1, 3]);
+        assert_eq!(find("fox -quick"), vec![2]);
+        assert_eq!(find("-fox"), vec![3]);
+        assert_eq!(find("-fox -late"), Vec::<u32>::new());
+        assert_eq!(find("slow OR late"), vec![2, 3]);
+        assert_eq!(find("(brown OR slow) fox"), vec![0, 2]);
+        assert_eq!(find("fox NOT (quick OR slow)"), Vec::<u32>::new());
+        assert_eq!(find("\"quick fox\" OR runn*"), vec![1, 3]);
+        assert_eq!(find("NOT fox OR brown"), vec![0, 3]);
+        assert_eq!(find("\"quick fox\" -slow run*"), vec![1]);
+        assert_eq!(find("missing*"), Vec::<u32>::new());
+        assert_eq!(find(""), Vec::<u32>::new());
+
+        let query = parse_query("quick -slow run* \"brown fox\"").unwrap();
+        assert_eq!(query.scoring_terms(&index).unwrap(),
+                   ["quick", "running", "runs", "brown", "fox"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn min_freq_applies_to_whole_phrases() {
+        let dir = scratch_dir("query-min-freq");
+        let docs = ["big fish big fish", "big fish fish big", "big big big"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let find = |query: &str, min_freq| parse_query(query).unwrap()
+            .doc_ids(&index, min_freq).unwrap();
+        assert_eq!(find("\"big fish\"", 0), vec![0, 1]);
+        assert_eq!(find("\"big fish\"", 2), vec![0]);
+        assert_eq!(find("bi*", 3), vec![2]);
+        assert_eq!(find("big -\"fish big\"", 2), vec![2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn phrases_need_word_positions() {
+        let dir = scratch_dir("query-no-positions");
+        let mut index = InMemoryIndex::from_single_document(0, "quick fox", &DefaultTokenizer);
+        index.drop_frequencies();
+        let mut merge = crate::merge::FileMerge::new(&dir, None);
+        merge.add_last_index(index);
+        merge.finish().unwrap();
+        let index = Index::open(dir.join(crate::merge::MERGED_FILENAME)).unwrap();
+        assert_eq!(parse_query("quick fox").unwrap().doc_ids(&index, 0).unwrap(), vec![0]);
+        let err = parse_query("\"quick fox\"").unwrap().doc_ids(&index, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -4,9 +4,14 @@
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::io::{self, BufReader, SeekFrom};
-use std::path::Path;
-use byteorder::{LittleEndian, ReadBytesExt};
-use crate::write::IndexFileWriter;
+use std::path::{Path, PathBuf};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use crate::compress::Codec;
+use crate::error::Error;
+use crate::index::{CaseLocale, DocRange, HIT_HEADER_SIZE, PostingList, Spellings,
+                   hit_document_id, hit_frequency, hits_from_document_ids};
+use crate::write::{IndexFileWriter, CASE_LOCALE_SHIFT, CODEC_SHIFT, FORMAT_VERSION, MAGIC,
+                   NO_FREQUENCIES_FLAG, SPELLINGS_FLAG};
 
 /// A `IndexFileReader` does a single linear pass over an index file from
 /// beginning to end. Needless to say, this is not how an index is normally
@@ -14,7 +19,16 @@ use crate::write::IndexFileWriter;
 ///
 /// The only way to advance through the file is to use the `.move_entry_to()`
 /// method.
+///
+/// Compressed files (see `IndexFileWriter::with_codec`) are decompressed as
+/// they're read, so the entries look the same whatever the codec. Likewise,
+/// in a file built without frequencies, each document id is read as a hit
+/// with a frequency of 0.
 pub struct IndexFileReader {
+    /// The name of the file, for error messages. (After `open_and_delete`,
+    /// the file no longer has this name, or any other.)
+    path: PathBuf,
+
     /// Reader that reads the actual index data.
     ///
     /// We have two readers. The index data is most of the file. There's also a
@@ -27,6 +41,25 @@ pub struct IndexFileReader {
     /// in `IndexFileReader::open_and_delete`.)
     contents: BufReader<File>,
 
+    /// The range of document ids covered by this file, from the file header.
+    docs: DocRange,
+
+    /// How the main entries are stored.
+    codec: Codec,
+
+    /// Whether the table of contents includes each term's spellings.
+    spellings: bool,
+
+    /// False if the main entries store only document ids.
+    frequencies: bool,
+
+    /// How the documents were lowercased.
+    case_locale: CaseLocale,
+
+    /// The amount added to each document id read from the file (see
+    /// `shift_doc_ids`).
+    id_shift: u32,
+
     /// The next entry in the table of contents, if any; or `None` if we've
     /// reached the end of the table. `IndexFileReader` always reads ahead one
     /// entry in the contents and stores it here.
@@ -50,8 +83,13 @@ pub struct Entry {
     /// Offset of the index data for this term from the beginning of the file, in bytes.
     pub offset: u64,
 
-    /// Length of the index data for this term, in bytes.
-    pub nbytes: u64
+    /// Length of the index data for this term, in bytes. In a file built
+    /// without frequencies, this is 4 bytes per document id.
+    pub nbytes: u64,
+
+    /// How the term was spelled before it was lowercased, if the file keeps
+    /// spellings (see `IndexFileWriter::keep_spellings`); otherwise empty.
+    pub spellings: Spellings
 }
 
 impl IndexFileReader {
@@ -64,10 +102,18 @@ impl IndexFileReader {
     /// dropped.
     pub fn open_and_delete<P: AsRef<Path>>(filename: P) -> io::Result<IndexFileReader> {
         let filename = filename.as_ref();
-        let mut main_raw = File::open(filename)?;
+        let reader = IndexFileReader::open(filename)?;
+        fs::remove_file(filename)?;  // YOLO
+        Ok(reader)
+    }
 
-        // Read the file header.
-        let contents_offset = main_raw.read_u64::<LittleEndian>()?;
+    /// Open an index file to read it from beginning to end, leaving the file
+    /// in place.
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<IndexFileReader, Error> {
+        let filename = filename.as_ref();
+        let mut main_raw = File::open(filename)?;
+        let Header { contents_offset, codec, spellings, frequencies, case_locale, docs } =
+            read_header(&mut main_raw)?;
         println!("opened {}, table of contents starts at {}", filename.display(), contents_offset);
 
         // Open again so we have two read heads;
@@ -79,50 +125,53 @@ impl IndexFileReader {
         let mut contents = BufReader::new(contents_raw);
 
         // We always read ahead one entry, so load the first entry right away.
-        let first = IndexFileReader::read_entry(&mut contents)?;
-
-        fs::remove_file(filename)?;  // YOLO
+        let first = read_entry(&mut contents, spellings)?;
 
         Ok(IndexFileReader {
-            main: main,
-            contents: contents,
+            path: filename.to_owned(),
+            main,
+            contents,
+            docs,
+            codec,
+            spellings,
+            frequencies,
+            case_locale,
+            id_shift: 0,
             next: first
         })
     }
 
-    /// Read the next entry from the table of contents.
-    ///
-    /// Returns `Ok(None)` if we have reached the end of the file.
-    fn read_entry(f: &mut BufReader<File>) -> io::Result<Option<Entry>> {
-        // If the first read here fails with `UnexpectedEof`,
-        // that's considered a success, with no entry read.
-        let offset = match f.read_u64::<LittleEndian>() {
-            Ok(value) => value,
-            Err(err) =>
-                if err.kind() == io::ErrorKind::UnexpectedEof {
-                    return Ok(None)
-                } else {
-                    return Err(err)
-                }
-        };
+    /// The name of the file this reads.
+    pub fn path(&self) -> &Path { &self.path }
 
-        let nbytes = f.read_u64::<LittleEndian>()?;
-        let df = f.read_u32::<LittleEndian>()?;
-        let term_len = f.read_u32::<LittleEndian>()? as usize;
-        let mut bytes = Vec::with_capacity(term_len);
-        bytes.resize(term_len, 0);
-        f.read_exact(&mut bytes)?;
-        let term = match String::from_utf8(bytes) {
-            Ok(s) => s,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "unicode fail"))
-        };
+    /// The range of document ids covered by this file.
+    pub fn doc_range(&self) -> DocRange { self.docs }
+
+    /// How the index data in this file is stored.
+    pub fn codec(&self) -> Codec { self.codec }
+
+    /// True if the entries in this file include each term's spellings.
+    pub fn has_spellings(&self) -> bool { self.spellings }
+
+    /// False if this file was built without frequencies (see
+    /// `IndexFileWriter::drop_frequencies`).
+    pub fn has_frequencies(&self) -> bool { self.frequencies }
+
+    /// How the documents in this file were lowercased.
+    pub fn case_locale(&self) -> CaseLocale { self.case_locale }
 
-        Ok(Some(Entry {
-            term: term,
-            df: df,
-            offset: offset,
-            nbytes: nbytes
-        }))
+    /// Add `by` to every document id in this file, as it's read, and to
+    /// `doc_range`. This is for merging files whose document ids overlap (see
+    /// `merge_files`). Call it before reading any entries.
+    pub fn shift_doc_ids(&mut self, by: u32) -> io::Result<()> {
+        if self.docs.is_empty() {
+            return Ok(());
+        }
+        let end = self.docs.end.checked_add(by).ok_or_else(|| io::Error::other(format!(
+            "{}: document ids would go past {}", self.path.display(), u32::MAX)))?;
+        self.docs = DocRange { start: self.docs.start + by, end };
+        self.id_shift += by;
+        Ok(())
     }
 
     /// Borrow a reference to the next entry in the table of contents.
@@ -142,22 +191,317 @@ impl IndexFileReader {
     /// Copy the current entry to the specified output stream, then read the
     /// header for the next entry.
     pub fn move_entry_to(&mut self, out: &mut IndexFileWriter) -> io::Result<()> {
+        let buf = self.take_entry()?;
+        out.write_main(&buf)
+    }
+
+    /// Skip over the index data for the current entry without reading it, then
+    /// read the header for the next entry.
+    pub fn skip_entry(&mut self) -> io::Result<()> {
+        let e = self.next.as_ref().expect("no entry to skip");
+        let nbytes = if self.codec != Codec::Raw {
+            self.main.read_u64::<LittleEndian>()?
+        } else {
+            e.nbytes
+        };
+        if nbytes > i64::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "index entry too big to skip"));
+        }
+        self.main.seek_relative(nbytes as i64)?;
+        self.next = read_entry(&mut self.contents, self.spellings)?;
+        Ok(())
+    }
+
+    /// Read the index data for the current entry into memory, then read the
+    /// header for the next entry.
+    pub fn take_entry(&mut self) -> io::Result<Vec<u8>> {
         // This block limits the scope of borrowing `self.next` (for `e`),
         // because after this block is over we'll want to assign to `self.next`.
-        {
+        let mut buf = {
             let e = self.next.as_ref().expect("no entry to move");
-            if e.nbytes > usize::max_value() as u64 {
+            if e.nbytes > usize::MAX as u64 {
                 // This can only happen on 32-bit platforms.
-                return Err(io::Error::new(io::ErrorKind::Other,
-                                          "computer not big enough to hold index entry"));
+                return Err(io::Error::other("computer not big enough to hold index entry"));
             }
-            let mut buf = Vec::with_capacity(e.nbytes as usize);
-            buf.resize(e.nbytes as usize, 0);
-            self.main.read_exact(&mut buf)?;
-            out.write_main(&buf)?;
+            if self.codec != Codec::Raw {
+                let len = self.main.read_u64::<LittleEndian>()?;
+                let mut compressed = vec![];
+                (&mut self.main).take(len).read_to_end(&mut compressed)?;
+                if compressed.len() as u64 != len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                let mut buf = Vec::with_capacity(e.nbytes as usize);
+                if self.frequencies {
+                    self.codec.decompress(&compressed, &mut buf)?;
+                } else {
+                    self.codec.decompress_doc_ids(&compressed, &mut buf)?;
+                }
+                if buf.len() as u64 != e.nbytes {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "compressed index entry is the wrong size"));
+                }
+                buf
+            } else {
+                let mut buf = vec![0; e.nbytes as usize];
+                self.main.read_exact(&mut buf)?;
+                buf
+            }
+        };
+
+        if !self.frequencies {
+            buf = hits_from_document_ids(&buf)?;
+        }
+        if self.id_shift != 0 {
+            shift_document_ids(&mut buf, self.id_shift)?;
         }
+        self.next = read_entry(&mut self.contents, self.spellings)?;
+        Ok(buf)
+    }
 
-        self.next = Self::read_entry(&mut self.contents)?;
-        Ok(())
+    /// Like `take_entry`, but split the index data into hits. It's an error
+    /// if they aren't a valid `PostingList`.
+    pub fn take_postings(&mut self) -> io::Result<PostingList> {
+        PostingList::decode(Codec::Raw, &self.take_entry()?)
+    }
+}
+
+/// Add `by` to the document id of each hit in `hits`, the index data for one
+/// term.
+fn shift_document_ids(hits: &mut [u8], by: u32) -> io::Result<()> {
+    let mut start = 0;
+    while start < hits.len() {
+        let hit = &mut hits[start..];
+        if hit.len() < HIT_HEADER_SIZE
+            || hit.len() < HIT_HEADER_SIZE + 4 * hit_frequency(hit) as usize
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "index entry ends in the middle of a hit"));
+        }
+        let id = hit_document_id(hit).checked_add(by).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "document id outside the file's range")
+        })?;
+        LittleEndian::write_u32(&mut hit[0..4], id);
+        start += HIT_HEADER_SIZE + 4 * hit_frequency(hit) as usize;
+    }
+    Ok(())
+}
+
+/// The fixed-size header at the start of an index file.
+pub struct Header {
+    /// Where the table of contents starts, in bytes from the beginning of the
+    /// file.
+    pub contents_offset: u64,
+
+    /// How the main entries are stored.
+    pub codec: Codec,
+
+    /// Whether the table of contents includes each term's spellings.
+    pub spellings: bool,
+
+    /// False if the main entries store only document ids (see
+    /// `IndexFileWriter::drop_frequencies`).
+    pub frequencies: bool,
+
+    /// How the documents were lowercased.
+    pub case_locale: CaseLocale,
+
+    /// The range of document ids covered by the file.
+    pub docs: DocRange
+}
+
+/// Read the header from the beginning of an index file.
+///
+/// It's an `InvalidData` error if the file doesn't start with `MAGIC`, as
+/// files written before the format had a version number don't, or if it's in
+/// a format version other than `FORMAT_VERSION`. These wrap an
+/// `Error::Format` and an `Error::UnsupportedVersion` respectively, which
+/// converting the error to an `Error` gets back.
+pub fn read_header<R: Read>(f: &mut R) -> io::Result<Header> {
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::Format("not an index file, or one written by a version of \
+                                  fingertips too old to read; rebuild the index".to_string())
+                   .into());
+    }
+    let version = f.read_u32::<LittleEndian>()?;
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedVersion(version).into());
+    }
+    let contents_offset = f.read_u64::<LittleEndian>()?;
+    let codec = Codec::from_id((contents_offset >> CODEC_SHIFT) as u8)?;
+    let case_locale = CaseLocale::from_id(
+        ((contents_offset & (NO_FREQUENCIES_FLAG - 1)) >> CASE_LOCALE_SHIFT) as u8)?;
+    Ok(Header {
+        contents_offset: contents_offset & ((1 << CASE_LOCALE_SHIFT) - 1),
+        codec,
+        spellings: contents_offset & SPELLINGS_FLAG != 0,
+        frequencies: contents_offset & NO_FREQUENCIES_FLAG == 0,
+        case_locale,
+        docs: DocRange {
+            start: f.read_u32::<LittleEndian>()?,
+            end: f.read_u32::<LittleEndian>()?
+        }
+    })
+}
+
+/// Read the next entry from the table of contents of an index file.
+/// `spellings` says whether the file keeps spellings (see `Header`).
+///
+/// Returns `Ok(None)` if we have reached the end of the file.
+pub fn read_entry<R: Read>(f: &mut R, spellings: bool) -> io::Result<Option<Entry>> {
+    // If the first read here fails with `UnexpectedEof`,
+    // that's considered a success, with no entry read.
+    let offset = match f.read_u64::<LittleEndian>() {
+        Ok(value) => value,
+        Err(err) =>
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(None)
+            } else {
+                return Err(err)
+            }
+    };
+
+    let nbytes = f.read_u64::<LittleEndian>()?;
+    let df = f.read_u32::<LittleEndian>()?;
+    let term_len = f.read_u32::<LittleEndian>()? as usize;
+    let term = read_string(f, term_len)?;
+    let mut term_spellings = vec![];
+    if spellings {
+        let count = f.read_u32::<LittleEndian>()?;
+        for _ in 0..count {
+            let n = f.read_u32::<LittleEndian>()?;
+            let len = f.read_u32::<LittleEndian>()? as usize;
+            term_spellings.push((read_string(f, len)?, n));
+        }
+    }
+
+    Ok(Some(Entry {
+        term,
+        df,
+        offset,
+        nbytes,
+        spellings: term_spellings
+    }))
+}
+
+/// Read a string of `len` bytes.
+fn read_string<R: Read>(f: &mut R, len: usize) -> io::Result<String> {
+    let mut bytes = vec![0; len];
+    f.read_exact(&mut bytes)?;
+    match String::from_utf8(bytes) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "unicode fail"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    /// A header for an empty, uncompressed index file in format `version`.
+    fn header(version: u32) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.write_u32::<LittleEndian>(version).unwrap();
+        bytes.write_u64::<LittleEndian>(24).unwrap();
+        bytes.write_u32::<LittleEndian>(3).unwrap();
+        bytes.write_u32::<LittleEndian>(7).unwrap();
+        bytes
+    }
+
+    fn unsupported_version(err: &io::Error) -> Option<u32> {
+        match err.get_ref().and_then(|e| e.downcast_ref::<Error>()) {
+            Some(&Error::UnsupportedVersion(version)) => Some(version),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn reads_current_header() {
+        let header = read_header(&mut &header(FORMAT_VERSION)[..]).unwrap();
+        assert_eq!(header.contents_offset, 24);
+        assert_eq!(header.codec, Codec::Raw);
+        assert!(!header.spellings);
+        assert_eq!(header.case_locale, CaseLocale::Default);
+        assert_eq!(header.docs, DocRange { start: 3, end: 7 });
+    }
+
+    #[test]
+    fn reads_flags() {
+        let mut bytes = header(FORMAT_VERSION);
+        bytes[15] = Codec::Varint.id();
+        bytes[14] = 0x80 | CaseLocale::Turkic.id();
+        let header = read_header(&mut &bytes[..]).unwrap();
+        assert_eq!(header.contents_offset, 24);
+        assert_eq!(header.codec, Codec::Varint);
+        assert!(header.spellings);
+        assert_eq!(header.case_locale, CaseLocale::Turkic);
+    }
+
+    #[test]
+    fn unknown_case_locale_is_invalid() {
+        let mut bytes = header(FORMAT_VERSION);
+        bytes[14] = 0x7f;
+        let err = read_header(&mut &bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_version_is_unsupported() {
+        let err = read_header(&mut &header(FORMAT_VERSION + 1)[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(unsupported_version(&err), Some(FORMAT_VERSION + 1));
+    }
+
+    #[test]
+    fn file_without_magic_is_invalid() {
+        // The header of an empty index file from before there was a version.
+        let mut old = vec![];
+        old.write_u64::<LittleEndian>(16).unwrap();
+        old.write_u64::<LittleEndian>(0).unwrap();
+        let err = read_header(&mut &old[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(unsupported_version(&err), None);
+    }
+
+    #[test]
+    fn unknown_codec_is_invalid() {
+        let mut bytes = header(FORMAT_VERSION);
+        bytes[15] = 0x7f;
+        let err = read_header(&mut &bytes[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(unsupported_version(&err), None);
+    }
+
+    #[test]
+    fn truncated_header_is_unexpected_eof() {
+        let bytes = header(FORMAT_VERSION);
+        let err = read_header(&mut &bytes[..bytes.len() - 1]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_entry_is_unexpected_eof() {
+        let mut bytes = vec![];
+        bytes.write_u64::<LittleEndian>(24).unwrap();
+        bytes.write_u64::<LittleEndian>(8).unwrap();
+        bytes.write_u32::<LittleEndian>(1).unwrap();
+        bytes.write_u32::<LittleEndian>(5).unwrap();
+        bytes.extend_from_slice(b"rus");
+        let err = read_entry(&mut &bytes[..], false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn term_that_is_not_utf8_is_invalid() {
+        let mut bytes = vec![];
+        bytes.write_u64::<LittleEndian>(24).unwrap();
+        bytes.write_u64::<LittleEndian>(8).unwrap();
+        bytes.write_u32::<LittleEndian>(1).unwrap();
+        bytes.write_u32::<LittleEndian>(2).unwrap();
+        bytes.extend_from_slice(&[0xc3, 0x28]);
+        let err = read_entry(&mut &bytes[..], false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
     }
 }
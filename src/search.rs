@@ -0,0 +1,1172 @@
+//! Looking up terms in a finished index file.
+//!
+//! `read` goes through an index file once, from beginning to end, which is
+//! what merging needs. Searching is different: a program that answers lots of
+//! queries wants to open the index once, and then jump straight to the hits
+//! for each term it's asked about.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crate::compress::Codec;
+use crate::error::Error;
+use crate::index::{CaseLocale, DocRange, Hit, PostingList, hit_document_id, hit_frequency,
+                   most_common_spelling};
+use crate::query::parse_query;
+use crate::read::{read_entry, read_header};
+
+/// An index file, opened for searching.
+///
+/// Opening reads just the header. The first lookup reads the whole table of
+/// contents into memory, so each lookup after that costs a single read from
+/// the file. (For a big index, that first lookup takes a while; call
+/// `term_count` right after opening to get it out of the way.)
+///
+/// An `Index` can be shared between threads. The table of contents is only
+/// read once, however many threads want it at the same time, and then they
+/// all share it. Lookups take turns reading the file.
+pub struct Index {
+    /// The index file. Every lookup seeks to the data it wants, so they can't
+    /// overlap.
+    file: Mutex<File>,
+
+    /// How the hits are stored.
+    codec: Codec,
+
+    /// The range of document ids covered by the index.
+    docs: DocRange,
+
+    /// Where the table of contents starts, in bytes from the beginning of the
+    /// file.
+    contents_offset: u64,
+
+    /// Whether the table of contents includes each term's spellings.
+    spellings: bool,
+
+    /// False if the index was built without frequencies, so that it stores
+    /// only which documents contain each term.
+    frequencies: bool,
+
+    /// How the documents were lowercased. Query terms are lowercased the same
+    /// way.
+    case_locale: CaseLocale,
+
+    /// Where to find the hits for each term, once the table of contents has
+    /// been read (see `Index::terms`).
+    terms: OnceLock<HashMap<String, Location>>
+}
+
+/// A document that matched a query, with the reasons why; see `Index::explain`.
+pub struct Match {
+    /// The id of the document.
+    pub doc_id: u32,
+
+    /// How each query term appears in the document, in the order the terms
+    /// were given.
+    pub terms: Vec<TermMatch>
+}
+
+/// How one query term appears in a matching document.
+pub struct TermMatch {
+    /// The query term.
+    pub term: String,
+
+    /// The number of documents in the index that contain the term.
+    pub df: u32,
+
+    /// The number of times the term appears in this document, or `None` if
+    /// the index was built without frequencies (see
+    /// `InMemoryIndex::drop_frequencies`).
+    pub tf: Option<u32>
+}
+
+/// A document found by `search`.
+pub struct SearchHit {
+    /// The id of the document.
+    pub doc_id: u32,
+
+    /// How well the document matches: for each query term, how often it
+    /// appears in the document (see `term_weight`) times its inverse document
+    /// frequency (see `idf`), added up. Higher is better. In an index built
+    /// without frequencies, each term counts as appearing once.
+    pub score: f64,
+
+    /// The file the document was read from, if known (see
+    /// `SearchOptions::docs`).
+    pub path: Option<PathBuf>,
+
+    /// The number of times each query term the document contains appears in
+    /// it, in the order the terms were given, or `None` if the index was
+    /// built without frequencies. (See `search` for which terms count.)
+    pub term_freqs: Vec<(String, Option<u32>)>
+}
+
+/// How `search` orders the documents it finds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// The best matches first, by `SearchHit::score`, and in order by
+    /// document id among equally good ones.
+    ByScore,
+
+    /// In order by document id.
+    #[default]
+    ByDocId,
+
+    /// In order by `SearchHit::path`, and then by document id. This needs
+    /// `SearchOptions::docs`; documents it doesn't list come last.
+    ByPath
+}
+
+impl SortOrder {
+    /// Look up a sort order by the name used on the command line:
+    /// `by-score`, `by-docid`, or `by-path`.
+    pub fn from_name(name: &str) -> io::Result<SortOrder> {
+        match name {
+            "by-score" => Ok(SortOrder::ByScore),
+            "by-docid" => Ok(SortOrder::ByDocId),
+            "by-path" => Ok(SortOrder::ByPath),
+            _ => Err(io::Error::other(format!(
+                "unknown sort order: {} (expected by-score, by-docid, or by-path)", name)))
+        }
+    }
+}
+
+/// Options for `search`.
+#[derive(Clone, Copy, Default)]
+pub struct SearchOptions<'a> {
+    /// The order to put the hits in. This is applied before `limit`, so with
+    /// `SortOrder::ByScore`, the hits returned are the best ones.
+    pub sort: SortOrder,
+
+    /// Return at most this many hits, if given.
+    pub limit: Option<usize>,
+
+    /// Only count a document as containing a query term if the term appears
+    /// in it at least this many times (see `Index::query_min_freq`).
+    pub min_freq: u32,
+
+    /// The path and length of each document, by id, for filling in
+    /// `SearchHit::path` and for scoring. Index files don't record these, but
+    /// a `--doc-stats` file does (see `read_doc_stats`). Without them, every
+    /// document is scored as if it were of average length.
+    pub docs: Option<&'a HashMap<u32, DocInfo>>
+}
+
+/// What a `--doc-stats` file says about a document.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DocInfo {
+    /// The file the document was read from.
+    pub path: PathBuf,
+
+    /// The number of tokens in the document.
+    pub tokens: u64
+}
+
+/// Where the hits for one term are stored in an index file.
+struct Location {
+    /// The number of documents that contain the term.
+    df: u32,
+
+    /// Offset of the stored data from the beginning of the file, in bytes.
+    offset: u64,
+
+    /// Length of the stored data, in bytes. If the file is compressed, this is
+    /// the compressed length.
+    stored_bytes: u64,
+
+    /// Length of the hit data once it's decompressed, in bytes. In an index
+    /// built without frequencies, this is 4 bytes per document id.
+    nbytes: u64,
+
+    /// How the term is usually spelled in the documents, if that's different
+    /// from the term itself.
+    display: Option<String>
+}
+
+impl Index {
+    /// Open an index file, such as the `index.dat` written by `FileMerge`,
+    /// for searching.
+    pub fn open<P: AsRef<Path>>(filename: P) -> Result<Index, Error> {
+        let mut file = File::open(filename)?;
+        let header = read_header(&mut file)?;
+        Ok(Index {
+            file: Mutex::new(file),
+            codec: header.codec,
+            docs: header.docs,
+            contents_offset: header.contents_offset,
+            spellings: header.spellings,
+            frequencies: header.frequencies,
+            case_locale: header.case_locale,
+            terms: OnceLock::new()
+        })
+    }
+
+    /// Where to find the hits for each term, reading the table of contents
+    /// if this is the first lookup. If reading fails, the next lookup tries
+    /// again.
+    fn terms(&self) -> io::Result<&HashMap<String, Location>> {
+        if let Some(terms) = self.terms.get() {
+            return Ok(terms);
+        }
+        let mut file = self.file.lock().unwrap();
+        // Another thread may have read it while this one waited for the lock.
+        if let Some(terms) = self.terms.get() {
+            return Ok(terms);
+        }
+        let terms = self.read_terms(&mut file)?;
+        Ok(self.terms.get_or_init(|| terms))
+    }
+
+    /// Read the table of contents from `file`.
+    fn read_terms(&self, file: &mut File) -> io::Result<HashMap<String, Location>> {
+        let mut entries = vec![];
+        file.seek(SeekFrom::Start(self.contents_offset))?;
+        let mut contents = BufReader::new(&*file);
+        while let Some(entry) = read_entry(&mut contents, self.spellings)? {
+            entries.push(entry);
+        }
+
+        // The table of contents gives offsets as if the data weren't
+        // compressed. If it is, each entry is stored as its compressed length
+        // followed by the data, so walk through them to see where each one
+        // really is. (The first one starts right after the header either way.)
+        let mut main = BufReader::new(&*file);
+        let mut next = entries.first().map_or(0, |e| e.offset);
+        main.seek(SeekFrom::Start(next))?;
+        let mut terms = HashMap::with_capacity(entries.len());
+        for e in entries {
+            let (offset, stored_bytes) = if self.codec == Codec::Raw {
+                (e.offset, e.nbytes)
+            } else {
+                let len = main.read_u64::<LittleEndian>()?;
+                if len > i64::MAX as u64 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "index entry too big to skip"));
+                }
+                main.seek_relative(len as i64)?;
+                let offset = next + 8;
+                next = offset + len;
+                (offset, len)
+            };
+            let display = most_common_spelling(&e.spellings)
+                .filter(|&spelling| spelling != e.term)
+                .map(str::to_string);
+            terms.insert(e.term, Location {
+                df: e.df,
+                offset,
+                stored_bytes,
+                nbytes: e.nbytes,
+                display
+            });
+        }
+
+        Ok(terms)
+    }
+
+    /// The range of document ids covered by the index.
+    pub fn doc_range(&self) -> DocRange { self.docs }
+
+    /// The number of distinct terms in the index.
+    pub fn term_count(&self) -> io::Result<usize> { Ok(self.terms()?.len()) }
+
+    /// False if the index was built without frequencies (see
+    /// `InMemoryIndex::drop_frequencies`). Its hits all have a frequency of
+    /// 0, and it can't rank search results.
+    pub fn has_frequencies(&self) -> bool { self.frequencies }
+
+    /// How the documents were lowercased when the index was built (see
+    /// `Tokenizer::case_locale`).
+    pub fn case_locale(&self) -> CaseLocale { self.case_locale }
+
+    /// Look up `term`, lowercased the way the documents were. Returns the
+    /// term as stored in the index, and where its hits are.
+    fn location(&self, term: &str) -> io::Result<Option<(&str, &Location)>> {
+        let term = self.case_locale.lowercase(term);
+        Ok(self.terms()?.get_key_value(&term).map(|(term, location)| (term.as_str(), location)))
+    }
+
+    /// The number of documents that contain `term`. See `hits` for how terms
+    /// are matched.
+    pub fn doc_freq(&self, term: &str) -> io::Result<u32> {
+        Ok(self.location(term)?.map_or(0, |(_, location)| location.df))
+    }
+
+    /// How to show `term` to a user: the spelling it most often has in the
+    /// documents, like `"Rust"` for the term `"rust"`. This is the term as
+    /// stored in the index if the index wasn't built with spellings (see
+    /// `InMemoryIndex::add_spellings`), or `None` if the term isn't in the
+    /// index at all.
+    pub fn display_form(&self, term: &str) -> io::Result<Option<&str>> {
+        Ok(self.location(term)?
+           .map(|(term, location)| location.display.as_deref().unwrap_or(term)))
+    }
+
+    /// All the hits for `term`, in order by document id. If the term isn't in
+    /// the index, there are none.
+    ///
+    /// `term` is lowercased the same way the documents were (see
+    /// `case_locale`), so `"Rust"` finds the term `"rust"`. Beyond that, terms
+    /// are matched exactly, so `term` must be in the form the tokenizer
+    /// produced when the index was built.
+    pub fn hits(&self, term: &str) -> io::Result<Vec<Hit>> {
+        match self.location(term)? {
+            Some((_, location)) => self.read_hits(location),
+            None => Ok(vec![])
+        }
+    }
+
+    /// Read the hits stored at `location`.
+    fn read_hits(&self, location: &Location) -> io::Result<Vec<Hit>> {
+        if location.stored_bytes > usize::MAX as u64 {
+            // This can only happen on 32-bit platforms.
+            return Err(io::Error::other("computer not big enough to hold index entry"));
+        }
+        let mut stored = vec![0; location.stored_bytes as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(location.offset))?;
+            file.read_exact(&mut stored)?;
+        }
+        let (postings, nbytes) = if self.frequencies {
+            let postings = PostingList::decode(self.codec, &stored)?;
+            let nbytes = postings.nbytes();
+            (postings, nbytes)
+        } else {
+            let postings = PostingList::decode_doc_ids(self.codec, &stored)?;
+            let nbytes = 4 * postings.len() as u64;
+            (postings, nbytes)
+        };
+        if nbytes != location.nbytes {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "compressed index entry is the wrong size"));
+        }
+        Ok(postings.into_hits())
+    }
+
+    /// The ids of the documents that contain every one of `terms`, in
+    /// increasing order. See `hits` for how terms are matched.
+    pub fn query(&self, terms: &[&str]) -> Result<Vec<u32>, Error> {
+        Ok(self.query_min_freq(terms, 0)?)
+    }
+
+    /// Like `query`, but a document only counts as containing a term if the
+    /// term appears in it at least `min_freq` times. (A `min_freq` of 0 or 1
+    /// makes no difference.) For an index built without frequencies, any
+    /// higher `min_freq` is an error, since there's no telling.
+    pub fn query_min_freq(&self, terms: &[&str], min_freq: u32) -> io::Result<Vec<u32>> {
+        let mut documents: Option<Vec<u32>> = None;
+        for (_, term) in self.rarest_first(terms)? {
+            let ids = self.doc_ids(term, min_freq)?;
+            documents = Some(match documents {
+                None => ids,
+                Some(mut documents) => {
+                    documents.retain(|id| ids.binary_search(id).is_ok());
+                    documents
+                }
+            });
+            if documents.as_ref().is_some_and(|documents| documents.is_empty()) {
+                break;
+            }
+        }
+        Ok(documents.unwrap_or_default())
+    }
+
+    /// The number of documents that contain every one of `terms`; that is,
+    /// the length of `query(terms)`. For a single term, this comes straight
+    /// from the table of contents, without reading any hits. For several, the
+    /// documents are counted as they're checked, without building a list of
+    /// them; and if any term is in no documents, no hits are read at all.
+    pub fn count(&self, terms: &[&str]) -> Result<usize, Error> {
+        Ok(self.count_min_freq(terms, 0)?)
+    }
+
+    /// Like `count`, with a minimum frequency as for `query_min_freq`. With a
+    /// threshold, even a single term's hits have to be read.
+    pub fn count_min_freq(&self, terms: &[&str], min_freq: u32) -> io::Result<usize> {
+        match self.rarest_first(terms)?.as_slice() {
+            [] => Ok(0),
+            [(df, _)] if min_freq <= 1 => Ok(*df as usize),
+            [(0, _), ..] => Ok(0),
+            [(_, rarest), rest @ ..] => {
+                let candidates = self.doc_ids(rarest, min_freq)?;
+                let others = rest.iter()
+                    .map(|&(_, term)| self.doc_ids(term, min_freq))
+                    .collect::<io::Result<Vec<_>>>()?;
+                Ok(candidates.iter()
+                   .filter(|id| others.iter().all(|ids| ids.binary_search(id).is_ok()))
+                   .count())
+            }
+        }
+    }
+
+    /// Every term in the index that starts with `prefix`, lowercased the way
+    /// the documents were, in order.
+    pub(crate) fn terms_with_prefix(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let prefix = self.case_locale.lowercase(prefix);
+        let mut terms: Vec<String> = self.terms()?.keys()
+            .filter(|term| term.starts_with(&prefix))
+            .cloned()
+            .collect();
+        terms.sort();
+        Ok(terms)
+    }
+
+    /// Each of `terms` with its document count, the rarest first. Starting
+    /// with the rarest term leaves as few documents as possible to check
+    /// against the others.
+    fn rarest_first<'t>(&self, terms: &[&'t str]) -> io::Result<Vec<(u32, &'t str)>> {
+        let mut by_df = Vec::with_capacity(terms.len());
+        for &term in terms {
+            by_df.push((self.doc_freq(term)?, term));
+        }
+        by_df.sort_by_key(|&(df, _)| df);
+        Ok(by_df)
+    }
+
+    /// The ids of the documents that contain `term` at least `min_freq`
+    /// times, in increasing order.
+    pub(crate) fn doc_ids(&self, term: &str, min_freq: u32) -> io::Result<Vec<u32>> {
+        Ok(self.frequencies(term, min_freq)?.into_iter().map(|(id, _)| id).collect())
+    }
+
+    /// The id of each document that contains `term` at least `min_freq` times,
+    /// with the number of times, in order by id. See `query_min_freq`.
+    fn frequencies(&self, term: &str, min_freq: u32) -> io::Result<Vec<(u32, u32)>> {
+        let hits = self.hits(term)?;
+        let pairs = hits.iter().map(|hit| (hit_document_id(hit), hit_frequency(hit)));
+        if min_freq <= 1 {
+            return Ok(pairs.collect());
+        }
+        if !self.frequencies {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "the index was built without frequencies, so there's no telling which \
+                 documents contain {:?} at least {} times", term, min_freq)));
+        }
+        Ok(pairs.filter(|&(_, tf)| tf >= min_freq).collect())
+    }
+
+    /// Like `query`, but for each matching document, also say how often each
+    /// of `terms` appears in it, and in how many documents overall. This is
+    /// for finding out why a document matched.
+    pub fn explain(&self, terms: &[&str]) -> Result<Vec<Match>, Error> {
+        Ok(self.explain_min_freq(terms, 0)?)
+    }
+
+    /// Like `explain`, with a minimum frequency as for `query_min_freq`. Each
+    /// term's `df` still counts every document it's in.
+    pub fn explain_min_freq(&self, terms: &[&str], min_freq: u32) -> io::Result<Vec<Match>> {
+        // For each term, its document count, and (document id, frequency)
+        // pairs, sorted by id.
+        let mut dfs = Vec::with_capacity(terms.len());
+        let mut frequencies = Vec::with_capacity(terms.len());
+        for term in terms {
+            dfs.push(self.doc_freq(term)?);
+            frequencies.push(self.frequencies(term, min_freq)?);
+        }
+
+        let rarest = match frequencies.iter().min_by_key(|pairs| pairs.len()) {
+            Some(pairs) => pairs,
+            None => return Ok(vec![])
+        };
+        let mut matches = vec![];
+        'documents: for &(doc_id, _) in rarest {
+            let mut term_matches = Vec::with_capacity(terms.len());
+            for ((term, &df), pairs) in terms.iter().zip(&dfs).zip(&frequencies) {
+                let tf = match pairs.binary_search_by_key(&doc_id, |&(id, _)| id) {
+                    Ok(i) => pairs[i].1,
+                    Err(_) => continue 'documents
+                };
+                term_matches.push(TermMatch {
+                    term: term.to_string(),
+                    df,
+                    tf: Some(tf).filter(|_| self.frequencies)
+                });
+            }
+            matches.push(Match { doc_id, terms: term_matches });
+        }
+        Ok(matches)
+    }
+
+    /// The `n` terms that appear most often in the document `doc_id`, most
+    /// frequent first, and in term order among terms that appear equally
+    /// often. (In an index built without frequencies, that's simply the first
+    /// `n` of the document's terms in term order.) This is for debugging, and
+    /// for finding other documents like this one.
+    ///
+    /// The index maps terms to documents, not the other way around, so this
+    /// reads the hits for every term in the index. It's much slower than a
+    /// query.
+    pub fn top_terms(&self, doc_id: u32, n: usize) -> io::Result<Vec<TermMatch>> {
+        if !self.docs.overlaps(&DocRange::single(doc_id)) {
+            return Ok(vec![]);
+        }
+        let mut found = vec![];
+        for (term, location) in self.terms()? {
+            let hits = self.read_hits(location)?;
+            if let Some(hit) = hits.iter().find(|hit| hit_document_id(hit) == doc_id) {
+                found.push(TermMatch {
+                    term: term.clone(),
+                    df: location.df,
+                    tf: Some(hit_frequency(hit)).filter(|_| self.frequencies)
+                });
+            }
+        }
+        found.sort_by(|a, b| b.tf.cmp(&a.tf).then_with(|| a.term.cmp(&b.term)));
+        found.truncate(n);
+        Ok(found)
+    }
+}
+
+/// Several index files, searched as if they had been merged into one: say,
+/// the shards of a build that haven't been merged yet, or the temporary files
+/// `FileMerge` writes along the way.
+///
+/// As with merging, no two of the files can have any document ids in common.
+/// That makes a merged lookup simple: each document is in exactly one shard,
+/// and the shards are kept in order by document id, so the hits for a term
+/// are just the hits from each shard, one shard after another.
+pub struct MultiShardReader {
+    /// The shards, in order by document id.
+    shards: Vec<Index>
+}
+
+impl MultiShardReader {
+    /// Open each of `filenames` for searching (see `Index::open`).
+    pub fn open<P: AsRef<Path>>(filenames: &[P]) -> io::Result<MultiShardReader> {
+        let mut shards: Vec<Index> = Vec::with_capacity(filenames.len());
+        for filename in filenames {
+            let filename = filename.as_ref();
+            let shard = Index::open(filename).map_err(|err| {
+                let err = io::Error::from(err);
+                io::Error::new(err.kind(), format!("{}: {}", filename.display(), err))
+            })?;
+            for other in &shards {
+                let (a, b) = (other.doc_range(), shard.doc_range());
+                if a.overlaps(&b) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                        "can't search index files with overlapping document ids as one \
+                         ({}..{} and {}..{})", a.start, a.end, b.start, b.end)));
+                }
+            }
+            shards.push(shard);
+        }
+        shards.sort_by_key(|shard| shard.doc_range().start);
+        Ok(MultiShardReader { shards })
+    }
+
+    /// The shards, in order by document id.
+    pub fn shards(&self) -> &[Index] { &self.shards }
+
+    /// The range of document ids covered by all the shards together.
+    pub fn doc_range(&self) -> DocRange {
+        self.shards.iter().fold(DocRange::empty(), |docs, shard| docs.union(&shard.doc_range()))
+    }
+
+    /// The number of documents that contain `term`, in all the shards.
+    pub fn doc_freq(&self, term: &str) -> io::Result<u32> {
+        let mut df = 0u32;
+        for shard in &self.shards {
+            df = df.saturating_add(shard.doc_freq(term)?);
+        }
+        Ok(df)
+    }
+
+    /// All the hits for `term`, from every shard, in order by document id.
+    /// See `Index::hits`.
+    pub fn hits(&self, term: &str) -> io::Result<Vec<Hit>> {
+        let mut hits = vec![];
+        for shard in &self.shards {
+            hits.extend(shard.hits(term)?);
+        }
+        Ok(hits)
+    }
+
+    /// The ids of the documents, in any shard, that contain every one of
+    /// `terms`, in increasing order. See `Index::query_min_freq`.
+    pub fn query_min_freq(&self, terms: &[&str], min_freq: u32) -> io::Result<Vec<u32>> {
+        let mut ids = vec![];
+        for shard in &self.shards {
+            ids.extend(shard.query_min_freq(terms, min_freq)?);
+        }
+        Ok(ids)
+    }
+
+    /// The number of documents, in all the shards, that contain every one of
+    /// `terms`. See `Index::count_min_freq`.
+    pub fn count_min_freq(&self, terms: &[&str], min_freq: u32) -> io::Result<usize> {
+        let mut count = 0;
+        for shard in &self.shards {
+            count += shard.count_min_freq(terms, min_freq)?;
+        }
+        Ok(count)
+    }
+}
+
+/// Find the documents in `index` that match `query`, written in the syntax
+/// described in the `query` module. Words are matched as `Index::hits`
+/// describes.
+///
+/// Each document is scored on the terms the query looks for (see
+/// `Query::scoring_terms`) that it contains; a `SearchHit`'s `term_freqs`
+/// lists those terms.
+///
+/// A query that doesn't parse is an `Error::QueryParse`. Ranking needs
+/// frequencies, so `SortOrder::ByScore` is an error for an index built
+/// without them (see `Index::has_frequencies`); and `SortOrder::ByPath` needs
+/// `options.docs`.
+pub fn search(index: &Index, query: &str, options: &SearchOptions)
+    -> Result<Vec<SearchHit>, Error>
+{
+    let query = parse_query(query)?;
+    if options.sort == SortOrder::ByScore && !index.has_frequencies() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                            "the index was built without frequencies, so its \
+                                             search results can't be ranked")));
+    }
+    if options.sort == SortOrder::ByPath && options.docs.is_none() {
+        return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                            "sorting by path needs the documents' paths, \
+                                             from a --doc-stats file")));
+    }
+    let doc_ids = query.doc_ids(index, options.min_freq)?;
+    let ndocs = index.doc_range().len();
+    let average_tokens = options.docs.and_then(average_tokens);
+    let mut scoring = vec![];
+    for term in query.scoring_terms(index)? {
+        let df = index.doc_freq(&term)?;
+        let frequencies = index.frequencies(&term, 0)?;
+        scoring.push((term, df, frequencies));
+    }
+
+    let mut hits: Vec<SearchHit> = doc_ids.into_iter()
+        .map(|doc_id| {
+            let info = options.docs.and_then(|docs| docs.get(&doc_id));
+            let relative_length = match (info, average_tokens) {
+                (Some(info), Some(average)) => info.tokens as f64 / average,
+                _ => 1.0
+            };
+            let mut score = 0.0;
+            let mut term_freqs = vec![];
+            for (term, df, frequencies) in &scoring {
+                if let Ok(i) = frequencies.binary_search_by_key(&doc_id, |&(id, _)| id) {
+                    let tf = Some(frequencies[i].1).filter(|_| index.has_frequencies());
+                    score += term_weight(tf.unwrap_or(1), relative_length) * idf(ndocs, *df);
+                    term_freqs.push((term.clone(), tf));
+                }
+            }
+            SearchHit {
+                doc_id,
+                score,
+                path: info.map(|info| info.path.clone()),
+                term_freqs
+            }
+        })
+        .collect();
+    // `doc_ids` come in order by id already.
+    match options.sort {
+        SortOrder::ByScore =>
+            hits.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.doc_id.cmp(&b.doc_id))),
+        SortOrder::ByDocId => {}
+        SortOrder::ByPath => hits.sort_by(|a, b| {
+            match (&a.path, &b.path) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal
+            }.then(a.doc_id.cmp(&b.doc_id))
+        })
+    }
+    if let Some(limit) = options.limit {
+        hits.truncate(limit);
+    }
+    Ok(hits)
+}
+
+/// How quickly `term_weight` levels off as a term appears more often.
+const TF_SATURATION: f64 = 1.2;
+
+/// How much `term_weight` takes the length of the document into account,
+/// from 0 (not at all) to 1.
+const LENGTH_NORMALIZATION: f64 = 0.75;
+
+/// How much it says about a document that a term appears in it `tf` times,
+/// if the document is `relative_length` times as long as the average one.
+///
+/// This is the term frequency part of BM25. It grows with `tf`, but levels
+/// off, never reaching `TF_SATURATION + 1`: a word repeated a hundred
+/// thousand times doesn't make a document a hundred thousand times more
+/// relevant. And the same number of occurrences counts for less in a longer
+/// document, which has more words of every kind.
+fn term_weight(tf: u32, relative_length: f64) -> f64 {
+    let tf = tf as f64;
+    let length = 1.0 - LENGTH_NORMALIZATION + LENGTH_NORMALIZATION * relative_length;
+    tf * (TF_SATURATION + 1.0) / (tf + TF_SATURATION * length)
+}
+
+/// The average number of tokens per document in `docs`, or `None` if there
+/// are none. The total is added up with saturating arithmetic, since it can
+/// get far bigger than any one document.
+fn average_tokens(docs: &HashMap<u32, DocInfo>) -> Option<f64> {
+    let total = docs.values().fold(0u64, |total, info| total.saturating_add(info.tokens));
+    Some(total as f64 / docs.len() as f64).filter(|&average| average > 0.0)
+}
+
+/// How much a term that appears in `df` of `ndocs` documents says about a
+/// document it's in: the rarer the term, the more. This is the smoothed
+/// form, `ln((1 + ndocs) / (1 + df)) + 1`, which is never zero, so a term
+/// that's in every document still counts for something.
+fn idf(ndocs: u32, df: u32) -> f64 {
+    ((1.0 + ndocs as f64) / (1.0 + df as f64)).ln() + 1.0
+}
+
+/// Read the path and length of each document from a `--doc-stats` file.
+/// That's tab-separated values, with a header line, and then a line for each
+/// document starting with its id, its path, in which backslashes, tabs, and
+/// line breaks are escaped as `\\`, `\t`, `\n`, and `\r`, and its number of
+/// tokens.
+pub fn read_doc_stats<R: BufRead>(doc_stats: R) -> io::Result<HashMap<u32, DocInfo>> {
+    let mut lines = doc_stats.lines();
+    match lines.next() {
+        Some(Ok(header)) if header.starts_with("doc_id\tpath\ttokens\t") => {}
+        Some(Err(err)) => return Err(err),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       "not a --doc-stats file (bad header line)"))
+    }
+    let mut docs = HashMap::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let mut fields = line.split('\t');
+        let bad = || io::Error::new(io::ErrorKind::InvalidData, format!(
+            "line {} of the --doc-stats file doesn't start with a document id, a path, \
+             and a number of tokens", i + 2));
+        let doc_id = fields.next().and_then(|id| id.parse().ok()).ok_or_else(bad)?;
+        let path = fields.next().ok_or_else(bad)?;
+        let tokens = fields.next().and_then(|n| n.parse().ok()).ok_or_else(bad)?;
+        docs.insert(doc_id, DocInfo { path: PathBuf::from(unescape_tsv_field(path)), tokens });
+    }
+    Ok(docs)
+}
+
+/// Undo the escaping in a field of a `--doc-stats` file.
+fn unescape_tsv_field(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(ch) = chars.next() {
+        out.push(match ch {
+            '\\' => match chars.next() {
+                Some('t') => '\t',
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some(ch) => ch,
+                None => '\\'
+            },
+            ch => ch
+        });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::index::{CjkBigramTokenizer, CodeTokenizer, DefaultTokenizer, LocaleTokenizer,
+                       NgramTokenizer};
+    use crate::test_util::{build_index, scratch_dir};
+
+    const DOCS: &[&str] = &["Rust is fast", "rust and go", "Go is fast too"];
+
+    #[test]
+    fn open_once_and_query_several_times() {
+        let dir = scratch_dir("search-several");
+        let index = Index::open(build_index(&dir, 0, DOCS, &DefaultTokenizer)).unwrap();
+        assert_eq!(index.query(&["rust"]).unwrap(), vec![0, 1]);
+        assert_eq!(index.query(&["fast"]).unwrap(), vec![0, 2]);
+        assert_eq!(index.query(&["rust", "fast"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["rust", "too"]).unwrap(), Vec::<u32>::new());
+        assert_eq!(index.query(&["missing"]).unwrap(), Vec::<u32>::new());
+        assert_eq!(index.query(&["rust"]).unwrap(), vec![0, 1]);
+        assert_eq!(index.doc_freq("go").unwrap(), 2);
+        assert_eq!(index.term_count().unwrap(), 6);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn explain_gives_tf_and_df() {
+        let dir = scratch_dir("search-explain");
+        let docs = ["fast fast rust", "slow rust", "fast cars"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let matches = index.explain(&["rust", "Fast"]).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].doc_id, 0);
+        let terms: Vec<(&str, u32, Option<u32>)> = matches[0].terms.iter()
+            .map(|m| (m.term.as_str(), m.df, m.tf))
+            .collect();
+        assert_eq!(terms, vec![("rust", 2, Some(1)), ("Fast", 2, Some(2))]);
+        assert!(index.explain(&["rust", "cars"]).unwrap().is_empty());
+        assert!(index.explain(&[]).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ngram_index_finds_part_of_a_word() {
+        let dir = scratch_dir("search-ngram");
+        let trigrams = NgramTokenizer::new(Box::new(DefaultTokenizer), 3, false);
+        let docs = ["indexing", "an index", "dexterity"];
+        let index = Index::open(build_index(&dir, 0, &docs, &trigrams)).unwrap();
+        assert_eq!(index.query(&["dex"]).unwrap(), vec![0, 1, 2]);
+        assert_eq!(index.query(&["nde", "ing"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["^de"]).unwrap(), vec![2]);
+        assert_eq!(index.query(&["indexing"]).unwrap(), Vec::<u32>::new());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cjk_bigram_index_finds_words_in_a_chinese_sentence() {
+        let dir = scratch_dir("search-cjk");
+        let docs = ["我爱北京天安门。", "北京欢迎你", "我在学rust编程"];
+        let tokenizer = CjkBigramTokenizer::new(Box::new(DefaultTokenizer));
+        let index = Index::open(build_index(&dir, 0, &docs, &tokenizer)).unwrap();
+        assert_eq!(index.query(&["北京"]).unwrap(), vec![0, 1]);
+        assert_eq!(index.query(&["天安", "安门"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["欢迎"]).unwrap(), vec![1]);
+        assert_eq!(index.query(&["编程", "rust"]).unwrap(), vec![2]);
+        // Only pairs of characters are indexed, not whole runs.
+        assert!(index.query(&["北京天安门"]).unwrap().is_empty());
+        assert!(index.query(&["门。"]).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn code_index_finds_identifiers_and_operators() {
+        let dir = scratch_dir("search-code");
+        let docs = ["use std::collections::HashMap;\nlet m: HashMap<u32, u32> = HashMap::new();",
+                    "fn main() -> Result<(), Error> { Ok(()) }"];
+        let index = Index::open(build_index(&dir, 0, &docs, &CodeTokenizer::new(false))).unwrap();
+        assert_eq!(index.query(&["HashMap"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["::"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["HashMap", "::", "new"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["->"]).unwrap(), vec![1]);
+        assert_eq!(index.query(&["main", "()"]).unwrap(), vec![1]);
+        assert_eq!(index.doc_freq("hashmap").unwrap(), 1);
+        assert_eq!(index.explain(&["hashmap"]).unwrap()[0].terms[0].tf, Some(3));
+
+        let paths = dir.join("paths");
+        fs::create_dir(&paths).unwrap();
+        let index = Index::open(build_index(&paths, 0, &docs, &CodeTokenizer::new(true))).unwrap();
+        assert_eq!(index.query(&["std::collections::HashMap"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["HashMap::new"]).unwrap(), vec![0]);
+        assert_eq!(index.doc_freq("::").unwrap(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn count_matches_query() {
+        let dir = scratch_dir("search-count");
+        let docs = ["a b c", "a b", "a c", "b c", "a", "c c c"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let queries: &[&[&str]] = &[
+            &[], &["a"], &["A"], &["missing"], &["a", "b"], &["b", "a"], &["a", "c"],
+            &["a", "b", "c"], &["a", "missing"], &["c", "c"]
+        ];
+        for terms in queries {
+            assert_eq!(index.count(terms).unwrap(), index.query(terms).unwrap().len(),
+                       "{:?}", terms);
+        }
+        assert_eq!(index.count(&["a", "b"]).unwrap(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn min_freq_leaves_out_documents_with_fewer_occurrences() {
+        let dir = scratch_dir("search-min-freq");
+        let docs = ["rust", "rust rust go", "rust rust rust", "go rust go rust go rust go rust"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        for min_freq in [0, 1] {
+            assert_eq!(index.query_min_freq(&["rust"], min_freq).unwrap(), vec![0, 1, 2, 3]);
+            assert_eq!(index.count_min_freq(&["rust"], min_freq).unwrap(), 4);
+        }
+        assert_eq!(index.query_min_freq(&["rust"], 2).unwrap(), vec![1, 2, 3]);
+        assert_eq!(index.query_min_freq(&["rust"], 3).unwrap(), vec![2, 3]);
+        assert_eq!(index.query_min_freq(&["rust"], 5).unwrap(), Vec::<u32>::new());
+        // Every term has to meet the threshold.
+        assert_eq!(index.query_min_freq(&["rust", "go"], 2).unwrap(), vec![3]);
+        assert_eq!(index.count_min_freq(&["rust", "go"], 2).unwrap(), 1);
+        assert_eq!(index.count_min_freq(&["rust"], 3).unwrap(), 2);
+
+        let matches = index.explain_min_freq(&["rust"], 3).unwrap();
+        let found: Vec<(u32, Option<u32>, u32)> = matches.iter()
+            .map(|m| (m.doc_id, m.terms[0].tf, m.terms[0].df))
+            .collect();
+        assert_eq!(found, vec![(2, Some(3), 4), (3, Some(4), 4)]);
+
+        let options = SearchOptions { min_freq: 4, ..SearchOptions::default() };
+        let ids: Vec<u32> = search(&index, "rust", &options).unwrap().iter()
+            .map(|hit| hit.doc_id)
+            .collect();
+        assert_eq!(ids, vec![3]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shards_are_searched_as_one_index() {
+        let dir = scratch_dir("search-shards");
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        let a = build_index(&a, 0, &["red fish", "blue fish fish"], &DefaultTokenizer);
+        let b = build_index(&b, 100, &["one fish", "red car"], &DefaultTokenizer);
+
+        // The order they're named in doesn't matter.
+        let shards = MultiShardReader::open(&[&b, &a]).unwrap();
+        assert_eq!(shards.doc_range(), DocRange { start: 0, end: 102 });
+        assert_eq!(shards.shards().len(), 2);
+        assert_eq!(shards.doc_freq("fish").unwrap(), 3);
+        let ids: Vec<u32> = shards.hits("Fish").unwrap().iter().map(|hit| hit_document_id(hit))
+            .collect();
+        assert_eq!(ids, vec![0, 1, 100]);
+        assert_eq!(shards.query_min_freq(&["red"], 0).unwrap(), vec![0, 101]);
+        assert_eq!(shards.query_min_freq(&["red", "fish"], 0).unwrap(), vec![0]);
+        assert_eq!(shards.query_min_freq(&["fish"], 2).unwrap(), vec![1]);
+        assert_eq!(shards.count_min_freq(&["fish"], 0).unwrap(), 3);
+        assert_eq!(shards.count_min_freq(&["missing"], 0).unwrap(), 0);
+
+        // Merging gives the same hits.
+        let merged = dir.join("merged.dat");
+        crate::merge::merge_files(&[a.clone(), b.clone()], &merged).unwrap();
+        let merged = Index::open(&merged).unwrap();
+        for term in ["fish", "red", "blue", "car", "missing"] {
+            assert_eq!(shards.hits(term).unwrap(), merged.hits(term).unwrap(), "{}", term);
+        }
+
+        let err = MultiShardReader::open(&[&a, &a]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn lookups_from_many_threads_share_one_table_of_contents() {
+        use std::sync::{Arc, Barrier};
+        use std::thread;
+
+        let dir = scratch_dir("search-threads");
+        let docs: Vec<String> = (0..50).map(|i| format!("doc{} mod{} all", i, i % 7)).collect();
+        let docs: Vec<&str> = docs.iter().map(String::as_str).collect();
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        assert!(index.terms.get().is_none());
+        let index = Arc::new(index);
+
+        // The threads all start at once, so that several of them find the
+        // table of contents not read yet.
+        let nthreads = 8;
+        let barrier = Arc::new(Barrier::new(nthreads));
+        let threads: Vec<_> = (0..nthreads).map(|t| {
+            let (index, barrier) = (index.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for round in 0..20 {
+                    let i = (t * 20 + round) % 50;
+                    let ids: Vec<u32> = index.hits(&format!("doc{}", i)).unwrap().iter()
+                        .map(|hit| hit_document_id(hit))
+                        .collect();
+                    assert_eq!(ids, vec![i as u32]);
+                    let expected = if i % 7 == 0 { 8 } else { 7 };
+                    assert_eq!(index.doc_freq(&format!("mod{}", i % 7)).unwrap(), expected);
+                    assert_eq!(index.doc_freq("all").unwrap(), 50);
+                }
+                // Where this thread found the table of contents.
+                index.terms().unwrap() as *const HashMap<String, Location> as usize
+            })
+        }).collect();
+        let tables: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(tables.iter().all(|&table| table == tables[0]));
+        assert_eq!(index.term_count().unwrap(), 50 + 7 + 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn top_terms_of_a_document() {
+        let dir = scratch_dir("search-top-terms");
+        let docs = ["fast fast rust fast go", "rust rust slow", "go"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let top = |doc_id, n| -> Vec<(String, u32, Option<u32>)> {
+            index.top_terms(doc_id, n).unwrap().into_iter()
+                .map(|m| (m.term, m.df, m.tf))
+                .collect()
+        };
+        // Most frequent first; "go" and "rust" tie, so they're in term order.
+        assert_eq!(top(0, 2), vec![("fast".to_string(), 1, Some(3)),
+                                   ("go".to_string(), 2, Some(1))]);
+        assert_eq!(top(0, 10).len(), 3);
+        assert_eq!(top(1, 1), vec![("rust".to_string(), 2, Some(2))]);
+        assert_eq!(top(2, 10), vec![("go".to_string(), 2, Some(1))]);
+        assert!(top(3, 10).is_empty());
+        assert!(top(0, 0).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn search_returns_hits_with_scores_paths_and_frequencies() {
+        let dir = scratch_dir("search-hits");
+        let docs = ["fast cars", "fast fast fast", "slow", "fast slow"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let info = |path: &str, tokens| DocInfo { path: PathBuf::from(path), tokens };
+        let docs: HashMap<u32, DocInfo> =
+            vec![(0, info("a.txt", 2)), (1, info("b.txt", 3))].into_iter().collect();
+        let options = SearchOptions { docs: Some(&docs), ..SearchOptions::default() };
+
+        let hits = search(&index, "Fast", &options).unwrap();
+        let ids: Vec<u32> = hits.iter().map(|hit| hit.doc_id).collect();
+        assert_eq!(ids, vec![0, 1, 3]);
+        assert_eq!(hits[1].path, Some(PathBuf::from("b.txt")));
+        assert_eq!(hits[2].path, None);
+        assert_eq!(hits[1].term_freqs, vec![("Fast".to_string(), Some(3))]);
+        // 4 documents, 3 of them with "fast". The two with known lengths
+        // average 2.5 tokens; the other is taken to be average.
+        let idf = (5.0f64 / 4.0).ln() + 1.0;
+        let expected = [term_weight(1, 0.8), term_weight(3, 1.2), term_weight(1, 1.0)];
+        for (hit, weight) in hits.iter().zip(expected) {
+            assert!((hit.score - weight * idf).abs() < 1e-9, "{}", hit.score);
+        }
+
+        // A rarer term counts for more.
+        let hits = search(&index, "fast slow", &options).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].term_freqs,
+                   vec![("fast".to_string(), Some(1)), ("slow".to_string(), Some(1))]);
+
+        let ranked = SearchOptions { sort: SortOrder::ByScore, limit: Some(2), ..options };
+        let ids: Vec<u32> = search(&index, "fast", &ranked).unwrap().iter()
+            .map(|hit| hit.doc_id)
+            .collect();
+        assert_eq!(ids, vec![1, 0]);
+        assert!(search(&index, "", &options).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sort_orders() {
+        let dir = scratch_dir("search-sort");
+        let docs = ["fast", "fast fast fast", "fast fast", "fast"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        // All the same length, so the scores go by how often "fast" appears.
+        // Document 3 has no path.
+        let info = |path: &str| DocInfo { path: PathBuf::from(path), tokens: 2 };
+        let doc_stats: HashMap<u32, DocInfo> =
+            vec![(0, info("b.txt")), (1, info("c.txt")), (2, info("a.txt"))].into_iter().collect();
+        let ids = |sort| -> Vec<u32> {
+            let options = SearchOptions { sort, docs: Some(&doc_stats),
+                                          ..SearchOptions::default() };
+            search(&index, "fast", &options).unwrap().iter().map(|hit| hit.doc_id).collect()
+        };
+        assert_eq!(ids(SortOrder::ByScore), vec![1, 2, 0, 3]);
+        assert_eq!(ids(SortOrder::ByDocId), vec![0, 1, 2, 3]);
+        assert_eq!(ids(SortOrder::ByPath), vec![2, 0, 1, 3]);
+        assert_eq!(SortOrder::default(), SortOrder::ByDocId);
+
+        // The limit applies after sorting.
+        let options = SearchOptions { sort: SortOrder::ByPath, limit: Some(1),
+                                      docs: Some(&doc_stats), ..SearchOptions::default() };
+        let hits = search(&index, "fast", &options).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].path, Some(PathBuf::from("a.txt")));
+
+        let options = SearchOptions { sort: SortOrder::ByPath, ..SearchOptions::default() };
+        assert!(matches!(search(&index, "fast", &options), Err(Error::Io(_))));
+        assert_eq!(SortOrder::from_name("by-path").unwrap(), SortOrder::ByPath);
+        assert!(SortOrder::from_name("by-size").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn term_weight_levels_off_and_favors_short_documents() {
+        let mut last = 0.0;
+        for tf in [1, 2, 10, 1000, 100_000, u32::MAX] {
+            let weight = term_weight(tf, 1.0);
+            assert!(weight > last && weight < TF_SATURATION + 1.0, "{}: {}", tf, weight);
+            last = weight;
+        }
+        assert!(term_weight(3, 0.5) > term_weight(3, 1.0));
+        assert!(term_weight(3, 1.0) > term_weight(3, 4.0));
+        assert!(term_weight(1, 0.0).is_finite());
+    }
+
+    #[test]
+    fn a_hundred_thousand_repeats_give_a_sane_score() {
+        let dir = scratch_dir("search-huge-tf");
+        let huge = "spam ".repeat(100_000);
+        let docs = [huge.as_str(), "spam and eggs", "eggs"];
+        let index = Index::open(build_index(&dir, 0, &docs, &DefaultTokenizer)).unwrap();
+        let info = |tokens| DocInfo { path: PathBuf::from("doc.txt"), tokens };
+        let doc_stats: HashMap<u32, DocInfo> =
+            vec![(0, info(100_000)), (1, info(3)), (2, info(1))].into_iter().collect();
+
+        let mut huge_scores = vec![];
+        for docs in [None, Some(&doc_stats)] {
+            let options = SearchOptions { docs, sort: SortOrder::ByScore,
+                                          ..SearchOptions::default() };
+            let hits = search(&index, "spam", &options).unwrap();
+            assert_eq!(hits.len(), 2);
+            let huge = hits.iter().find(|hit| hit.doc_id == 0).unwrap();
+            assert_eq!(huge.term_freqs, vec![("spam".to_string(), Some(100_000))]);
+            let max = (TF_SATURATION + 1.0) * idf(3, 2);
+            for hit in &hits {
+                assert!(hit.score.is_finite() && hit.score > 0.0 && hit.score < max,
+                        "{}: {}", hit.doc_id, hit.score);
+            }
+            huge_scores.push(huge.score);
+        }
+        // Knowing how long the document is makes all those repeats count for
+        // less.
+        assert!(huge_scores[1] < huge_scores[0], "{:?}", huge_scores);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn doc_stats_are_read_from_the_tsv_file() {
+        let text = "doc_id\tpath\ttokens\tdistinct_terms\tavg_token_len\n\
+                    0\tdocs/a.txt\t2\t2\t4.00\n\
+                    7\tdocs/tab\\there\\\\.txt\t1\t1\t3.00\n";
+        let docs = read_doc_stats(text.as_bytes()).unwrap();
+        assert_eq!(docs.len(), 2);
+        assert_eq!(docs[&0], DocInfo { path: PathBuf::from("docs/a.txt"), tokens: 2 });
+        assert_eq!(docs[&7].path, PathBuf::from("docs/tab\there\\.txt"));
+        assert_eq!(average_tokens(&docs), Some(1.5));
+        let bad = read_doc_stats(&b"doc_id\tpath\ttokens\tx\nseven\tp\t1\n"[..]);
+        assert_eq!(bad.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let bad = read_doc_stats(&b"doc_id\tpath\ttokens\tx\n7\tp\tmany\n"[..]);
+        assert_eq!(bad.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(read_doc_stats(&b"term,doc_id,freq\n"[..]).is_err());
+    }
+
+    #[test]
+    fn query_terms_are_lowercased() {
+        let dir = scratch_dir("search-lowercase");
+        let index = Index::open(build_index(&dir, 0, DOCS, &DefaultTokenizer)).unwrap();
+        assert_eq!(index.case_locale(), CaseLocale::Default);
+        assert_eq!(index.query(&["RUST", "Fast"]).unwrap(), vec![0]);
+        assert_eq!(index.hits("Go").unwrap(), index.hits("go").unwrap());
+        assert_eq!(index.doc_freq("GO").unwrap(), 2);
+        assert_eq!(index.display_form("Rust").unwrap(), Some("rust"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn query_terms_are_lowercased_like_the_documents() {
+        let dir = scratch_dir("search-turkic");
+        let tokenizer = LocaleTokenizer::new(Box::new(DefaultTokenizer), CaseLocale::Turkic);
+        let path = build_index(&dir, 0, &["IRMAK", "\u{130}stanbul"], &tokenizer);
+        let index = Index::open(path).unwrap();
+        assert_eq!(index.case_locale(), CaseLocale::Turkic);
+        assert_eq!(index.query(&["IRMAK"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["\u{131}rmak"]).unwrap(), vec![0]);
+        assert_eq!(index.query(&["irmak"]).unwrap(), Vec::<u32>::new());
+        assert_eq!(index.query(&["\u{130}STANBUL"]).unwrap(), vec![1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use crate::index::{InMemoryIndex, Tokenizer};
+use crate::merge::{FileMerge, MERGED_FILENAME};
+
+/// A new, empty directory for a test to write files in, named after the test.
+/// Anything left there by an earlier run is removed first.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("fingertips-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Index `docs` with `tokenizer`, numbering them from `first_id`, and write
+/// the finished index to `dir`. Returns the path of the index file.
+pub fn build_index(dir: &Path, first_id: usize, docs: &[&str], tokenizer: &dyn Tokenizer)
+    -> PathBuf
+{
+    let mut index = InMemoryIndex::new();
+    for (i, text) in docs.iter().enumerate() {
+        index.merge(InMemoryIndex::from_single_document(first_id + i, text, tokenizer), None);
+    }
+    let mut merge = FileMerge::new(dir, None);
+    merge.add_last_index(index);
+    merge.finish().unwrap();
+    dir.join(MERGED_FILENAME)
+}
@@ -2,20 +2,51 @@ use std::io::{self, BufWriter};
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
+use crate::compress::Codec;
+
+/// The size of the buffer for writing each temporary file. Index files are
+/// written sequentially, in one pass, so a big buffer means fewer system
+/// calls, but there may be several of these at once.
+const WRITE_BUFFER_SIZE: usize = 1 << 20;
+
 #[derive(Clone)]
 pub struct TmpDir {
     dir: PathBuf,
-    n: usize
+    n: usize,
+    codec: Codec
 }
 
 impl TmpDir {
     pub fn new<P: AsRef<Path>>(dir: P) -> TmpDir {
         TmpDir {
             dir: dir.as_ref().to_owned(),
-            n: 1
+            n: 1,
+            codec: Codec::Raw
         }
     }
 
+    /// Ask for the index files written to this directory to be stored using
+    /// `codec`. Compressing saves disk space during a big build, at some cost
+    /// in CPU time. It's up to the code that writes each file to check
+    /// `codec`.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// The codec to use for index files written to this directory.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// True if `filename` looks like the name of a temporary file created by
+    /// `TmpDir::create`.
+    pub fn is_tmp_filename(filename: &str) -> bool {
+        filename.len() == "tmp00000000.dat".len()
+            && filename.starts_with("tmp")
+            && filename.ends_with(".dat")
+            && filename[3..11].chars().all(|ch| ch.is_ascii_hexdigit())
+    }
+
     pub fn create(&mut self) -> io::Result<(PathBuf, BufWriter<File>)> {
         let mut r#try = 1;
         loop {
@@ -27,7 +58,7 @@ impl TmpDir {
                   .open(&filename)
             {
                 Ok(f) =>
-                    return Ok((filename, BufWriter::new(f))),
+                    return Ok((filename, BufWriter::with_capacity(WRITE_BUFFER_SIZE, f))),
                 Err(exc) =>
                     if r#try < 999 && exc.kind() == io::ErrorKind::AlreadyExists {
                         // keep going
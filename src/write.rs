@@ -1,88 +1,382 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, BufWriter, SeekFrom};
 use std::io::prelude::*;
-use std::path::PathBuf;
-use crate::index::InMemoryIndex;
+use std::path::{Path, PathBuf};
+use crate::compress::Codec;
+use crate::index::{CaseLocale, DocRange, InMemoryIndex, TermOrder, hit_document_ids};
 use crate::tmp::TmpDir;
 use byteorder::{LittleEndian, WriteBytesExt};
 
 /// Writer for saving an index to a binary file.
 ///
-/// The first 8 bytes of the index file contain the offset of the table of
-/// contents, in bytes. Then come the main entries, all stored back-to-back
-/// with no particular metadata.
+/// An index file starts with the 4 bytes `MAGIC`, then the `FORMAT_VERSION`
+/// it was written in (a u32). The next 8 bytes contain the offset of the
+/// table of contents, in bytes. The next 8 bytes are the range of document
+/// ids covered by the file (two u32 values, `start` and `end`). Then come the
+/// main entries, all stored back-to-back with no particular metadata. The
+/// table of contents comes last.
 ///
-
-/// An index file has two parts. The main part of the file is a sequence of
-/// entries, stored back-to-back; the
+/// The top byte of the table of contents offset says which `Codec` the main
+/// entries are stored with (see `IndexFileWriter::with_codec`). The next bit
+/// says whether the file keeps spellings, the next whether it was built
+/// without frequencies, and the 6 bits below that which `CaseLocale` the
+/// documents were lowercased with. With any codec
+/// but `Codec::Raw`, each main entry is stored as its compressed length (a
+/// u64) followed by the compressed data. The table of contents still gives
+/// the offsets and sizes of the entries as if they weren't compressed.
+///
+/// A file built without frequencies (see `IndexFileWriter::drop_frequencies`)
+/// stores just the document id of each hit in its main entries, 4 bytes
+/// apiece, rather than the whole hit.
+///
+/// Each entry in the table of contents gives the term, its document count,
+/// and the offset and size of its main entry. If the file keeps spellings
+/// (see `IndexFileWriter::keep_spellings`), that's followed by the number of
+/// different spellings of the term, then each spelling's count and text.
+///
+/// All integers are stored little-endian, whatever kind of machine writes
+/// the file, so an index built on one machine can be read on any other.
 pub struct IndexFileWriter {
-    /// The number of bytes written so far.
+    /// The number of bytes of index data written so far, before compression.
     offset: u64,
 
+    /// The number of bytes actually written to the file so far, not counting
+    /// the table of contents.
+    file_offset: u64,
+
     /// The open file we're writing to.
     writer: BufWriter<File>,
 
+    /// How to store the main entries.
+    codec: Codec,
+
+    /// If we're compressing, the data for the current main entry, which is
+    /// compressed and written out all at once when the entry is finished.
+    pending: Vec<u8>,
+
     /// The table of contents for this file.
-    contents_buf: Vec<u8>
+    contents_buf: Vec<u8>,
+
+    /// Whether the table of contents includes each term's spellings.
+    spellings: bool,
+
+    /// False if the main entries store only document ids.
+    frequencies: bool,
+
+    /// How the documents were lowercased.
+    case_locale: CaseLocale
 }
 
+/// The first 4 bytes of every index file, marking it as one.
+pub const MAGIC: [u8; 4] = *b"FTIX";
+
+/// The version of the index file format that this crate writes, and the only
+/// one it can read. It goes up whenever the format changes in a way that
+/// older code would misread.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The size of the header at the start of every index file, in bytes.
+pub const HEADER_SIZE: u64 = 24;
+
+/// Where the table of contents offset is stored in the header: right after
+/// the magic number and the version.
+const CONTENTS_OFFSET_POSITION: u64 = 8;
+
+/// Where the codec id is stored in the table of contents offset: the top
+/// byte.
+pub const CODEC_SHIFT: u32 = 56;
+
+/// The bit of the table of contents offset that says the file keeps
+/// spellings.
+pub const SPELLINGS_FLAG: u64 = 1 << 55;
+
+/// The bit of the table of contents offset that says the file was built
+/// without frequencies.
+pub const NO_FREQUENCIES_FLAG: u64 = 1 << 54;
+
+/// Where the case locale id is stored in the table of contents offset: the 6
+/// bits below `NO_FREQUENCIES_FLAG`. The bits below it are the offset itself.
+pub const CASE_LOCALE_SHIFT: u32 = 48;
+
 impl IndexFileWriter {
-    pub fn new(mut f: BufWriter<File>) -> io::Result<IndexFileWriter> {
-        const HEADER_SIZE: u64 = 8;
+    pub fn new(f: BufWriter<File>, docs: DocRange) -> io::Result<IndexFileWriter> {
+        IndexFileWriter::with_codec(f, docs, Codec::Raw)
+    }
+
+    /// Like `new`, but store the main entries using `codec`. Compressing makes
+    /// the file smaller at the cost of some CPU time. `IndexFileReader`
+    /// decompresses the entries automatically.
+    pub fn with_codec(mut f: BufWriter<File>, docs: DocRange, codec: Codec)
+        -> io::Result<IndexFileWriter>
+    {
+        f.write_all(&MAGIC)?;
+        f.write_u32::<LittleEndian>(FORMAT_VERSION)?;
         f.write_u64::<LittleEndian>(0)?;
+        f.write_u32::<LittleEndian>(docs.start)?;
+        f.write_u32::<LittleEndian>(docs.end)?;
         Ok(IndexFileWriter {
             offset: HEADER_SIZE,
+            file_offset: HEADER_SIZE,
             writer: f,
-            contents_buf: vec![]
+            codec,
+            pending: vec![],
+            contents_buf: vec![],
+            spellings: false,
+            frequencies: true,
+            case_locale: CaseLocale::Default
         })
     }
 
+    /// The number of bytes of index data written so far, before compression;
+    /// this is where the next main entry will start.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Store each term's spellings (see `InMemoryIndex::spellings`) in the
+    /// table of contents. Call this before writing any entries.
+    pub fn keep_spellings(&mut self) {
+        self.spellings = true;
+    }
+
+    /// Store only the document id of each hit, leaving out the frequencies and
+    /// offsets, for an index built with `InMemoryIndex::drop_frequencies`.
+    /// Call this before writing any entries. The hits passed to `write_main`
+    /// are still whole hits; it keeps just the ids.
+    pub fn drop_frequencies(&mut self) {
+        self.frequencies = false;
+    }
+
+    /// Record that the documents were lowercased following `locale`, so that
+    /// searches can lowercase query terms the same way. The default is
+    /// `CaseLocale::Default`.
+    pub fn set_case_locale(&mut self, locale: CaseLocale) {
+        self.case_locale = locale;
+    }
+
     pub fn write_main(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.writer.write_all(buf)?;
+        let ids;
+        let buf = if self.frequencies {
+            buf
+        } else {
+            ids = hit_document_ids(buf)?;
+            &ids[..]
+        };
+        if self.codec == Codec::Raw {
+            self.writer.write_all(buf)?;
+            self.file_offset += buf.len() as u64;
+        } else {
+            self.pending.extend_from_slice(buf);
+        }
         self.offset += buf.len() as u64;
         Ok(())
     }
 
-    pub fn write_contents_entry(&mut self, term: String, df: u32, offset: u64, nbytes: u64) {
+    /// Add an entry to the table of contents, for the main entry written
+    /// since the last call. Every main entry must have a contents entry.
+    ///
+    /// `spellings` is ignored unless the file keeps spellings.
+    pub fn write_contents_entry(&mut self, term: String, df: u32, offset: u64, nbytes: u64,
+                                spellings: &[(String, u32)])
+        -> io::Result<()>
+    {
+        if self.codec != Codec::Raw {
+            let mut compressed = vec![];
+            if self.frequencies {
+                self.codec.compress(&self.pending, &mut compressed)?;
+            } else {
+                self.codec.compress_doc_ids(&self.pending, &mut compressed)?;
+            }
+            self.pending.clear();
+            self.writer.write_u64::<LittleEndian>(compressed.len() as u64)?;
+            self.writer.write_all(&compressed)?;
+            self.file_offset += 8 + compressed.len() as u64;
+        }
         self.contents_buf.write_u64::<LittleEndian>(offset).unwrap();
         self.contents_buf.write_u64::<LittleEndian>(nbytes).unwrap();
         self.contents_buf.write_u32::<LittleEndian>(df).unwrap();
         let bytes = term.bytes();
         self.contents_buf.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
         self.contents_buf.extend(bytes);
+        if self.spellings {
+            self.contents_buf.write_u32::<LittleEndian>(spellings.len() as u32).unwrap();
+            for (spelling, count) in spellings {
+                self.contents_buf.write_u32::<LittleEndian>(*count).unwrap();
+                self.contents_buf.write_u32::<LittleEndian>(spelling.len() as u32).unwrap();
+                self.contents_buf.extend(spelling.bytes());
+            }
+        }
+        Ok(())
     }
 
     /// Finish writing the index file and close it.
     pub fn finish(mut self) -> io::Result<()> {
-        let contents_start = self.offset;
+        let contents_start = self.file_offset;
         self.writer.write_all(&self.contents_buf)?;
         println!("{} bytes main, {} bytes total", contents_start, contents_start + self.contents_buf.len() as u64);
-        self.writer.seek(SeekFrom::Start(0))?;
-        self.writer.write_u64::<LittleEndian>(contents_start)?;
+        let mut flags = (self.codec.id() as u64) << CODEC_SHIFT;
+        if self.spellings {
+            flags |= SPELLINGS_FLAG;
+        }
+        if !self.frequencies {
+            flags |= NO_FREQUENCIES_FLAG;
+        }
+        flags |= (self.case_locale.id() as u64) << CASE_LOCALE_SHIFT;
+        self.writer.seek(SeekFrom::Start(CONTENTS_OFFSET_POSITION))?;
+        self.writer.write_u64::<LittleEndian>(contents_start | flags)?;
+        // Flush explicitly: if the `BufWriter` were left to flush itself when
+        // dropped, any error would be silently ignored.
+        self.writer.flush()?;
         Ok(())
     }
 }
 
-pub fn write_index_to_tmp_file(index: InMemoryIndex, tmp_dir: &mut TmpDir) -> io::Result<PathBuf> {
+/// Write `index` to a new temporary file, with the terms sorted in `order`.
+/// The hits are stored using `tmp_dir`'s codec (see `TmpDir::set_codec`).
+///
+/// This empties `index`, whether it succeeds or not, but leaves its memory
+/// allocated so it can be reused (see `InMemoryIndex::clear`).
+///
+/// If this fails, the partly written file is deleted. If it fails because the
+/// disk is full, the error says which file it was and what to do about it.
+pub fn write_index_to_tmp_file(index: &mut InMemoryIndex, tmp_dir: &mut TmpDir,
+                               order: &TermOrder)
+    -> io::Result<PathBuf>
+{
     let (filename, f) = tmp_dir.create()?;
-    let mut writer = IndexFileWriter::new(f)?;
+    let result = write_index(index, f, tmp_dir.codec(), order);
+    index.clear();
+    match result {
+        Ok(()) => {
+            println!("wrote file {:?}", filename);
+            Ok(filename)
+        }
+        Err(err) => {
+            // Don't let the partial file go on using up space; but report the
+            // original error, not any error deleting it.
+            let _ = fs::remove_file(&filename);
+            Err(explain_write_error(err, &filename))
+        }
+    }
+}
+
+/// Write `index` to `f`, with the terms sorted in `order`.
+fn write_index(index: &mut InMemoryIndex, f: BufWriter<File>, codec: Codec, order: &TermOrder)
+    -> io::Result<()>
+{
+    let InMemoryIndex { docs, ref mut map, ref mut spellings, case_locale, presence_only, .. } =
+        *index;
+    let mut writer = IndexFileWriter::with_codec(f, docs, codec)?;
+    writer.set_case_locale(case_locale);
+    if presence_only {
+        writer.drop_frequencies();
+    }
+    if !spellings.is_empty() {
+        writer.keep_spellings();
+    }
 
     // The merge algorithm requires the entries within each file to be sorted by term.
     // Sort before writing anything.
-    let mut index_as_vec: Vec<_> = index.map.into_iter().collect();
-    index_as_vec.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    let mut index_as_vec: Vec<_> = map.drain().collect();
+    index_as_vec.sort_by(|(a, _), (b, _)| order.compare(a, b));
 
-    for (term, hits) in index_as_vec {
-        let df = hits.len() as u32;
+    for (term, postings) in index_as_vec {
+        let df = postings.len() as u32;
         let start = writer.offset;
-        for buffer in hits {
-            writer.write_main(&buffer)?;
+        for hit in postings.hits() {
+            writer.write_main(hit)?;
         }
         let stop = writer.offset;
-        writer.write_contents_entry(term, df, start, stop - start);
+        let term_spellings = spellings.remove(&term).unwrap_or_default();
+        writer.write_contents_entry(term, df, start, stop - start, &term_spellings)?;
     }
 
-    writer.finish()?;
-    println!("wrote file {:?}", filename);
-    Ok(filename)
+    writer.finish()
+}
+
+/// If `err` means the disk filled up while writing the temporary file
+/// `filename`, replace it with an error that says so plainly. Other errors
+/// are returned unchanged.
+pub(crate) fn explain_write_error(err: io::Error, filename: &Path) -> io::Error {
+    if err.kind() != io::ErrorKind::StorageFull {
+        return err;
+    }
+    let dir = filename.parent().unwrap_or(filename);
+    io::Error::new(err.kind(), format!(
+        "ran out of disk space writing temporary index file {}: {}. The temporary \
+         files for a big index can take up several times the size of the \
+         finished index; free up space in {}, or use --temp-dir to put the \
+         temporary files on a disk with more room",
+        filename.display(), err, dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::index::{DefaultTokenizer, TermHasher};
+    use crate::test_util::scratch_dir;
+
+    #[test]
+    fn explains_storage_full() {
+        let filename = Path::new("/scratch/tmp00000001.dat");
+        let err = io::Error::new(io::ErrorKind::StorageFull, "No space left on device");
+        let err = explain_write_error(err, filename);
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        let message = err.to_string();
+        assert!(message.contains("ran out of disk space"), "{}", message);
+        assert!(message.contains("/scratch/tmp00000001.dat: No space left on device"),
+                "{}", message);
+        assert!(message.contains("free up space in /scratch"), "{}", message);
+        assert!(message.contains("--temp-dir"), "{}", message);
+    }
+
+    #[test]
+    fn other_errors_are_unchanged() {
+        let err = io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied");
+        let err = explain_write_error(err, Path::new("/scratch/tmp00000001.dat"));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(err.to_string(), "Permission denied");
+    }
+
+    /// The index file doesn't depend on the order the hash table happens to
+    /// keep the terms in, so it comes out the same whichever `TermHasher` the
+    /// crate is built with (see the `fast-hash` feature).
+    #[test]
+    fn file_does_not_depend_on_hash_order() {
+        let dir = scratch_dir("write-hash-order");
+        let mut tmp_dir = TmpDir::new(&dir);
+        let text = "the quick brown fox jumps over the lazy dog while five boxing wizards \
+                    jump quickly and a wizard's job is to vex chumps quickly in fog";
+        let mut files = vec![];
+        let mut orders = vec![];
+        for capacity in [0, 4096] {
+            let mut index = InMemoryIndex::from_single_document(0, text, &DefaultTokenizer);
+            // Rehashing into a table of another size puts the terms in a
+            // different order.
+            let mut map = HashMap::with_capacity_and_hasher(capacity, TermHasher::default());
+            map.extend(index.map.drain());
+            index.map = map;
+            orders.push(index.map.keys().cloned().collect::<Vec<_>>());
+            let filename = write_index_to_tmp_file(&mut index, &mut tmp_dir, &TermOrder::bytes())
+                .unwrap();
+            files.push(fs::read(filename).unwrap());
+        }
+        assert_ne!(orders[0], orders[1]);
+        assert!(files[0] == files[1]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Writing to `/dev/full` always fails the way a full disk does.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_disk_is_storage_full() {
+        let mut index = InMemoryIndex::from_single_document(0, "some words", &DefaultTokenizer);
+        let f = fs::OpenOptions::new().write(true).open("/dev/full").unwrap();
+        let err = write_index(&mut index, BufWriter::new(f), Codec::Raw, &TermOrder::bytes())
+            .err().unwrap();
+        let err = explain_write_error(err, Path::new("/dev/full"));
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert!(err.to_string().contains("ran out of disk space"), "{}", err);
+    }
 }
@@ -0,0 +1,995 @@
+//! Tests that run the `fingertips` program itself.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{self, Command, Output, Stdio};
+use fingertips::index::{CaseLocale, DocRange};
+use fingertips::search::Index;
+
+/// A new, empty directory for a test to write files in, named after the test.
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("fingertips-cli-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write each of `docs` to a `.txt` file in a new directory, `dir/name`, in
+/// order, and return the directory.
+fn write_docs(dir: &Path, name: &str, docs: &[&str]) -> PathBuf {
+    let docs_dir = dir.join(name);
+    fs::create_dir(&docs_dir).unwrap();
+    for (i, text) in docs.iter().enumerate() {
+        fs::write(docs_dir.join(format!("{}.txt", i)), text).unwrap();
+    }
+    docs_dir
+}
+
+/// Run `fingertips` with `args`, in `dir`.
+fn fingertips(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fingertips"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn merge_two_shards() {
+    let dir = scratch_dir("merge");
+    write_docs(&dir, "docs-a", &["red fish"]);
+    write_docs(&dir, "docs-b", &["blue fish"]);
+    for (shard, docs, base) in [("a", "docs-a", "0"), ("b", "docs-b", "5")] {
+        let output = fingertips(&dir, &["-1", "--docid-base", base, "-o", shard, docs]);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    }
+
+    let output = fingertips(&dir, &["--merge", "-o", "merged", "b", "a/index.dat"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let index = Index::open(dir.join("merged/index.dat")).unwrap();
+    assert_eq!(index.query(&["fish"]).unwrap(), vec![0, 5]);
+    assert_eq!(index.query(&["blue"]).unwrap(), vec![5]);
+
+    // The same shard twice: the second copy is renumbered to follow the first.
+    let output = fingertips(&dir, &["--merge", "-o", "merged", "a", "a"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let index = Index::open(dir.join("merged/index.dat")).unwrap();
+    assert_eq!(index.query(&["red"]).unwrap(), vec![0, 1]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn output_files_are_not_indexed() {
+    let dir = scratch_dir("outputs");
+    let docs = write_docs(&dir, "docs", &["hello"]);
+    // A temporary file left behind by a build that was interrupted.
+    fs::write(docs.join("tmp00000001.dat"), "stale").unwrap();
+    let args = ["-1", "-o", "docs", "--stats-json", "--output-format", "csv",
+                "--doc-stats", "docs/doc-stats.tsv", "--profile", "docs/profile.tsv",
+                "--temp-dir", "docs", "docs"];
+    // The second time, every output file is already there.
+    for _ in 0..2 {
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        let index = Index::open(docs.join("index.dat")).unwrap();
+        assert_eq!(index.doc_range().len(), 1);
+        assert_eq!(index.query(&["hello"]).unwrap(), vec![0]);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn exit_status_2_when_some_files_cannot_be_read() {
+    let dir = scratch_dir("partial");
+    let docs = write_docs(&dir, "docs", &["good words", "more good words"]);
+    // Not UTF-8, so it can't be read as text. (Permissions won't do, since
+    // tests may run as root.)
+    fs::write(docs.join("bad.txt"), b"bad \xff\xfe words").unwrap();
+    for threading in [&["-1"][..], &[][..]] {
+        let mut args = threading.to_vec();
+        args.extend(["-o", "out", "docs"]);
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("1 file(s) could not be read"), "{}", stdout);
+        let index = Index::open(dir.join("out/index.dat")).unwrap();
+        assert_eq!(index.query(&["good"]).unwrap().len(), 2);
+        assert_eq!(index.doc_freq("bad").unwrap(), 0);
+    }
+
+    // If no index can be built at all, the status is 1.
+    let output = fingertips(&dir, &["-o", "out", "missing"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn quiet_errors_after_summarizes_the_rest() {
+    let dir = scratch_dir("quiet-errors");
+    let docs = write_docs(&dir, "docs", &["good words"]);
+    for i in 0..10 {
+        fs::write(docs.join(format!("bad{}.txt", i)), b"\xff").unwrap();
+    }
+    for threading in [&["-1"][..], &[][..]] {
+        let mut args = threading.to_vec();
+        args.extend(["--quiet-errors-after", "3", "-o", "out", "docs"]);
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(2), "{:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let errors = stdout.lines().filter(|line| line.starts_with("error: ")).count();
+        assert_eq!(errors, 3, "{}", stdout);
+        assert!(stdout.contains("... and 7 more errors\n\
+                                 10 file(s) could not be read"), "{}", stdout);
+    }
+
+    // Without the option, every error is printed, and there's no summary.
+    let output = fingertips(&dir, &["-1", "-o", "out", "docs"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.lines().filter(|line| line.starts_with("error: ")).count(), 10);
+    assert!(!stdout.contains("more errors"), "{}", stdout);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn max_file_size_skips_the_big_file() {
+    let dir = scratch_dir("max-file-size");
+    let big = format!("huge {}", "filler ".repeat(100));
+    write_docs(&dir, "docs", &["small words", &big, "more small words"]);
+    for threading in [&["-1"][..], &[][..]] {
+        for inputs in [&["docs"][..], &["docs/0.txt", "docs/1.txt", "docs/2.txt"]] {
+            let mut args = threading.to_vec();
+            args.extend(["--max-file-size", "100", "-o", "out"]);
+            args.extend(inputs);
+            let output = fingertips(&dir, &args);
+            assert_eq!(output.status.code(), Some(0), "{:?}", output);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("skipping docs/1.txt: 705 bytes is over the \
+                                     --max-file-size limit of 100"), "{}", stdout);
+
+            let index = Index::open(dir.join("out/index.dat")).unwrap();
+            assert_eq!(index.doc_range().len(), 2);
+            assert_eq!(index.query(&["small"]).unwrap().len(), 2);
+            assert_eq!(index.doc_freq("huge").unwrap(), 0);
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn exit_status_0_when_everything_is_indexed() {
+    let dir = scratch_dir("success");
+    write_docs(&dir, "docs", &["good words"]);
+    let output = fingertips(&dir, &["-o", "out", "docs"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn parallel_reading_matches_single_threaded() {
+    let dir = scratch_dir("parallel-read");
+    // More files than the readers may get ahead by (16 per thread), with
+    // sizes all over the place, so reads finish out of order.
+    let texts: Vec<String> = (0..100)
+        .map(|i| format!("w{} common {}", i, "filler ".repeat(i * 37 % 500)))
+        .collect();
+    let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+    write_docs(&dir, "docs", &texts);
+    let files: Vec<String> = (0..texts.len()).map(|i| format!("docs/{}.txt", i)).collect();
+    for args in [&["-1", "-o", "serial"][..], &["--read-threads", "3", "-o", "parallel"]] {
+        let args: Vec<&str> = args.iter().copied().chain(files.iter().map(String::as_str))
+            .collect();
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    }
+
+    let index = Index::open(dir.join("parallel/index.dat")).unwrap();
+    for i in 0..texts.len() {
+        assert_eq!(index.query(&[&format!("w{}", i)]).unwrap(), vec![i as u32]);
+    }
+    assert!(fs::read(dir.join("serial/index.dat")).unwrap()
+            == fs::read(dir.join("parallel/index.dat")).unwrap(),
+            "parallel index.dat differs from the single-threaded one");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sample_is_reproducible() {
+    let dir = scratch_dir("sample");
+    let texts: Vec<String> = (0..40).map(|i| format!("w{} common", i)).collect();
+    let texts: Vec<&str> = texts.iter().map(String::as_str).collect();
+    write_docs(&dir, "docs", &texts);
+    let sample = |seed: &str, out: &str| {
+        let out = stdout(&dir, &["-1", "--sample-rate", "0.5", "--sample-seed", seed,
+                                 "-o", out, "docs"]);
+        assert!(out.contains(" of 40 documents"), "{}", out);
+    };
+    sample("7", "a");
+    sample("7", "b");
+    sample("8", "c");
+    let read = |out: &str| fs::read(dir.join(out).join("index.dat")).unwrap();
+    assert!(read("a") == read("b"), "the same seed picked different documents");
+    assert!(read("a") != read("c"), "a different seed picked the same documents");
+
+    // The documents picked are numbered consecutively from 0.
+    let index = Index::open(dir.join("a/index.dat")).unwrap();
+    let ndocs = index.doc_range().len();
+    assert!(ndocs > 5 && ndocs < 35, "{} documents sampled", ndocs);
+    assert_eq!(index.query(&["common"]).unwrap(), (0..ndocs).collect::<Vec<u32>>());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn nul_separated_file_names_from_stdin() {
+    let dir = scratch_dir("files-from0");
+    fs::create_dir(dir.join("my docs")).unwrap();
+    fs::write(dir.join("my docs/first file.txt"), "alpha shared").unwrap();
+    fs::write(dir.join("second.txt"), "beta shared").unwrap();
+    fs::write(dir.join("third.txt"), "gamma shared").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fingertips"))
+        .args(["-1", "-0", "-o", "out", "third.txt"])
+        .current_dir(&dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"my docs/first file.txt\0second.txt\0").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+    let index = Index::open(dir.join("out/index.dat")).unwrap();
+    assert_eq!(index.query(&["shared"]).unwrap(), vec![0, 1, 2]);
+    // Files named on the command line come first.
+    assert_eq!(index.query(&["gamma"]).unwrap(), vec![0]);
+    assert_eq!(index.query(&["alpha"]).unwrap(), vec![1]);
+    assert_eq!(index.query(&["beta"]).unwrap(), vec![2]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn compressed_tmp_files_give_the_same_index() {
+    let dir = scratch_dir("compress-tmp");
+    write_docs(&dir, "docs", &["one fish two fish", "red fish blue fish", "one red car"]);
+    let mut outputs = vec![];
+    for compress in [&[][..], &["--compress-tmp"]] {
+        let mut args = vec!["-1", "--flush-every", "1", "-o", "out", "docs"];
+        args.extend(compress);
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+        outputs.push(fs::read(dir.join("out/index.dat")).unwrap());
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn distinct_temp_dir() {
+    let dir = scratch_dir("temp-dir");
+    write_docs(&dir, "docs", &["one fish two fish", "red fish blue fish", "one red car"]);
+    fs::create_dir(dir.join("scratch")).unwrap();
+    let mut outputs = vec![];
+    for temp_dir in [&[][..], &["--temp-dir", "scratch"]] {
+        for threading in [&["-1"][..], &[][..]] {
+            let mut args = vec!["--flush-every", "1", "-o", "out", "docs"];
+            args.extend(temp_dir);
+            args.extend(threading);
+            let output = fingertips(&dir, &args);
+            assert_eq!(output.status.code(), Some(0), "{:?}", output);
+            outputs.push(fs::read(dir.join("out/index.dat")).unwrap());
+            // Temporary files don't outlive the build, in either directory.
+            assert_eq!(fs::read_dir(dir.join("scratch")).unwrap().count(), 0);
+            let names: Vec<_> = fs::read_dir(dir.join("out")).unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(names, ["index.dat"]);
+        }
+    }
+    assert!(outputs.iter().all(|output| *output == outputs[0]));
+
+    // The temporary files really do go to --temp-dir: if it can't be written
+    // to, the build fails.
+    fs::write(dir.join("not-a-dir"), "").unwrap();
+    let output = fingertips(&dir, &["-1", "--flush-every", "1", "--temp-dir", "not-a-dir",
+                                    "-o", "out2", "docs"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn ascii_options() {
+    let dir = scratch_dir("ascii");
+    write_docs(&dir, "docs", &["Plain OLD text", "caf\u{e9} text"]);
+    let output = fingertips(&dir, &["-1", "-o", "default", "docs"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let output = fingertips(&dir, &["-1", "-o", "ascii", "--ascii", "docs"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert_eq!(fs::read(dir.join("default/index.dat")).unwrap(),
+               fs::read(dir.join("ascii/index.dat")).unwrap());
+
+    let output = fingertips(&dir, &["-1", "-o", "strict", "--ascii-strict", "docs"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    let index = Index::open(dir.join("strict/index.dat")).unwrap();
+    assert_eq!(index.query(&["text"]).unwrap().len(), 1);
+    assert_eq!(index.doc_freq("old").unwrap(), 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// Build an index of `docs` in `dir/index`, numbering them in order.
+fn build(dir: &Path, docs: &[&str]) {
+    // One file per directory, named on the command line in order, so that the
+    // document ids don't depend on the order a directory is listed in.
+    let mut args = vec!["-1".to_string(), "-o".to_string(), "index".to_string()];
+    for (i, text) in docs.iter().enumerate() {
+        let name = format!("doc{}", i);
+        write_docs(dir, &name, &[text]);
+        args.push(format!("{}/0.txt", name));
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let output = fingertips(dir, &args);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+}
+
+/// Run `fingertips` with `args` and return what it printed, checking that it
+/// succeeded.
+fn stdout(dir: &Path, args: &[&str]) -> String {
+    let output = fingertips(dir, args);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn search_and_explain() {
+    let dir = scratch_dir("search");
+    build(&dir, &["fast fast rust", "slow rust", "fast cars"]);
+    assert_eq!(stdout(&dir, &["-o", "index", "--search", "rust"]), "0\n1\n");
+    assert_eq!(stdout(&dir, &["--search", "Rust fast", "index"]), "0\n");
+    assert_eq!(stdout(&dir, &["--search", "rust cars", "index/index.dat"]), "");
+    assert_eq!(stdout(&dir, &["--search", "rust fast", "--explain", "index"]),
+               "0\trust tf=1 df=2\tfast tf=2 df=2\n");
+
+    let output = fingertips(&dir, &["--explain", "index"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn no_frequencies_index() {
+    let dir = scratch_dir("no-frequencies");
+    let docs = ["fast fast rust", "slow rust", "fast cars"];
+    write_docs(&dir, "docs", &docs);
+    for codec in ["raw", "varint", "for"] {
+        let full = format!("full-{}", codec);
+        let presence = format!("presence-{}", codec);
+        stdout(&dir, &["-1", "--codec", codec, "-o", &full, "docs"]);
+        stdout(&dir, &["-1", "--codec", codec, "--no-frequencies", "-o", &presence, "docs"]);
+        let full_size = fs::metadata(dir.join(&full).join("index.dat")).unwrap().len();
+        let presence_size = fs::metadata(dir.join(&presence).join("index.dat")).unwrap().len();
+        assert!(presence_size < full_size, "{}: {} < {}", codec, presence_size, full_size);
+
+        let index = Index::open(dir.join(&presence).join("index.dat")).unwrap();
+        assert!(!index.has_frequencies());
+        assert_eq!(index.query(&["fast"]).unwrap().len(), 2);
+        assert_eq!(index.query(&["rust", "fast"]).unwrap().len(), 1);
+        assert_eq!(stdout(&dir, &["--search", "fast -cars", "--count-only", &presence]), "1\n");
+
+        let explained = stdout(&dir, &["--search", "rust fast", "--explain", &presence]);
+        assert!(explained.ends_with("\trust frequencies unavailable df=2\
+                                     \tfast frequencies unavailable df=2\n"),
+                "{}", explained);
+        let output = fingertips(&dir, &["--search", "rust", "--ranked", &presence]);
+        assert_eq!(output.status.code(), Some(1), "{:?}", output);
+        let printed = String::from_utf8_lossy(&output.stdout);
+        assert!(printed.contains("can't be ranked"), "{}", printed);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn index_filenames_finds_documents_by_path() {
+    let dir = scratch_dir("index-filenames");
+    fs::create_dir(dir.join("notes")).unwrap();
+    fs::write(dir.join("notes/groceries.txt"), "eggs, milk, and tips for the barista").unwrap();
+    fs::write(dir.join("notes/rust-tips.txt"), "borrow checking explained").unwrap();
+    let files = ["notes/groceries.txt", "notes/rust-tips.txt"];
+
+    stdout(&dir, &["-1", "-o", "plain", files[0], files[1]]);
+    assert_eq!(stdout(&dir, &["--search", "tips", "plain"]), "0\n");
+    assert_eq!(stdout(&dir, &["--search", "rust", "plain"]), "");
+
+    stdout(&dir, &["-1", "--index-filenames", "-o", "paths", files[0], files[1]]);
+    assert_eq!(stdout(&dir, &["--search", "tips", "paths"]), "0\n1\n");
+    assert_eq!(stdout(&dir, &["--search", "rust tips", "paths"]), "1\n");
+    assert_eq!(stdout(&dir, &["--search", "notes", "paths"]), "0\n1\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn terms_file_allows_only_those_terms() {
+    let dir = scratch_dir("terms-file");
+    write_docs(&dir, "docs", &["Rust is fast, and so are cars", "slow cars", "fast food"]);
+    fs::write(dir.join("terms.txt"), "Rust\n  fast\n\ncars\n").unwrap();
+    stdout(&dir, &["-1", "--terms-file", "terms.txt", "-o", "index",
+                   "docs/0.txt", "docs/1.txt", "docs/2.txt"]);
+
+    let index = Index::open(dir.join("index/index.dat")).unwrap();
+    assert_eq!(index.term_count().unwrap(), 3);
+    assert_eq!(index.query(&["rust"]).unwrap(), vec![0]);
+    assert_eq!(index.query(&["fast"]).unwrap(), vec![0, 2]);
+    assert_eq!(index.query(&["cars"]).unwrap(), vec![0, 1]);
+    for word in ["is", "so", "slow", "food"] {
+        assert_eq!(index.doc_freq(word).unwrap(), 0, "{}", word);
+    }
+    assert_eq!(stdout(&dir, &["--search", "Rust fast", "index"]), "0\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn exclude_glob_leaves_files_out() {
+    let dir = scratch_dir("exclude-glob");
+    fs::create_dir_all(dir.join("corpus/tmp")).unwrap();
+    fs::write(dir.join("corpus/app.txt"), "kept alpha").unwrap();
+    fs::write(dir.join("corpus/app.min.txt"), "minified beta").unwrap();
+    fs::write(dir.join("corpus/tmp/scratch.txt"), "scratch gamma").unwrap();
+    fs::write(dir.join("corpus/notes.txt"), "notes delta").unwrap();
+    stdout(&dir, &["-1", "--exclude-glob", "*.min.txt", "--exclude-glob", "corpus/tmp/*.txt",
+                   "-o", "index", "corpus", "corpus/tmp"]);
+
+    let index = Index::open(dir.join("index/index.dat")).unwrap();
+    assert_eq!(index.doc_range().len(), 2);
+    assert_eq!(index.count(&["kept"]).unwrap(), 1);
+    assert_eq!(index.count(&["notes"]).unwrap(), 1);
+    for word in ["minified", "beta", "scratch", "gamma"] {
+        assert_eq!(index.doc_freq(word).unwrap(), 0, "{}", word);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_several_indexes() {
+    let dir = scratch_dir("search-several");
+    // Two indexes built separately, so both number their documents from 0.
+    for (name, docs) in [("a", ["red fish", "blue fish"]), ("b", ["one fish", "red car"])] {
+        fs::create_dir(dir.join(name)).unwrap();
+        build(&dir.join(name), &docs);
+    }
+    assert_eq!(stdout(&dir, &["--search", "fish", "a/index", "b/index"]),
+               "a/index\t0\na/index\t1\nb/index\t0\n");
+    assert_eq!(stdout(&dir, &["--search", "red", "b/index", "a/index"]),
+               "b/index\t1\na/index\t0\n");
+    assert_eq!(stdout(&dir, &["--search", "fish", "--count-only", "a/index", "b/index"]),
+               "a/index\t2\nb/index\t1\n");
+    assert_eq!(stdout(&dir, &["--search", "one fish", "--explain", "a/index", "b/index"]),
+               "b/index\t0\tone tf=1 df=1\tfish tf=1 df=1\n");
+    // The same index twice is only searched once.
+    assert_eq!(stdout(&dir, &["--search", "blue", "a/index", "a/index/index.dat"]), "1\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn dry_run_count() {
+    let dir = scratch_dir("dry-run-count");
+    write_docs(&dir, "docs", &["one two three", "four, five!"]);
+    assert_eq!(stdout(&dir, &["--dry-run-count", "docs"]),
+               "2 documents, 24 bytes, 5 words\n");
+    assert!(!dir.join("index.dat").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn modes_cannot_be_combined() {
+    let dir = scratch_dir("modes");
+    write_docs(&dir, "docs", &["words"]);
+    let modes = [&["--tokenize-only"][..], &["--dry-run-count"], &["--estimate"], &["--merge"],
+                 &["--search", "words"]];
+    for (i, a) in modes.iter().enumerate() {
+        for b in &modes[i + 1..] {
+            let args: Vec<&str> = a.iter().chain(b.iter()).chain(&["docs"]).copied().collect();
+            let output = fingertips(&dir, &args);
+            assert_eq!(output.status.code(), Some(1), "{:?}", args);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("can't be used together"), "{:?}: {}", args, stdout);
+        }
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn min_doc_count_reports_pruned_terms() {
+    let dir = scratch_dir("min-doc-count");
+    write_docs(&dir, "docs", &["red fish", "blue fish", "red typo"]);
+    let output = fingertips(&dir, &["--min-doc-count", "2", "-o", "index", "docs"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("pruned 2 terms that appear in fewer than 2 documents"), "{}", stdout);
+    let index = Index::open(dir.join("index/index.dat")).unwrap();
+    assert_eq!(index.term_count().unwrap(), 2);
+    assert_eq!(index.doc_freq("typo").unwrap(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn empty_corpus_gives_an_empty_index() {
+    let dir = scratch_dir("empty-corpus");
+    fs::create_dir(dir.join("docs")).unwrap();
+    for threading in [&["-1"][..], &[][..]] {
+        let mut args = threading.to_vec();
+        args.extend(["-o", "index", "docs"]);
+        let stdout = stdout(&dir, &args);
+        assert!(stdout.contains("0 documents indexed; the index is empty"), "{}", stdout);
+
+        let index = Index::open(dir.join("index/index.dat")).unwrap();
+        assert!(index.doc_range().is_empty());
+        assert_eq!(index.term_count().unwrap(), 0);
+        assert!(index.query(&["anything"]).unwrap().is_empty());
+    }
+    assert_eq!(stdout(&dir, &["--search", "anything", "index"]), "");
+    assert_eq!(stdout(&dir, &["--search", "anything", "--count-only", "index"]), "0\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn tokenize_only_prints_the_words_to_be_indexed() {
+    let dir = scratch_dir("tokenize-only");
+    write_docs(&dir, "docs", &["Rust's std::io::Read, 3.14"]);
+    let file = "docs/0.txt";
+    assert_eq!(stdout(&dir, &["--tokenize-only", file]),
+               "==> docs/0.txt <==\nrust\ns\nstd\nio\nread\n3\n14\n");
+    assert_eq!(stdout(&dir, &["--tokenize-only", "--with-positions", "--tokenizer", "numbers",
+                              file]),
+               "==> docs/0.txt <==\n0\trust\n1\ts\n2\tstd\n3\tio\n4\tread\n5\t3.14\n");
+    assert_eq!(stdout(&dir, &["--tokenize-only", "--tokenizer", "code", "--code-paths",
+                              "--max-token-len", "6", file]),
+               "==> docs/0.txt <==\nrust\n'\ns\nstd::i\n,\n3\n.\n14\n");
+    assert_eq!(stdout(&dir, &["--tokenize-only", "--with-positions", "--index-filenames",
+                              "--case-sensitive", file]),
+               "==> docs/0.txt <==\n0\tRust\n1\ts\n2\tstd\n3\tio\n4\tRead\n5\t3\n6\t14\n\
+                7\tdocs\n8\t0\n9\ttxt\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_count_only() {
+    let dir = scratch_dir("search-count");
+    build(&dir, &["a b c", "a b", "a c", "b c"]);
+    for query in ["a", "a b", "b c", "a b c", "missing", "a missing"] {
+        let ids = stdout(&dir, &["--search", query, "index"]);
+        let count = stdout(&dir, &["--search", query, "--count-only", "index"]);
+        assert_eq!(count, format!("{}\n", ids.lines().count()), "{:?}", query);
+    }
+    let output = fingertips(&dir, &["--search", "a", "--count-only", "--explain", "index"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_min_freq() {
+    let dir = scratch_dir("search-min-freq");
+    build(&dir, &["rust", "rust rust go", "rust rust rust go go"]);
+    assert_eq!(stdout(&dir, &["--search", "rust", "--min-freq", "2", "index"]), "1\n2\n");
+    assert_eq!(stdout(&dir, &["--search", "rust go", "--min-freq", "2", "index"]), "2\n");
+    assert_eq!(stdout(&dir, &["--search", "rust", "--min-freq", "3", "--count-only", "index"]),
+               "1\n");
+    assert_eq!(stdout(&dir, &["--search", "rust", "--min-freq", "3", "--explain", "index"]),
+               "2\trust tf=3 df=3\n");
+    let output = fingertips(&dir, &["--min-freq", "2", "-o", "index", "doc0"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_query_syntax() {
+    let dir = scratch_dir("search-query-syntax");
+    build(&dir, &["the quick brown fox", "a quick fox runs", "the slow fox"]);
+    assert_eq!(stdout(&dir, &["--search", "\"quick fox\"", "index"]), "1\n");
+    assert_eq!(stdout(&dir, &["--search", "fox -quick", "index"]), "2\n");
+    assert_eq!(stdout(&dir, &["--search", "qu* -run*", "--count-only", "index"]), "1\n");
+    assert_eq!(stdout(&dir, &["--search", "fox -brown run*", "--explain", "index"]),
+               "1\tfox tf=1 df=3\truns tf=1 df=1\n");
+    assert_eq!(stdout(&dir, &["--search", "(brown OR slow) -quick", "index"]), "2\n");
+    assert_eq!(stdout(&dir, &["--search", "brown OR slow", "--count-only", "index"]), "2\n");
+    let output = fingertips(&dir, &["--search", "fox (quick OR", "index"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let output = fingertips(&dir, &["--search", "fox title:quick", "index"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("character 5"), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_ranked_with_paths() {
+    let dir = scratch_dir("search-ranked");
+    write_docs(&dir, "docs", &["fast cars", "fast fast fast", "slow"]);
+    let output = fingertips(&dir, &["-1", "-o", "index", "--doc-stats", "stats.tsv",
+                                    "docs/0.txt", "docs/1.txt", "docs/2.txt"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+    assert_eq!(stdout(&dir, &["--search", "fast", "index"]), "0\n1\n");
+    // 3 documents, 2 of them with "fast": idf = ln(4 / 3) + 1. Without
+    // --doc-stats, every document counts as average length, so 3 times
+    // weighs 3 * 2.2 / (3 + 1.2), and once weighs 1.
+    assert_eq!(stdout(&dir, &["--search", "fast", "--ranked", "index"]),
+               "1\t2.0235\n0\t1.2877\n");
+    assert_eq!(stdout(&dir, &["--search", "fast", "--ranked", "--limit", "1", "index"]),
+               "1\t2.0235\n");
+    assert_eq!(stdout(&dir, &["--search", "fast", "--doc-stats", "stats.tsv", "index"]),
+               "0\tdocs/0.txt\n1\tdocs/1.txt\n");
+    // The documents average 2 tokens, so document 1 is 1.5 times as long
+    // as average, and its 3 occurrences count for less.
+    assert_eq!(stdout(&dir, &["--search", "fast", "--ranked", "--doc-stats", "stats.tsv",
+                              "index"]),
+               "1\t1.8277\tdocs/1.txt\n0\t1.2877\tdocs/0.txt\n");
+
+    assert_eq!(stdout(&dir, &["--search", "fast", "--sort", "by-score", "index"]),
+               "1\t2.0235\n0\t1.2877\n");
+    assert_eq!(stdout(&dir, &["--search", "fast", "--sort", "by-docid", "index"]), "0\n1\n");
+    assert_eq!(stdout(&dir, &["--search", "fast OR slow", "--sort", "by-path",
+                              "--doc-stats", "stats.tsv", "index"]),
+               "0\tdocs/0.txt\n1\tdocs/1.txt\n2\tdocs/2.txt\n");
+
+    for args in [&["--search", "fast", "--explain", "--ranked", "index"][..],
+                 &["--search", "fast", "--sort", "by-path", "index"],
+                 &["--search", "fast", "--sort", "by-size", "index"],
+                 &["--search", "fast", "--sort", "by-score", "--ranked", "index"],
+                 &["--search", "fast", "--count-only", "--limit", "1", "index"],
+                 &["--ranked", "docs"]] {
+        let output = fingertips(&dir, args);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {:?}", args, output);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn search_prints_matching_lines_with_context() {
+    let dir = scratch_dir("search-context");
+    write_docs(&dir, "docs", &["one\ntwo\nThe quick brown fox\nfour\nfive\nsix\nfoxes\nfox\n",
+                               "no match here"]);
+    let output = fingertips(&dir, &["-1", "-o", "index", "--doc-stats", "stats.tsv",
+                                    "docs/0.txt", "docs/1.txt"]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+    let search = ["--search", "fox", "--doc-stats", "stats.tsv", "--print-matches-with-context",
+                  "index"];
+    assert_eq!(stdout(&dir, &search), "0\tdocs/0.txt\n\t3:The quick brown fox\n\t8:fox\n");
+    let mut with_context = search.to_vec();
+    with_context.extend(["--context", "1"]);
+    assert_eq!(stdout(&dir, &with_context),
+               "0\tdocs/0.txt\n\t2-two\n\t3:The quick brown fox\n\t4-four\n\t--\n\
+                \t7-foxes\n\t8:fox\n");
+
+    for args in [&["--search", "fox", "--print-matches-with-context", "index"][..],
+                 &["--search", "fox", "--doc-stats", "stats.tsv", "--context", "1", "index"]] {
+        let output = fingertips(&dir, args);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {:?}", args, output);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn resume_reading_after_an_interruption() {
+    let dir = scratch_dir("resume");
+    let docs = write_docs(&dir, "docs", &["alpha common", "beta common", "gamma common",
+                                          "delta common", "epsilon common"]);
+    fs::write(docs.join("bad.txt"), [0xff, 0xfe]).unwrap();
+    let args = ["--resume-reading", "--checkpoint-every", "2", "-o", "out",
+                "docs/0.txt", "docs/bad.txt", "docs/1.txt", "docs/2.txt", "docs/3.txt",
+                "docs/4.txt"];
+
+    // Stand in for a crash: with a directory in the way, the finished index
+    // can't be written, but all three pieces are.
+    fs::create_dir_all(dir.join("out/index.dat/in-the-way")).unwrap();
+    let output = fingertips(&dir, &args);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir.join("out/index.dat")).unwrap();
+    // Pretend the crash came before the last piece was recorded.
+    let checkpoint = dir.join("out/checkpoint/checkpoint.tsv");
+    let text = fs::read_to_string(&checkpoint).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4, "{:?}", text);
+    fs::write(&checkpoint, lines[..3].join("\n") + "\n").unwrap();
+    // Documents in the finished pieces aren't read again.
+    fs::write(docs.join("0.txt"), "omega common").unwrap();
+
+    let output = fingertips(&dir, &args);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("resuming: 4 of 6 documents already indexed"), "{}", stdout);
+    assert!(stdout.contains("1 file(s) could not be read"), "{}", stdout);
+    let index = Index::open(dir.join("out/index.dat")).unwrap();
+    assert_eq!(index.doc_range().len(), 5);
+    assert_eq!(index.query(&["common"]).unwrap(), vec![0, 1, 2, 3, 4]);
+    for (word, id) in [("alpha", 0), ("beta", 1), ("gamma", 2), ("delta", 3), ("epsilon", 4)] {
+        assert_eq!(index.query(&[word]).unwrap(), vec![id], "{}", word);
+    }
+    assert_eq!(index.query(&["omega"]).unwrap(), Vec::<u32>::new());
+    assert!(!dir.join("out/checkpoint").exists());
+
+    // A checkpoint from a different build isn't used.
+    fs::create_dir_all(dir.join("out/checkpoint")).unwrap();
+    fs::write(&checkpoint, text).unwrap();
+    let output = fingertips(&dir, &["--resume-reading", "-o", "out", "docs/1.txt"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn case_sensitive_index_and_audit() {
+    let dir = scratch_dir("audit");
+    write_docs(&dir, "docs", &["Rust is fast"]);
+    write_docs(&dir, "more", &["rust and go"]);
+    let args = ["-1", "--case-sensitive", "--audit-terms", "-o", "index", "docs", "more"];
+    let out = stdout(&dir, &args);
+    assert!(out.contains("terms that differ only in case: Rust (1 documents), rust (1 documents)"),
+            "{}", out);
+    assert!(out.contains("1 word(s) are indexed as more than one term"), "{}", out);
+    assert_eq!(stdout(&dir, &["--search", "Rust", "index"]), "0\n");
+    assert_eq!(stdout(&dir, &["--search", "rust", "index"]), "1\n");
+    assert_eq!(stdout(&dir, &["--search", "RUST", "index"]), "");
+
+    let out = stdout(&dir, &["-1", "--audit-terms", "-o", "index", "docs", "more"]);
+    assert!(out.contains("no terms differ only in case"), "{}", out);
+    assert_eq!(stdout(&dir, &["--search", "RUST", "index"]), "0\n1\n");
+
+    let output = fingertips(&dir, &["--case-sensitive", "--locale", "tr", "-o", "index", "docs"]);
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// The documents in `tests/data/golden.dat`, and the arguments it was built
+/// with. Between them they set every field of the header: a docid base, a
+/// codec, spellings, and a case locale.
+const GOLDEN_DOCS: &[&str] = &["Istanbul is big", "İstanbul ile Irmak", "big fish, BIG fish"];
+const GOLDEN_ARGS: &[&str] = &["-1", "--locale", "tr", "--keep-spellings", "--codec", "varint",
+                               "--docid-base", "3", "-o", "index",
+                               "docs/0.txt", "docs/1.txt", "docs/2.txt"];
+
+/// `tests/data/golden.dat` is an index file checked into the repo, so a change
+/// to the file format, or to how it's read on some other platform, shows up as
+/// a failure here. If the format changes on purpose, rebuild it: run
+/// `fingertips` with `GOLDEN_ARGS` on `GOLDEN_DOCS`, and copy the index over.
+#[test]
+fn golden_index_file() {
+    let golden = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/golden.dat");
+    let index = Index::open(&golden).unwrap();
+    assert_eq!(index.doc_range(), DocRange { start: 3, end: 6 });
+    assert_eq!(index.case_locale(), CaseLocale::Turkic);
+    assert_eq!(index.query(&["fish"]).unwrap(), vec![5]);
+    // Turkish lowercases I to dotless ı, in the documents and the query alike.
+    assert_eq!(index.query(&["IRMAK"]).unwrap(), vec![4]);
+    assert_eq!(index.query(&["BIG"]).unwrap(), vec![5]);
+    assert_eq!(index.query(&["big"]).unwrap(), vec![3, 5]);
+    assert_eq!(index.display_form("ırmak").unwrap(), Some("Irmak"));
+    let fish = &index.explain(&["fish"]).unwrap()[0].terms[0];
+    assert_eq!((fish.tf, fish.df), (Some(2), 1));
+
+    // Building the same documents today gives the same bytes.
+    let dir = scratch_dir("golden");
+    write_docs(&dir, "docs", GOLDEN_DOCS);
+    let output = fingertips(&dir, GOLDEN_ARGS);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    assert!(fs::read(dir.join("index/index.dat")).unwrap() == fs::read(&golden).unwrap(),
+            "index.dat differs from {}", golden.display());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+/// A JSON value, as read by `parse_json`.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>)
+}
+
+impl Json {
+    /// The value of the member `key`, if this is an object that has one.
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match self {
+            Json::Number(n) => *n,
+            other => panic!("expected a number, got {:?}", other)
+        }
+    }
+}
+
+/// Parse `text` as a single JSON value, panicking if it isn't one. This is
+/// just enough JSON to check the files `fingertips` writes.
+fn parse_json(text: &str) -> Json {
+    fn skip_ws(s: &mut &str) {
+        *s = s.trim_start();
+    }
+
+    fn expect(s: &mut &str, token: &str) {
+        skip_ws(s);
+        assert!(s.starts_with(token), "expected {:?} at {:?}", token, s);
+        *s = &s[token.len()..];
+    }
+
+    fn string(s: &mut &str) -> String {
+        expect(s, "\"");
+        let mut out = String::new();
+        let mut chars = s.char_indices();
+        while let Some((i, ch)) = chars.next() {
+            match ch {
+                '"' => {
+                    *s = &s[i + 1..];
+                    return out;
+                }
+                '\\' => out.push(match chars.next().map(|(_, ch)| ch) {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('u') => {
+                        let hex: String = (0..4).map(|_| chars.next().unwrap().1).collect();
+                        char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap()
+                    }
+                    Some(ch) => ch,
+                    None => panic!("unterminated string")
+                }),
+                ch => out.push(ch)
+            }
+        }
+        panic!("unterminated string");
+    }
+
+    fn value(s: &mut &str) -> Json {
+        skip_ws(s);
+        match s.chars().next() {
+            Some('{') => {
+                expect(s, "{");
+                let mut members = vec![];
+                skip_ws(s);
+                if !s.starts_with('}') {
+                    loop {
+                        let key = string(s);
+                        expect(s, ":");
+                        members.push((key, value(s)));
+                        skip_ws(s);
+                        if !s.starts_with(',') {
+                            break;
+                        }
+                        expect(s, ",");
+                    }
+                }
+                expect(s, "}");
+                Json::Object(members)
+            }
+            Some('[') => {
+                expect(s, "[");
+                let mut items = vec![];
+                skip_ws(s);
+                if !s.starts_with(']') {
+                    loop {
+                        items.push(value(s));
+                        skip_ws(s);
+                        if !s.starts_with(',') {
+                            break;
+                        }
+                        expect(s, ",");
+                    }
+                }
+                expect(s, "]");
+                Json::Array(items)
+            }
+            Some('"') => Json::String(string(s)),
+            _ => {
+                for (word, json) in [("null", Json::Null), ("true", Json::Bool(true)),
+                                     ("false", Json::Bool(false))] {
+                    if let Some(rest) = s.strip_prefix(word) {
+                        *s = rest;
+                        return json;
+                    }
+                }
+                let len = s.find(|ch: char| !"+-.eE0123456789".contains(ch)).unwrap_or(s.len());
+                let n = s[..len].parse().unwrap_or_else(|_| panic!("bad JSON at {:?}", s));
+                *s = &s[len..];
+                Json::Number(n)
+            }
+        }
+    }
+
+    let mut s = text;
+    let json = value(&mut s);
+    assert!(s.trim().is_empty(), "trailing text after JSON: {:?}", s);
+    json
+}
+
+#[test]
+fn stats_json_describes_the_build() {
+    let dir = scratch_dir("stats-json");
+    let docs = write_docs(&dir, "docs", &["good words", "more good words"]);
+    fs::write(docs.join("bad.txt"), b"bad \xff\xfe words").unwrap();
+    let output = fingertips(&dir, &["-1", "--stats-json", "-o", "out", "docs"]);
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+
+    let stats = parse_json(&fs::read_to_string(dir.join("out/stats.json")).unwrap());
+    assert_eq!(stats.get("documents"), Some(&Json::Number(2.0)));
+    // "good", "words", "more".
+    assert_eq!(stats.get("terms"), Some(&Json::Number(3.0)));
+    let nbytes = fs::metadata(dir.join("out/index.dat")).unwrap().len();
+    assert_eq!(stats.get("bytes"), Some(&Json::Number(nbytes as f64)));
+    assert!(stats.get("elapsed_secs").unwrap().as_f64() >= 0.0);
+    let stages = stats.get("stage_secs").unwrap();
+    assert!(stages.get("index").unwrap().as_f64() >= 0.0, "{:?}", stages);
+    assert_eq!(stats.get("failed_files"),
+               Some(&Json::Array(vec![Json::String("docs/bad.txt".to_string())])));
+    assert!(matches!(stats.get("merged_files"), Some(Json::Array(_))), "{:?}", stats);
+
+    // Written under a temporary name, then renamed, so nothing else is left.
+    let mut names: Vec<_> = fs::read_dir(dir.join("out")).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["index.dat", "stats.json"]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn profile_trace_has_events_for_every_stage() {
+    let dir = scratch_dir("profile");
+    write_docs(&dir, "docs", &["one fish", "two fish", "red fish", "blue fish", "no fish"]);
+    for threading in [&["-1"][..], &[][..]] {
+        let mut args = threading.to_vec();
+        args.extend(["--flush-every", "2", "--profile", "trace.tsv", "-o", "out", "docs"]);
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+        let trace = fs::read_to_string(dir.join("trace.tsv")).unwrap();
+        let mut lines = trace.lines();
+        assert_eq!(lines.next(), Some("stage\titem\tstart_us\tend_us"));
+        let mut stages = vec![];
+        let mut documents_read = vec![];
+        for line in lines {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), 4, "{:?}", line);
+            let start: u64 = fields[2].parse().unwrap();
+            let end: u64 = fields[3].parse().unwrap();
+            assert!(start <= end, "{:?}", line);
+            if fields[0] == "read" {
+                documents_read.push(fields[1].parse::<usize>().unwrap());
+            }
+            if !stages.contains(&fields[0]) {
+                stages.push(fields[0]);
+            }
+        }
+        stages.sort();
+        assert_eq!(stages, ["accumulate", "final merge", "index", "merge", "read", "write"],
+                   "{:?}", threading);
+        documents_read.sort();
+        assert_eq!(documents_read, [0, 1, 2, 3, 4], "{:?}", threading);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn doc_stats_match_hand_counts() {
+    let dir = scratch_dir("doc-stats");
+    write_docs(&dir, "docs", &["The cat sat", "a a a", "", "H\u{e9}llo w\u{f6}rld"]);
+    for threading in [&["-1"][..], &[][..]] {
+        let mut args = threading.to_vec();
+        args.extend(["-o", "index", "--doc-stats", "stats.tsv", "docs/0.txt", "docs/1.txt",
+                     "docs/2.txt", "docs/3.txt"]);
+        let output = fingertips(&dir, &args);
+        assert_eq!(output.status.code(), Some(0), "{:?}", output);
+
+        let stats = fs::read_to_string(dir.join("stats.tsv")).unwrap();
+        let mut lines: Vec<&str> = stats.lines().collect();
+        assert_eq!(lines.remove(0), "doc_id\tpath\ttokens\tdistinct_terms\tavg_token_len");
+        lines.sort();
+        // Token lengths count characters, not bytes: "h\u{e9}llo" is 5.
+        assert_eq!(lines, ["0\tdocs/0.txt\t3\t3\t3.00",
+                           "1\tdocs/1.txt\t3\t1\t1.00",
+                           "2\tdocs/2.txt\t0\t0\t0.00",
+                           "3\tdocs/3.txt\t2\t2\t5.00"], "{:?}", threading);
+    }
+    fs::remove_dir_all(dir).unwrap();
+}